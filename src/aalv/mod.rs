@@ -22,7 +22,11 @@ pub fn write_aalv_file_with_offset<P: AsRef<Path>>(
     path: P,
     file_offset: u64,
 ) -> Result<AalvWriter<BufWriter<File>>> {
-    let f = File::options().create(true).write(true).open(path)?;
+    let f = File::options()
+        .create(true)
+        .write(true)
+        .truncate(false)
+        .open(path)?;
     f.set_len(file_offset)?;
     AalvWriter::new(BufWriter::new(f), file_offset)
 }
@@ -111,7 +115,7 @@ impl<F: BufRead + Seek> AalvReader<F> {
 
             let name: Box<str> = String::from_utf8_lossy(&name_buf).into();
 
-            if name.len() == 0 {
+            if name.is_empty() {
                 self.file_end = self.file.stream_position()?;
                 break;
             }
@@ -131,7 +135,6 @@ impl<F: BufRead + Seek> AalvReader<F> {
         Ok(())
     }
 
-    #[must_use]
     pub fn end(self) -> Result<F> {
         let Self {
             mut file,
@@ -1,11 +1,15 @@
 use std::{
+    cmp::Ordering,
     collections::BTreeMap,
     fmt::{self, Display},
-    io::{self, BufRead, Seek},
+    io::{self, BufRead, Seek, Write},
     path::Path,
 };
 
-use super::{read_aalv_file, write_aalv_file_with_offset, AalvReader, Section};
+use serde::{Deserialize, Serialize};
+
+use super::{read_aalv_file, write_aalv_file_with_offset, AalvReader, AalvWriter, Section};
+use crate::checked_align_end;
 
 mod sec_impl;
 
@@ -19,21 +23,35 @@ pub struct Object {
     pub stack_size: Option<StackSize>,
     pub heap_size: Option<HeapSize>,
     pub segs: BTreeMap<SegmentType, (u16, Vec<u8>)>,
+    /// Placement constraints of the segments, segments without one use the default
+    pub layouts: BTreeMap<SegmentType, SegmentLayout>,
     pub symbols: SymbolTable,
     pub relocation_table: RelocationTable,
+    /// Source lines of the code for debuggers, dropped when linking as the segments move
+    pub line_numbers: Option<LineNumberTable>,
+    /// Names of the undefined symbols that the host provides when the object is loaded
+    pub externs: Option<ExternTable>,
+    /// Names of the global symbols that a global symbol of the same name in another object
+    /// replaces when linking
+    pub weak: Option<WeakTable>,
 }
 
 impl Object {
     pub fn from_aalv_reader<F: BufRead + Seek>(aalvur: &mut AalvReader<F>) -> io::Result<Self> {
         let mut segs = BTreeMap::new();
+        let mut layouts = BTreeMap::new();
 
         while let Some(seg) = aalvur.read_section() {
             let BinarySegment {
                 offset,
                 stype,
+                layout,
                 bytes,
             } = seg?;
 
+            if layout != SegmentLayout::default() {
+                layouts.insert(stype, layout);
+            }
             if segs.insert(stype, (offset, bytes)).is_some() {
                 return Err(io::Error::new(
                     io::ErrorKind::InvalidData,
@@ -42,13 +60,25 @@ impl Object {
             }
         }
 
+        // the stack size used to be written first under the name of the heap size,
+        // so objects from before `_stack_size` can have two `_heap_size` sections
+        let heap_sizes = aalvur.remaing_sections().filter(|&s| s == HeapSize::NAME);
+        let old_stack_size = heap_sizes.count() == 2;
+
         let obj = Object {
             file_offset: aalvur.file_offset,
             entry: aalvur.read_section().transpose()?,
             flags: aalvur.read_section().transpose()?,
-            stack_size: aalvur.read_section().transpose()?,
+            stack_size: match aalvur.read_section().transpose()? {
+                None if old_stack_size => {
+                    let size = aalvur.read_section().transpose()?;
+                    size.map(|HeapSize(size)| StackSize(size))
+                }
+                size => size,
+            },
             heap_size: aalvur.read_section().transpose()?,
             segs,
+            layouts,
             symbols: aalvur
                 .read_section()
                 .transpose()?
@@ -57,6 +87,9 @@ impl Object {
                 .read_section()
                 .transpose()?
                 .unwrap_or_else(|| RelocationTable(Vec::new())),
+            line_numbers: aalvur.read_section().transpose()?,
+            externs: aalvur.read_section().transpose()?,
+            weak: aalvur.read_section().transpose()?,
         };
 
         if aalvur.remaing_sections().any(|s| s.starts_with('_')) {
@@ -79,19 +112,26 @@ impl Object {
         }
     }
     pub fn write_to_file<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut aalvur = write_aalv_file_with_offset(path, self.file_offset)?;
+        self.write(&mut aalvur)
+    }
+    /// Writes the sections of the object, the file offset is not used
+    pub fn write<F: Write + Seek>(&self, aalvur: &mut AalvWriter<F>) -> io::Result<()> {
         let Object {
-            file_offset,
+            file_offset: _,
             entry,
             flags,
             stack_size,
             heap_size,
             segs,
+            layouts,
             symbols,
             relocation_table,
+            line_numbers,
+            externs,
+            weak,
         } = self;
 
-        let mut aalvur = write_aalv_file_with_offset(path, *file_offset)?;
-
         if let Some(entry) = entry {
             aalvur.write_section(entry)?;
         }
@@ -108,6 +148,7 @@ impl Object {
             aalvur.write_section(&BinarySegment {
                 stype,
                 offset,
+                layout: layouts.get(&stype).copied().unwrap_or_default(),
                 bytes: bytes.clone(),
             })?;
         }
@@ -117,6 +158,15 @@ impl Object {
         if !relocation_table.0.is_empty() {
             aalvur.write_section(relocation_table)?;
         }
+        if let Some(line_numbers) = line_numbers {
+            aalvur.write_section(line_numbers)?;
+        }
+        if let Some(externs) = externs {
+            aalvur.write_section(externs)?;
+        }
+        if let Some(weak) = weak {
+            aalvur.write_section(weak)?;
+        }
 
         Ok(())
     }
@@ -139,22 +189,14 @@ impl Object {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct Entry(pub SegmentType, pub u16);
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Default)]
 pub struct Flags {
     pub readable_text: bool,
 }
 
-impl Default for Flags {
-    fn default() -> Self {
-        Flags {
-            readable_text: false,
-        }
-    }
-}
-
 #[derive(Debug, Clone, Copy)]
 pub struct StackSize(pub u16);
 impl Default for StackSize {
@@ -170,19 +212,58 @@ impl Default for HeapSize {
         Self(0x400)
     }
 }
+/// The kind of a segment
+///
+/// Segments are ordered the way they are laid out in memory, so a `BTreeMap` of segments is
+/// iterated in load order
 #[repr(u8)]
-#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum SegmentType {
     Unknown = 0xff,
     Zero = 0,
     Data = 0x10,
     RoData = 0x18,
+    /// Code run before the entry point, placed before all other segments
+    Boot = 0x1c,
     Text = 0x20,
     Heap = 0x70,
+    /// Space reserved for the stack, it has no bytes and its size is the stack size
+    Stack = 0x78,
     // new layout:
     // zero (), text (x), rodata (r), data (rw), heap (rw)
 }
 
+impl PartialOrd for SegmentType {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl SegmentType {
+    /// Where the segment comes in the load order
+    ///
+    /// The discriminants are what is written in object files, so they can't give the order
+    const fn load_rank(self) -> u8 {
+        use self::SegmentType::*;
+        match self {
+            Zero => 0,
+            Boot => 1,
+            RoData => 2,
+            Text => 3,
+            Data => 4,
+            Heap => 5,
+            Stack => 6,
+            Unknown => 7,
+        }
+    }
+}
+
+impl Ord for SegmentType {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.load_rank().cmp(&other.load_rank())
+    }
+}
+
 impl Display for SegmentType {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -190,8 +271,10 @@ impl Display for SegmentType {
             SegmentType::Zero => write!(f, "zero"),
             SegmentType::Data => write!(f, "data"),
             SegmentType::RoData => write!(f, "rodata"),
+            SegmentType::Boot => write!(f, "boot"),
             SegmentType::Text => write!(f, "text"),
             SegmentType::Heap => write!(f, "heap"),
+            SegmentType::Stack => write!(f, "stack"),
         }
     }
 }
@@ -206,17 +289,68 @@ impl TryFrom<u8> for SegmentType {
             0x00 => Ok(Zero),
             0x10 => Ok(Data),
             0x18 => Ok(RoData),
+            0x1c => Ok(Boot),
             0x20 => Ok(Text),
             0x70 => Ok(Heap),
+            0x78 => Ok(Stack),
             _ => Err(()),
         }
     }
 }
 
+/// Constraints on where a segment may be placed in memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SegmentLayout {
+    /// A 2^n value the start address of the segment has to be a multiple of
+    pub alignment: u16,
+    /// The lowest address the segment may start at
+    pub min_addr: u16,
+    /// The highest address the segment may occupy
+    pub max_addr: Option<u16>,
+}
+
+impl Default for SegmentLayout {
+    fn default() -> Self {
+        Self {
+            alignment: 1,
+            min_addr: 0,
+            max_addr: None,
+        }
+    }
+}
+
+impl SegmentLayout {
+    /// Gives the first address at or after `addr` that satisfies the alignment and minimum address,
+    /// or `None` if there is no such address
+    pub fn place(&self, addr: u16) -> Option<u16> {
+        checked_align_end(addr.max(self.min_addr), self.alignment)
+    }
+    /// Whether `size` bytes starting at `start` stay within the maximum address
+    pub fn fits(&self, start: u16, size: u16) -> bool {
+        let end = start as u32 + size as u32;
+        match self.max_addr {
+            Some(max) => size == 0 || end - 1 <= max as u32,
+            None => end <= 0x1_0000,
+        }
+    }
+    /// Combines two layouts into one that satisfies both
+    pub fn merge(self, other: Self) -> Self {
+        Self {
+            alignment: self.alignment.max(other.alignment),
+            min_addr: self.min_addr.max(other.min_addr),
+            max_addr: match (self.max_addr, other.max_addr) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            },
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct BinarySegment {
     pub offset: u16,
     pub stype: SegmentType,
+    pub layout: SegmentLayout,
     pub bytes: Vec<u8>,
 }
 
@@ -270,7 +404,173 @@ pub struct RelocationEntry {
 #[derive(Debug, Clone, Default)]
 pub struct RelocationTable(pub Vec<RelocationEntry>);
 
+/// The source line that the bytes from `offset` up to the next entry came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineEntry {
+    pub offset: u16,
+    /// Index into the file table
+    pub file_id: u8,
+    pub line: u32,
+}
+
+/// Maps addresses of the object to source lines, the entries are sorted by offset
+///
+/// Its section is optional, so objects with one can still be read by readers not knowing it
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LineNumberTable {
+    pub files: Vec<Box<str>>,
+    pub entries: Vec<LineEntry>,
+}
+
+impl LineNumberTable {
+    /// Finds the file and line of the entry covering `addr`
+    pub fn lookup(&self, addr: u16) -> Option<(&str, u32)> {
+        let i = self.entries.partition_point(|e| e.offset <= addr);
+        let entry = self.entries.get(i.checked_sub(1)?)?;
+        let file = self.files.get(entry.file_id as usize)?;
+        Some((file, entry.line))
+    }
+}
+
+/// Symbols that are not defined by any object but by the environment running it
+///
+/// Its section is optional like the line number table
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExternTable(pub Vec<Box<str>>);
+
+impl ExternTable {
+    pub fn contains(&self, name: &str) -> bool {
+        self.0.iter().any(|e| **e == *name)
+    }
+}
+
+/// Global symbols that are only defaults, see [`crate::source::SymbolType::Weak`]
+///
+/// Its section is optional like the extern table
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WeakTable(pub Vec<Box<str>>);
+
+impl WeakTable {
+    pub fn contains(&self, name: &str) -> bool {
+        self.0.iter().any(|w| **w == *name)
+    }
+}
+
 fn segment_type_from_u8(n: u8) -> io::Result<SegmentType> {
     SegmentType::try_from(n)
         .map_err(|()| io::Error::new(io::ErrorKind::InvalidData, "unrecognised segment type"))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn segment_layout_round_trip() {
+        let seg = BinarySegment {
+            offset: 0x8000,
+            stype: SegmentType::Text,
+            layout: SegmentLayout {
+                alignment: 4,
+                min_addr: 0x8000,
+                max_addr: Some(0xbfff),
+            },
+            bytes: vec![0x0a],
+        };
+        let mut buf = Vec::new();
+        seg.write(&mut buf).unwrap();
+        let read = BinarySegment::read(&buf[..]).unwrap();
+
+        assert_eq!(read.layout, seg.layout);
+        assert_eq!(read.offset, 0x8000);
+        assert_eq!(read.bytes, [0x0a]);
+    }
+
+    #[test]
+    fn segment_without_layout() {
+        // written like before segments had layouts: offset, segment type and then the bytes
+        let old = [0x00, 0x80, SegmentType::Text as u8, 0x0a, 0x0b];
+        let read = BinarySegment::read(&old[..]).unwrap();
+        assert_eq!(read.offset, 0x8000);
+        assert_eq!(read.stype, SegmentType::Text);
+        assert_eq!(read.layout, SegmentLayout::default());
+        assert_eq!(read.bytes, [0x0a, 0x0b]);
+
+        let mut buf = Vec::new();
+        read.write(&mut buf).unwrap();
+        assert_eq!(buf, old);
+    }
+
+    #[test]
+    fn old_stack_size() {
+        let mut buf = io::Cursor::new(Vec::new());
+        {
+            let mut aalvur = AalvWriter::new(&mut buf, 0).unwrap();
+            aalvur.write_section(&HeapSize(0x100)).unwrap();
+            aalvur.write_section(&HeapSize(0x200)).unwrap();
+        }
+        buf.set_position(0);
+        let obj = Object::from_aalv_reader(&mut AalvReader::new(buf).unwrap()).unwrap();
+        assert_eq!(obj.stack_size.map(|s| s.0), Some(0x100));
+        assert_eq!(obj.heap_size.map(|s| s.0), Some(0x200));
+    }
+
+    #[test]
+    fn line_number_table_round_trip() {
+        let table = LineNumberTable {
+            files: vec!["main.tasm".into(), "lib.tasm".into()],
+            entries: vec![
+                LineEntry {
+                    offset: 0x400,
+                    file_id: 0,
+                    line: 4,
+                },
+                LineEntry {
+                    offset: 0x404,
+                    file_id: 0,
+                    line: 2,
+                },
+                LineEntry {
+                    offset: 0x8000,
+                    file_id: 1,
+                    line: 100_000,
+                },
+            ],
+        };
+        let mut buf = Vec::new();
+        table.write(&mut buf).unwrap();
+        assert_eq!(LineNumberTable::read(&buf[..]).unwrap(), table);
+
+        assert_eq!(table.lookup(0x3ff), None);
+        assert_eq!(table.lookup(0x406), Some(("main.tasm", 2)));
+        assert_eq!(table.lookup(0xffff), Some(("lib.tasm", 100_000)));
+    }
+
+    #[test]
+    fn extern_table_round_trip() {
+        let table = ExternTable(vec!["host_print".into(), "".into(), "rand".into()]);
+        let mut buf = Vec::new();
+        table.write(&mut buf).unwrap();
+        assert_eq!(ExternTable::read(&buf[..]).unwrap(), table);
+        assert!(table.contains("rand"));
+        assert!(!table.contains("print"));
+    }
+
+    #[test]
+    fn segment_layout_placement() {
+        let layout = SegmentLayout {
+            alignment: 4,
+            min_addr: 0x201,
+            max_addr: Some(0x20f),
+        };
+
+        assert_eq!(layout.place(0x80), Some(0x204));
+        assert_eq!(layout.place(0x305), Some(0x308));
+        assert_eq!(layout.place(0xfffc), Some(0xfffc));
+        assert_eq!(layout.place(0xfffd), None);
+        assert!(layout.fits(0x204, 12));
+        assert!(!layout.fits(0x204, 13));
+        assert!(SegmentLayout::default().fits(0xff00, 0x100));
+        assert!(!SegmentLayout::default().fits(0xff00, 0x101));
+    }
+}
@@ -60,6 +60,10 @@ impl Section for HeapSize {
     }
 }
 
+/// Set in the segment type byte of a `_seg` section when a layout follows it,
+/// segments without a layout are written like they were before layouts existed
+const SEG_HAS_LAYOUT: u8 = 0x80;
+
 impl Section for BinarySegment {
     const NAME: &'static str = "_seg";
 
@@ -68,24 +72,64 @@ impl Section for BinarySegment {
         reader.read_exact(&mut buf)?;
         let [ol, oh, stype] = buf;
 
+        let layout = if stype & SEG_HAS_LAYOUT != 0 {
+            let mut buf = [0; 6];
+            reader.read_exact(&mut buf)?;
+            let [al, ah, minl, minh, maxl, maxh] = buf;
+
+            let alignment = u16::from_le_bytes([al, ah]);
+            if alignment.count_ones() != 1 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "segment alignment is not a power of 2",
+                ));
+            }
+            // 0xffff as the maximum address is the same as having none
+            let max_addr = match u16::from_le_bytes([maxl, maxh]) {
+                0xffff => None,
+                max => Some(max),
+            };
+            SegmentLayout {
+                alignment,
+                min_addr: u16::from_le_bytes([minl, minh]),
+                max_addr,
+            }
+        } else {
+            SegmentLayout::default()
+        };
+
         let mut bytes = Vec::new();
         reader.read_to_end(&mut bytes)?;
 
         Ok(Self {
             offset: u16::from_le_bytes([ol, oh]),
-            stype: segment_type_from_u8(stype)?,
+            stype: segment_type_from_u8(stype & !SEG_HAS_LAYOUT)?,
+            layout,
             bytes,
         })
     }
     fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        let SegmentLayout {
+            alignment,
+            min_addr,
+            max_addr,
+        } = self.layout;
+
         writer.write_all(&self.offset.to_le_bytes())?;
-        writer.write_all(&[self.stype as u8])?;
+        if self.layout == SegmentLayout::default() {
+            writer.write_all(&[self.stype as u8])?;
+        } else {
+            writer.write_all(&[self.stype as u8 | SEG_HAS_LAYOUT])?;
+            writer.write_all(&alignment.to_le_bytes())?;
+            writer.write_all(&min_addr.to_le_bytes())?;
+            writer.write_all(&max_addr.unwrap_or(0xffff).to_le_bytes())?;
+        }
         writer.write_all(&self.bytes)?;
         Ok(())
     }
 }
 impl Section for StackSize {
-    const NAME: &'static str = "_heap_size";
+    const NAME: &'static str = "_stack_size";
     fn read<R: Read>(mut reader: R) -> io::Result<Self> {
         let mut buf = [0; 2];
         reader.read_exact(&mut buf)?;
@@ -198,3 +242,147 @@ impl Section for RelocationTable {
         Ok(())
     }
 }
+
+/// Writes `n` in little-endian groups of 7 bits with the high bit set on all but the last
+fn write_varint<W: Write>(writer: &mut W, mut n: u64) -> io::Result<()> {
+    loop {
+        let b = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            return writer.write_all(&[b]);
+        }
+        writer.write_all(&[b | 0x80])?;
+    }
+}
+
+fn read_varint<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut n = 0;
+    for shift in (0..64).step_by(7) {
+        let mut buf = [0];
+        reader.read_exact(&mut buf)?;
+        n |= ((buf[0] & 0x7f) as u64) << shift;
+        if buf[0] & 0x80 == 0 {
+            return Ok(n);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "variable-length number is too long",
+    ))
+}
+
+// The number of files and then each file name prefixed by its length as 16-bit numbers,
+// followed by the entries until the end of the section.
+// Each entry is the offset as a varint difference from the last one, the file index and
+// the line as a zigzagged varint difference from the last one
+impl Section for LineNumberTable {
+    const NAME: &'static str = "line_numbers";
+
+    fn read<R: Read>(mut reader: R) -> io::Result<Self> {
+        let mut buf = [0; 2];
+        reader.read_exact(&mut buf)?;
+        let file_count = u16::from_le_bytes(buf);
+        let mut files = Vec::with_capacity(file_count as usize);
+        for _ in 0..file_count {
+            reader.read_exact(&mut buf)?;
+            let mut name = vec![0; u16::from_le_bytes(buf) as usize];
+            reader.read_exact(&mut name)?;
+            files.push(String::from_utf8_lossy(&name).into());
+        }
+
+        let mut entries = Vec::new();
+        let (mut offset, mut line) = (0u16, 0u32);
+        loop {
+            let offset_delta = match read_varint(&mut reader) {
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            };
+            let mut file_id = [0];
+            reader.read_exact(&mut file_id)?;
+            let line_delta = read_varint(&mut reader)?;
+            // undo the zigzag
+            let line_delta = (line_delta >> 1) as i64 ^ -((line_delta & 1) as i64);
+
+            offset = offset.wrapping_add(offset_delta as u16);
+            line = (line as i64 + line_delta) as u32;
+            entries.push(LineEntry {
+                offset,
+                file_id: file_id[0],
+                line,
+            });
+        }
+
+        Ok(LineNumberTable { files, entries })
+    }
+    fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_all(&(self.files.len() as u16).to_le_bytes())?;
+        for file in &self.files {
+            writer.write_all(&(file.len() as u16).to_le_bytes())?;
+            writer.write_all(file.as_bytes())?;
+        }
+
+        let (mut offset, mut line) = (0u16, 0u32);
+        for &LineEntry {
+            offset: entry_offset,
+            file_id,
+            line: entry_line,
+        } in &self.entries
+        {
+            write_varint(&mut writer, entry_offset.wrapping_sub(offset) as u64)?;
+            writer.write_all(&[file_id])?;
+            let line_delta = entry_line as i64 - line as i64;
+            // zigzag so small negative differences stay short
+            write_varint(&mut writer, ((line_delta << 1) ^ (line_delta >> 63)) as u64)?;
+            offset = entry_offset;
+            line = entry_line;
+        }
+        Ok(())
+    }
+}
+
+/// Reads names that are each prefixed by their length
+fn read_names<R: Read>(mut reader: R) -> io::Result<Vec<Box<str>>> {
+    let mut buf = [0; 2];
+    let mut names = Vec::new();
+    loop {
+        match reader.read_exact(&mut buf) {
+            Ok(()) => (),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+        let mut name = vec![0; u16::from_le_bytes(buf) as usize];
+        reader.read_exact(&mut name)?;
+        names.push(String::from_utf8_lossy(&name).into());
+    }
+    Ok(names)
+}
+fn write_names<W: Write>(names: &[Box<str>], mut writer: W) -> io::Result<()> {
+    for name in names {
+        writer.write_all(&(name.len() as u16).to_le_bytes())?;
+        writer.write_all(name.as_bytes())?;
+    }
+    Ok(())
+}
+
+impl Section for ExternTable {
+    const NAME: &'static str = "externs";
+
+    fn read<R: Read>(reader: R) -> io::Result<Self> {
+        read_names(reader).map(ExternTable)
+    }
+    fn write<W: Write>(&self, writer: W) -> io::Result<()> {
+        write_names(&self.0, writer)
+    }
+}
+
+impl Section for WeakTable {
+    const NAME: &'static str = "weak";
+
+    fn read<R: Read>(reader: R) -> io::Result<Self> {
+        read_names(reader).map(WeakTable)
+    }
+    fn write<W: Write>(&self, writer: W) -> io::Result<()> {
+        write_names(&self.0, writer)
+    }
+}
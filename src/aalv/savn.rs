@@ -1,14 +1,20 @@
 use std::{
-    fs::File, io::{self, BufReader, ErrorKind, Read, Result}, mem, path::Path
+    fs::File, io::{self, BufRead, BufReader, ErrorKind, Read, Result, Seek, SeekFrom, Write}, mem, path::Path
 };
 
-use super::AalvReader;
+use super::{AalvReader, Section};
 
 const SAVN_MAGIC: &str = "álvasavn\n";
 
+/// Extension of archives
+pub const SAVN_EXT: &str = "savn";
+
 pub fn read_archive<P: AsRef<Path>>(path: P) -> Result<Iter> {
-    let mut f = BufReader::new(File::open(path)?);
+    read_archive_from(BufReader::new(File::open(path)?))
+}
 
+/// Reads an archive from anything, `read_archive` reads it from a file
+pub fn read_archive_from<R: BufRead + Seek>(mut f: R) -> Result<Iter<R>> {
     let mut magic_buf = [0; SAVN_MAGIC.len()];
     f.read_exact(&mut magic_buf)?;
 
@@ -19,27 +25,53 @@ pub fn read_archive<P: AsRef<Path>>(path: P) -> Result<Iter> {
     Ok(Iter(IterInner::OpenFile(f)))
 }
 
-#[derive(Default)]
+/// Writes what comes before the álvur files of the members of an archive
+pub fn write_archive_magic<W: Write>(mut w: W) -> Result<()> {
+    w.write_all(SAVN_MAGIC.as_bytes())
+}
+
+/// Name of an archive member, usually the file name of the object it was made from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemberName(pub Box<str>);
+
+impl Section for MemberName {
+    const NAME: &'static str = "member_name";
+    fn read<R: Read>(mut reader: R) -> Result<Self> {
+        let mut name = String::new();
+        reader.read_to_string(&mut name)?;
+        Ok(MemberName(name.into()))
+    }
+    fn write<W: Write>(&self, mut writer: W) -> Result<()> {
+        writer.write_all(self.0.as_bytes())
+    }
+}
+
 #[repr(transparent)]
-pub struct Iter(IterInner);
+pub struct Iter<R = BufReader<File>>(IterInner<R>);
+
+impl<R> Default for Iter<R> {
+    fn default() -> Self {
+        Iter(IterInner::Empty)
+    }
+}
 
-impl Iter {
+impl<R: BufRead + Seek> Iter<R> {
     #[inline(always)]
-    pub fn next<T, F: FnOnce(&mut AalvReader<BufReader<File>>) -> Result<T>>(&mut self, load_aalv: F) -> Result<Option<T>> {
+    pub fn next<T, F: FnOnce(&mut AalvReader<R>) -> Result<T>>(&mut self, load_aalv: F) -> Result<Option<T>> {
         self.0.next(load_aalv)
     }
 }
 
 #[derive(Default)]
-pub enum IterInner {
+pub enum IterInner<R = BufReader<File>> {
     #[default]
     Empty,
-    OpenFile(BufReader<File>),
+    OpenFile(R),
 }
 
-impl IterInner {
-    fn next<T, F: FnOnce(&mut AalvReader<BufReader<File>>) -> Result<T>>(&mut self, load_aalv: F) -> Result<Option<T>> {
-        match mem::take(self) {
+impl<R: BufRead + Seek> IterInner<R> {
+    fn next<T, F: FnOnce(&mut AalvReader<R>) -> Result<T>>(&mut self, load_aalv: F) -> Result<Option<T>> {
+        match mem::replace(self, Self::Empty) {
             Self::Empty => Ok(None),
             Self::OpenFile(f) => {
                 let mut reader = AalvReader::new(f)?;
@@ -48,7 +80,7 @@ impl IterInner {
 
                 match f.read_exact(&mut [0]) {
                     Ok(_) => {
-                        let _ = f.seek_relative(-1);
+                        f.seek(SeekFrom::Current(-1))?;
                         *self = Self::OpenFile(f);
                     }
                     Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => (),
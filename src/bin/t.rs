@@ -1,43 +1,62 @@
 use std::{
-    fs::File,
-    io::{self, Read},
+    fs::{self, File},
+    io::{self, BufReader, Read},
     mem::replace,
-    path::PathBuf,
+    path::{Path, PathBuf},
     process::ExitCode,
 };
 
 use clap::Parser;
+#[cfg(feature = "profiling")]
+use telda2::machine::profile::profile_report;
 use telda2::{
     aalv::obj::{Object, SymbolDefinition, SymbolTable},
+    bin_format::{is_telda_elf, ParseError, TELF_MAGIC},
     blf4::{Blf4, TrapMode},
-    machine::Machine,
-    mem::{LazyMain, StdIo},
+    machine::{ConfigError, Machine, MachineBuilder},
+    mem::{LazyMain, StdIo, Uart},
+    sym::parse_sym_file,
+    PAGE_SIZE,
 };
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
-    /// Binary file
+    /// Binary file, or map file with `--dump-symbols`
     ///
-    /// By default this is an object file that will be loaded in as a user program with memory mapping
+    /// By default this is an object file that will be loaded in as a user program with memory mapping.
+    /// Flat binaries with a header (made with `tc --format=telf`) are recognised and run like raw binaries
+    /// starting at their entry point
     binary: PathBuf,
 
     /// If set, the binary is interpreted as raw binary data rather than an object file and is loaded in at 0x00_0080 (ROM)
     ///
     /// No emulated kernel will be present and the cpu will go through normal startup,
+    /// a UART connected to the terminal is available at ports 0x00-0x03
     // the CPU will start execution at 0x0080 in direct where the binary is loaded
     #[arg(short, long)]
     raw_binary: bool,
 
+    /// Print the symbols of a map file (made with `tc --map`) sorted by address instead of running
+    #[arg(long)]
+    dump_symbols: bool,
+
     /// Whether the termination point should be displayed
     #[arg(short, long)]
     termination_point: bool,
+
+    /// Prints how many times each instruction was executed to stderr at exit
+    #[cfg(feature = "profiling")]
+    #[arg(long)]
+    profile: bool,
 }
 
 enum Error {
     NoEntry,
     Trap(TrapMode),
-    IoError(io::Error),
+    Io(io::Error),
+    Telf(ParseError),
+    Config(ConfigError),
 }
 
 pub fn main() -> ExitCode {
@@ -46,8 +65,10 @@ pub fn main() -> ExitCode {
         Err(e) => {
             match e {
                 Error::NoEntry => eprintln!("no entry point in binary"),
-                Error::Trap(tm) => eprintln!("trapped with {tm:?}"),
-                Error::IoError(e) => eprintln!("unexpected io error occured: {e}"),
+                Error::Trap(tm) => eprintln!("trapped: {tm}"),
+                Error::Io(e) => eprintln!("unexpected io error occured: {e}"),
+                Error::Telf(e) => eprintln!("invalid flat binary: {e}"),
+                Error::Config(e) => eprintln!("cannot load binary: {e}"),
             }
             ExitCode::FAILURE
         }
@@ -58,32 +79,69 @@ fn t_main() -> Result<(), Error> {
     let Cli {
         binary,
         raw_binary,
+        dump_symbols,
         termination_point,
+        #[cfg(feature = "profiling")]
+        profile,
     } = Cli::parse();
 
-    let mut machine = Machine::new(LazyMain::new(StdIo), Blf4::new());
+    if dump_symbols {
+        let file = File::open(binary).map_err(Error::Io)?;
+        let mut symbols = parse_sym_file(BufReader::new(file)).map_err(Error::Io)?;
+        symbols.sort_by(|a, b| (a.2, &a.0).cmp(&(b.2, &b.0)));
+        for (name, is_global, addr) in symbols {
+            let visibility = if is_global { "global" } else { "internal" };
+            println!("0x{addr:04x} {visibility:<8} {name}");
+        }
+        return Ok(());
+    }
 
     let mut symbols = SymbolTable::default();
+    let tm;
+    let cpu;
     if raw_binary {
-        let mut file = File::open(binary).map_err(Error::IoError)?;
+        let mut file = File::open(binary).map_err(Error::Io)?;
         let mut raw_binary_data = Vec::new();
-        file.read_to_end(&mut raw_binary_data)
-            .map_err(Error::IoError)?;
+        file.read_to_end(&mut raw_binary_data).map_err(Error::Io)?;
 
-        machine.memory = machine.memory.with_rom(&raw_binary_data);
+        let mut machine = MachineBuilder::new(Uart::new(0))
+            .load_binary(&raw_binary_data, PAGE_SIZE)
+            .build()
+            .map_err(Error::Config)?;
+        tm = machine.run_until_abort();
+        cpu = machine.cpu;
+    } else if starts_with_telf_magic(&binary).map_err(Error::Io)? {
+        let bytes = fs::read(binary).map_err(Error::Io)?;
+        let mut machine = MachineBuilder::from_telf(Uart::new(0), &bytes)
+            .map_err(Error::Telf)?
+            .build()
+            .map_err(Error::Config)?;
+        tm = machine.run_until_abort();
+        cpu = machine.cpu;
     } else {
-        let mut obj = Object::from_file(binary).map_err(Error::IoError)?;
+        let mut machine = Machine::new(LazyMain::new(StdIo), Blf4::new());
+        let mut obj = Object::from_file(binary).map_err(Error::Io)?;
         // error if there is no entry
         obj.entry.is_some().then_some(()).ok_or(Error::NoEntry)?;
-        symbols = replace(&mut obj.symbols, symbols);
         machine.load_user_binary(&obj);
+        symbols = replace(&mut obj.symbols, symbols);
+        tm = machine.run_until_abort();
+        cpu = machine.cpu;
     }
+    let pc = cpu.program_counter;
     let symbols = symbols.into_iter();
 
-    let tm = machine.run_until_abort();
+    #[cfg(feature = "profiling")]
+    if profile {
+        let total = cpu.profile.total();
+        eprintln!("executed {total} instructions");
+        for (opcode, mnemonic, count) in profile_report(&cpu.profile) {
+            let percentage = 100. * count as f64 / total as f64;
+            eprintln!("{opcode:02x} {mnemonic:<8} {count:>12} {percentage:>6.2}%");
+        }
+    }
 
     if termination_point {
-        let pc = machine.cpu.program_counter;
         let mut diff = pc;
         let mut closest = "".into();
         for SymbolDefinition { name, location, .. } in symbols {
@@ -98,10 +156,19 @@ fn t_main() -> Result<(), Error> {
                 }
             }
         }
-        println!("Ended with {tm:?} at <{closest}+{diff:02X}>");
+        println!("Ended with {tm} at <{closest}+{diff:02X}>");
     } else if tm != TrapMode::Halt {
         return Err(Error::Trap(tm));
     }
 
     Ok(())
 }
+
+fn starts_with_telf_magic(path: &Path) -> io::Result<bool> {
+    let mut magic = [0; TELF_MAGIC.len()];
+    match File::open(path)?.read_exact(&mut magic) {
+        Ok(()) => Ok(is_telda_elf(&magic)),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
@@ -1,8 +1,9 @@
 use std::{
-    fs::File, io::{self, Write}, path::PathBuf, process::ExitCode
+    fs::File, io::{self, BufWriter, Seek}, path::PathBuf, process::ExitCode
 };
 
 use clap::Parser;
+use telda2::aalv::{obj::Object, write_archive_magic, AalvWriter, MemberName};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -34,14 +35,20 @@ fn tar_main() -> Result<(), io::Error> {
         output_file,
     } = Cli::parse();
 
-    let mut out = File::create(output_file)?;
-    out.write_all("álvasavn\n".as_bytes())?;
+    let mut out = BufWriter::new(File::create(output_file)?);
+    write_archive_magic(&mut out)?;
 
     for input_file in input_files {
-        let mut input = File::open(input_file)?;
-        io::copy(&mut input, &mut out)?;
+        let obj = Object::from_file(&input_file)?;
+        // members are named after their file so the linker can say which one it includes
+        let name = input_file.file_name().unwrap_or_default().to_string_lossy().into();
+
+        let offset = out.stream_position()?;
+        let mut aalvur = AalvWriter::new(&mut out, offset)?;
+        aalvur.write_section(&MemberName(name))?;
+        obj.write(&mut aalvur)?;
     }
-    out.sync_all()?;
+    out.into_inner()?.sync_all()?;
 
     Ok(())
 }
@@ -1,123 +1,418 @@
-use std::{collections::BTreeMap, env::args, path::Path, process::ExitCode};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, File},
+    io::{self, BufRead, BufReader, BufWriter, Cursor, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
 
+use clap::{Parser, ValueEnum};
 use telda2::{
-    aalv::obj::{
-        Object, RelocationEntry, RelocationTable, SegmentType, SymbolDefinition, SymbolTable,
-        AALV_OBJECT_EXT,
+    aalv::{
+        obj::{Entry, Object, SegmentType, AALV_OBJECT_EXT},
+        AalvWriter,
+    },
+    bin_format::{parse_telda_elf, DATA_START, TELF_EXT},
+    object::{load_cache, save_cache, CACHE_EXT},
+    opt::{optimize, Pattern},
+    output_format::{
+        IntelHex, MotorolaSRec, OutputFormat, RawBinary, Telf, IHEX_EXT, RAW_EXT, SREC_EXT,
     },
     source::{
-        process, write_data_operand, DataLine, Error as TeldaError, LabelRead, ProcessedSource,
-        SourceLines, SymbolType, Wide,
+        expand, line_number_table, parse_all, process, write_lines_json, write_source_map,
+        Error as TeldaError, ProcessedSource, Recovery, SourceLines, SourceLocation, SymbolType,
+        Warning, SOURCE_MAP_EXT,
     },
+    sym::{write_map, MAP_EXT},
 };
 
-fn main() -> ExitCode {
-    let mut ret = ExitCode::SUCCESS;
-    for arg in args().skip(1) {
-        let p = Path::new(&arg);
-        let ProcessedSource { labels, dls, entry } = match SourceLines::new(p).and_then(process) {
-            Ok(s) => s,
-            Err(e) => {
-                eprintln!("{}", e);
-                ret = ExitCode::FAILURE;
-                continue;
-            }
-        };
-        let mut label_reads: Vec<Vec<LabelRead>> = Vec::new();
-        label_reads.resize_with(labels.len(), Vec::new);
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    /// Telda source files to assemble into object files, standard input is read if there are none
+    input_files: Vec<PathBuf>,
+
+    /// Treat warnings as errors, no object file is written for a source with warnings
+    #[arg(long)]
+    warn_error: bool,
+
+    /// Stop assembling a source at its first error instead of reporting all of them
+    #[arg(long)]
+    first_error: bool,
+
+    /// Warn about instructions after a jump, `ret` or `halt` that no label leads to
+    ///
+    /// Put a label like `.allow_unreachable:` in front of code that is unreachable on purpose
+    #[arg(long)]
+    warn_unreachable: bool,
+
+    /// Also write a listing file (.lst) with the address and bytes of each source line
+    #[arg(short, long)]
+    listing: bool,
+
+    /// Also write a map file (.map) with the placement of each segment and symbol
+    ///
+    /// Each line is an entry of tab-separated fields, the first field says what kind of entry it is
+    #[arg(short, long)]
+    map: bool,
+
+    /// Only write the map file, no object file or other files are written
+    #[arg(long)]
+    symbols_only: bool,
+
+    /// Also write a source map file (.srcmap) saying which source line each address came from
+    ///
+    /// `tdbg` uses it to show the source of the code it runs
+    #[arg(long)]
+    source_map: bool,
+
+    /// Put a line number table in the object file saying which source line each address came from
+    ///
+    /// `tdbg` uses it when there is no source map file
+    #[arg(short, long)]
+    debug: bool,
 
-        let mut segs = BTreeMap::new();
-        let mut lines = Vec::with_capacity(dls.len());
+    /// Keep the assembled source and its warnings in a cache file (.tobj) and reuse it while it
+    /// is newer than the source files, includes and included binaries it came from
+    #[arg(long)]
+    cache: bool,
+
+    /// Write the object file to this path instead of next to the source file
+    ///
+    /// The listing and map files are put next to it. Only allowed with a single input file
+    ///
+    /// `-` writes it to standard output, which is the default when reading standard input
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+
+    /// Wipe the names of internal symbols, they are only used for debugging
+    #[arg(long)]
+    no_symbols: bool,
+
+    /// What kind of file to write
+    #[arg(
+        short,
+        long,
+        visible_alias = "output-format",
+        value_enum,
+        default_value_t = Format::Object
+    )]
+    format: Format,
+
+    /// Run the peephole optimiser on the assembled code, `--opt=2` also enables replacements
+    /// that change the flags
+    ///
+    /// Removed code moves the labels after it back, except in segments with an alignment
+    #[arg(
+        long,
+        value_name = "LEVEL",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "1",
+        value_parser = clap::value_parser!(u8).range(1..=2)
+    )]
+    opt: Option<u8>,
+
+    /// Print the formats that can be written with --format and exit
+    #[arg(long)]
+    list_formats: bool,
+
+    /// Write the source lines as JSON to standard output or the output path instead of assembling
+    #[arg(long, value_enum)]
+    emit: Option<Emit>,
+
+    /// Check that the checksum in this flat binary matches the assembled source instead of
+    /// writing anything
+    ///
+    /// Only allowed with a single input file
+    #[arg(long, value_name = "TELF")]
+    verify: Option<PathBuf>,
+
+    /// Only report the errors and warnings of the sources without writing any files
+    ///
+    /// Exits with failure if any source has errors, or warnings with --warn-error
+    #[arg(long, conflicts_with_all = ["emit", "verify"])]
+    check: bool,
+
+    /// Do not print anything on success, only errors and warnings
+    #[arg(short, long)]
+    quiet: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Emit {
+    /// The lines as they are parsed
+    Ast,
+    /// The lines that get assembled, after includes, conditions and repetitions
+    Expanded,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Format {
+    /// Object file (.to) that can be linked with other objects
+    Object,
+    /// Flat binary (.telf) with a header, runnable with `t`
+    ///
+    /// Segments below 0x8000 are put in ROM and the rest in RAM,
+    /// so writeable segments need to be placed at 0x8000 or above with `.seg`.
+    /// The source cannot reference symbols from other objects
+    Telf,
+    /// Raw binary (.bin) of memory from 0x0080 as it is loaded by `t -r`
+    ///
+    /// Like a flat binary but without a header, so execution starts at 0x0080
+    Raw,
+    /// Intel HEX (.hex) of the segments for ROM programmers and bootloaders
+    Ihex,
+    /// Motorola S-records (.srec) of the segments for ROM programmers and bootloaders
+    Srec,
+}
+
+fn main() -> ExitCode {
+    let Cli {
+        input_files,
+        warn_error,
+        first_error,
+        warn_unreachable,
+        listing,
+        map,
+        symbols_only,
+        source_map,
+        debug,
+        cache,
+        output,
+        no_symbols,
+        format,
+        opt,
+        list_formats,
+        emit,
+        verify,
+        check,
+        quiet,
+    } = Cli::parse();
 
-        for (stype, dls) in dls {
-            segs.insert(stype, (dls.start, Vec::with_capacity(dls.size as usize)));
-            lines.push(dls.lines);
+    if list_formats {
+        for format in Format::value_variants() {
+            let value = format.to_possible_value().expect("formats are not hidden");
+            let help = value.get_help().map(|h| h.to_string()).unwrap_or_default();
+            println!("{:<8} {}", value.get_name(), help.lines().next().unwrap_or(""));
         }
+        return ExitCode::SUCCESS;
+    }
 
-        for ((&st, &mut (segment_start, ref mut mem)), lines) in segs.iter_mut().zip(lines) {
-            for data_line in lines {
-                match data_line {
-                    DataLine::Raw(mut bytes) => {
-                        mem.append(&mut bytes);
-                    }
-                    DataLine::Wide(Wide::Number(w)) => mem.extend_from_slice(&w.to_le_bytes()),
-                    DataLine::Wide(Wide::Label(id)) => {
-                        let lr = LabelRead {
-                            segment: st,
-                            position: mem.len() as u16 + segment_start,
-                        };
-                        label_reads[id].push(lr);
-                        let w = labels[id].3;
-                        mem.extend_from_slice(&w.to_le_bytes());
-                    }
-                    DataLine::Ins(opcode, dat_op) => {
-                        mem.push(opcode);
+    if output.is_some() && input_files.len() > 1 {
+        eprintln!("cannot use -o with more than one input file");
+        return ExitCode::FAILURE;
+    }
+    if verify.is_some() && input_files.len() > 1 {
+        eprintln!("--verify needs exactly one input file");
+        return ExitCode::FAILURE;
+    }
 
-                        let read_label = |id: usize, lr| {
-                            label_reads[id].push(lr);
-                            labels[id].3
-                        };
+    let from_stdin = input_files.is_empty();
+    let (input_files, output) = if from_stdin {
+        if cache || listing {
+            eprintln!("--cache and --listing need an input file");
+            return ExitCode::FAILURE;
+        }
+        // named like the source of the lines so messages agree with the errors
+        let output = output.unwrap_or_else(|| PathBuf::from(STDOUT));
+        (vec![PathBuf::from("<input>")], Some(output))
+    } else {
+        (input_files, output)
+    };
+    if output.as_deref().is_some_and(is_stdout) && (listing || map || symbols_only || source_map) {
+        eprintln!("cannot write listing, map or source map files next to standard output");
+        return ExitCode::FAILURE;
+    }
 
-                        write_data_operand(st, mem, read_label, dat_op);
-                    }
+    let mut ret = ExitCode::SUCCESS;
+    for p in input_files {
+        if let Some(emit) = emit {
+            let res = if from_stdin {
+                let lines = SourceLines::from_reader(io::stdin().lock());
+                emit_lines(lines, emit, first_error, output.as_deref())
+            } else {
+                SourceLines::new(&p)
+                    .and_then(|lines| emit_lines(lines, emit, first_error, output.as_deref()))
+            };
+            match res {
+                Ok(true) => (),
+                Ok(false) => ret = ExitCode::FAILURE,
+                Err(e) => {
+                    eprintln!("{e}");
+                    ret = ExitCode::FAILURE;
                 }
             }
+            continue;
         }
-
-        let mut aalvur = Object {
-            segs,
-            entry,
-            ..Object::default()
+        let out_path = match &output {
+            Some(out_path) => out_path.clone(),
+            None => p.with_extension(match format {
+                Format::Object => AALV_OBJECT_EXT,
+                Format::Telf => TELF_EXT,
+                Format::Raw => RAW_EXT,
+                Format::Ihex => IHEX_EXT,
+                Format::Srec => SREC_EXT,
+            }),
+        };
+        let cache_path = p.with_extension(CACHE_EXT);
+        let cached = if cache {
+            load_cached(&p, &cache_path)
+        } else {
+            None
         };
 
-        let mut symbol_table = Vec::new();
-        {
-            for &(ref lbl, st, segment_type, location) in labels.iter() {
-                let is_global = match st {
-                    SymbolType::Global => true,
-                    SymbolType::Internal => false,
-                    SymbolType::Reference => {
-                        assert_eq!(
-                            segment_type,
-                            SegmentType::Unknown,
-                            "reference symbols should have unknown segment type"
-                        );
-                        true
+        let (mut src, mut warnings) = match cached {
+            Some(cached) => cached,
+            None => {
+                let recovery = if first_error {
+                    Recovery::Stop
+                } else {
+                    Recovery::Continue
+                };
+                let (src, mut errors, warnings) = if from_stdin {
+                    process(SourceLines::from_reader(io::stdin().lock()), recovery)
+                } else {
+                    match SourceLines::new(&p) {
+                        Ok(lines) => process(lines, recovery),
+                        Err(e) => (None, vec![e], Vec::new()),
                     }
                 };
-
-                symbol_table.push(SymbolDefinition {
-                    name: lbl.clone(),
-                    is_global,
-                    segment_type,
-                    location,
-                })
+                match src {
+                    // the cache has the source as it was assembled so it can be used without --opt,
+                    // --check promises not to write any files
+                    Some(src) if cache && !check => {
+                        let res = File::create(&cache_path)
+                            .map_err(bincode::Error::from)
+                            .and_then(|f| save_cache(&src, &warnings, BufWriter::new(f)));
+                        if let Err(e) = res {
+                            eprintln!("{}: could not write cache: {e}", cache_path.display());
+                        }
+                        (src, warnings)
+                    }
+                    Some(src) => (src, warnings),
+                    None => {
+                        for warning in &warnings {
+                            eprintln!("{warning}");
+                        }
+                        errors.sort_by(|a, b| {
+                            (a.file(), a.line_number()).cmp(&(b.file(), b.line_number()))
+                        });
+                        for e in &errors {
+                            eprintln!("{e}");
+                        }
+                        ret = ExitCode::FAILURE;
+                        continue;
+                    }
+                }
             }
+        };
+        if warn_unreachable {
+            warnings.extend(src.unreachable_code());
+        }
+        for warning in &warnings {
+            eprintln!("{warning}");
+        }
+        if warn_error && !warnings.is_empty() {
+            ret = ExitCode::FAILURE;
+            continue;
+        }
+        if check {
+            // a flat binary cannot be written from every valid source
+            if format != Format::Object {
+                if let Err(e) = check_flat(&src.to_object(), format) {
+                    eprintln!("{}: {e}", p.display());
+                    ret = ExitCode::FAILURE;
+                }
+            }
+            continue;
+        }
+        if let Some(level) = opt {
+            optimize(&mut src, &Pattern::for_level(level));
+        }
+        if let Some(telf) = &verify {
+            match verify_checksum(telf, &src) {
+                Ok(()) if quiet => (),
+                Ok(()) => println!("{}: checksum matches {}", telf.display(), p.display()),
+                Err(e) => {
+                    eprintln!("{}: {e}", telf.display());
+                    ret = ExitCode::FAILURE;
+                }
+            }
+            continue;
+        }
+        let checksum = src.checksum();
+        if map || symbols_only {
+            let res = File::create(out_path.with_extension(MAP_EXT)).and_then(|f| {
+                let mut out = BufWriter::new(f);
+                write_map(&mut out, &src)?;
+                out.flush()
+            });
+            if let Err(e) = res {
+                eprintln!("{}", TeldaError::from(e));
+                ret = ExitCode::FAILURE;
+            }
+            if symbols_only {
+                continue;
+            }
+        }
+        if source_map {
+            let res = File::create(out_path.with_extension(SOURCE_MAP_EXT)).and_then(|f| {
+                let mut out = BufWriter::new(f);
+                write_source_map(&mut out, &src.source_map)?;
+                out.flush()
+            });
+            if let Err(e) = res {
+                eprintln!("{}", TeldaError::from(e));
+                ret = ExitCode::FAILURE;
+            }
+        }
+        let line_numbers = if debug {
+            let table = line_number_table(&src.source_map);
+            if table.is_none() {
+                eprintln!("{}: too many source files for a line number table", p.display());
+            }
+            table
+        } else {
+            None
+        };
+        let mut aalvur = src.to_object();
+        aalvur.line_numbers = line_numbers;
+        if no_symbols {
+            aalvur.symbols.mutate(|name, &mut is_global, _, _| {
+                if !is_global {
+                    *name = "".into()
+                }
+            });
         }
-        aalvur.symbols = SymbolTable(symbol_table);
-
-        let reloc_table;
-        {
-            let mut reloc_t = Vec::new();
-
-            for (i, label_reads) in label_reads.into_iter().enumerate() {
-                let symbol_index = i as u16;
-
-                for LabelRead { segment, position } in label_reads {
-                    let entry = RelocationEntry {
-                        reference_location: aalvur.segs[&segment].0 + position,
-                        reference_segment: segment,
-                        symbol_index,
-                    };
 
-                    reloc_t.push(entry);
+        let mut emitted = Vec::new();
+        if listing {
+            for (&st, dls) in &src.dls {
+                let (_, ranges) = src.encode_segment(st);
+                for (loc, range) in dls.locations.iter().zip(ranges) {
+                    emitted.push((loc.clone(), st, range));
                 }
             }
-            reloc_table = RelocationTable(reloc_t);
         }
-        aalvur.relocation_table = reloc_table;
 
-        match aalvur.write_to_file(p.with_extension(AALV_OBJECT_EXT)) {
+        let res = match format {
+            Format::Object if is_stdout(&out_path) => write_object_to_stdout(&aalvur),
+            Format::Object => aalvur.write_to_file(&out_path),
+            format => {
+                let res = check_flat(&aalvur, format).and_then(|()| {
+                    write_flat(&aalvur, format, checksum, &out_path).map_err(|e| e.to_string())
+                });
+                if let Err(e) = res {
+                    eprintln!("{}: {e}", p.display());
+                    ret = ExitCode::FAILURE;
+                    continue;
+                }
+                Ok(())
+            }
+        };
+        match res {
             Ok(()) => (),
             Err(e) => {
                 eprintln!("{}", TeldaError::from(e));
@@ -125,6 +420,242 @@ fn main() -> ExitCode {
                 continue;
             }
         }
+
+        if listing {
+            let lst_path = out_path.with_extension("lst");
+            let res = write_listing(&p, &lst_path, &emitted, &aalvur.segs, src.labels());
+            if let Err(e) = res {
+                eprintln!("{}", TeldaError::from(e));
+                ret = ExitCode::FAILURE;
+            }
+        }
     }
     ret
 }
+
+/// Writes the lines of the source as JSON and prints its errors, gives whether there were none
+fn emit_lines<B: BufRead>(
+    lines: SourceLines<B>,
+    emit: Emit,
+    first_error: bool,
+    output: Option<&Path>,
+) -> Result<bool, TeldaError> {
+    let recovery = if first_error {
+        Recovery::Stop
+    } else {
+        Recovery::Continue
+    };
+    let (lines, errors) = match emit {
+        Emit::Ast => parse_all(lines),
+        Emit::Expanded => expand(lines, recovery),
+    };
+    for e in &errors {
+        eprintln!("{e}");
+    }
+
+    match output.filter(|&path| !is_stdout(path)) {
+        Some(path) => {
+            let mut out = BufWriter::new(File::create(path)?);
+            write_lines_json(&mut out, &lines)?;
+            out.flush()?;
+        }
+        None => write_lines_json(&mut io::stdout().lock(), &lines)?,
+    }
+    Ok(errors.is_empty())
+}
+
+/// Checks the checksum stored in a flat binary against the source
+fn verify_checksum(telf: &Path, src: &ProcessedSource) -> Result<(), String> {
+    let bytes = fs::read(telf).map_err(|e| e.to_string())?;
+    let elf = parse_telda_elf(&bytes).map_err(|e| e.to_string())?;
+    let checksum = src.checksum();
+    if elf.checksum == checksum {
+        Ok(())
+    } else {
+        Err(format!(
+            "checksum 0x{:08x} does not match 0x{checksum:08x} of the source",
+            elf.checksum
+        ))
+    }
+}
+
+/// Checks that the object can be written without its symbols in a format other than an object
+fn check_flat(obj: &Object, format: Format) -> Result<(), String> {
+    if let Some(reference) = obj
+        .symbols
+        .iter()
+        .find(|s| s.segment_type == SegmentType::Unknown)
+    {
+        return Err(format!(
+            "{} files cannot reference `{}' from another object",
+            format_name(format),
+            reference.name
+        ));
+    }
+
+    // these are loaded into memory with ROM below DATA_START
+    if matches!(format, Format::Telf | Format::Raw) {
+        for (&st, &(start, _)) in &obj.segs {
+            let writeable = matches!(
+                st,
+                SegmentType::Data | SegmentType::Heap | SegmentType::Stack
+            );
+            if writeable && start < DATA_START {
+                return Err(format!(
+                    "{st} segment is writeable but would be put in ROM, place it at 0x{DATA_START:04x} or above"
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn format_name(format: Format) -> String {
+    format
+        .to_possible_value()
+        .expect("formats are not hidden")
+        .get_name()
+        .to_owned()
+}
+
+/// Writes the segments and entry point of the object in a format other than an object
+fn write_flat(obj: &Object, format: Format, checksum: u32, out_path: &Path) -> io::Result<()> {
+    let mut segments: Vec<(u16, &[u8])> = obj
+        .segs
+        .values()
+        .map(|(start, bytes)| (*start, &bytes[..]))
+        .collect();
+    segments.sort_by_key(|&(start, _)| start);
+    let entry = obj.entry.map(|Entry(_, addr)| addr);
+
+    let writer: &dyn OutputFormat = match format {
+        Format::Object => unreachable!("objects are written with their symbols"),
+        Format::Telf => &Telf { checksum },
+        Format::Raw => &RawBinary,
+        Format::Ihex => &IntelHex,
+        Format::Srec => &MotorolaSRec,
+    };
+    let mut out = Vec::new();
+    writer.write(entry, &segments, &mut out)?;
+    if is_stdout(out_path) {
+        io::stdout().lock().write_all(&out)
+    } else {
+        fs::write(out_path, out)
+    }
+}
+
+/// The output path that means standard output
+const STDOUT: &str = "-";
+
+fn is_stdout(path: &Path) -> bool {
+    path == Path::new(STDOUT)
+}
+
+/// Writes the object to standard output, it is made in memory first since álvur files are seeked
+fn write_object_to_stdout(obj: &Object) -> io::Result<()> {
+    let mut buf = Cursor::new(Vec::new());
+    {
+        // the writer ends the file when it is dropped
+        let mut aalvur = AalvWriter::new(&mut buf, 0)?;
+        obj.write(&mut aalvur)?;
+    }
+    io::stdout().lock().write_all(buf.get_ref())
+}
+
+/// Loads the cached processed source and its warnings if the cache is newer than all the files
+/// that went into it
+fn load_cached(p: &Path, cache_path: &Path) -> Option<(ProcessedSource, Vec<Warning>)> {
+    let cached_at = fs::metadata(cache_path).ok()?.modified().ok()?;
+    let unchanged = |source: &Path| {
+        fs::metadata(source)
+            .and_then(|m| m.modified())
+            .map(|t| t <= cached_at)
+            .unwrap_or(false)
+    };
+    if !unchanged(p) {
+        return None;
+    }
+
+    let (src, warnings) = load_cache(BufReader::new(File::open(cache_path).ok()?)).ok()?;
+    src.inputs
+        .iter()
+        .all(|input| unchanged(input))
+        .then_some((src, warnings))
+}
+
+/// How many bytes are shown on each line of a listing
+const LISTING_BYTES: usize = 4;
+
+fn write_listing(
+    p: &Path,
+    lst_path: &Path,
+    emitted: &[(SourceLocation, SegmentType, Range<usize>)],
+    segs: &BTreeMap<SegmentType, (u16, Vec<u8>)>,
+    labels: &[(Box<str>, SymbolType, SegmentType, u16)],
+) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(lst_path)?);
+
+    let mut sources = vec![p.display().to_string()];
+    let mut by_location = HashMap::new();
+    for (loc, st, range) in emitted {
+        if !sources.iter().any(|s| s == loc.source()) {
+            sources.push(loc.source().to_owned());
+        }
+        let (start, ref bytes) = segs[st];
+        let addr = start + range.start as u16;
+        // a line can emit several data lines right after each other
+        by_location
+            .entry((loc.source(), loc.line_number()))
+            .and_modify(|(line_addr, line_bytes): &mut (u16, &[u8])| {
+                let line_start = (*line_addr - start) as usize;
+                *line_bytes = &bytes[line_start..range.end];
+            })
+            .or_insert((addr, &bytes[range.clone()]));
+    }
+
+    let bytes_width = LISTING_BYTES * 3 - 1;
+    for (i, source) in sources.iter().enumerate() {
+        if i != 0 {
+            writeln!(out)?;
+            writeln!(out, "; {source}")?;
+        }
+        let text = fs::read_to_string(source)?;
+
+        for (ln, line) in (1..).zip(text.lines()) {
+            let align = line.trim_start().starts_with(".align");
+            let row = if let Some(&(addr, bytes)) = by_location.get(&(&**source, ln)) {
+                if align && !bytes.is_empty() {
+                    writeln!(out, "0x{addr:04x}  -- align padding --  {line}")?;
+                    continue;
+                }
+                let mut chunks = bytes.chunks(LISTING_BYTES);
+                let first = chunks.next().map(hex_bytes).unwrap_or_default();
+                let mut row = format!("0x{addr:04x}  {first:bytes_width$}  {line}");
+                for (j, chunk) in (1..).zip(chunks) {
+                    let addr = addr + (j * LISTING_BYTES) as u16;
+                    row.push_str(&format!("\n0x{addr:04x}  {}", hex_bytes(chunk)));
+                }
+                row
+            } else if let Some(&(.., addr)) = line.trim().strip_suffix(':').and_then(|lbl| {
+                labels
+                    .iter()
+                    .find(|(l, _, st, _)| &**l == lbl && *st != SegmentType::Unknown)
+            }) {
+                format!("0x{addr:04x}  {:bytes_width$}  {line}", "")
+            } else {
+                format!("{:6}  {:bytes_width$}  {line}", "", "")
+            };
+            writeln!(out, "{}", row.trim_end())?;
+        }
+    }
+
+    out.flush()
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
@@ -1,6 +1,7 @@
 use std::{
     collections::{HashMap, VecDeque},
-    io::{stdin, stdout, Write},
+    fs::{self, File},
+    io::{stdin, stdout, BufReader, Write},
     path::PathBuf,
     process::ExitCode,
 };
@@ -9,8 +10,13 @@ use telda2::{
     aalv::obj::{Object, SymbolDefinition},
     blf4::*,
     disassemble::disassemble_instruction,
+    gdb_stub::GdbStub,
     machine::Machine,
     mem::{Io, LazyMain},
+    source::{
+        lookup_source, read_source_map, source_map_from_line_numbers, SourceMapEntry,
+        SOURCE_MAP_EXT,
+    },
 };
 
 struct DbgIo {
@@ -51,6 +57,9 @@ use clap::Parser;
 #[command(author, version, about, long_about = None)]
 struct Cli {
     /// Binary to debug
+    ///
+    /// If a source map (.srcmap) is next to it or it has a line number table,
+    /// the source lines are shown along with the disassembly
     #[arg(required = true)]
     input_file: PathBuf,
 
@@ -59,14 +68,32 @@ struct Cli {
     /// Can be either a hexadecimal address prefixed by 0x or a symbol
     #[arg(short = 'E', long)]
     entry: Option<String>,
+
+    /// Waits for a GDB client to connect on this port instead of showing the prompt
+    #[arg(long, value_name = "PORT", num_args = 0..=1, default_missing_value = "1234")]
+    gdb: Option<u16>,
 }
 
 fn main() -> ExitCode {
-    let Cli { input_file, entry } = Cli::parse();
+    let Cli {
+        input_file,
+        entry,
+        gdb,
+    } = Cli::parse();
 
     let mut machine;
     let mut labels = HashMap::new();
     let mut pos_to_labels = HashMap::new();
+    let mut source_map = match File::open(input_file.with_extension(SOURCE_MAP_EXT)) {
+        Ok(f) => match read_source_map(&mut BufReader::new(f)) {
+            Ok(entries) => entries,
+            Err(e) => {
+                eprintln!("could not read source map: {e}");
+                Vec::new()
+            }
+        },
+        Err(_) => Vec::new(),
+    };
     {
         let obj = match Object::from_file(input_file) {
             Ok(o) => o,
@@ -76,6 +103,9 @@ fn main() -> ExitCode {
                 return ExitCode::FAILURE;
             }
         };
+        if let (true, Some(table)) = (source_map.is_empty(), &obj.line_numbers) {
+            source_map = source_map_from_line_numbers(table);
+        }
 
         machine = Machine::new(
             LazyMain::new(DbgIo {
@@ -137,20 +167,39 @@ fn main() -> ExitCode {
         }
     }
 
-    tdbg_loop(machine, pos_to_labels);
+    if let Some(port) = gdb {
+        println!("waiting for gdb to connect on port {port}");
+        if let Err(e) = GdbStub::new(machine)
+            .with_source_map(source_map)
+            .listen(("127.0.0.1", port))
+        {
+            eprintln!("gdb connection failed: {e}");
+            return ExitCode::FAILURE;
+        }
+        return ExitCode::SUCCESS;
+    }
+
+    tdbg_loop(machine, pos_to_labels, &source_map);
 
     ExitCode::SUCCESS
 }
 
-fn tdbg_loop(mut machine: Machine<LazyMain<DbgIo>, Blf4>, pos_to_labels: HashMap<u16, Box<str>>) {
+fn tdbg_loop(
+    mut machine: Machine<LazyMain<DbgIo>, Blf4>,
+    pos_to_labels: HashMap<u16, Box<str>>,
+    source_map: &[SourceMapEntry],
+) {
     let stdin = stdin();
     let mut input = String::new();
     let mut target_nesting = 0;
     let mut current_nesting = 0;
+    let mut sources = HashMap::new();
+    let mut last_source_line = None;
 
     'disassemble_loop: loop {
         let dins =
-            disassemble_instruction(&mut machine, |p| pos_to_labels.get(&p).map(|s| &**s)).unwrap();
+            disassemble_instruction(&mut machine, |p| pos_to_labels.get(&p).map(|s| (&**s, 0)))
+                .unwrap();
 
         if false && todo!("is trapping") {
             println!("handled trap encountered!");
@@ -163,6 +212,13 @@ fn tdbg_loop(mut machine: Machine<LazyMain<DbgIo>, Blf4>, pos_to_labels: HashMap
             if let Some(label) = pos_to_labels.get(&machine.cpu.program_counter) {
                 println!("<{label}>:");
             }
+            if let Some(entry) = lookup_source(source_map, machine.cpu.program_counter) {
+                let source_line = (&*entry.source, entry.line);
+                if last_source_line != Some(source_line) {
+                    print_source_line(&mut sources, entry);
+                    last_source_line = Some(source_line);
+                }
+            }
 
             println!("{}", dins.annotated_source);
 
@@ -213,6 +269,19 @@ fn tdbg_loop(mut machine: Machine<LazyMain<DbgIo>, Blf4>, pos_to_labels: HashMap
                         c.read(addr + 1).unwrap()
                     );
                 }
+                l if l == "x" || l.starts_with("x ") || l.starts_with("x/") => {
+                    let examine = match parse_examine(&l[1..]) {
+                        Ok(examine) => examine,
+                        Err(s) => {
+                            eprintln!("{s}");
+                            continue;
+                        }
+                    };
+                    let mut c = machine.cpu.context(&mut machine.memory);
+                    if let Err(e) = examine.print(|addr| c.read(addr).ok()) {
+                        eprintln!("{e}");
+                    }
+                }
                 "r0b" => print_byte_register("r0b", R0B, &machine.cpu),
                 "r1l" => print_byte_register("r1l", R1L, &machine.cpu),
                 "r1h" => print_byte_register("r1h", R1H, &machine.cpu),
@@ -274,6 +343,17 @@ fn tdbg_loop(mut machine: Machine<LazyMain<DbgIo>, Blf4>, pos_to_labels: HashMap
     }
 }
 
+fn print_source_line(sources: &mut HashMap<Box<str>, Option<String>>, entry: &SourceMapEntry) {
+    let text = sources
+        .entry(entry.source.clone())
+        .or_insert_with(|| fs::read_to_string(&*entry.source).ok());
+    let line = text
+        .as_deref()
+        .and_then(|text| text.lines().nth(entry.line.checked_sub(1)? as usize))
+        .unwrap_or("");
+    println!("{}:{}: {}", entry.source, entry.line, line.trim());
+}
+
 fn print_byte_register(name: &str, r: ByteRegister, reg: &Blf4) {
     let val = reg.read_br(r);
     print!("{name} = {val} 0x{val:02x}");
@@ -284,6 +364,141 @@ fn print_byte_register(name: &str, r: ByteRegister, reg: &Blf4) {
     println!();
 }
 
+/// How `x` shows memory, like GDB's `x/Nfs`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExamineFormat {
+    Hex,
+    Decimal,
+    Char,
+    /// NUL-terminated, UTF-16LE with the wide size
+    String,
+}
+
+struct Examine {
+    addr: u16,
+    count: u16,
+    format: ExamineFormat,
+    wide: bool,
+}
+
+/// Longest string `x/s` prints before cutting it off
+const MAX_EXAMINE_STRING: usize = 256;
+const EXAMINE_PER_ROW: u16 = 8;
+
+/// Parses what comes after `x`, either `/Nfs addr` or ` addr [count]`
+fn parse_examine(arg: &str) -> Result<Examine, &'static str> {
+    let mut examine = Examine {
+        addr: 0,
+        count: 1,
+        format: ExamineFormat::Hex,
+        wide: false,
+    };
+    let rest = if let Some(spec) = arg.strip_prefix('/') {
+        let (spec, rest) = spec.split_once(' ').ok_or("missing address")?;
+        let digits = spec
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(spec.len());
+        if digits > 0 {
+            examine.count = spec[..digits].parse().map_err(|_| "invalid count")?;
+        }
+        for c in spec[digits..].chars() {
+            match c {
+                'x' => examine.format = ExamineFormat::Hex,
+                'd' => examine.format = ExamineFormat::Decimal,
+                'c' => examine.format = ExamineFormat::Char,
+                's' => examine.format = ExamineFormat::String,
+                'b' => examine.wide = false,
+                'w' => examine.wide = true,
+                _ => return Err("unknown format, expected x, d, c or s and size b or w"),
+            }
+        }
+        rest
+    } else {
+        arg
+    };
+
+    let mut args = rest.split_whitespace();
+    examine.addr = parse_num(args.next().ok_or("missing address")?)?;
+    if let Some(count) = args.next() {
+        examine.count = parse_num(count)?;
+    }
+    if args.next().is_some() {
+        return Err("too many arguments");
+    }
+    Ok(examine)
+}
+
+impl Examine {
+    /// Prints the memory as read by `read`, stopping with an error at the first address that
+    /// cannot be read
+    fn print(&self, mut read: impl FnMut(u16) -> Option<u8>) -> Result<(), String> {
+        let mut read = |addr: u16| read(addr).ok_or_else(|| format!("cannot read 0x{addr:04x}"));
+        let mut read_unit = |addr: u16| -> Result<u16, String> {
+            Ok(if self.wide {
+                u16::from_le_bytes([read(addr)?, read(addr.wrapping_add(1))?])
+            } else {
+                read(addr)? as u16
+            })
+        };
+        let unit = if self.wide { 2 } else { 1 };
+        let mut addr = self.addr;
+
+        if self.format == ExamineFormat::String {
+            for _ in 0..self.count {
+                let start = addr;
+                let mut units = Vec::new();
+                let mut terminated = false;
+                while units.len() < MAX_EXAMINE_STRING {
+                    let u = read_unit(addr)?;
+                    addr = addr.wrapping_add(unit);
+                    if u == 0 {
+                        terminated = true;
+                        break;
+                    }
+                    units.push(u);
+                }
+                let s = if self.wide {
+                    String::from_utf16_lossy(&units)
+                } else {
+                    let bytes: Vec<u8> = units.into_iter().map(|u| u as u8).collect();
+                    String::from_utf8_lossy(&bytes).into_owned()
+                };
+                println!(
+                    "0x{start:04x}: {s:?}{}",
+                    if terminated { "" } else { "..." }
+                );
+            }
+            return Ok(());
+        }
+
+        for row in 0..self.count.div_ceil(EXAMINE_PER_ROW) {
+            let n = (self.count - row * EXAMINE_PER_ROW).min(EXAMINE_PER_ROW);
+            let row_addr = addr;
+            // a whole row is read first so a failed read does not leave half a line
+            let mut units = Vec::with_capacity(n as usize);
+            for _ in 0..n {
+                units.push(read_unit(addr)?);
+                addr = addr.wrapping_add(unit);
+            }
+            print!("0x{row_addr:04x}:");
+            for u in units {
+                match (self.format, self.wide) {
+                    (ExamineFormat::Hex, false) => print!(" 0x{u:02x}"),
+                    (ExamineFormat::Hex, true) => print!(" 0x{u:04x}"),
+                    (ExamineFormat::Decimal, _) => print!(" {u}"),
+                    (ExamineFormat::Char, _) => {
+                        let c = char::from_u32(u as u32).unwrap_or(char::REPLACEMENT_CHARACTER);
+                        print!(" {c:?}");
+                    }
+                    (ExamineFormat::String, _) => unreachable!(),
+                }
+            }
+            println!();
+        }
+        Ok(())
+    }
+}
+
 fn parse_num(num: &str) -> Result<u16, &'static str> {
     Ok(if let Some(num) = num.strip_prefix("0x") {
         u16::from_str_radix(num, 16).map_err(|_| "invalid hex number")?
@@ -1,14 +1,15 @@
 use std::{
-    collections::{BTreeMap, HashMap}, fs::{self, File}, io::{self, Seek, Write}, num::ParseIntError, ops::Deref, os::unix::prelude::PermissionsExt, path::PathBuf, process::ExitCode
+    collections::{BTreeMap, HashMap, HashSet}, fs::{self, File}, io::{self, Seek, Write}, mem, num::ParseIntError, ops::Deref, os::unix::prelude::PermissionsExt, path::PathBuf, process::ExitCode
 };
 
 use clap::Parser;
 use collect_result::CollectResult;
 use telda2::{
     aalv::{obj::{
-        Entry, Object, RelocationEntry, RelocationTable, SegmentType, SymbolDefinition, SymbolTable,
-    }, read_archive},
-    align_end, PAGE_SIZE,
+        Entry, ExternTable, Object, RelocationEntry, RelocationTable, SegmentLayout, SegmentType, StackSize, SymbolDefinition,
+        SymbolTable, WeakTable,
+    }, read_archive, MemberName},
+    align_end, checked_align_end, PAGE_SIZE,
 };
 
 fn one_one(s: &str) -> Result<u16, &'static str> {
@@ -47,6 +48,8 @@ struct Cli {
 
     /// A 2^n value which segments will be aligned to, i.e. segments will start on an address
     /// that is a multiple f this value
+    ///
+    /// Segments requiring a larger alignment in their object file will use that instead
     #[arg(short = 'A', long = "alignment", default_value = "128", value_parser = one_one)]
     segment_alignment: u16,
 
@@ -58,6 +61,9 @@ struct Cli {
     /// disallows undefined references
     ///
     /// Errors if no entry-point is defined in input files or with -E
+    ///
+    /// Text, read-only data and data segments of objects from archives that cannot be reached
+    /// from the input objects through their symbols are left out
     #[arg(short = 'e', long)]
     executable: bool,
     /// Output as raw binary skipping the first 128 bytes (IO-mapped) bytes
@@ -82,6 +88,7 @@ fn main() -> ExitCode {
                 Error::InvalidEntryPointFormat(e) => eprintln!("invalid entry point format: {e}"),
                 Error::NoEntryPoint => eprintln!("No entry point was defined, cannot make executable. Perhaps use -E to set one?"),
                 Error::ReferenceToNonExistantSegment => eprintln!("reference to a segment that was not defined"),
+                Error::SegmentDoesNotFit(st) => eprintln!("segment {st} does not fit below its maximum address"),
                 Error::ObjectFailure => (),
             }
 
@@ -90,12 +97,14 @@ fn main() -> ExitCode {
     }
 }
 
+#[derive(Debug)]
 enum Error {
     Io(io::Error),
     InvalidEntryPointFormat(ParseIntError),
     ObjectFailure,
     NoEntryPoint,
     ReferenceToNonExistantSegment,
+    SegmentDoesNotFit(SegmentType),
 }
 
 fn tl_main() -> Result<(), Error> {
@@ -119,29 +128,104 @@ fn tl_main() -> Result<(), Error> {
 
     let lib_objects = read_archives(verbose, archives, objects.iter().map(|no| &no.1)).map_err(Error::Io)?;
 
-    let objects: Vec<_> = objects.into_iter().chain(lib_objects).collect();
-
     if raw_binary {
         unimplemented!("unsupported rn :3");
     }
 
+    let options = LinkOptions {
+        set_entry,
+        strip_internal,
+        executable,
+        segment_alignment,
+        verbose,
+    };
+    let obj = link(objects, lib_objects, &options)?;
+    let out = out.unwrap_or_else(|| PathBuf::from("a.to"));
+
+    if executable {
+        let mut obj = obj;
+        {
+            let mut file = File::create(&out).map_err(Error::Io)?;
+            writeln!(file, "#!/bin/env t").map_err(Error::Io)?;
+
+            obj.file_offset = file.stream_position().map_err(Error::Io)?;
+        }
+
+        obj.write_to_file(&out).map_err(Error::Io)?;
+
+        let mut perms = fs::metadata(&out).map_err(Error::Io)?.permissions();
+        perms.set_mode(perms.mode() | 0o111);
+        fs::set_permissions(&out, perms).map_err(Error::Io)?;
+    } else {
+        obj.write_to_file(out).map_err(Error::Io)?;
+    }
+
+    Ok(())
+}
+
+/// How to link objects, see [`Cli`]
+struct LinkOptions {
+    set_entry: Option<String>,
+    strip_internal: bool,
+    executable: bool,
+    segment_alignment: u16,
+    verbose: bool,
+}
+
+/// Links the objects and then the objects from archives into one, their parts of each segment are
+/// placed in the order they are given
+fn link(
+    objects: Vec<(String, Object)>,
+    lib_objects: Vec<(String, Object)>,
+    options: &LinkOptions,
+) -> Result<Object, Error> {
+    let LinkOptions {
+        ref set_entry,
+        strip_internal,
+        executable,
+        segment_alignment,
+        verbose,
+    } = *options;
+
+    let explicit = objects.len();
+    let mut objects: Vec<_> = objects.into_iter().chain(lib_objects).collect();
+    if executable {
+        objects = eliminate_unreachable(verbose, objects, explicit, set_entry.as_deref());
+    }
+
     let mut segs_out = BTreeMap::new();
+    let mut layouts_out = BTreeMap::new();
+    let stack_size;
 
     {
         let mut lengths = BTreeMap::new();
 
         for (_, obj) in &objects {
             for (&stype, &(_start, ref v)) in &obj.segs {
-                *lengths.entry(stype).or_insert(0) += v.len() as u16;
+                let layout = obj.layouts.get(&stype).copied().unwrap_or_default();
+                let length = lengths.entry(stype).or_insert(0);
+                // each object's part of the segment starts aligned to what it requires
+                *length = align_end(*length, layout.alignment) + part_size(obj.stack_size, stype, v);
+                layouts_out
+                    .entry(stype)
+                    .and_modify(|l: &mut SegmentLayout| *l = l.merge(layout))
+                    .or_insert(layout);
             }
         }
         let mut last_end = lengths.remove(&SegmentType::Zero).unwrap_or(0);
         last_end = last_end.max(PAGE_SIZE);
+        layouts_out.remove(&SegmentType::Zero);
+        layouts_out.retain(|_, l| *l != SegmentLayout::default());
+        stack_size = lengths.get(&SegmentType::Stack).copied().map(StackSize);
 
         for (st, size) in lengths {
-            let start = align_end(last_end, segment_alignment);
+            let layout = layouts_out.get(&st).copied().unwrap_or_default();
+            let start = checked_align_end(last_end, segment_alignment).and_then(|a| layout.place(a));
+            let Some(start) = start.filter(|&start| layout.fits(start, size)) else {
+                return Err(Error::SegmentDoesNotFit(st));
+            };
             segs_out.insert(st, (start, Vec::with_capacity(size as usize)));
-            last_end = start + size;
+            last_end = start.saturating_add(size);
 
             if verbose {
                 println!("segment {st} @ 0x{start:04x} w/ {} bytes (0x{0:02x})", size);
@@ -154,23 +238,42 @@ fn tl_main() -> Result<(), Error> {
         .map(|(&st, &mut (start, ref mut bytes))| (st, (start, bytes)))
         .collect();
 
-    let out = out.unwrap_or_else(|| PathBuf::from("a.to"));
-
     let mut global_symbols = HashMap::new();
     let mut symbols_out = Vec::new();
     let mut reloc_out = Vec::new();
     let mut undefined_references = Vec::new();
+    let mut externs = ExternTable::default();
+    // global symbols whose definition so far is weak
+    let mut weak = WeakTable::default();
 
     let mut entry_point = None;
 
     let mut failure = false;
 
     for (input_file, mut obj) in objects {
+        for &st in obj.segs.keys() {
+            let alignment = obj.layouts.get(&st).map(|l| l.alignment).unwrap_or(1);
+            let seg = segs.get_mut(&st).expect("segment guaranteed to exist");
+            let padding = align_end(seg.0, alignment) - seg.0;
+            seg.0 += padding;
+            if st != SegmentType::Stack {
+                seg.1.resize(seg.1.len() + padding as usize, 0);
+            }
+        }
+
         entry_point = entry_point.or_else(|| {
             obj.entry
                 .map(|Entry(st, ep)| Entry(st, ep - obj.segs[&st].0 + segs[&st].0))
         });
 
+        for name in obj.externs.take().into_iter().flat_map(|e| e.0) {
+            if !externs.contains(&name) {
+                externs.0.push(name);
+            }
+        }
+
+        let obj_weak = obj.weak.take().unwrap_or_default();
+
         let mut file_symbol_to_out_symbol = Vec::new();
         let reloc;
         {
@@ -182,9 +285,14 @@ fn tl_main() -> Result<(), Error> {
                 symdef.location += segs.get(&symdef.segment_type).map(|s| s.0).unwrap_or(0);
 
                 if symdef.is_global {
+                    let is_weak = symdef.segment_type != SegmentType::Unknown
+                        && obj_weak.contains(&symdef.name);
                     match global_symbols.get(&symdef.name) {
                         None => {
                             global_symbols.insert(symdef.name.clone(), next_id);
+                            if is_weak {
+                                weak.0.push(symdef.name.clone());
+                            }
                         }
                         Some(&id) => {
                             let cur_symdef: &mut SymbolDefinition = &mut symbols_out[id];
@@ -192,6 +300,15 @@ fn tl_main() -> Result<(), Error> {
                             if let SegmentType::Unknown = symdef.segment_type {
                             } else if let SegmentType::Unknown = cur_symdef.segment_type {
                                 *cur_symdef = symdef.clone();
+                                if is_weak {
+                                    weak.0.push(symdef.name.clone());
+                                }
+                            } else if is_weak {
+                                // the definition already there takes precedence
+                            } else if weak.contains(&symdef.name) {
+                                // a strong definition replaces a weak one
+                                weak.0.retain(|w| *w != symdef.name);
+                                *cur_symdef = symdef.clone();
                             } else {
                                 eprintln!("global symbol {} defined in {} but was already defined in a previous file at location 0x{:02x} in {}",
                                     symdef.name,
@@ -239,7 +356,10 @@ fn tl_main() -> Result<(), Error> {
                 .1;
 
             let symdef = &symbols_out[symbol_index];
-            let undefined = matches!(symdef.segment_type, SegmentType::Unknown);
+            // references to weak symbols are resolved at the end when it is known which
+            // definition wins
+            let undefined = matches!(symdef.segment_type, SegmentType::Unknown)
+                || weak.contains(&symdef.name);
 
             bytes[location_in_file as usize..location_in_file as usize + 2]
                 .copy_from_slice(&symdef.location.to_le_bytes());
@@ -256,9 +376,9 @@ fn tl_main() -> Result<(), Error> {
             }
         }
 
-        for (t, (_, bytes)) in obj.segs {
-            let seg = segs.get_mut(&t).expect("segment guaranteed to exist");
-            seg.0 += bytes.len() as u16;
+        for (t, (_, bytes)) in &obj.segs {
+            let seg = segs.get_mut(t).expect("segment guaranteed to exist");
+            seg.0 += part_size(obj.stack_size, *t, bytes);
             seg.1.extend(bytes);
         }
     }
@@ -272,7 +392,8 @@ fn tl_main() -> Result<(), Error> {
     {
         let symdef = &symbols_out[symbol_index as usize];
         if let SegmentType::Unknown = symdef.segment_type {
-            if executable {
+            // externs are provided by whatever loads the executable
+            if executable && !externs.contains(&symdef.name) {
                 eprintln!(
                     "undefined reference to {} (0x{:03x}) at 0x{reference_location:04x}",
                     symdef.name, symdef.location
@@ -296,7 +417,7 @@ fn tl_main() -> Result<(), Error> {
                     SegmentType::Zero,
                     u16::from_str_radix(entry, 16).map_err(Error::InvalidEntryPointFormat)?,
                 )
-            } else if let Some(&pos) = global_symbols.get(&*entry) {
+            } else if let Some(&pos) = global_symbols.get(&**entry) {
                 let sym = &symbols_out[pos];
                 Entry(sym.segment_type, sym.location)
             } else {
@@ -312,37 +433,30 @@ fn tl_main() -> Result<(), Error> {
         return Err(Error::ObjectFailure);
     }
 
+    // an extern that another object defines is just a normal symbol
+    externs.0.retain(|name| {
+        global_symbols
+            .get(name)
+            .is_some_and(|&id| symbols_out[id].segment_type == SegmentType::Unknown)
+    });
+
     let obj = Object {
         segs: segs_out,
+        layouts: layouts_out,
         entry: entry_point,
         symbols: SymbolTable(symbols_out),
         relocation_table: RelocationTable(reloc_out),
+        stack_size,
+        externs: (!externs.0.is_empty()).then_some(externs),
+        weak: (!weak.0.is_empty()).then_some(weak),
         ..Object::default()
     };
 
-    if executable {
-        if obj.entry.is_none() {
-            return Err(Error::NoEntryPoint);
-        }
-
-        let mut obj = obj;
-        {
-            let mut file = File::create(&out).map_err(Error::Io)?;
-            writeln!(file, "#!/bin/env t").map_err(Error::Io)?;
-
-            obj.file_offset = file.stream_position().map_err(Error::Io)?;
-        }
-
-        obj.write_to_file(&out).map_err(Error::Io)?;
-
-        let mut perms = fs::metadata(&out).map_err(Error::Io)?.permissions();
-        perms.set_mode(perms.mode() | 0o111);
-        fs::set_permissions(&out, perms).map_err(Error::Io)?;
-    } else {
-        obj.write_to_file(out).map_err(Error::Io)?;
+    if executable && obj.entry.is_none() {
+        return Err(Error::NoEntryPoint);
     }
 
-    Ok(())
+    Ok(obj)
 }
 
 #[repr(transparent)]
@@ -384,6 +498,14 @@ fn name_and_defined_if_global(sd: &SymbolDefinition) -> Option<(Box<str>, bool)>
     sd.is_global.then_some((sd.name.clone(), sd.segment_type != SegmentType::Unknown))
 }
 
+/// Size of an object's part of a segment, the stack segment has no bytes but the stack size
+fn part_size(stack_size: Option<StackSize>, st: SegmentType, bytes: &[u8]) -> u16 {
+    match st {
+        SegmentType::Stack => stack_size.map_or(0, |s| s.0),
+        _ => bytes.len() as u16,
+    }
+}
+
 fn read_archives<'a, I: 'a + Iterator<Item=&'a Object>>(verbose: bool, archives: Vec<PathBuf>, objs: I) -> Result<Vec<(String, Object)>, io::Error> {
     let input_globals: DefinedMap = objs
         .flat_map(|o| o.symbols.iter())
@@ -394,14 +516,21 @@ fn read_archives<'a, I: 'a + Iterator<Item=&'a Object>>(verbose: bool, archives:
     for arch_path in archives {
         let mut arch = read_archive(&arch_path)?;
         let mut i = 0;
-        while let Some(obj) = arch.next(Object::from_aalv_reader)? {
+        while let Some((name, obj)) = arch.next(|r| {
+            let name = r.read_section::<MemberName>().transpose()?;
+            Ok((name, Object::from_aalv_reader(r)?))
+        })? {
             i += 1;
             let o_glbls: DefinedMap = obj.symbols
                 .iter()
                 .filter_map(name_and_defined_if_global)
                 .collect();
 
-            a_objs.push((format!("{}.{i}", arch_path.display()), obj, o_glbls));
+            let oname = match name {
+                Some(MemberName(name)) => format!("{}({name})", arch_path.display()),
+                None => format!("{}.{i}", arch_path.display()),
+            };
+            a_objs.push((oname, obj, o_glbls));
         }
     }
 
@@ -438,3 +567,220 @@ fn read_archives<'a, I: 'a + Iterator<Item=&'a Object>>(verbose: bool, archives:
 
     Ok(objs)
 }
+
+/// Segments that are left out of executables when they cannot be reached
+const ELIMINABLE: [SegmentType; 3] = [SegmentType::Text, SegmentType::RoData, SegmentType::Data];
+
+/// Removes the text, read-only data and data segments of objects from archives that cannot be
+/// reached, objects without any segments left are removed
+///
+/// Every segment of the first `explicit` objects, which are the ones given as input, is reached,
+/// as well as the segment with the entry point and the other segments of archive objects.
+/// A segment reaches the segments defining the symbols it has relocations of, this follows every
+/// `call`, jump and `ldi` of a label since they all have relocations
+fn eliminate_unreachable(
+    verbose: bool,
+    objects: Vec<(String, Object)>,
+    explicit: usize,
+    set_entry: Option<&str>,
+) -> Vec<(String, Object)> {
+    let is_weak = |o: &Object, sym: &str| o.weak.as_ref().is_some_and(|w| w.contains(sym));
+    // the object and segment of the definition of a global symbol, the strong definition wins
+    let defined_in = |sym: &str| {
+        let mut definers = objects.iter().enumerate().filter_map(|(i, (_, o))| {
+            let sd = o.symbols.iter().find(|sd| {
+                sd.is_global && sd.segment_type != SegmentType::Unknown && &*sd.name == sym
+            })?;
+            Some((i, sd.segment_type, is_weak(o, sym)))
+        });
+        let first = definers.clone().next();
+        definers
+            .find(|&(_, _, weak)| !weak)
+            .or(first)
+            .map(|(i, st, _)| (i, st))
+    };
+
+    let mut to_visit = Vec::new();
+    for (i, (_, obj)) in objects.iter().enumerate() {
+        for &st in obj.segs.keys() {
+            if i < explicit || !ELIMINABLE.contains(&st) {
+                to_visit.push((i, st));
+            }
+        }
+    }
+    let entry = objects
+        .iter()
+        .enumerate()
+        .find_map(|(i, (_, o))| Some((i, o.entry?.0)));
+    to_visit.extend(entry);
+    match set_entry {
+        // an address could be in any object, but the objects given as input are kept anyway
+        Some(entry) if entry.starts_with("0x") => (),
+        Some(entry) => to_visit.extend(defined_in(entry)),
+        None => (),
+    }
+
+    let mut reachable = HashSet::new();
+    while let Some((i, st)) = to_visit.pop() {
+        if !reachable.insert((i, st)) {
+            continue;
+        }
+        let obj = &objects[i].1;
+        for reloc in obj.relocation_table.0.iter() {
+            if reloc.reference_segment != st {
+                continue;
+            }
+            let sd = &obj.symbols.0[reloc.symbol_index as usize];
+            if sd.is_global {
+                to_visit.extend(defined_in(&sd.name));
+            } else if sd.segment_type != SegmentType::Unknown {
+                to_visit.push((i, sd.segment_type));
+            }
+        }
+    }
+
+    objects
+        .into_iter()
+        .enumerate()
+        .filter_map(|(i, (name, mut obj))| {
+            let unreachable: Vec<_> = obj
+                .segs
+                .keys()
+                .copied()
+                .filter(|&st| !reachable.contains(&(i, st)))
+                .collect();
+            if verbose {
+                for st in &unreachable {
+                    println!("eliminating segment {st} of {name} because it is not reachable");
+                }
+            }
+            remove_segments(&mut obj, &unreachable);
+            (i < explicit || !obj.segs.is_empty()).then_some((name, obj))
+        })
+        .collect()
+}
+
+/// Removes the segments and their relocations and symbols from the object
+///
+/// A symbol in a removed segment that the rest of the object still uses becomes a reference, which
+/// only happens to global symbols whose definition in another object wins
+fn remove_segments(obj: &mut Object, removed: &[SegmentType]) {
+    if removed.is_empty() {
+        return;
+    }
+    for st in removed {
+        obj.segs.remove(st);
+        obj.layouts.remove(st);
+    }
+    obj.relocation_table
+        .0
+        .retain(|r| !removed.contains(&r.reference_segment));
+    let used: HashSet<u16> = obj
+        .relocation_table
+        .0
+        .iter()
+        .map(|r| r.symbol_index)
+        .collect();
+
+    let mut new_indices = Vec::with_capacity(obj.symbols.0.len());
+    let mut symbols = Vec::new();
+    for (i, mut sd) in mem::take(&mut obj.symbols.0).into_iter().enumerate() {
+        if removed.contains(&sd.segment_type) {
+            if !used.contains(&(i as u16)) {
+                new_indices.push(None);
+                continue;
+            }
+            sd.segment_type = SegmentType::Unknown;
+            sd.location = 0;
+        }
+        new_indices.push(Some(symbols.len() as u16));
+        symbols.push(sd);
+    }
+    obj.symbols.0 = symbols;
+    for reloc in &mut obj.relocation_table.0 {
+        reloc.symbol_index =
+            new_indices[reloc.symbol_index as usize].expect("used symbols are kept");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use telda2::{blf4::isa::*, source::ProcessedSource};
+
+    use super::*;
+
+    fn object(src: &str) -> (String, Object) {
+        let obj = ProcessedSource::assemble(src).unwrap().to_object();
+        (String::new(), obj)
+    }
+
+    fn options(executable: bool) -> LinkOptions {
+        LinkOptions {
+            set_entry: None,
+            strip_internal: false,
+            executable,
+            segment_alignment: PAGE_SIZE,
+            verbose: false,
+        }
+    }
+
+    #[test]
+    fn reference_across_objects() {
+        let main = object(".ref value\n.seg text\n.entry\nmain:\nldi r1, value\nhalt\n");
+        let value = object(".global value\n.seg data\n.wide 7\nvalue:\n.wide 9\n");
+        let obj = link(vec![main, value], Vec::new(), &options(false)).unwrap();
+
+        let [lo, hi] = (obj.segs[&SegmentType::Data].0 + 2).to_le_bytes();
+        assert_eq!(obj.segs[&SegmentType::Text].1, [LDI_W, 0x10, lo, hi, HALT]);
+        assert_eq!(obj.segs[&SegmentType::Data].1, [7, 0, 9, 0]);
+    }
+
+    #[test]
+    fn strong_definition_wins() {
+        let main = ".ref handler\n.seg text\n.entry\nmain:\ncall handler\nhalt\n";
+        let weak = ".weak handler\n.seg text\nhandler:\nldi r1, 1\nret\n";
+        let strong = ".global handler\n.seg text\nhandler:\nldi r1, 0x222\nret\n";
+        let strong_code = object(strong).1.segs[&SegmentType::Text].1.clone();
+
+        for executable in [false, true] {
+            for order in [[weak, strong], [strong, weak]] {
+                let lib_objects = order.into_iter().map(object).collect();
+                let obj = link(vec![object(main)], lib_objects, &options(executable)).unwrap();
+                let (start, ref text) = obj.segs[&SegmentType::Text];
+                assert_eq!(text[0], CALL);
+                let handler = (u16::from_le_bytes([text[1], text[2]]) - start) as usize;
+                assert_eq!(text[handler..handler + strong_code.len()], strong_code[..]);
+                // the weak definition from an archive is left out of executables
+                if executable {
+                    assert_eq!(text.len(), 4 + strong_code.len());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn unreachable_segments() {
+        let main = ".ref used\n.seg text\n.entry\nmain:\ncall used\nhalt\n.seg data\n.byte 5\n";
+        let used = ".global used\n.global unused\n\
+                    .seg text\nused:\nldi r1, msg\nret\n\
+                    .seg rodata\nmsg:\n.byte 1\n\
+                    .seg data\nunused:\n.byte 2\n";
+        let other = ".global other\n.seg text\nother:\nret\n";
+        let lib_objects = vec![object(used), object(other)];
+        let obj = link(vec![object(main)], lib_objects, &options(true)).unwrap();
+
+        assert_eq!(obj.segs[&SegmentType::RoData].1, [1]);
+        // the data segment of the input object is kept even though nothing uses it
+        assert_eq!(obj.segs[&SegmentType::Data].1, [5]);
+        let names: Vec<_> = obj.symbols.iter().map(|sd| &*sd.name).collect();
+        assert!(names.contains(&"used"));
+        assert!(!names.contains(&"unused"));
+        assert!(!names.contains(&"other"));
+
+        // objects given as input are kept whole
+        let objects = vec![object(main), object(used), object(other)];
+        let obj = link(objects, Vec::new(), &options(true)).unwrap();
+        assert_eq!(obj.segs[&SegmentType::Data].1, [5, 2]);
+        assert!(obj.symbols.iter().any(|sd| &*sd.name == "other"));
+    }
+}
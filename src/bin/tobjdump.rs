@@ -220,7 +220,7 @@ fn disassembly(obj: &Object, start_symbol: &Option<String>, show_relocations: bo
                     }
 
                     label_name = get_name(l);
-                    Some(&label_name)
+                    Some((&*label_name, 0))
                 } else {
                     None
                 }
@@ -54,7 +54,7 @@ fn inner_main(all: bool, force: bool, input_file: &PathBuf) -> Result<(), String
 
     if all {
         if obj.entry.is_none() && !force {
-            return Err(format!("no entry point, will not strip, output object would be neither be linkable nor runnable: use -f to strip anyways"));
+            return Err("no entry point, will not strip, output object would be neither be linkable nor runnable: use -f to strip anyways".to_owned());
         }
         obj.relocation_table = RelocationTable::default();
         obj.symbols.0.clear();
@@ -0,0 +1,228 @@
+//! Flat binaries with a header saying what they are and where they start
+//!
+//! The header is 20 bytes:
+//! magic (6), format version (1), ISA version (1), entry point (2),
+//! data offset (2), text offset (2), file size (2) and checksum (4), all little-endian.
+//! The text is loaded into ROM at 0x0080 and the data into RAM at 0x8000
+
+use std::fmt::{self, Display};
+
+use crate::{
+    blf4::{Blf4, CPU_VERSION},
+    machine::Machine,
+    mem::{write_n, Io, LazyMain, HALF_CELL, ROM_SIZE},
+    PAGE_SIZE,
+};
+
+pub const TELF_MAGIC: [u8; 6] = *b"\x7fTELDA";
+/// Version of the format, bumped whenever it changes
+pub const TELF_VERSION: u8 = 2;
+pub const TELF_HEADER_SIZE: usize = 20;
+/// Extension of flat binaries with a header
+pub const TELF_EXT: &str = "telf";
+
+/// Where the text of a flat binary is loaded
+pub const TEXT_START: u16 = PAGE_SIZE;
+/// Where the data of a flat binary is loaded
+pub const DATA_START: u16 = HALF_CELL as u16;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TeldaElf {
+    /// Version of the CPU the binary was made for
+    pub isa_version: u8,
+    pub entry: u16,
+    /// [`ProcessedSource::checksum`](crate::source::ProcessedSource::checksum) of the source
+    /// it was assembled from, or 0 if it is not known
+    pub checksum: u32,
+    /// Contents of ROM starting at [`TEXT_START`]
+    pub text: Vec<u8>,
+    /// Contents of RAM starting at [`DATA_START`]
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    TooShort,
+    BadMagic,
+    UnsupportedVersion(u8),
+    /// The file size in the header does not match the actual size
+    SizeMismatch,
+    /// The segments overlap the header, each other or go past the end of the file
+    InvalidOffsets,
+    /// The text does not fit in ROM or the data does not fit in RAM
+    TooBig,
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::TooShort => write!(f, "file is too short to have a header"),
+            ParseError::BadMagic => write!(f, "not a telda flat binary"),
+            ParseError::UnsupportedVersion(v) => write!(
+                f,
+                "format version {v} is not supported, only version {TELF_VERSION} is"
+            ),
+            ParseError::SizeMismatch => write!(f, "file size does not match the header"),
+            ParseError::InvalidOffsets => write!(f, "invalid segment offsets"),
+            ParseError::TooBig => write!(f, "segments do not fit in memory"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl TeldaElf {
+    pub fn new(entry: u16, text: Vec<u8>, data: Vec<u8>) -> Self {
+        TeldaElf {
+            isa_version: CPU_VERSION as u8,
+            entry,
+            checksum: 0,
+            text,
+            data,
+        }
+    }
+    pub fn with_checksum(mut self, checksum: u32) -> Self {
+        self.checksum = checksum;
+        self
+    }
+    /// Writes the header followed by the text and then the data
+    ///
+    /// Panics if the segments do not fit in memory
+    pub fn to_bytes(&self) -> Vec<u8> {
+        assert!(self.fits(), "segments do not fit in memory");
+        let text_offset = TELF_HEADER_SIZE as u16;
+        let data_offset = text_offset + self.text.len() as u16;
+        let file_size = data_offset + self.data.len() as u16;
+
+        let mut bytes = Vec::with_capacity(file_size as usize);
+        bytes.extend_from_slice(&TELF_MAGIC);
+        bytes.push(TELF_VERSION);
+        bytes.push(self.isa_version);
+        for field in [self.entry, data_offset, text_offset, file_size] {
+            bytes.extend_from_slice(&field.to_le_bytes());
+        }
+        bytes.extend_from_slice(&self.checksum.to_le_bytes());
+        bytes.extend_from_slice(&self.text);
+        bytes.extend_from_slice(&self.data);
+
+        bytes
+    }
+    /// Whether the segments fit in memory and the file size fits in the header
+    pub fn fits(&self) -> bool {
+        self.text.len() <= ROM_SIZE
+            && self.data.len() <= 0x1_0000 - DATA_START as usize
+            && TELF_HEADER_SIZE + self.text.len() + self.data.len() <= u16::MAX as usize
+    }
+}
+
+/// Whether the bytes start like a flat binary with a header
+pub fn is_telda_elf(bytes: &[u8]) -> bool {
+    bytes.starts_with(&TELF_MAGIC)
+}
+
+pub fn parse_telda_elf(bytes: &[u8]) -> Result<TeldaElf, ParseError> {
+    if bytes.len() < TELF_HEADER_SIZE {
+        return Err(ParseError::TooShort);
+    }
+    if !is_telda_elf(bytes) {
+        return Err(ParseError::BadMagic);
+    }
+    let version = bytes[6];
+    if version != TELF_VERSION {
+        return Err(ParseError::UnsupportedVersion(version));
+    }
+    let isa_version = bytes[7];
+    let field = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]) as usize;
+    let entry = field(8) as u16;
+    let data_offset = field(10);
+    let text_offset = field(12);
+    let file_size = field(14);
+    let checksum = u32::from_le_bytes([bytes[16], bytes[17], bytes[18], bytes[19]]);
+
+    if file_size != bytes.len() {
+        return Err(ParseError::SizeMismatch);
+    }
+    // the text comes right before the data
+    if text_offset < TELF_HEADER_SIZE || data_offset < text_offset || data_offset > file_size {
+        return Err(ParseError::InvalidOffsets);
+    }
+
+    let elf = TeldaElf {
+        isa_version,
+        entry,
+        checksum,
+        text: bytes[text_offset..data_offset].to_vec(),
+        data: bytes[data_offset..].to_vec(),
+    };
+    if !elf.fits() {
+        return Err(ParseError::TooBig);
+    }
+    Ok(elf)
+}
+
+impl<P: Io> Machine<LazyMain<P>, Blf4> {
+    /// Makes a machine with the binary loaded that starts at its entry point
+    pub fn from_telda_elf(ports: P, elf: &TeldaElf) -> Self {
+        let mut memory = LazyMain::new(ports).with_rom(&elf.text);
+        write_n(&mut memory, DATA_START as u32, &elf.data);
+
+        let mut cpu = Blf4::new();
+        cpu.program_counter = elf.entry;
+        Machine::new(memory, cpu)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        blf4::{isa::*, TrapMode},
+        mem::{MainMemory, PanickingIO},
+    };
+
+    #[test]
+    fn round_trip() {
+        let elf = TeldaElf::new(0x84, vec![1, 2, 3, 4, 5], vec![6, 7]).with_checksum(0xdeadbeef);
+        let bytes = elf.to_bytes();
+        assert_eq!(bytes.len(), TELF_HEADER_SIZE + 7);
+        assert_eq!(&bytes[..6], b"\x7fTELDA");
+        assert_eq!(&bytes[16..20], [0xef, 0xbe, 0xad, 0xde]);
+        assert_eq!(parse_telda_elf(&bytes), Ok(elf));
+
+        assert_eq!(parse_telda_elf(&bytes[..10]), Err(ParseError::TooShort));
+        assert_eq!(
+            parse_telda_elf(&bytes[..bytes.len() - 1]),
+            Err(ParseError::SizeMismatch)
+        );
+        let mut bad = bytes.clone();
+        bad[6] = 1;
+        assert_eq!(
+            parse_telda_elf(&bad),
+            Err(ParseError::UnsupportedVersion(1))
+        );
+        let mut bad = bytes;
+        bad[12] = 3;
+        assert_eq!(parse_telda_elf(&bad), Err(ParseError::InvalidOffsets));
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn runs_from_entry() {
+        let text = vec![
+            // never run since the entry point is after it
+            HALT,
+            // r1 = [0x8000]; [0x8002] = r1
+            LDI_W, 0x20, 0x00, 0x80,
+            LOAD_WR, 0x12, 0x00,
+            LDI_W, 0x30, 0x02, 0x80,
+            STORE_WR, 0x30, 0x10,
+            HALT,
+        ];
+        let mut machine = Machine::from_telda_elf(PanickingIO, &TeldaElf::new(0x81, text, vec![0x34, 0x12]));
+
+        assert_eq!(machine.run_until_abort(), TrapMode::Halt);
+        assert_eq!(machine.cpu.program_counter, 0x90);
+        assert_eq!(machine.memory.read(0x8002), 0x34);
+        assert_eq!(machine.memory.read(0x8003), 0x12);
+    }
+}
@@ -0,0 +1,47 @@
+use super::isa::*;
+
+/// Version word given by `cpuid`
+pub const CPU_VERSION: u16 = 4;
+
+/// Paging with `vmon` and `vmoff`
+pub const FEAT_VIRTUAL_MEMORY: u16 = 0b0000_0001;
+/// Dropping to user mode with `usr`
+pub const FEAT_USER_MODE: u16 = 0b0000_0010;
+/// `mul` and `div`
+pub const FEAT_MUL_DIV: u16 = 0b0000_0100;
+/// Atomic memory operations
+pub const FEAT_ATOMICS: u16 = 0b0000_1000;
+/// `xchg`
+pub const FEAT_XCHG: u16 = 0b0001_0000;
+/// `adc` and `sbb`
+pub const FEAT_CARRY_ARITH: u16 = 0b0010_0000;
+/// Physical memory access with `pstore`, `pload` and `pmset`
+pub const FEAT_PHYSICAL_ACCESS: u16 = 0b0100_0000;
+/// Bulk memory copying with `mcpy`
+pub const FEAT_BULK_MEMORY: u16 = 0b1000_0000;
+
+/// Which instructions need to be implemented for a feature to be present
+const FEATURE_OPCODES: &[(u16, &[u8])] = &[
+    (FEAT_VIRTUAL_MEMORY, &[VMON, VMOFF]),
+    (FEAT_USER_MODE, &[USR]),
+    (FEAT_MUL_DIV, &[MUL_B, MUL_W, DIV_B, DIV_W]),
+    (FEAT_ATOMICS, &[CAS_W]),
+    (FEAT_XCHG, &[XCHG_B, XCHG_W]),
+    (FEAT_CARRY_ARITH, &[ADC_B, ADC_W, SBB_B, SBB_W]),
+    (FEAT_PHYSICAL_ACCESS, &[PSTORE, PLOAD, PMSET]),
+    (FEAT_BULK_MEMORY, &[MCPY]),
+];
+
+/// Feature flags of the instructions that have a handler
+pub fn supported_features() -> u16 {
+    // the null instruction always uses the invalid instruction handler
+    let invalid = OP_HANDLERS[NULL as usize] as usize;
+
+    FEATURE_OPCODES
+        .iter()
+        .filter(|(_, ops)| {
+            ops.iter()
+                .all(|&op| OP_HANDLERS[op as usize] as usize != invalid)
+        })
+        .fold(0, |feats, &(feat, _)| feats | feat)
+}
@@ -1,5 +1,8 @@
 use crate::{
-    blf4::{ByteRegister as Br, HandlerContext, TrapMode, WideRegister as Wr, R0},
+    blf4::{
+        supported_features, ByteRegister as Br, HandlerContext, TrapMode, WideRegister as Wr,
+        CPU_VERSION, R0, R0B, R1, R2,
+    },
     U4,
 };
 
@@ -10,7 +13,7 @@ pub fn arg_pair<T, U, F1: FnOnce(U4) -> T, F2: FnOnce(U4) -> U>(
     f2: F2,
 ) -> OpRes<(T, U)> {
     let operand = c.fetch()?;
-    let (a, b) = U4::paired(operand);
+    let (a, b) = U4::to_nibbles(operand);
     Ok((f1(a), f2(b)))
 }
 
@@ -26,6 +29,7 @@ pub fn arg_imm_wide(c: &mut HandlerContext) -> OpRes<u16> {
     Ok(u16::from_le_bytes([l, h]))
 }
 
+/// Result of a handler, the error is always the [`TrapMode`] of the trap the instruction causes
 pub type OpRes<T = ()> = Result<T, TrapMode>;
 pub type OpHandler = fn(c: &mut HandlerContext) -> OpRes;
 
@@ -36,23 +40,30 @@ pub static OP_HANDLERS: [OpHandler; 256] = {
 
     handlers[NULL as usize] = n;
     handlers[HALT as usize] = halt;
+    handlers[WAIT as usize] = wait;
     handlers[CTF as usize] = ctf;
     handlers[SYSCALL as usize] = syscall;
     handlers[RETH as usize] = reth;
+    handlers[CPUID as usize] = cpuid;
 
     handlers[USR as usize] = usr;
     handlers[VMON as usize] = vmon;
     handlers[VMOFF as usize] = vmoff;
     handlers[PSTORE as usize] = pstore;
     handlers[PLOAD as usize] = pload;
+    handlers[PMSET as usize] = pmset;
 
     handlers[NOP as usize] = nop;
     handlers[PUSH_B as usize] = push_b;
     handlers[PUSH_W as usize] = push_w;
+    handlers[PUSH_IMM_B as usize] = push_imm_b;
+    handlers[PUSH_IMM_W as usize] = push_imm_w;
+    handlers[CALL_R as usize] = call_r;
     handlers[POP_B as usize] = pop_b;
     handlers[POP_W as usize] = pop_w;
     handlers[CALL as usize] = call;
     handlers[RET as usize] = ret;
+    handlers[RET_W as usize] = ret_w;
     handlers[STORE_BI as usize] = store_bi;
     handlers[STORE_WI as usize] = store_wi;
     handlers[STORE_BR as usize] = store_br;
@@ -98,6 +109,42 @@ pub static OP_HANDLERS: [OpHandler; 256] = {
     handlers[DIV_W as usize] = div_w;
     handlers[MUL_B as usize] = mul_b;
     handlers[MUL_W as usize] = mul_w;
+    handlers[XCHG_B as usize] = xchg_b;
+    handlers[XCHG_W as usize] = xchg_w;
+    handlers[ADC_B as usize] = adc_b;
+    handlers[ADC_W as usize] = adc_w;
+    handlers[SBB_B as usize] = sbb_b;
+    handlers[SBB_W as usize] = sbb_w;
+    handlers[CAS_W as usize] = cas_w;
+    handlers[MCPY as usize] = mcpy;
+    handlers[NEG_B as usize] = neg_b;
+    handlers[NEG_W as usize] = neg_w;
+    handlers[SXT_B as usize] = sxt_b;
+    handlers[ZXT_B as usize] = zxt_b;
+    handlers[BSWAP_B as usize] = bswap_b;
+    handlers[BSWAP_W as usize] = bswap_w;
+    handlers[POPCNT_B as usize] = popcnt_b;
+    handlers[POPCNT_W as usize] = popcnt_w;
+    handlers[CLZ_B as usize] = clz_b;
+    handlers[CLZ_W as usize] = clz_w;
+    handlers[CTZ_B as usize] = ctz_b;
+    handlers[CTZ_W as usize] = ctz_w;
+    handlers[SHL_B_IMM as usize] = shl_b_imm;
+    handlers[SHL_W_IMM as usize] = shl_w_imm;
+    handlers[ASR_B_IMM as usize] = asr_b_imm;
+    handlers[ASR_W_IMM as usize] = asr_w_imm;
+    handlers[LSR_B_IMM as usize] = lsr_b_imm;
+    handlers[LSR_W_IMM as usize] = lsr_w_imm;
+    handlers[ADD_B_IMM as usize] = add_b_imm;
+    handlers[ADD_W_IMM as usize] = add_w_imm;
+    handlers[SUB_B_IMM as usize] = sub_b_imm;
+    handlers[SUB_W_IMM as usize] = sub_w_imm;
+    handlers[MOVB_WR as usize] = movb_wr;
+    handlers[MOVBH_WR as usize] = movbh_wr;
+    handlers[LOAD_D as usize] = load_d;
+    handlers[STORE_D as usize] = store_d;
+    handlers[IN_B as usize] = in_b;
+    handlers[OUT_B as usize] = out_b;
 
     handlers
 };
@@ -108,9 +155,20 @@ fn n(_c: &mut HandlerContext) -> OpRes {
 fn halt(_c: &mut HandlerContext) -> OpRes {
     Err(TrapMode::Halt)
 }
+/// Only the kernel handles interrupts, so only it may wait for one
+fn wait(c: &mut HandlerContext) -> OpRes {
+    if c.cpu.flags.user_mode {
+        return Err(TrapMode::IllegalOperation);
+    }
+    Err(TrapMode::WaitingForInterrupt)
+}
 fn syscall(_c: &mut HandlerContext) -> OpRes {
     Err(TrapMode::SysCall)
 }
+fn cpuid(c: &mut HandlerContext) -> OpRes {
+    c.cpu.write_wr(R1, CPU_VERSION)?;
+    c.cpu.write_wr(R2, supported_features())
+}
 
 fn ctf(c: &mut HandlerContext) -> OpRes {
     c.cpu.flags.trap = false;
@@ -120,9 +178,7 @@ fn reth(c: &mut HandlerContext) -> OpRes {
     if !c.cpu.flags.trap {
         return Err(TrapMode::IllegalHandlerReturn);
     }
-    c.pop_registers()?;
-    c.cpu.flags.trap = false;
-    Ok(())
+    c.pop_registers()
 }
 fn usr(c: &mut HandlerContext) -> OpRes {
     if c.cpu.flags.user_mode {
@@ -185,12 +241,26 @@ fn pload(c: &mut HandlerContext) -> OpRes {
 
     Ok(())
 }
+fn pmset(c: &mut HandlerContext) -> OpRes {
+    if c.cpu.flags.user_mode {
+        return Err(TrapMode::IllegalOperation);
+    }
+    let (br1, wr1) = arg_pair(c, Br, Wr)?;
+    let (wr2, br2) = arg_pair(c, Wr, Br)?;
+
+    let high_byte = c.cpu.read_br(br1);
+    let low_wide = c.cpu.read_wr(wr1)?;
+    let addr = low_wide as u32 | ((high_byte as u32) << 16);
+    let count = c.cpu.read_wr(wr2)?;
+
+    c.physical_memset(addr, c.cpu.read_br(br2), count as u32)
+}
 
 #[inline]
 fn binop_b(
     c: &mut HandlerContext,
-    binop: fn(u8, u8) -> (u8, bool),
-    ibinop: fn(i8, i8) -> (i8, bool),
+    binop: impl FnOnce(u8, u8) -> (u8, bool),
+    ibinop: impl FnOnce(i8, i8) -> (i8, bool),
 ) -> OpRes {
     let (r1, r2) = arg_pair(c, Br, Br)?;
     let (r3, r4) = arg_pair(c, Br, u8::from)?;
@@ -201,22 +271,50 @@ fn binop_b(
         return Err(TrapMode::Invalid);
     }
 
-    let (res, carry) = binop(r2, r3);
-    let (ires, overflowing) = ibinop(r2 as i8, r3 as i8);
+    let res = arith_b(c, r2, r3, binop, ibinop);
+    c.cpu.write_br(r1, res);
+
+    Ok(())
+}
+#[inline]
+fn binop_b_imm(
+    c: &mut HandlerContext,
+    binop: impl FnOnce(u8, u8) -> (u8, bool),
+    ibinop: impl FnOnce(i8, i8) -> (i8, bool),
+) -> OpRes {
+    let (r1, r2) = arg_pair(c, Br, Br)?;
+    let imm = arg_imm_byte(c)?;
+
+    let r2 = c.cpu.read_br(r2);
+
+    let res = arith_b(c, r2, imm, binop, ibinop);
+    c.cpu.write_br(r1, res);
+
+    Ok(())
+}
+/// Applies the operation and sets the flags from the result
+#[inline]
+fn arith_b(
+    c: &mut HandlerContext,
+    x: u8,
+    y: u8,
+    binop: impl FnOnce(u8, u8) -> (u8, bool),
+    ibinop: impl FnOnce(i8, i8) -> (i8, bool),
+) -> u8 {
+    let (res, carry) = binop(x, y);
+    let (ires, overflowing) = ibinop(x as i8, y as i8);
     c.cpu.flags.carry = carry;
     c.cpu.flags.overflow = overflowing;
     c.cpu.flags.sign = ires.is_negative();
     c.cpu.flags.zero = res == 0;
 
-    c.cpu.write_br(r1, res);
-
-    Ok(())
+    res
 }
 #[inline]
 fn binop_w(
     c: &mut HandlerContext,
-    binop: fn(u16, u16) -> (u16, bool),
-    ibinop: fn(i16, i16) -> (i16, bool),
+    binop: impl FnOnce(u16, u16) -> (u16, bool),
+    ibinop: impl FnOnce(i16, i16) -> (i16, bool),
 ) -> OpRes {
     let (r1, r2) = arg_pair(c, Wr, Wr)?;
     let (r3, r4) = arg_pair(c, Wr, u8::from)?;
@@ -227,16 +325,43 @@ fn binop_w(
         return Err(TrapMode::Invalid);
     }
 
-    let (res, carry) = binop(r2, r3);
-    let (ires, overflowing) = ibinop(r2 as i16, r3 as i16);
+    let res = arith_w(c, r2, r3, binop, ibinop);
+    c.cpu.write_wr(r1, res)?;
+
+    Ok(())
+}
+#[inline]
+fn binop_w_imm(
+    c: &mut HandlerContext,
+    binop: impl FnOnce(u16, u16) -> (u16, bool),
+    ibinop: impl FnOnce(i16, i16) -> (i16, bool),
+) -> OpRes {
+    let (r1, r2) = arg_pair(c, Wr, Wr)?;
+    let imm = arg_imm_wide(c)?;
+
+    let r2 = c.cpu.read_wr(r2)?;
+
+    let res = arith_w(c, r2, imm, binop, ibinop);
+    c.cpu.write_wr(r1, res)?;
+
+    Ok(())
+}
+#[inline]
+fn arith_w(
+    c: &mut HandlerContext,
+    x: u16,
+    y: u16,
+    binop: impl FnOnce(u16, u16) -> (u16, bool),
+    ibinop: impl FnOnce(i16, i16) -> (i16, bool),
+) -> u16 {
+    let (res, carry) = binop(x, y);
+    let (ires, overflowing) = ibinop(x as i16, y as i16);
     c.cpu.flags.carry = carry;
     c.cpu.flags.overflow = overflowing;
     c.cpu.flags.sign = ires.is_negative();
     c.cpu.flags.zero = res == 0;
 
-    c.cpu.write_wr(r1, res)?;
-
-    Ok(())
+    res
 }
 
 fn add_b(c: &mut HandlerContext) -> OpRes {
@@ -251,6 +376,188 @@ fn sub_b(c: &mut HandlerContext) -> OpRes {
 fn sub_w(c: &mut HandlerContext) -> OpRes {
     binop_w(c, u16::overflowing_sub, i16::overflowing_sub)
 }
+fn add_b_imm(c: &mut HandlerContext) -> OpRes {
+    binop_b_imm(c, u8::overflowing_add, i8::overflowing_add)
+}
+fn add_w_imm(c: &mut HandlerContext) -> OpRes {
+    binop_w_imm(c, u16::overflowing_add, i16::overflowing_add)
+}
+fn sub_b_imm(c: &mut HandlerContext) -> OpRes {
+    binop_b_imm(c, u8::overflowing_sub, i8::overflowing_sub)
+}
+fn sub_w_imm(c: &mut HandlerContext) -> OpRes {
+    binop_w_imm(c, u16::overflowing_sub, i16::overflowing_sub)
+}
+/// Applies `op` to `x` and `y` and then to that result and `carry`, which is 0 or 1
+///
+/// The flag is set if exactly one of the two operations overflowed. With a carry of 0 or 1
+/// they cannot both overflow, so it tells whether the whole operation overflowed
+#[inline]
+fn with_carry<T>(op: fn(T, T) -> (T, bool), x: T, y: T, carry: T) -> (T, bool) {
+    let (res, overflow1) = op(x, y);
+    let (res, overflow2) = op(res, carry);
+    (res, overflow1 != overflow2)
+}
+fn adc_b(c: &mut HandlerContext) -> OpRes {
+    let carry = c.cpu.flags.carry;
+    binop_b(
+        c,
+        |x, y| with_carry(u8::overflowing_add, x, y, carry as u8),
+        |x, y| with_carry(i8::overflowing_add, x, y, carry as i8),
+    )
+}
+fn adc_w(c: &mut HandlerContext) -> OpRes {
+    let carry = c.cpu.flags.carry;
+    binop_w(
+        c,
+        |x, y| with_carry(u16::overflowing_add, x, y, carry as u16),
+        |x, y| with_carry(i16::overflowing_add, x, y, carry as i16),
+    )
+}
+fn sbb_b(c: &mut HandlerContext) -> OpRes {
+    let carry = c.cpu.flags.carry;
+    binop_b(
+        c,
+        |x, y| with_carry(u8::overflowing_sub, x, y, carry as u8),
+        |x, y| with_carry(i8::overflowing_sub, x, y, carry as i8),
+    )
+}
+fn sbb_w(c: &mut HandlerContext) -> OpRes {
+    let carry = c.cpu.flags.carry;
+    binop_w(
+        c,
+        |x, y| with_carry(u16::overflowing_sub, x, y, carry as u16),
+        |x, y| with_carry(i16::overflowing_sub, x, y, carry as i16),
+    )
+}
+/// Sets the flags like subtracting from zero
+fn neg_b(c: &mut HandlerContext) -> OpRes {
+    let (r1, r2) = arg_pair(c, Br, Br)?;
+    if r1 == R0B {
+        return Err(TrapMode::Invalid);
+    }
+    let val = c.cpu.read_br(r2);
+
+    let (res, carry) = 0u8.overflowing_sub(val);
+    let (ires, overflowing) = 0i8.overflowing_sub(val as i8);
+    c.cpu.flags.carry = carry;
+    c.cpu.flags.overflow = overflowing;
+    c.cpu.flags.sign = ires.is_negative();
+    c.cpu.flags.zero = res == 0;
+
+    c.cpu.write_br(r1, res);
+
+    Ok(())
+}
+/// Sets the flags like subtracting from zero
+fn neg_w(c: &mut HandlerContext) -> OpRes {
+    let (r1, r2) = arg_pair(c, Wr, Wr)?;
+    if r1 == R0 {
+        return Err(TrapMode::Invalid);
+    }
+    let val = c.cpu.read_wr(r2)?;
+
+    let (res, carry) = 0u16.overflowing_sub(val);
+    let (ires, overflowing) = 0i16.overflowing_sub(val as i16);
+    c.cpu.flags.carry = carry;
+    c.cpu.flags.overflow = overflowing;
+    c.cpu.flags.sign = ires.is_negative();
+    c.cpu.flags.zero = res == 0;
+
+    c.cpu.write_wr(r1, res)?;
+
+    Ok(())
+}
+fn sxt_b(c: &mut HandlerContext) -> OpRes {
+    let (r1, r2) = arg_pair(c, Wr, Br)?;
+    let b = c.cpu.read_br(r2);
+    c.cpu.write_wr(r1, b as i8 as u16)
+}
+fn zxt_b(c: &mut HandlerContext) -> OpRes {
+    let (r1, r2) = arg_pair(c, Wr, Br)?;
+    let b = c.cpu.read_br(r2);
+    c.cpu.write_wr(r1, b as u16)
+}
+fn movb_wr(c: &mut HandlerContext) -> OpRes {
+    let (r1, r2) = arg_pair(c, Br, Wr)?;
+    let [low, _] = c.cpu.read_wr(r2)?.to_le_bytes();
+    c.cpu.write_br(r1, low);
+    Ok(())
+}
+fn movbh_wr(c: &mut HandlerContext) -> OpRes {
+    let (r1, r2) = arg_pair(c, Br, Wr)?;
+    let [_, high] = c.cpu.read_wr(r2)?.to_le_bytes();
+    c.cpu.write_br(r1, high);
+    Ok(())
+}
+/// Swaps the nibbles of a byte
+fn bswap_b(c: &mut HandlerContext) -> OpRes {
+    let (r1, r2) = arg_pair(c, Br, Br)?;
+    let res = c.cpu.read_br(r2).rotate_left(4);
+
+    c.cpu.flags.carry = false;
+    c.cpu.flags.overflow = false;
+    c.cpu.flags.sign = (res as i8).is_negative();
+    c.cpu.flags.zero = res == 0;
+
+    c.cpu.write_br(r1, res);
+
+    Ok(())
+}
+fn bswap_w(c: &mut HandlerContext) -> OpRes {
+    let (r1, r2) = arg_pair(c, Wr, Wr)?;
+    let res = c.cpu.read_wr(r2)?.swap_bytes();
+
+    c.cpu.flags.carry = false;
+    c.cpu.flags.overflow = false;
+    c.cpu.flags.sign = (res as i16).is_negative();
+    c.cpu.flags.zero = res == 0;
+
+    c.cpu.write_wr(r1, res)
+}
+/// Writes a count of bits of the source register, the count is never negative and cannot overflow
+fn bit_count_b(c: &mut HandlerContext, count: fn(u8) -> u32) -> OpRes {
+    let (r1, r2) = arg_pair(c, Br, Br)?;
+    let res = count(c.cpu.read_br(r2)) as u8;
+
+    c.cpu.flags.carry = false;
+    c.cpu.flags.overflow = false;
+    c.cpu.flags.sign = false;
+    c.cpu.flags.zero = res == 0;
+
+    c.cpu.write_br(r1, res);
+
+    Ok(())
+}
+fn bit_count_w(c: &mut HandlerContext, count: fn(u16) -> u32) -> OpRes {
+    let (r1, r2) = arg_pair(c, Wr, Wr)?;
+    let res = count(c.cpu.read_wr(r2)?) as u16;
+
+    c.cpu.flags.carry = false;
+    c.cpu.flags.overflow = false;
+    c.cpu.flags.sign = false;
+    c.cpu.flags.zero = res == 0;
+
+    c.cpu.write_wr(r1, res)
+}
+fn popcnt_b(c: &mut HandlerContext) -> OpRes {
+    bit_count_b(c, u8::count_ones)
+}
+fn popcnt_w(c: &mut HandlerContext) -> OpRes {
+    bit_count_w(c, u16::count_ones)
+}
+fn clz_b(c: &mut HandlerContext) -> OpRes {
+    bit_count_b(c, u8::leading_zeros)
+}
+fn clz_w(c: &mut HandlerContext) -> OpRes {
+    bit_count_w(c, u16::leading_zeros)
+}
+fn ctz_b(c: &mut HandlerContext) -> OpRes {
+    bit_count_b(c, u8::trailing_zeros)
+}
+fn ctz_w(c: &mut HandlerContext) -> OpRes {
+    bit_count_w(c, u16::trailing_zeros)
+}
 fn and_b(c: &mut HandlerContext) -> OpRes {
     binop_b(c, |x, y| (x & y, false), |x, y| (x & y, false))
 }
@@ -270,38 +577,158 @@ fn xor_w(c: &mut HandlerContext) -> OpRes {
     binop_w(c, |x, y| (x ^ y, false), |x, y| (x ^ y, false))
 }
 fn shl_b(c: &mut HandlerContext) -> OpRes {
-    binop_b(c, |x, y| (x << y, false), |x, y| (x << y, false))
+    shift_reg_b(c, shl_8)
 }
 fn shl_w(c: &mut HandlerContext) -> OpRes {
-    binop_w(c, |x, y| (x << y, false), |x, y| (x << y, false))
+    shift_reg_w(c, shl_16)
 }
 fn asr_b(c: &mut HandlerContext) -> OpRes {
-    binop_b(
-        c,
-        |x, y| (((x as i8) >> y) as u8, false),
-        |x, y| (x >> y, false),
-    )
+    shift_reg_b(c, asr_8)
 }
 fn asr_w(c: &mut HandlerContext) -> OpRes {
-    binop_w(
-        c,
-        |x, y| (((x as i16) >> y) as u16, false),
-        |x, y| (x >> y, false),
-    )
+    shift_reg_w(c, asr_16)
 }
 fn lsr_b(c: &mut HandlerContext) -> OpRes {
-    binop_b(
-        c,
-        |x, y| (x >> y, false),
-        |x, y| (((x as u8) >> y) as i8, false),
-    )
+    shift_reg_b(c, lsr_8)
 }
 fn lsr_w(c: &mut HandlerContext) -> OpRes {
-    binop_w(
-        c,
-        |x, y| (x >> y, false),
-        |x, y| (((x as u16) >> y) as i16, false),
-    )
+    shift_reg_w(c, lsr_16)
+}
+/// Shifts by the count in a register, the last operand nibble has to be zero
+fn shift_reg_b(c: &mut HandlerContext, shift: fn(u8, u32) -> (u8, bool)) -> OpRes {
+    let (r1, r2) = arg_pair(c, Br, Br)?;
+    let (r3, zero) = arg_pair(c, Br, u8::from)?;
+    if zero != 0 {
+        return Err(TrapMode::Invalid);
+    }
+
+    let val = c.cpu.read_br(r2);
+    let count = c.cpu.read_br(r3);
+    let res = shifted_b(c, val, count as u32, shift);
+    c.cpu.write_br(r1, res);
+
+    Ok(())
+}
+fn shift_reg_w(c: &mut HandlerContext, shift: fn(u16, u32) -> (u16, bool)) -> OpRes {
+    let (r1, r2) = arg_pair(c, Wr, Wr)?;
+    let (r3, zero) = arg_pair(c, Wr, u8::from)?;
+    if zero != 0 {
+        return Err(TrapMode::Invalid);
+    }
+
+    let val = c.cpu.read_wr(r2)?;
+    let count = c.cpu.read_wr(r3)?;
+    let res = shifted_w(c, val, count as u32, shift);
+    c.cpu.write_wr(r1, res)
+}
+/// Shifts by a count in the upper nibble of the last operand byte, the lower nibble has to be zero
+fn shift_imm_b(c: &mut HandlerContext, shift: fn(u8, u32) -> (u8, bool)) -> OpRes {
+    let (r1, r2) = arg_pair(c, Br, Br)?;
+    let (count, zero) = arg_pair(c, u8::from, u8::from)?;
+    if zero != 0 {
+        return Err(TrapMode::Invalid);
+    }
+
+    let val = c.cpu.read_br(r2);
+    let res = shifted_b(c, val, count as u32, shift);
+    c.cpu.write_br(r1, res);
+
+    Ok(())
+}
+fn shift_imm_w(c: &mut HandlerContext, shift: fn(u16, u32) -> (u16, bool)) -> OpRes {
+    let (r1, r2) = arg_pair(c, Wr, Wr)?;
+    let (count, zero) = arg_pair(c, u8::from, u8::from)?;
+    if zero != 0 {
+        return Err(TrapMode::Invalid);
+    }
+
+    let val = c.cpu.read_wr(r2)?;
+    let res = shifted_w(c, val, count as u32, shift);
+    c.cpu.write_wr(r1, res)
+}
+/// Shifts the value and sets the flags, the carry is the last bit shifted out
+///
+/// The shift gets the value and a count of at least 1 and gives the result and the last bit
+/// shifted out, a count of zero leaves the value as is and clears the carry
+#[inline]
+fn shifted_b(
+    c: &mut HandlerContext,
+    val: u8,
+    count: u32,
+    shift: fn(u8, u32) -> (u8, bool),
+) -> u8 {
+    let (res, carry) = match count {
+        0 => (val, false),
+        n => shift(val, n),
+    };
+    c.cpu.flags.carry = carry;
+    c.cpu.flags.overflow = false;
+    c.cpu.flags.sign = (res as i8).is_negative();
+    c.cpu.flags.zero = res == 0;
+
+    res
+}
+#[inline]
+fn shifted_w(
+    c: &mut HandlerContext,
+    val: u16,
+    count: u32,
+    shift: fn(u16, u32) -> (u16, bool),
+) -> u16 {
+    let (res, carry) = match count {
+        0 => (val, false),
+        n => shift(val, n),
+    };
+    c.cpu.flags.carry = carry;
+    c.cpu.flags.overflow = false;
+    c.cpu.flags.sign = (res as i16).is_negative();
+    c.cpu.flags.zero = res == 0;
+
+    res
+}
+// counts are capped at one past the width, where every bit including the carry is shifted out
+fn shl_8(x: u8, n: u32) -> (u8, bool) {
+    let shifted = (x as u32) << n.min(9);
+    (shifted as u8, shifted >> 8 & 1 == 1)
+}
+fn shl_16(x: u16, n: u32) -> (u16, bool) {
+    let shifted = (x as u64) << n.min(17);
+    (shifted as u16, shifted >> 16 & 1 == 1)
+}
+// the lowest bit of the shifted value is the last bit shifted out of the result
+fn asr_8(x: u8, n: u32) -> (u8, bool) {
+    let shifted = (x as i8 as i32) << 1 >> n.min(9);
+    ((shifted >> 1) as u8, shifted & 1 == 1)
+}
+fn asr_16(x: u16, n: u32) -> (u16, bool) {
+    let shifted = (x as i16 as i32) << 1 >> n.min(17);
+    ((shifted >> 1) as u16, shifted & 1 == 1)
+}
+fn lsr_8(x: u8, n: u32) -> (u8, bool) {
+    let shifted = (x as u32) << 1 >> n.min(9);
+    ((shifted >> 1) as u8, shifted & 1 == 1)
+}
+fn lsr_16(x: u16, n: u32) -> (u16, bool) {
+    let shifted = (x as u32) << 1 >> n.min(17);
+    ((shifted >> 1) as u16, shifted & 1 == 1)
+}
+fn shl_b_imm(c: &mut HandlerContext) -> OpRes {
+    shift_imm_b(c, shl_8)
+}
+fn shl_w_imm(c: &mut HandlerContext) -> OpRes {
+    shift_imm_w(c, shl_16)
+}
+fn asr_b_imm(c: &mut HandlerContext) -> OpRes {
+    shift_imm_b(c, asr_8)
+}
+fn asr_w_imm(c: &mut HandlerContext) -> OpRes {
+    shift_imm_w(c, asr_16)
+}
+fn lsr_b_imm(c: &mut HandlerContext) -> OpRes {
+    shift_imm_b(c, lsr_8)
+}
+fn lsr_w_imm(c: &mut HandlerContext) -> OpRes {
+    shift_imm_w(c, lsr_16)
 }
 fn mul_b(c: &mut HandlerContext) -> OpRes {
     let (r1, r2) = arg_pair(c, Br, Br)?;
@@ -373,6 +800,82 @@ fn div_w(c: &mut HandlerContext) -> OpRes {
 
     Ok(())
 }
+fn xchg_b(c: &mut HandlerContext) -> OpRes {
+    let (r1, r2) = arg_pair(c, Br, Br)?;
+    if r1 == R0B || r2 == R0B {
+        return Err(TrapMode::Invalid);
+    }
+
+    let b1 = c.cpu.read_br(r1);
+    let b2 = c.cpu.read_br(r2);
+
+    c.cpu.write_br(r1, b2);
+    c.cpu.write_br(r2, b1);
+
+    Ok(())
+}
+fn xchg_w(c: &mut HandlerContext) -> OpRes {
+    let (r1, r2) = arg_pair(c, Wr, Wr)?;
+    if r1 == R0 || r2 == R0 {
+        return Err(TrapMode::Invalid);
+    }
+
+    let w1 = c.cpu.read_wr(r1)?;
+    let w2 = c.cpu.read_wr(r2)?;
+
+    c.cpu.write_wr(r1, w2)?;
+    c.cpu.write_wr(r2, w1)?;
+
+    Ok(())
+}
+/// Compare-and-swap, this is one indivisible operation
+/// so no other memory access may happen between its read and write
+fn cas_w(c: &mut HandlerContext) -> OpRes {
+    let (expected, new) = arg_pair(c, Wr, Wr)?;
+    let (addr, z) = arg_pair(c, Wr, u8::from)?;
+    if z != 0 {
+        return Err(TrapMode::Invalid);
+    }
+    let addr = c.cpu.read_wr(addr)?;
+
+    let current = c.read_wide(addr)?;
+    if current == c.cpu.read_wr(expected)? {
+        c.write_wide(addr, c.cpu.read_wr(new)?)?;
+        c.cpu.flags.zero = true;
+    } else {
+        c.cpu.write_wr(expected, current)?;
+        c.cpu.flags.zero = false;
+    }
+
+    Ok(())
+}
+
+/// Copies bytes through the bus one at a time, so translation and peripherals apply to each byte
+fn mcpy(c: &mut HandlerContext) -> OpRes {
+    let (dst, src) = arg_pair(c, Wr, Wr)?;
+    let (count, z) = arg_pair(c, Wr, u8::from)?;
+    if z != 0 {
+        return Err(TrapMode::Invalid);
+    }
+    let dst = c.cpu.read_wr(dst)?;
+    let src = c.cpu.read_wr(src)?;
+    let count = c.cpu.read_wr(count)?;
+
+    if dst <= src {
+        for i in 0..count {
+            let b = c.read(src.wrapping_add(i))?;
+            c.write(dst.wrapping_add(i), b)?;
+        }
+    } else {
+        // copy backwards so overlapping bytes are read before being overwritten
+        for i in (0..count).rev() {
+            let b = c.read(src.wrapping_add(i))?;
+            c.write(dst.wrapping_add(i), b)?;
+        }
+    }
+
+    Ok(())
+}
 
 fn nop(_c: &mut HandlerContext) -> OpRes {
     Ok(())
@@ -387,6 +890,14 @@ fn push_b(c: &mut HandlerContext) -> OpRes {
 
     Ok(())
 }
+fn push_imm_b(c: &mut HandlerContext) -> OpRes {
+    let b = arg_imm_byte(c)?;
+    c.pushb(b)
+}
+fn push_imm_w(c: &mut HandlerContext) -> OpRes {
+    let w = arg_imm_wide(c)?;
+    c.pushw(w)
+}
 fn push_w(c: &mut HandlerContext) -> OpRes {
     let (w, z) = arg_pair(c, Wr, u8::from)?;
     let w = c.cpu.read_wr(w)?;
@@ -426,9 +937,28 @@ fn call(c: &mut HandlerContext) -> OpRes {
 
     Ok(())
 }
+fn call_r(c: &mut HandlerContext) -> OpRes {
+    let (r, z) = arg_pair(c, Wr, u8::from)?;
+    if z != 0 || r == R0 {
+        return Err(TrapMode::Invalid);
+    }
+    let w = c.cpu.read_wr(r)?;
+    c.cpu.link = c.cpu.program_counter;
+    c.cpu.program_counter = w;
+
+    Ok(())
+}
 fn ret(c: &mut HandlerContext) -> OpRes {
     let b = arg_imm_byte(c)?;
-    c.cpu.stack += b as u16;
+    c.cpu.stack = c.cpu.stack.wrapping_add(b as u16);
+    c.cpu.program_counter = c.cpu.link;
+
+    Ok(())
+}
+/// `ret` for removing more bytes from the stack than fit in a byte
+fn ret_w(c: &mut HandlerContext) -> OpRes {
+    let w = arg_imm_wide(c)?;
+    c.cpu.stack = c.cpu.stack.wrapping_add(w);
     c.cpu.program_counter = c.cpu.link;
 
     Ok(())
@@ -525,6 +1055,42 @@ fn load_wr(c: &mut HandlerContext) -> OpRes {
 
     Ok(())
 }
+/// Loads two wides with the low one first in memory
+fn load_d(c: &mut HandlerContext) -> OpRes {
+    let (r_hi, r_lo) = arg_pair(c, Wr, Wr)?;
+    let (r_base, r_offset) = arg_pair(c, Wr, Wr)?;
+    let offset = c.cpu.read_wr(r_offset)?;
+
+    let addr = c.cpu.read_wr(r_base)? + offset;
+    let lo = c.read_wide(addr)?;
+    let hi = c.read_wide(addr.wrapping_add(2))?;
+    c.cpu.write_wr(r_lo, lo)?;
+    c.cpu.write_wr(r_hi, hi)?;
+
+    Ok(())
+}
+/// Stores two wides with the low one first in memory
+fn store_d(c: &mut HandlerContext) -> OpRes {
+    let (r_base, r_offset) = arg_pair(c, Wr, Wr)?;
+    let (r_hi, r_lo) = arg_pair(c, Wr, Wr)?;
+    let offset = c.cpu.read_wr(r_offset)?;
+
+    let addr = c.cpu.read_wr(r_base)? + offset;
+    c.write_wide(addr, c.cpu.read_wr(r_lo)?)?;
+    c.write_wide(addr.wrapping_add(2), c.cpu.read_wr(r_hi)?)?;
+
+    Ok(())
+}
+fn in_b(c: &mut HandlerContext) -> OpRes {
+    let (r1, r2) = arg_pair(c, Br, Br)?;
+    let val = c.port_read(c.cpu.read_br(r2))?;
+    c.cpu.write_br(r1, val);
+    Ok(())
+}
+fn out_b(c: &mut HandlerContext) -> OpRes {
+    let (r1, r2) = arg_pair(c, Br, Br)?;
+    c.port_write(c.cpu.read_br(r1), c.cpu.read_br(r2))
+}
 
 fn jez(c: &mut HandlerContext) -> OpRes {
     jif(c.cpu.flags.zero, c)
@@ -534,7 +1100,7 @@ fn jlt(c: &mut HandlerContext) -> OpRes {
 }
 fn jle(c: &mut HandlerContext) -> OpRes {
     jif(
-        c.cpu.flags.sign != c.cpu.flags.overflow && c.cpu.flags.zero,
+        c.cpu.flags.sign != c.cpu.flags.overflow || c.cpu.flags.zero,
         c,
     )
 }
@@ -588,9 +1154,20 @@ fn ldi_b(c: &mut HandlerContext) -> OpRes {
 
     Ok(())
 }
+/// `ldi` and `jmp` share an opcode, the nibble after the register is the mode
+///
+/// - 0: `ldi wr, w`
+/// - 1: `jmp w` when the register is `r0`, otherwise `jmp wr` which jumps to the value of the
+///   register and ignores the immediate (the assembler writes it as zero)
+/// - 2: `ldi wr, b` with a sign-extended byte instead of a wide
 fn ldi_w(c: &mut HandlerContext) -> OpRes {
     let (r1, o) = arg_pair(c, Wr, u8::from)?;
 
+    if o == 2 {
+        // ldi with a sign-extended byte
+        let b = arg_imm_byte(c)?;
+        return c.cpu.write_wr(r1, b as i8 as u16);
+    }
     let w = arg_imm_wide(c)?;
 
     match o {
@@ -611,3 +1188,783 @@ fn ldi_w(c: &mut HandlerContext) -> OpRes {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        blf4::{
+            isa::*, Blf4, TrapMode, CPU_VERSION, FEAT_VIRTUAL_MEMORY, R1, R10, R10B, R1L, R2, R2L,
+            R3, R3L, R4, R5, R5L, R6, R6B, R7, R8, R9,
+        },
+        machine::Machine,
+        mem::{Io, LazyMain, MainMemory, PanickingIO},
+    };
+
+    fn run(rom: &[u8]) -> Blf4 {
+        let mut m = Machine::new(LazyMain::new(PanickingIO).with_rom(rom), Blf4::new());
+        assert_eq!(m.run_until_abort(), TrapMode::Halt);
+        m.cpu
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn add_32_bit_with_carry() {
+        // r2:r1 = 0x0001_ffff, r4:r3 = 0x0002_0001
+        let cpu = run(&[
+            LDI_W, 0x10, 0xff, 0xff,
+            LDI_W, 0x20, 0x01, 0x00,
+            LDI_W, 0x30, 0x01, 0x00,
+            LDI_W, 0x40, 0x02, 0x00,
+            // add r5, r1, r3
+            ADD_W, 0x51, 0x30,
+            // adc r6, r2, r4
+            ADC_W, 0x62, 0x40,
+            HALT,
+        ]);
+        assert_eq!(cpu.read_wr(R5), Ok(0x0000));
+        assert_eq!(cpu.read_wr(R6), Ok(0x0004));
+        assert!(!cpu.flags.carry);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn sub_32_bit_with_borrow() {
+        // r2:r1 = 0x0001_0000, r4:r3 = 0x0000_0001
+        let cpu = run(&[
+            LDI_W, 0x10, 0x00, 0x00,
+            LDI_W, 0x20, 0x01, 0x00,
+            LDI_W, 0x30, 0x01, 0x00,
+            LDI_W, 0x40, 0x00, 0x00,
+            // sub r5, r1, r3
+            SUB_W, 0x51, 0x30,
+            // sbb r6, r2, r4
+            SBB_W, 0x62, 0x40,
+            HALT,
+        ]);
+        assert_eq!(cpu.read_wr(R5), Ok(0xffff));
+        assert_eq!(cpu.read_wr(R6), Ok(0x0000));
+        assert!(!cpu.flags.carry);
+        assert!(cpu.flags.zero);
+    }
+
+    #[test]
+    fn cpuid_reports_virtual_memory() {
+        let cpu = run(&[CPUID, HALT]);
+        assert_eq!(cpu.read_wr(R1), Ok(CPU_VERSION));
+        let features = cpu.read_wr(R2).unwrap();
+        assert_ne!(features & FEAT_VIRTUAL_MEMORY, 0);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn test_and_set_lock() {
+        let cpu = run(&[
+            // lock at 0x8000, r2 = 1 (held), r1 = r4 = r6 = 0 (free)
+            LDI_W, 0x30, 0x00, 0x80,
+            LDI_W, 0x20, 0x01, 0x00,
+            LDI_W, 0x10, 0x00, 0x00,
+            LDI_W, 0x40, 0x00, 0x00,
+            LDI_W, 0x60, 0x00, 0x00,
+            // acquire with cas r1, r2, r3
+            CAS_W, 0x12, 0x30,
+            // acquiring again fails: cas r4, r2, r3
+            CAS_W, 0x42, 0x30,
+            // release and acquire again: cas r6, r2, r3
+            STORE_WR, 0x30, 0x00,
+            CAS_W, 0x62, 0x30,
+            // r5 = lock
+            LOAD_WR, 0x53, 0x00,
+            HALT,
+        ]);
+        assert_eq!(cpu.read_wr(R1), Ok(0));
+        assert_eq!(cpu.read_wr(R4), Ok(1));
+        assert_eq!(cpu.read_wr(R6), Ok(0));
+        assert_eq!(cpu.read_wr(R5), Ok(1));
+        assert!(cpu.flags.zero);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn trap_handler_keeps_flags() {
+        let cpu = run(&[
+            // stack at 0x9000 and trap handler at 0x96
+            LDI_W, 0xb0, 0x00, 0x90,
+            LDI_W, 0xf0, 0x96, 0x00,
+            // r1 = 0xffff + 1 sets carry
+            LDI_W, 0x10, 0xff, 0xff,
+            ADD_W_IMM, 0x11, 0x01, 0x00,
+            SYSCALL,
+            // remove the trap handler so halting stops the machine
+            LDI_W, 0xf0, 0x00, 0x00,
+            HALT,
+            // the handler clears carry with r2 = r2 + 0
+            ADD_W_IMM, 0x22, 0x00, 0x00,
+            RETH,
+        ]);
+        assert!(cpu.flags.carry);
+        assert!(cpu.flags.zero);
+        // the trap mode the handler got in r1 is gone too
+        assert_eq!(cpu.read_wr(R1), Ok(0));
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn syscall_from_user_mode() {
+        let mut rom = vec![
+            // stack at 0x9000
+            LDI_W, 0xb0, 0x00, 0x90,
+            USR,
+            LDI_W, 0x10, 0x34, 0x12,
+            SYSCALL,
+            // back in user mode, so writing rp is illegal
+            LDI_W, 0xe0, 0x00, 0x00,
+        ];
+        rom.resize(0x10, 0);
+        rom.extend([
+            // the handler of syscalls at 0x90 stores the trap mode at 0x8000,
+            // it may write rp since it runs in supervisor mode
+            STORE_BI, 0x01, 0x00, 0x80,
+            LDI_W, 0xe0, 0x00, 0x00,
+            RETH,
+        ]);
+        rom.resize(0x80, 0);
+        let mut table = [0; 0x40];
+        table[2 * TrapMode::SysCall as usize] = 0x90;
+        rom.extend(table);
+
+        let cpu = Blf4::new().with_trap_table(0x100);
+        let mut m = Machine::new(LazyMain::new(PanickingIO).with_rom(&rom), cpu);
+        assert_eq!(m.run_until_abort(), TrapMode::IllegalOperation);
+        assert_eq!(m.memory.read(0x8000), TrapMode::SysCall as u8);
+        // the registers of the user code are back
+        assert_eq!(m.cpu.read_wr(R1), Ok(0x1234));
+        assert_eq!(m.cpu.program_counter, 0x8e);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn syscall_handler_before_trap_table() {
+        let mut rom = vec![
+            LDI_W, 0xb0, 0x00, 0x90,
+            USR,
+            SYSCALL,
+            HALT,
+        ];
+        rom.resize(0x10, 0);
+        rom.extend([
+            // the handler in the table at 0x90 would store at 0x8000
+            STORE_BI, 0x01, 0x00, 0x80,
+            RETH,
+        ]);
+        rom.resize(0x18, 0);
+        rom.extend([
+            // the system call handler at 0x98 stores at 0x8001
+            STORE_BI, 0x01, 0x01, 0x80,
+            RETH,
+        ]);
+        rom.resize(0x80, 0);
+        let mut table = [0; 0x40];
+        table[2 * TrapMode::SysCall as usize] = 0x90;
+        rom.extend(table);
+
+        let cpu = Blf4::new().with_trap_table(0x100).with_syscall_handler(0x98);
+        let mut m = Machine::new(LazyMain::new(PanickingIO).with_rom(&rom), cpu);
+        assert_eq!(m.run_until_abort(), TrapMode::Halt);
+        assert_eq!(m.memory.read(0x8000), 0);
+        assert_eq!(m.memory.read(0x8001), TrapMode::SysCall as u8);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn trap_table() {
+        let mut rom = vec![
+            // stack at 0x9000
+            LDI_W, 0xb0, 0x00, 0x90,
+            SYSCALL,
+            // there is no entry for halting, so it stops the machine
+            HALT,
+        ];
+        rom.resize(0x10, 0);
+        rom.extend([
+            // the handler of syscalls at 0x90 stores 0x42 at 0x8000
+            LDI_B, 0x10, 0x42,
+            STORE_BI, 0x01, 0x00, 0x80,
+            RETH,
+        ]);
+        // the table at 0x100 has the syscall handler
+        rom.resize(0x80, 0);
+        let mut table = [0; 0x40];
+        table[2 * TrapMode::SysCall as usize] = 0x90;
+        rom.extend(table);
+
+        let cpu = Blf4::new().with_trap_table(0x100);
+        let mut m = Machine::new(LazyMain::new(PanickingIO).with_rom(&rom), cpu);
+        assert_eq!(m.run_until_abort(), TrapMode::Halt);
+        assert_eq!(m.memory.read(0x8000), 0x42);
+        assert_eq!(m.cpu.program_counter, 0x86);
+    }
+
+    #[test]
+    fn trap_table_wraps_around() {
+        // the entry of syscalls is at 0xfff6 + 2 * 5 = 0, which is a port
+        let cpu = Blf4::new().with_trap_table(0xfff6);
+        let mut m = Machine::new(LazyMain::new(Latch(0)).with_rom(&[SYSCALL]), cpu);
+        assert_eq!(m.run_until_abort(), TrapMode::SysCall);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn double_wide_load_store() {
+        let cpu = run(&[
+            // r1 = 0x8001, r2 = 1, r4:r3 = 0x1234_5678
+            LDI_W, 0x10, 0x01, 0x80,
+            LDI_W, 0x20, 0x01, 0x00,
+            LDI_W, 0x30, 0x78, 0x56,
+            LDI_W, 0x40, 0x34, 0x12,
+            // store r1, r2, r4, r3 at the unaligned 0x8002
+            STORE_D, 0x12, 0x43,
+            // load r6, r5, r1, r2
+            LOAD_D, 0x65, 0x12,
+            // the bytes one by one
+            LOAD_WR, 0x71, 0x20,
+            LDI_W, 0x20, 0x03, 0x00,
+            LOAD_WR, 0x81, 0x20,
+            HALT,
+        ]);
+        assert_eq!(cpu.read_wr(R5), Ok(0x5678));
+        assert_eq!(cpu.read_wr(R6), Ok(0x1234));
+        // little-endian with the low wide first
+        assert_eq!(cpu.read_wr(R7), Ok(0x5678));
+        assert_eq!(cpu.read_wr(R8), Ok(0x1234));
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn pmset() {
+        let cpu = run(&[
+            // r1l = 0x01, r2 = 0x0010, r3 = 4, r4l = 0xab
+            LDI_W, 0x10, 0x01, 0x00,
+            LDI_W, 0x20, 0x10, 0x00,
+            LDI_W, 0x30, 0x04, 0x00,
+            LDI_W, 0x40, 0xab, 0x00,
+            // pmset r1l, r2, r3, r4l sets 0x1_0010 to 0x1_0013
+            PMSET, 0x12, 0x37,
+            // pload r5l, r1l, r2
+            PLOAD, 0x91, 0x20,
+            // pload r5h and r6b from 0x1_0013 and 0x1_0014
+            LDI_W, 0x20, 0x13, 0x00,
+            PLOAD, 0xa1, 0x20,
+            LDI_W, 0x20, 0x14, 0x00,
+            PLOAD, 0xb1, 0x20,
+            HALT,
+        ]);
+        assert_eq!(cpu.read_wr(R5), Ok(0xabab));
+        assert_eq!(cpu.read_br(R6B), 0);
+
+        // 4 bytes from 0xff_fffe go past the end of memory
+        let rom = [
+            LDI_W, 0x10, 0xff, 0x00,
+            LDI_W, 0x20, 0xfe, 0xff,
+            LDI_W, 0x30, 0x04, 0x00,
+            PMSET, 0x12, 0x37,
+            HALT,
+        ];
+        let mut m = Machine::new(LazyMain::new(PanickingIO).with_rom(&rom), Blf4::new());
+        assert_eq!(m.run_until_abort(), TrapMode::IllegalWrite);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn mcpy_overlapping() {
+        let cpu = run(&[
+            // r1 = 0x8000, r2 = 0x8001, r3 = 4
+            LDI_W, 0x10, 0x00, 0x80,
+            LDI_W, 0x20, 0x01, 0x80,
+            LDI_W, 0x30, 0x04, 0x00,
+            // [0x8000] = 0x0201, [0x8002] = 0x0403
+            LDI_W, 0x40, 0x01, 0x02,
+            STORE_WR, 0x10, 0x40,
+            LDI_W, 0x40, 0x03, 0x04,
+            LDI_W, 0x50, 0x02, 0x00,
+            STORE_WR, 0x15, 0x40,
+            // mcpy r2, r1, r3 copies backwards
+            MCPY, 0x21, 0x30,
+            LOAD_WR, 0x61, 0x00,
+            LOAD_WR, 0x71, 0x50,
+            LDI_W, 0x50, 0x04, 0x00,
+            LOAD_WR, 0x81, 0x50,
+            // mcpy r1, r2, r3 copies forwards
+            MCPY, 0x12, 0x30,
+            LOAD_WR, 0x91, 0x00,
+            LDI_W, 0x50, 0x02, 0x00,
+            LOAD_WR, 0xa1, 0x50,
+            // mcpy r1, r2, r0 does nothing
+            MCPY, 0x12, 0x00,
+            HALT,
+        ]);
+        assert_eq!(cpu.read_wr(R6), Ok(0x0101));
+        assert_eq!(cpu.read_wr(R7), Ok(0x0302));
+        assert_eq!(cpu.read_wr(R8), Ok(0x0004));
+        assert_eq!(cpu.read_wr(R9), Ok(0x0201));
+        assert_eq!(cpu.read_wr(R10), Ok(0x0403));
+    }
+
+    #[test]
+    fn neg() {
+        // value, negation, carry, overflow
+        let wide_cases = [
+            (0x0000, 0x0000, false, false),
+            (0x0001, 0xffff, true, false),
+            (0xffff, 0x0001, true, false),
+            (0x8000, 0x8000, true, true),
+        ];
+        for (val, res, carry, overflow) in wide_cases {
+            let [lo, hi] = u16::to_le_bytes(val);
+            let cpu = run(&[LDI_W, 0x20, lo, hi, NEG_W, 0x12, HALT]);
+            assert_eq!(cpu.read_wr(R1), Ok(res));
+            assert_eq!(cpu.flags.carry, carry);
+            assert_eq!(cpu.flags.overflow, overflow);
+            assert_eq!(cpu.flags.sign, res & 0x8000 != 0);
+            assert_eq!(cpu.flags.zero, res == 0);
+        }
+        let byte_cases = [
+            (0x00, 0x00, false, false),
+            (0x01, 0xff, true, false),
+            (0xff, 0x01, true, false),
+            (0x80, 0x80, true, true),
+        ];
+        for (val, res, carry, overflow) in byte_cases {
+            let cpu = run(&[LDI_B, 0x30, val, NEG_B, 0x13, HALT]);
+            assert_eq!(cpu.read_br(R1L), res);
+            assert_eq!(cpu.flags.carry, carry);
+            assert_eq!(cpu.flags.overflow, overflow);
+            assert_eq!(cpu.flags.sign, res & 0x80 != 0);
+            assert_eq!(cpu.flags.zero, res == 0);
+        }
+
+        let mut m = Machine::new(
+            LazyMain::new(PanickingIO).with_rom(&[NEG_W, 0x01]),
+            Blf4::new(),
+        );
+        assert_eq!(m.run_until_abort(), TrapMode::Invalid);
+    }
+
+    #[test]
+    fn extend_byte() {
+        // r2l = 0x80; sxt r1, r2l; zxt r3, r2l
+        let cpu = run(&[LDI_B, 0x30, 0x80, SXT_B, 0x13, ZXT_B, 0x33, HALT]);
+        assert_eq!(cpu.read_wr(R1), Ok(0xff80));
+        assert_eq!(cpu.read_wr(R3), Ok(0x0080));
+
+        let cpu = run(&[LDI_B, 0x30, 0x7f, SXT_B, 0x13, ZXT_B, 0x33, HALT]);
+        assert_eq!(cpu.read_wr(R1), Ok(0x007f));
+        assert_eq!(cpu.read_wr(R3), Ok(0x007f));
+    }
+
+    #[test]
+    fn move_byte_of_wide() {
+        // r5 = 0x1234; movb r1l, r5; movbh r2l, r5
+        let cpu = run(&[LDI_W, 0x50, 0x34, 0x12, MOVB_WR, 0x15, MOVBH_WR, 0x35, HALT]);
+        assert_eq!(cpu.read_br(R1L), 0x34);
+        assert_eq!(cpu.read_br(R2L), 0x12);
+        assert_eq!(cpu.read_wr(R5), Ok(0x1234));
+        // flags are unaffected
+        assert!(!cpu.flags.zero);
+    }
+
+    #[test]
+    fn wait_until_notified() {
+        let rom = [WAIT, LDI_W, 0x10, 0x01, 0x00, HALT];
+        let mut m = Machine::new(LazyMain::new(PanickingIO).with_rom(&rom), Blf4::new());
+
+        let notifier = m.notifier();
+        let t = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            notifier.notify();
+        });
+        assert_eq!(m.run_until_abort(), TrapMode::Halt);
+        assert_eq!(m.cpu.read_wr(R1), Ok(1));
+        t.join().unwrap();
+    }
+
+    /// Reads back the last byte written to it
+    struct Latch(u8);
+    impl Io for Latch {
+        fn read(&mut self, _port: u8) -> u8 {
+            self.0
+        }
+        fn write(&mut self, _port: u8, val: u8) {
+            self.0 = val;
+        }
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn port_io() {
+        let rom = [
+            // out r1l, r2l with r1l = 0x80, r2l = 0x42
+            LDI_B, 0x10, 0x80,
+            LDI_B, 0x30, 0x42,
+            OUT_B, 0x13,
+            // in r3l, r1l
+            IN_B, 0x51,
+            // in r5l, r1l from a port without a device
+            LDI_B, 0x10, 0x10,
+            IN_B, 0x91,
+            HALT,
+        ];
+        let memory = LazyMain::new(PanickingIO)
+            .with_rom(&rom)
+            .with_port_device(0x80..=0x83, Latch(0));
+        let mut m = Machine::new(memory, Blf4::new());
+        assert_eq!(m.run_until_abort(), TrapMode::Halt);
+        assert_eq!(m.cpu.read_br(R3L), 0x42);
+        assert_eq!(m.cpu.read_br(R5L), 0xff);
+
+        let rom = [USR, IN_B, 0x51];
+        let mut m = Machine::new(LazyMain::new(PanickingIO).with_rom(&rom), Blf4::new());
+        assert_eq!(m.run_until_abort(), TrapMode::IllegalOperation);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn bswap() {
+        let cpu = run(&[
+            LDI_W, 0x20, 0x34, 0x12,
+            BSWAP_W, 0x12,
+            // r3l = 0xab; bswap r2l, r3l
+            LDI_B, 0x50, 0xab,
+            BSWAP_B, 0x35,
+            HALT,
+        ]);
+        assert_eq!(cpu.read_wr(R1), Ok(0x3412));
+        assert_eq!(cpu.read_br(R2L), 0xba);
+        assert!(cpu.flags.sign);
+        assert!(!cpu.flags.zero);
+        assert!(!cpu.flags.carry);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn bit_counts() {
+        let cpu = run(&[
+            LDI_W, 0x20, 0xf0, 0x0b,
+            POPCNT_W, 0x12,
+            CLZ_W, 0x32,
+            CTZ_W, 0x42,
+            // r2l = 0; popcnt r5l, r2l
+            LDI_B, 0x30, 0x00,
+            POPCNT_B, 0x93,
+            HALT,
+        ]);
+        assert_eq!(cpu.read_wr(R1), Ok(7));
+        assert_eq!(cpu.read_wr(R3), Ok(4));
+        assert_eq!(cpu.read_wr(R4), Ok(4));
+        assert_eq!(cpu.read_br(R5L), 0);
+        assert!(cpu.flags.zero);
+        assert!(!cpu.flags.sign);
+        assert!(!cpu.flags.carry);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn shift_imm() {
+        let cpu = run(&[
+            LDI_W, 0x10, 0x81, 0x80,
+            // r2 = r1 << 1
+            SHL_W_IMM, 0x21, 0x10,
+            // r3 = r1 >> 15 (logical)
+            LSR_W_IMM, 0x31, 0xf0,
+            // r4 = r1 >> 4 (arithmetic)
+            ASR_W_IMM, 0x41, 0x40,
+            // r5 = r1 >> 0
+            LSR_W_IMM, 0x51, 0x00,
+            // r6b = r1l << 9, shifts out the last bit as zero
+            SHL_B_IMM, 0xb1, 0x90,
+            HALT,
+        ]);
+        assert_eq!(cpu.read_wr(R2), Ok(0x0102));
+        assert_eq!(cpu.read_wr(R3), Ok(0x0001));
+        assert_eq!(cpu.read_wr(R4), Ok(0xf808));
+        assert_eq!(cpu.read_wr(R5), Ok(0x8081));
+        assert_eq!(cpu.read_br(R6B), 0);
+        assert!(cpu.flags.zero);
+        assert!(!cpu.flags.carry);
+
+        let cpu = run(&[
+            LDI_W, 0x10, 0x81, 0x80,
+            // r1l >> 8 (arithmetic) fills with the sign and shifts it out
+            ASR_B_IMM, 0x11, 0x80,
+            HALT,
+        ]);
+        assert_eq!(cpu.read_br(R1L), 0xff);
+        assert!(cpu.flags.carry);
+        assert!(cpu.flags.sign);
+
+        let cpu = run(&[
+            LDI_W, 0x10, 0x01, 0x00,
+            LSR_W_IMM, 0x11, 0x10,
+            HALT,
+        ]);
+        assert_eq!(cpu.read_wr(R1), Ok(0));
+        assert!(cpu.flags.carry);
+        assert!(cpu.flags.zero);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn shift_reg_carry() {
+        let cpu = run(&[
+            LDI_W, 0x10, 0x81, 0x80,
+            LDI_W, 0x20, 0x01, 0x00,
+            // r3 = r1 << r2, the carry is caught by adc r4, r0, r0
+            SHL_W, 0x31, 0x20,
+            ADC_W, 0x40, 0x00,
+            // shifting by the width shifts out the lowest bit last
+            LDI_W, 0x20, 0x10, 0x00,
+            SHL_W, 0x51, 0x20,
+            ADC_W, 0x60, 0x00,
+            // and past it nothing is left for the carry
+            LDI_W, 0x20, 0x11, 0x00,
+            SHL_W, 0x71, 0x20,
+            ADC_W, 0x80, 0x00,
+            // r10b = r1l >> 200 (arithmetic)
+            LDI_W, 0x20, 0xc8, 0x00,
+            ASR_B, 0xf1, 0x30,
+            HALT,
+        ]);
+        assert_eq!(cpu.read_wr(R3), Ok(0x0102));
+        assert_eq!(cpu.read_wr(R4), Ok(1));
+        assert_eq!(cpu.read_wr(R5), Ok(0));
+        assert_eq!(cpu.read_wr(R6), Ok(1));
+        assert_eq!(cpu.read_wr(R7), Ok(0));
+        assert_eq!(cpu.read_wr(R8), Ok(0));
+        assert_eq!(cpu.read_br(R10B), 0xff);
+        assert!(cpu.flags.carry);
+        assert!(cpu.flags.sign);
+
+        let cpu = run(&[
+            LDI_W, 0x10, 0x03, 0x00,
+            LDI_W, 0x20, 0x02, 0x00,
+            // lsr r1, r1, r2 shifts out the second lowest bit last
+            LSR_W, 0x11, 0x20,
+            HALT,
+        ]);
+        assert_eq!(cpu.read_wr(R1), Ok(0));
+        assert!(cpu.flags.carry);
+        assert!(cpu.flags.zero);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn add_sub_imm() {
+        let cpu = run(&[
+            LDI_W, 0x10, 0xff, 0x00,
+            // r2 = r1 + 0x0101
+            ADD_W_IMM, 0x21, 0x01, 0x01,
+            // r3l = r1l + 1, carries out
+            ADD_B_IMM, 0x51, 0x01,
+            HALT,
+        ]);
+        assert_eq!(cpu.read_wr(R2), Ok(0x0200));
+        assert_eq!(cpu.read_br(R3L), 0);
+        assert!(cpu.flags.carry);
+        assert!(cpu.flags.zero);
+
+        let cpu = run(&[
+            LDI_W, 0x10, 0x00, 0x80,
+            // r2l = r1l - 0x80
+            SUB_B_IMM, 0x31, 0x80,
+            // r1 = r1 - 1, overflows to positive
+            SUB_W_IMM, 0x11, 0x01, 0x00,
+            HALT,
+        ]);
+        assert_eq!(cpu.read_br(R2L), 0x80);
+        assert_eq!(cpu.read_wr(R1), Ok(0x7fff));
+        assert!(cpu.flags.overflow);
+        assert!(!cpu.flags.carry);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn ldi_sign_extended_byte() {
+        let cpu = run(&[
+            LDI_W, 0x12, 0xff,
+            LDI_W, 0x22, 0x7f,
+            HALT,
+        ]);
+        assert_eq!(cpu.read_wr(R1), Ok(0xffff));
+        assert_eq!(cpu.read_wr(R2), Ok(0x007f));
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn push_immediates() {
+        let rom = [
+            PUSH_IMM_W, 0x34, 0x12,
+            PUSH_IMM_B, 0x56,
+            // r1 = 0x1234 pushed before the byte
+            POP_B, 0x10,
+            POP_W, 0x10,
+            HALT,
+        ];
+        let cpu = Blf4::new().with_stack_top(0x9000);
+        let mut m = Machine::new(LazyMain::new(PanickingIO).with_rom(&rom), cpu);
+
+        assert_eq!(m.run_until_abort(), TrapMode::Halt);
+        assert_eq!(m.cpu.read_br(R1L), 0x34);
+        assert_eq!(m.cpu.read_wr(R1), Ok(0x1234));
+        assert_eq!(m.memory.read(0x8fff), 0x12);
+        assert_eq!(m.memory.read(0x8ffd), 0x56);
+        assert_eq!(m.cpu.stack, 0x9000);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn call_through_jump_table() {
+        let rom = [
+            // r3 = table[1]; call r3
+            LDI_W, 0x10, 0x9a, 0x00,
+            LDI_W, 0x20, 0x02, 0x00,
+            LOAD_WR, 0x31, 0x20,
+            CALL_R, 0x30,
+            HALT,
+            // 0x8e: r4 = 0x11
+            LDI_W, 0x40, 0x11, 0x00,
+            RET, 0x00,
+            // 0x94: r4 = 0x22
+            LDI_W, 0x40, 0x22, 0x00,
+            RET, 0x00,
+            // 0x9a: table
+            0x8e, 0x00, 0x94, 0x00,
+        ];
+        let cpu = run(&rom);
+        assert_eq!(cpu.read_wr(R4), Ok(0x22));
+        assert_eq!(cpu.link, 0x8d);
+
+        for rom in [[CALL_R, 0x00], [CALL_R, 0x31]] {
+            let mut m = Machine::new(LazyMain::new(PanickingIO).with_rom(&rom), Blf4::new());
+            assert_eq!(m.run_until_abort(), TrapMode::Invalid);
+        }
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn ret_removes_arguments() {
+        let cpu = run(&[
+            // stack at 0x9000
+            LDI_W, 0xb0, 0x00, 0x90,
+            // five wide arguments
+            PUSH_IMM_W, 0x01, 0x00,
+            PUSH_IMM_W, 0x02, 0x00,
+            PUSH_IMM_W, 0x03, 0x00,
+            PUSH_IMM_W, 0x04, 0x00,
+            PUSH_IMM_W, 0x05, 0x00,
+            CALL, 0x97, 0x00,
+            HALT,
+            // 0x97: the callee removes them
+            RET, 10,
+        ]);
+        assert_eq!(cpu.stack, 0x9000);
+
+        let cpu = run(&[
+            LDI_W, 0xb0, 0x00, 0x90,
+            CALL, 0x88, 0x00,
+            HALT,
+            // 0x88: more than a byte can remove
+            RET_W, 0x2c, 0x01,
+        ]);
+        assert_eq!(cpu.stack, 0x912c);
+        assert_eq!(cpu.program_counter, 0x88);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn signed_conditions() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+
+        let edges = [
+            i16::MIN, i16::MIN + 1, -0x1234, -2, -1, 0, 1, 2, 0x1234, i16::MAX - 1, i16::MAX,
+        ];
+        let mut rng = StdRng::seed_from_u64(0x71e);
+        let pairs: Vec<(i16, i16)> = edges
+            .iter()
+            .flat_map(|&a| edges.iter().map(move |&b| (a, b)))
+            .chain((0..1000).map(|_| (rng.gen(), rng.gen())))
+            .collect();
+
+        for jcc in [JLT, JLE, JGT, JGE] {
+            let rom = [
+                // sub r4, r1, r2
+                SUB_W, 0x41, 0x20,
+                // jcc 0x87
+                jcc, 0x87, 0x00,
+                HALT,
+                // 0x87: r3 = 1
+                LDI_W, 0x30, 0x01, 0x00,
+                HALT,
+            ];
+            let mut m = Machine::new(LazyMain::new(PanickingIO).with_rom(&rom), Blf4::new());
+            for &(a, b) in &pairs {
+                m.cpu.write_wr(R1, a as u16).unwrap();
+                m.cpu.write_wr(R2, b as u16).unwrap();
+                m.cpu.write_wr(R3, 0).unwrap();
+                m.cpu.program_counter = 0x80;
+                assert_eq!(m.run_until_abort(), TrapMode::Halt);
+                let expected = match jcc {
+                    JLT => a < b,
+                    JLE => a <= b,
+                    JGT => a > b,
+                    _ => a >= b,
+                };
+                let jumped = m.cpu.read_wr(R3) == Ok(1);
+                assert_eq!(jumped, expected, "{} {a}, {b}", mnemonic(jcc).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn jmp_register() {
+        let cpu = run(&[
+            LDI_W, 0x50, 0x8a, 0x00,
+            // jmp r5, the immediate is not used
+            LDI_W, 0x51, 0x34, 0x12,
+            HALT,
+            NOP,
+            // 0x8a: r4 = 1
+            LDI_W, 0x40, 0x01, 0x00,
+            HALT,
+        ]);
+        assert_eq!(cpu.read_wr(R4), Ok(1));
+
+        // with r0 it is a jump to the immediate, so jmp r0 is jmp 0
+        let rom = [LDI_W, 0x01, 0x00, 0x00];
+        let mut m = Machine::new(LazyMain::new(PanickingIO).with_rom(&rom), Blf4::new());
+        m.execute_once().unwrap();
+        assert_eq!(m.cpu.program_counter, 0);
+    }
+
+    #[test]
+    fn recursion_overflows_stack() {
+        // f: push rl; call f
+        let rom = [PUSH_W, 0xc0, CALL, 0x80, 0x00];
+        let cpu = Blf4::new().with_stack_top(0x9000).with_stack_limit(0x8f00);
+        let mut m = Machine::new(LazyMain::new(PanickingIO).with_rom(&rom), cpu);
+
+        assert_eq!(m.run_until_abort(), TrapMode::StackOverflow);
+        assert_eq!(m.cpu.stack, 0x8f00);
+        assert_eq!(m.memory.read(0x8efe), 0);
+        assert_eq!(m.memory.read(0x8eff), 0);
+    }
+
+    #[test]
+    fn pop_past_top_underflows() {
+        let rom = [POP_W, 0x10];
+        let cpu = Blf4::new().with_stack_top(0x9000);
+        let mut m = Machine::new(LazyMain::new(PanickingIO).with_rom(&rom), cpu);
+
+        assert_eq!(m.run_until_abort(), TrapMode::StackUnderflow);
+        assert_eq!(m.cpu.stack, 0x9000);
+    }
+}
@@ -3,6 +3,8 @@ pub const HALT: u8 = 0x0a;
 pub const CTF: u8 = 0x0b;
 pub const SYSCALL: u8 = 0xc;
 pub const RETH: u8 = 0x0d;
+pub const CPUID: u8 = 0x0e;
+pub const WAIT: u8 = 0x0f;
 
 pub const USR: u8 = 0x10;
 pub const VMON: u8 = 0x11;
@@ -63,6 +65,115 @@ pub const DIV_B: u8 = 0x51;
 pub const DIV_W: u8 = 0x52;
 pub const MUL_B: u8 = 0x53;
 pub const MUL_W: u8 = 0x54;
+pub const XCHG_B: u8 = 0x55;
+pub const XCHG_W: u8 = 0x56;
+pub const ADC_B: u8 = 0x57;
+pub const ADC_W: u8 = 0x58;
+pub const SBB_B: u8 = 0x59;
+pub const SBB_W: u8 = 0x5a;
+pub const CAS_W: u8 = 0x5b;
+pub const MCPY: u8 = 0x5c;
+pub const NEG_B: u8 = 0x5d;
+pub const NEG_W: u8 = 0x5e;
+pub const SXT_B: u8 = 0x5f;
+pub const ZXT_B: u8 = 0x60;
+pub const BSWAP_B: u8 = 0x61;
+pub const BSWAP_W: u8 = 0x62;
+pub const POPCNT_B: u8 = 0x63;
+pub const POPCNT_W: u8 = 0x64;
+pub const CLZ_B: u8 = 0x65;
+pub const CLZ_W: u8 = 0x66;
+pub const CTZ_B: u8 = 0x67;
+pub const CTZ_W: u8 = 0x68;
+pub const SHL_B_IMM: u8 = 0x69;
+pub const SHL_W_IMM: u8 = 0x6a;
+pub const ASR_B_IMM: u8 = 0x6b;
+pub const ASR_W_IMM: u8 = 0x6c;
+pub const LSR_B_IMM: u8 = 0x6d;
+pub const LSR_W_IMM: u8 = 0x6e;
+pub const ADD_B_IMM: u8 = 0x6f;
+pub const ADD_W_IMM: u8 = 0x70;
+pub const SUB_B_IMM: u8 = 0x71;
+pub const SUB_W_IMM: u8 = 0x72;
+pub const MOVB_WR: u8 = 0x73;
+pub const MOVBH_WR: u8 = 0x74;
+pub const LOAD_D: u8 = 0x75;
+pub const STORE_D: u8 = 0x76;
+pub const IN_B: u8 = 0x77;
+pub const OUT_B: u8 = 0x78;
+pub const PUSH_IMM_B: u8 = 0x79;
+pub const PUSH_IMM_W: u8 = 0x7a;
+pub const CALL_R: u8 = 0x7b;
+pub const PMSET: u8 = 0x7c;
+pub const RET_W: u8 = 0x7d;
+
+/// Name of the instruction with `opcode`
+///
+/// Instructions that are written differently in assembly, like `jmp` being `ldi`, get one name
+pub fn mnemonic(opcode: u8) -> Option<&'static str> {
+    Some(match opcode {
+        NULL => "null",
+        HALT => "halt",
+        CTF => "ctf",
+        SYSCALL => "syscall",
+        RETH => "reth",
+        CPUID => "cpuid",
+        WAIT => "wait",
+        USR => "usr",
+        VMON => "vmon",
+        VMOFF => "vmoff",
+        PSTORE => "pstore",
+        PLOAD => "pload",
+        PMSET => "pmset",
+        NOP => "nop",
+        PUSH_B | PUSH_W | PUSH_IMM_B | PUSH_IMM_W => "push",
+        POP_B | POP_W => "pop",
+        CALL | CALL_R => "call",
+        RET | RET_W => "ret",
+        STORE_BI | STORE_WI | STORE_BR | STORE_WR | STORE_D => "store",
+        LOAD_BI | LOAD_WI | LOAD_BR | LOAD_WR | LOAD_D => "load",
+        JEZ => "jez",
+        JLT => "jlt",
+        JLE => "jle",
+        JGT => "jgt",
+        JGE => "jge",
+        JNZ => "jnz",
+        JO => "jo",
+        JNO => "jno",
+        JB => "jb",
+        JAE => "jae",
+        JA => "ja",
+        JBE => "jbe",
+        LDI_B | LDI_W => "ldi",
+        ADD_B | ADD_W | ADD_B_IMM | ADD_W_IMM => "add",
+        SUB_B | SUB_W | SUB_B_IMM | SUB_W_IMM => "sub",
+        AND_B | AND_W => "and",
+        OR_B | OR_W => "or",
+        XOR_B | XOR_W => "xor",
+        SHL_B | SHL_W | SHL_B_IMM | SHL_W_IMM => "shl",
+        ASR_B | ASR_W | ASR_B_IMM | ASR_W_IMM => "asr",
+        LSR_B | LSR_W | LSR_B_IMM | LSR_W_IMM => "lsr",
+        ADC_B | ADC_W => "adc",
+        SBB_B | SBB_W => "sbb",
+        CAS_W => "cas",
+        MCPY => "mcpy",
+        MUL_B | MUL_W => "mul",
+        DIV_B | DIV_W => "div",
+        SXT_B => "sxt",
+        ZXT_B => "zxt",
+        MOVB_WR => "movb",
+        MOVBH_WR => "movbh",
+        IN_B => "in",
+        OUT_B => "out",
+        BSWAP_B | BSWAP_W => "bswap",
+        POPCNT_B | POPCNT_W => "popcnt",
+        CLZ_B | CLZ_W => "clz",
+        CTZ_B | CTZ_W => "ctz",
+        NEG_B | NEG_W => "neg",
+        XCHG_B | XCHG_W => "xchg",
+        _ => return None,
+    })
+}
 
 mod handlers;
 pub use handlers::*;
@@ -1,21 +1,34 @@
 use std::fmt::{self, Display};
 
 use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    machine::Cpu,
+    machine::{Cpu, MAX_MEMORY_SIZE},
     mem::{self, MainMemory},
     PAGE_SIZE, U4,
 };
 
 pub mod isa;
 
+mod features;
 mod register_type;
 mod std_kernel;
+pub use self::std_kernel::{
+    HeapAllocator, SyscallHandler, SyscallTable, EXTERN_END, EXTERN_START, NULL_PTR, SYS_ALLOC,
+    SYS_DEBUG_MMAP, SYS_EXIT, SYS_FREE, SYS_GETTIME, SYS_READ, SYS_REALLOC, SYS_SET_ERROR_HANDLER,
+    SYS_WRITE,
+};
 
+pub use self::features::*;
 pub use self::register_type::*;
 use isa::OP_HANDLERS;
 
+#[cfg(feature = "coverage")]
+use crate::machine::CoverageMap;
+#[cfg(feature = "profiling")]
+use crate::machine::Profile;
+
 pub const PERM_U: u8 = 0b0010_0000;
 pub const FLAG_D: u8 = 0b0001_0000;
 pub const PERM_X: u8 = 0b1000;
@@ -23,9 +36,10 @@ pub const PERM_W: u8 = 0b0100;
 pub const PERM_R: u8 = 0b0010;
 pub const FLAG_P: u8 = 0b0001;
 
+/// Result of an operation on the processor, an error is the [`TrapMode`] of the trap it causes
 pub type OpRes<T, E = TrapMode> = Result<T, E>;
 
-#[derive(Debug, Clone, Copy, Default)]
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
 pub struct Blf4Flags {
     pub user_mode: bool,
     pub trap: bool,
@@ -118,7 +132,9 @@ impl Display for Blf4Flags {
     }
 }
 
-#[derive(Debug, Clone)]
+/// The state of a CPU, which is saved in snapshots, so changing its fields needs a new
+/// [`SNAPSHOT_VERSION`](crate::machine::snapshot::SNAPSHOT_VERSION)
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Blf4 {
     general_purposes: [u8; 20],
 
@@ -131,7 +147,37 @@ pub struct Blf4 {
     pub program_counter: u16,
     /// Zero means no trap handler, inits to zero
     pub trap_handler: u16,
+    /// Where `syscall` jumps to, zero means the trap table or [`Self::trap_handler`] is used as
+    /// for other traps, inits to zero
+    pub syscall_handler: u16,
     pub flags: Blf4Flags,
+    /// Virtual address of the last access that failed to translate
+    pub fault_address: u16,
+    /// Whether the last failed translation was for a write
+    pub fault_write: bool,
+    /// Number of the last interrupt request that was taken
+    pub irq: u8,
+    /// Lowest address the stack may grow down to, pushing below it traps
+    pub stack_limit: Option<u16>,
+    /// Address the stack starts at, popping past it traps, 0 is the end of memory
+    pub stack_top: Option<u16>,
+    /// Address of a table with a wide handler address for each trap number, see
+    /// [`Self::with_trap_table`]
+    pub trap_table: Option<u16>,
+    /// Every address that has been fetched from
+    #[cfg(feature = "coverage")]
+    #[serde(skip)]
+    pub coverage: CoverageMap,
+    /// How many times each opcode has been executed
+    #[cfg(feature = "profiling")]
+    #[serde(skip)]
+    pub profile: Profile,
+}
+
+impl Default for Blf4 {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Blf4 {
@@ -143,7 +189,18 @@ impl Blf4 {
             program_counter: PAGE_SIZE,
             link: PAGE_SIZE,
             trap_handler: 0,
+            syscall_handler: 0,
             flags: Blf4Flags::default(),
+            fault_address: 0,
+            fault_write: false,
+            irq: 0,
+            stack_limit: None,
+            stack_top: None,
+            trap_table: None,
+            #[cfg(feature = "coverage")]
+            coverage: CoverageMap::new(),
+            #[cfg(feature = "profiling")]
+            profile: Profile::new(),
 
             general_purposes: std::array::from_fn(|_| rng.gen()),
             page: rng.gen(),
@@ -151,6 +208,33 @@ impl Blf4 {
             stack: rng.gen(),
         }
     }
+    /// Traps with [`TrapMode::StackOverflow`] when pushing below `limit`
+    pub fn with_stack_limit(mut self, limit: u16) -> Self {
+        self.stack_limit = Some(limit);
+        self
+    }
+    /// Starts the stack at `top` and traps with [`TrapMode::StackUnderflow`] when popping past it
+    ///
+    /// A `top` of 0 starts the stack at the end of memory as pushing wraps around
+    pub fn with_stack_top(mut self, top: u16) -> Self {
+        self.stack = top;
+        self.stack_top = Some(top);
+        self
+    }
+    /// Looks up trap handlers in the table at `table`, indexed by the trap number
+    ///
+    /// When trapping, the wide at `table + 2 * trap number`, wrapping around the address space,
+    /// is used as the trap handler if it is not zero, otherwise [`Self::trap_handler`] is used as
+    /// without a table
+    pub fn with_trap_table(mut self, table: u16) -> Self {
+        self.trap_table = Some(table);
+        self
+    }
+    /// Makes `syscall` jump to `handler` regardless of the trap table and [`Self::trap_handler`]
+    pub fn with_syscall_handler(mut self, handler: u16) -> Self {
+        self.syscall_handler = handler;
+        self
+    }
     #[inline]
     pub fn context<'a>(&'a mut self, mem: &'a mut dyn MainMemory) -> HandlerContext<'a> {
         HandlerContext { cpu: self, mem }
@@ -231,22 +315,56 @@ impl Blf4 {
 
 impl Cpu for Blf4 {
     type TrapMode = TrapMode;
+    fn is_waiting(tm: &TrapMode) -> bool {
+        *tm == TrapMode::WaitingForInterrupt
+    }
     fn execute_instruction<M: MainMemory>(&mut self, mem: &mut M) -> OpRes<(), Self::TrapMode> {
         let mut ctx = HandlerContext { cpu: self, mem };
 
-        let opcode = ctx.fetch()?;
+        // interrupts cannot interrupt trap handlers
+        let res = match ctx.mem.tick() {
+            Some(irq) if !ctx.cpu.flags.trap => {
+                ctx.cpu.irq = irq;
+                Err(TrapMode::Interrupt)
+            }
+            _ => {
+                let opcode = ctx.fetch()?;
+                #[cfg(feature = "profiling")]
+                ctx.cpu.profile.count(opcode);
+                OP_HANDLERS[opcode as usize](&mut ctx)
+            }
+        };
 
-        match OP_HANDLERS[opcode as usize](&mut ctx) {
+        match res {
             Ok(()) => (),
+            // the machine does the waiting, so it is not handled like a trap
+            Err(TrapMode::WaitingForInterrupt) => return Err(TrapMode::WaitingForInterrupt),
             Err(tm) => {
+                let flags = ctx.cpu.flags;
                 ctx.cpu.flags.trap = true;
                 ctx.cpu.flags.user_mode = false;
-                if ctx.cpu.trap_handler == 0 {
+                let handler = match ctx.cpu.trap_table {
+                    _ if tm == TrapMode::SysCall && ctx.cpu.syscall_handler != 0 => {
+                        ctx.cpu.syscall_handler
+                    }
+                    Some(table) => match ctx.read_wide(table.wrapping_add(2 * tm as u8 as u16))? {
+                        0 => ctx.cpu.trap_handler,
+                        handler => handler,
+                    },
+                    None => ctx.cpu.trap_handler,
+                };
+                if handler == 0 {
                     return Err(tm);
                 } else {
-                    ctx.push_registers()?;
-                    self.program_counter = self.trap_handler;
+                    ctx.push_registers(flags)?;
+                    self.program_counter = handler;
                     self.write_wr(R1, tm as u8 as u16)?;
+                    if tm.is_address_fault() {
+                        self.write_wr(R2, self.fault_address)?;
+                        self.write_wr(R3, self.fault_write as u16)?;
+                    } else if tm == TrapMode::Interrupt {
+                        self.write_wr(R2, self.irq as u16)?;
+                    }
                 }
             }
         }
@@ -270,9 +388,59 @@ pub enum TrapMode {
     IllegalRead = 0x11,
     IllegalWrite = 0x12,
     IllegalExecute = 0x13,
+    StackOverflow = 0x14,
+    StackUnderflow = 0x15,
+    /// Not a real trap, the machine waits for a peripheral to notify it and then continues
+    WaitingForInterrupt = 0x16,
+    /// A peripheral requested an interrupt, its number is in [`Blf4::irq`]
+    Interrupt = 0x17,
+    /// A pointer that was not allocated was freed, see [`HeapAllocator`]
+    HeapCorruption = 0x18,
     IllegalHandlerReturn = 0x1f,
 }
 
+impl TrapMode {
+    /// Whether this trap was caused by an address failing to translate,
+    /// in which case the address is in [`Blf4::fault_address`] and whether it was written to in
+    /// [`Blf4::fault_write`]
+    pub const fn is_address_fault(self) -> bool {
+        matches!(
+            self,
+            Self::Level1PageFault
+                | Self::Level2PageFault
+                | Self::IllegalRead
+                | Self::IllegalWrite
+                | Self::IllegalExecute
+        )
+    }
+}
+
+impl Display for TrapMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            TrapMode::Invalid => "invalid instruction",
+            TrapMode::SysCall => "system call",
+            TrapMode::ZeroDiv => "divide by zero",
+            TrapMode::Halt => "halt requested",
+            TrapMode::Level1PageFault => "page fault in level 1 page table",
+            TrapMode::Level2PageFault => "page fault in level 2 page table",
+            TrapMode::IllegalOperation => "illegal operation in user mode",
+            TrapMode::IllegalRead => "illegal read",
+            TrapMode::IllegalWrite => "illegal write",
+            TrapMode::IllegalExecute => "illegal execute",
+            TrapMode::StackOverflow => "stack overflow",
+            TrapMode::StackUnderflow => "stack underflow",
+            TrapMode::WaitingForInterrupt => "waiting for interrupt",
+            TrapMode::Interrupt => "interrupt request",
+            TrapMode::HeapCorruption => "heap corruption",
+            TrapMode::IllegalHandlerReturn => "illegal handler return",
+        };
+        f.write_str(s)
+    }
+}
+
+impl std::error::Error for TrapMode {}
+
 #[derive(Debug, Clone, Copy)]
 enum AccessMode {
     Read,
@@ -337,6 +505,19 @@ impl HandlerContext<'_> {
 
     #[must_use = "error must be handled"]
     fn addr_resolve(&mut self, addr: u16, mode: AccessMode) -> OpRes<u32> {
+        let res = self.translate(addr, mode);
+        if res.is_err() {
+            // record the fault so the trap handler can find out what went wrong
+            self.cpu.fault_address = addr;
+            self.cpu.fault_write = matches!(mode, AccessMode::Write);
+        }
+        res
+    }
+    /// Gets the physical address a read of `addr` would access without recording any fault
+    pub fn translate_read(&mut self, addr: u16) -> OpRes<u32> {
+        self.translate(addr, AccessMode::Read)
+    }
+    fn translate(&mut self, addr: u16, mode: AccessMode) -> OpRes<u32> {
         if !self.cpu.flags.virtual_mode {
             // direct mode addresses the 0 block, which is usually ROM except
             // the first 128 bytes (page) which are mapped to IO ports
@@ -474,6 +655,8 @@ impl HandlerContext<'_> {
     pub fn fetch(&mut self) -> OpRes<u8> {
         let addr = self.cpu.program_counter;
         self.cpu.program_counter += 1;
+        #[cfg(feature = "coverage")]
+        self.cpu.coverage.mark(addr);
         let addr = self.addr_resolve(addr, AccessMode::Execute)?;
         Ok(self.mem.read(addr))
     }
@@ -488,10 +671,24 @@ impl HandlerContext<'_> {
         self.mem.write(addr, val);
         Ok(())
     }
+    /// Only the kernel may use the port space
+    pub fn port_read(&mut self, port: u8) -> OpRes<u8> {
+        if self.cpu.flags.user_mode {
+            return Err(TrapMode::IllegalOperation);
+        }
+        Ok(self.mem.port_read(port))
+    }
+    pub fn port_write(&mut self, port: u8, val: u8) -> OpRes<()> {
+        if self.cpu.flags.user_mode {
+            return Err(TrapMode::IllegalOperation);
+        }
+        self.mem.port_write(port, val);
+        Ok(())
+    }
     #[must_use = "error must be handled"]
     pub fn read_wide(&mut self, addr: u16) -> OpRes<u16> {
         let lower = self.read(addr)?;
-        let higher = self.read(addr + 1)?;
+        let higher = self.read(addr.wrapping_add(1))?;
 
         Ok(u16::from_le_bytes([lower, higher]))
     }
@@ -500,7 +697,7 @@ impl HandlerContext<'_> {
         let [lower, higher] = val.to_le_bytes();
 
         self.write(addr, lower)?;
-        self.write(addr + 1, higher)?;
+        self.write(addr.wrapping_add(1), higher)?;
 
         Ok(())
     }
@@ -512,32 +709,78 @@ impl HandlerContext<'_> {
         self.mem.write(physical_addr, val);
         Ok(())
     }
+    /// Sets `count` bytes from `physical_addr` to `val`, an error if they go past the end of memory
+    pub fn physical_memset(&mut self, physical_addr: u32, val: u8, count: u32) -> OpRes<()> {
+        let end = physical_addr
+            .checked_add(count)
+            .filter(|&end| end <= MAX_MEMORY_SIZE)
+            .ok_or(TrapMode::IllegalWrite)?;
+        for addr in physical_addr..end {
+            self.mem.write(addr, val);
+        }
+        Ok(())
+    }
 
     #[must_use = "error must be handled"]
     pub fn pushw(&mut self, w: u16) -> OpRes<()> {
-        self.cpu.stack -= 2;
+        self.grow_stack(2)?;
         self.write_wide(self.cpu.stack, w)
     }
     #[must_use = "error must be handled"]
     pub fn pushb(&mut self, b: u8) -> OpRes<()> {
-        self.cpu.stack -= 1;
+        self.grow_stack(1)?;
         self.write(self.cpu.stack, b)
     }
     #[must_use = "error must be handled"]
     pub fn popw(&mut self) -> OpRes<u16> {
+        self.check_shrink_stack(2)?;
         let w = self.read_wide(self.cpu.stack)?;
-        self.cpu.stack += 2;
+        self.cpu.stack = self.cpu.stack.wrapping_add(2);
         Ok(w)
     }
     #[must_use = "error must be handled"]
     pub fn popb(&mut self) -> OpRes<u8> {
+        self.check_shrink_stack(1)?;
         let b = self.read(self.cpu.stack)?;
-        self.cpu.stack += 1;
+        self.cpu.stack = self.cpu.stack.wrapping_add(1);
         Ok(b)
     }
+    fn grow_stack(&mut self, n: u16) -> OpRes<()> {
+        let (stack, wrapped) = self.cpu.stack.overflowing_sub(n);
+        if let Some(limit) = self.cpu.stack_limit {
+            if wrapped || stack < limit {
+                return Err(TrapMode::StackOverflow);
+            }
+        }
+        self.cpu.stack = stack;
+        Ok(())
+    }
+    fn check_shrink_stack(&self, n: u16) -> OpRes<()> {
+        let stack = self.cpu.stack as u32;
+        let (stack, top) = match self.cpu.stack_top {
+            // a top of 0 is the end of memory, where the first push wraps around to,
+            // so an empty stack is at 0 as well
+            Some(0) if stack == 0 => (0x1_0000, 0x1_0000),
+            Some(0) => (stack, 0x1_0000),
+            Some(top) => (stack, top as u32),
+            None => return Ok(()),
+        };
+        if stack + n as u32 > top {
+            Err(TrapMode::StackUnderflow)
+        } else {
+            Ok(())
+        }
+    }
+    /// Pushes the program counter, `flags` and then r1 to r15, 34 bytes in total
+    ///
+    /// The program counter is where [`Self::pop_registers`] continues, which lets an interrupt
+    /// resume the code it interrupted.
+    /// `flags` are the ones to go back to, so the ones from before trapping.
+    /// They are pushed as a wide with the layout of `u16::from(Blf4Flags)`
     #[must_use = "error must be handled"]
-    pub fn push_registers(&mut self) -> OpRes<()> {
-        self.pushw(self.cpu.flags.into())?;
+    pub fn push_registers(&mut self, flags: Blf4Flags) -> OpRes<()> {
+        self.pushw(self.cpu.program_counter)?;
+        self.pushw(flags.into())?;
         for r in 1..=15 {
             let w = self.cpu.read_wr(WideRegister(U4::new(r)))?;
             self.pushw(w)?;
@@ -545,6 +788,7 @@ impl HandlerContext<'_> {
 
         Ok(())
     }
+    /// Pops what [`Self::push_registers`] pushed, leaving the trap handler
     #[must_use = "error must be handled"]
     pub fn pop_registers(&mut self) -> OpRes<()> {
         for r in (1..=15).rev() {
@@ -552,6 +796,8 @@ impl HandlerContext<'_> {
             self.cpu.write_wr(WideRegister(U4::new(r)), w)?;
         }
         self.cpu.flags = self.popw()?.into();
+        self.cpu.flags.trap = false;
+        self.cpu.program_counter = self.popw()?;
 
         Ok(())
     }
@@ -1,5 +1,7 @@
 use std::fmt::{self, Display};
 
+use serde::{Deserialize, Serialize};
+
 use crate::U4;
 
 pub const R0B: ByteRegister = ByteRegister(U4::new_unchecked(0));
@@ -41,10 +43,10 @@ pub const RP: WideRegister = WideRegister(U4::new_unchecked(14));
 /// Trap handler pointer register
 pub const RH: WideRegister = WideRegister(U4::new_unchecked(15));
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 #[repr(transparent)]
 pub struct ByteRegister(pub U4);
-#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 #[repr(transparent)]
 pub struct WideRegister(pub U4);
 
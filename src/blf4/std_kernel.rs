@@ -5,33 +5,39 @@ use crate::{
     PAGE_SIZE, PAGE_SIZE_P,
 };
 
-use super::{Blf4, TrapMode, R1, R1L, R2, R2L};
+use super::{Blf4, TrapMode, R1, R2, R3};
 
+mod heap;
 mod load_user_binary;
+mod syscalls;
+pub use self::heap::*;
+pub use self::syscalls::*;
 
 /// Standard emulated kernel
 ///
-/// syscall R1=15 to set error handler with R2 as address
-/// error handler cannot return and should either halt or run a new program
+/// The syscall number is put in R1, see the `SYS_` constants for the built-in ones.
+/// Calls to the externs of the syscall table run their handler and return right away
 pub struct EKernel {
     error_handler: u16,
     page_bumper: u32,
     next_free: Option<u32>,
+    syscalls: SyscallTable,
 }
 
 impl EKernel {
-    pub fn new() -> Self {
+    pub fn with_syscalls(syscalls: SyscallTable) -> Self {
         Self {
             error_handler: 0,
             page_bumper: HALF_CELL as u32,
             next_free: None,
+            syscalls,
         }
     }
     fn mmapper<'a, M: MainMemory>(
         &'a mut self,
         mem: &'a mut M,
         page_table1: u32,
-    ) -> MmapBuilder<M> {
+    ) -> MmapBuilder<'a, M> {
         MmapBuilder {
             page_table1,
             kernel: self,
@@ -89,30 +95,30 @@ impl EmulatedKernel<Blf4> for EKernel {
                 let sys_n = ctx.cpu.read_wr(R1)?;
 
                 match sys_n {
-                    // show mmap (various debug syscalls)
-                    0 => {
-                        ctx.print_mmap();
-                    }
-                    // read
-                    3 => {
-                        let b = ctx.physical_read(1)?;
-                        ctx.cpu.write_br(R1L, b);
-                    }
-                    // write
-                    4 => {
-                        let b = ctx.cpu.read_br(R2L);
-                        ctx.physical_write(1, b)?;
-                    }
-                    // error handler vector
-                    15 => {
+                    SYS_SET_ERROR_HANDLER => {
                         self.error_handler = ctx.cpu.read_wr(R2)?;
                     }
-                    _ => return Err(TrapMode::SysCall),
+                    _ => self.syscalls.dispatch(sys_n, &mut ctx)?,
                 }
             }
             TrapMode::Halt => return Err(TrapMode::Halt),
+            // fetching the first byte of an extern, so it was called
+            e if e.is_address_fault()
+                && !cpu.fault_write
+                && cpu.program_counter == cpu.fault_address.wrapping_add(1)
+                && self.syscalls.is_extern(cpu.fault_address) =>
+            {
+                let addr = cpu.fault_address;
+                self.syscalls.call_extern(addr, &mut cpu.context(mem))?;
+                // return like `ret 0`
+                cpu.program_counter = cpu.link;
+            }
             e if self.error_handler != 0 => {
                 cpu.write_wr(R1, e as u8 as u16)?;
+                if e.is_address_fault() {
+                    cpu.write_wr(R2, cpu.fault_address)?;
+                    cpu.write_wr(R3, cpu.fault_write as u16)?;
+                }
                 cpu.program_counter = self.error_handler;
             }
             e => return Err(e),
@@ -0,0 +1,274 @@
+use std::{cell::RefCell, collections::BTreeMap, ops::Range, rc::Rc};
+
+use crate::{
+    aalv::obj::SegmentType,
+    blf4::{HandlerContext, OpRes, TrapMode, R1, R2, R3},
+    source::ProcessedSource,
+};
+
+use super::SyscallTable;
+
+/// Allocates r2 bytes and puts a pointer to them in r1, or `0xffff` if there is not enough room
+pub const SYS_ALLOC: u16 = 6;
+/// Frees the allocation r2 points to
+///
+/// Freeing a pointer that was not allocated or was already freed gives [`TrapMode::HeapCorruption`]
+pub const SYS_FREE: u16 = 7;
+/// Resizes the allocation r2 points to to r3 bytes and puts the new pointer in r1
+///
+/// The contents are kept up to the smaller of the sizes. If there is not enough room `0xffff`
+/// is put in r1 and the old allocation is kept. Resizing `0xffff` is the same as allocating
+pub const SYS_REALLOC: u16 = 8;
+
+/// Pointer given when an allocation fails
+pub const NULL_PTR: u16 = 0xffff;
+
+/// Allocates memory in the heap segment of a program with a free list
+///
+/// The bookkeeping is kept on the host, so the program cannot corrupt it.
+/// Allocations are rounded up to an even size so they are aligned for wides
+#[derive(Debug, Clone)]
+pub struct HeapAllocator {
+    /// Free blocks sorted by address, adjacent blocks are always merged
+    free: Vec<Range<u16>>,
+    /// Size of each allocation by its pointer
+    allocated: BTreeMap<u16, u16>,
+}
+
+impl HeapAllocator {
+    pub fn new(heap: Range<u16>) -> Self {
+        HeapAllocator {
+            free: if heap.is_empty() { vec![] } else { vec![heap] },
+            allocated: BTreeMap::new(),
+        }
+    }
+    /// Allocator of the heap segment of the source, if it has one
+    pub fn for_source(src: &ProcessedSource) -> Option<Self> {
+        src.dls
+            .get(&SegmentType::Heap)
+            .map(|dls| Self::new(dls.start..dls.start.saturating_add(dls.size)))
+    }
+    /// Adds the allocation syscalls to the table
+    pub fn register(self, table: &mut SyscallTable) {
+        let heap = Rc::new(RefCell::new(self));
+
+        let h = heap.clone();
+        table.register(
+            SYS_ALLOC,
+            Box::new(move |ctx: &mut HandlerContext| {
+                let size = ctx.cpu.read_wr(R2)?;
+                let ptr = h.borrow_mut().alloc(size).unwrap_or(NULL_PTR);
+                ctx.cpu.write_wr(R1, ptr)
+            }),
+        );
+        let h = heap.clone();
+        table.register(
+            SYS_FREE,
+            Box::new(move |ctx: &mut HandlerContext| {
+                let ptr = ctx.cpu.read_wr(R2)?;
+                h.borrow_mut().free(ptr)
+            }),
+        );
+        table.register(
+            SYS_REALLOC,
+            Box::new(move |ctx: &mut HandlerContext| {
+                let ptr = ctx.cpu.read_wr(R2)?;
+                let size = ctx.cpu.read_wr(R3)?;
+                let new_ptr = heap.borrow_mut().realloc(ctx, ptr, size)?;
+                ctx.cpu.write_wr(R1, new_ptr.unwrap_or(NULL_PTR))
+            }),
+        );
+    }
+    /// Allocates `size` bytes with the first free block big enough
+    pub fn alloc(&mut self, size: u16) -> Option<u16> {
+        let size = Self::block_size(size)?;
+        let i = self.free.iter().position(|b| b.len() >= size as usize)?;
+        let block = &mut self.free[i];
+        let ptr = block.start;
+        block.start += size;
+        if block.start == block.end {
+            self.free.remove(i);
+        }
+        self.allocated.insert(ptr, size);
+        Some(ptr)
+    }
+    /// Frees the allocation at `ptr`
+    pub fn free(&mut self, ptr: u16) -> OpRes<()> {
+        let size = self
+            .allocated
+            .remove(&ptr)
+            .ok_or(TrapMode::HeapCorruption)?;
+        self.release(ptr..ptr + size);
+        Ok(())
+    }
+    /// Resizes the allocation at `ptr`, moving its contents if it cannot grow where it is
+    ///
+    /// Gives `None` if there is not enough room, then the old allocation is kept
+    pub fn realloc(&mut self, ctx: &mut HandlerContext, ptr: u16, size: u16) -> OpRes<Option<u16>> {
+        if ptr == NULL_PTR {
+            return Ok(self.alloc(size));
+        }
+        let old_size = *self.allocated.get(&ptr).ok_or(TrapMode::HeapCorruption)?;
+        let Some(size) = Self::block_size(size) else {
+            return Ok(None);
+        };
+
+        if size <= old_size {
+            self.allocated.insert(ptr, size);
+            if size < old_size {
+                self.release(ptr + size..ptr + old_size);
+            }
+            return Ok(Some(ptr));
+        }
+
+        // grow in place if the block after it is free and big enough
+        let end = ptr + old_size;
+        if let Some(i) = self
+            .free
+            .iter()
+            .position(|b| b.start == end && b.len() >= (size - old_size) as usize)
+        {
+            let block = &mut self.free[i];
+            block.start = ptr + size;
+            if block.start == block.end {
+                self.free.remove(i);
+            }
+            self.allocated.insert(ptr, size);
+            return Ok(Some(ptr));
+        }
+
+        let Some(new_ptr) = self.alloc(size) else {
+            return Ok(None);
+        };
+        for i in 0..old_size {
+            let b = ctx.read(ptr + i)?;
+            ctx.write(new_ptr + i, b)?;
+        }
+        self.free(ptr)?;
+        Ok(Some(new_ptr))
+    }
+    /// Size of the allocation at `ptr` if it is allocated
+    pub fn size_of(&self, ptr: u16) -> Option<u16> {
+        self.allocated.get(&ptr).copied()
+    }
+    /// Number of free bytes, they might not be in one block
+    pub fn free_bytes(&self) -> usize {
+        self.free.iter().map(|b| b.len()).sum()
+    }
+
+    /// Rounds up to an even size of at least 2 so every allocation has its own pointer
+    fn block_size(size: u16) -> Option<u16> {
+        let size = size.max(2);
+        size.checked_add(size & 1)
+    }
+    /// Puts a block back in the free list, merging it with its neighbours
+    fn release(&mut self, block: Range<u16>) {
+        let i = self.free.partition_point(|b| b.start < block.start);
+        let merges_next = self.free.get(i).is_some_and(|b| b.start == block.end);
+        let merges_prev = i > 0 && self.free[i - 1].end == block.start;
+
+        match (merges_prev, merges_next) {
+            (true, true) => {
+                let next = self.free.remove(i);
+                self.free[i - 1].end = next.end;
+            }
+            (true, false) => self.free[i - 1].end = block.end,
+            (false, true) => self.free[i].start = block.start,
+            (false, false) => self.free.insert(i, block),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        blf4::Blf4,
+        mem::{LazyMain, PanickingIO},
+    };
+
+    #[test]
+    fn alloc_free_realloc() {
+        let mut mem = LazyMain::new(PanickingIO);
+        let mut cpu = Blf4::new();
+        let mut ctx = cpu.context(&mut mem);
+
+        let mut heap = HeapAllocator::new(0x9000..0x9400);
+        let ptrs: Vec<u16> = (1..=64).map(|n| heap.alloc(n % 7 + 1).unwrap()).collect();
+        for (i, &p) in ptrs.iter().enumerate() {
+            ctx.write(p, i as u8).unwrap();
+        }
+        for &p in ptrs.iter().step_by(2) {
+            heap.free(p).unwrap();
+        }
+        assert_eq!(heap.free(ptrs[0]), Err(TrapMode::HeapCorruption));
+        assert_eq!(heap.free(0x9001), Err(TrapMode::HeapCorruption));
+
+        // grow every kept buffer so some of them have to move
+        let mut kept = Vec::new();
+        for (i, &p) in ptrs.iter().enumerate().skip(1).step_by(2) {
+            let new = heap.realloc(&mut ctx, p, 12).unwrap().unwrap();
+            assert_eq!(ctx.read(new), Ok(i as u8));
+            kept.push(new);
+        }
+        kept.extend((0..16).map(|_| heap.alloc(5).unwrap()));
+
+        let mut blocks: Vec<_> = kept
+            .iter()
+            .map(|&p| p..p + heap.size_of(p).unwrap())
+            .collect();
+        blocks.sort_by_key(|b| b.start);
+        for pair in blocks.windows(2) {
+            assert!(pair[0].end <= pair[1].start, "{pair:?} overlap");
+        }
+        assert!(blocks.iter().all(|b| b.start >= 0x9000 && b.end <= 0x9400));
+
+        for p in kept {
+            heap.free(p).unwrap();
+        }
+        assert_eq!(heap.free_bytes(), 0x400);
+        assert_eq!(heap.alloc(0x400), Some(0x9000));
+        assert_eq!(heap.alloc(1), None);
+    }
+
+    #[test]
+    fn syscalls() {
+        let mut mem = LazyMain::new(PanickingIO);
+        let mut cpu = Blf4::new();
+        let mut ctx = cpu.context(&mut mem);
+
+        let mut table = SyscallTable::empty();
+        HeapAllocator::new(0x9000..0x9010).register(&mut table);
+
+        ctx.cpu.write_wr(R2, 8).unwrap();
+        table.dispatch(SYS_ALLOC, &mut ctx).unwrap();
+        assert_eq!(ctx.cpu.read_wr(R1), Ok(0x9000));
+        ctx.cpu.write_wr(R2, 0x20).unwrap();
+        table.dispatch(SYS_ALLOC, &mut ctx).unwrap();
+        assert_eq!(ctx.cpu.read_wr(R1), Ok(NULL_PTR));
+
+        ctx.write(0x9000, 0xab).unwrap();
+        ctx.cpu.write_wr(R2, 0x9000).unwrap();
+        ctx.cpu.write_wr(R3, 0x10).unwrap();
+        table.dispatch(SYS_REALLOC, &mut ctx).unwrap();
+        assert_eq!(ctx.cpu.read_wr(R1), Ok(0x9000));
+        assert_eq!(ctx.read(0x9000), Ok(0xab));
+
+        table.dispatch(SYS_FREE, &mut ctx).unwrap();
+        assert_eq!(
+            table.dispatch(SYS_FREE, &mut ctx),
+            Err(TrapMode::HeapCorruption)
+        );
+    }
+
+    #[test]
+    fn heap_segment() {
+        let src = ProcessedSource::assemble(".seg heap 0x9000\n.byte 0, 0, 0, 0\n").unwrap();
+        let mut heap = HeapAllocator::for_source(&src).unwrap();
+        assert_eq!(heap.alloc(4), Some(0x9000));
+        assert_eq!(heap.alloc(1), None);
+
+        let src = ProcessedSource::assemble(".seg text\nhalt\n").unwrap();
+        assert!(HeapAllocator::for_source(&src).is_none());
+    }
+}
@@ -1,3 +1,5 @@
+use std::collections::BTreeMap;
+
 use crate::{
     aalv::obj::{Flags, Object, SegmentType},
     align_end, align_start,
@@ -8,12 +10,20 @@ use crate::{
 
 use super::{
     super::{Blf4, PERM_R, PERM_W, PERM_X},
-    EKernel,
+    EKernel, SyscallTable,
 };
 
 impl<M: MainMemory> Machine<M, Blf4> {
     pub fn load_user_binary(&mut self, obj: &Object) {
-        let mut ekernel = EKernel::new();
+        self.load_user_binary_with_syscalls(obj, SyscallTable::default());
+    }
+    /// Loads the binary with an emulated kernel handling the syscalls in the table
+    ///
+    /// References to the externs of the object are loaded as the addresses of the externs
+    /// registered in the table, unregistered ones are left as they are
+    pub fn load_user_binary_with_syscalls(&mut self, obj: &Object, syscalls: SyscallTable) {
+        let segs = resolve_externs(obj, &syscalls);
+        let mut ekernel = EKernel::with_syscalls(syscalls);
 
         let page_table1 = ekernel.allocate_page(&mut self.memory);
         assert_eq!(page_table1 as u16 as u32, page_table1, "page_table1 needs to be within block 0");
@@ -24,13 +34,23 @@ impl<M: MainMemory> Machine<M, Blf4> {
         let heap_size = obj.heap_size.unwrap_or_default().0;
         let stack_size = obj.stack_size.unwrap_or_default().0;
 
-        for (&seg, &(offset, ref bytes)) in &obj.segs {
+        // the stack is placed at the end of memory unless the object has a stack segment
+        let mut stack = (
+            align_start(0xffff - stack_size.saturating_sub(1), PAGE_SIZE),
+            0xffff,
+        );
+
+        for (&seg, &(offset, ref bytes)) in &segs {
             let mut heap = false;
             use self::SegmentType::*;
             let permissions = match seg {
                 Data => PERM_W | PERM_R,
                 RoData => PERM_R,
-                Text => {
+                Stack => {
+                    stack = (offset, offset.saturating_add(stack_size));
+                    continue;
+                }
+                Boot | Text => {
                     if readable_text {
                         PERM_X | PERM_R
                     } else {
@@ -54,13 +74,16 @@ impl<M: MainMemory> Machine<M, Blf4> {
         }
 
         // map space for stack
-        let stack_start = align_start(0xffff - stack_size.saturating_sub(1), PAGE_SIZE);
-        mmbuilder.map_wr_pages(stack_start, stack_size);
+        let (stack_limit, stack_top) = stack;
+        let stack_start = align_start(stack_limit, PAGE_SIZE);
+        mmbuilder.map_wr_pages(stack_start, stack_top - stack_start);
 
         self.cpu.flags.virtual_mode = true;
         self.cpu.flags.user_mode = true;
-        self.cpu.stack = 0xffff;
-        self.cpu.frame = 0xffff;
+        self.cpu.stack = stack_top;
+        self.cpu.stack_top = Some(stack_top);
+        self.cpu.stack_limit = Some(stack_limit);
+        self.cpu.frame = stack_top;
         if let Some(entry) = obj.entry {
             self.cpu.program_counter = entry.1;
         }
@@ -68,3 +91,125 @@ impl<M: MainMemory> Machine<M, Blf4> {
         self.install_emulated_kernel(ekernel);
     }
 }
+
+/// The segments of the object with the references to its externs filled in
+fn resolve_externs(obj: &Object, syscalls: &SyscallTable) -> BTreeMap<SegmentType, (u16, Vec<u8>)> {
+    let mut segs = obj.segs.clone();
+    let Some(externs) = &obj.externs else {
+        return segs;
+    };
+
+    for r in &obj.relocation_table.0 {
+        let Some(symbol) = obj.symbols.0.get(r.symbol_index as usize) else {
+            continue;
+        };
+        if symbol.segment_type != SegmentType::Unknown || !externs.contains(&symbol.name) {
+            continue;
+        }
+        let (Some(addr), Some((start, bytes))) = (
+            syscalls.extern_address(&symbol.name),
+            segs.get_mut(&r.reference_segment),
+        ) else {
+            continue;
+        };
+        // a relocation outside of its segment cannot be filled in
+        let Some(field) = r
+            .reference_location
+            .checked_sub(*start)
+            .and_then(|i| bytes.get_mut(i as usize..i as usize + 2))
+        else {
+            continue;
+        };
+        field.copy_from_slice(&addr.to_le_bytes());
+    }
+
+    segs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::resolve_externs;
+    use crate::{
+        aalv::obj::{ExternTable, Object, SegmentType, StackSize},
+        blf4::{isa::*, std_kernel::SyscallTable, Blf4, HandlerContext, TrapMode, R1, R2, R3},
+        machine::Machine,
+        mem::{LazyMain, PanickingIO},
+        source::ProcessedSource,
+    };
+
+    #[test]
+    fn stack_segment() {
+        let mut obj = Object {
+            stack_size: Some(StackSize(0x100)),
+            ..Object::default()
+        };
+        obj.segs
+            .insert(SegmentType::Text, (0x400, vec![PUSH_W, 0x10, HALT]));
+        obj.segs.insert(SegmentType::Stack, (0x800, Vec::new()));
+
+        let mut m = Machine::new(LazyMain::new(PanickingIO), Blf4::new());
+        m.load_user_binary(&obj);
+        m.cpu.program_counter = 0x400;
+        assert_eq!(m.cpu.stack, 0x900);
+        assert_eq!(m.cpu.stack_limit, Some(0x800));
+
+        assert_eq!(m.run_until_abort(), TrapMode::Halt);
+        assert_eq!(m.cpu.stack, 0x8fe);
+    }
+
+    #[test]
+    fn externs() {
+        let src = ".extern double\n.extern missing\n.seg text\n.entry\nldi r1, 21\ncall double\ncall missing\n";
+        let obj = ProcessedSource::assemble(src).unwrap().to_object();
+        assert_eq!(
+            obj.externs,
+            Some(ExternTable(vec!["double".into(), "missing".into()]))
+        );
+
+        let mut syscalls = SyscallTable::empty();
+        syscalls.register_extern(
+            "double",
+            Box::new(|ctx: &mut HandlerContext| {
+                let n = ctx.cpu.read_wr(R1)?;
+                ctx.cpu.write_wr(R1, 2 * n)
+            }),
+        );
+
+        let mut m = Machine::new(LazyMain::new(PanickingIO), Blf4::new());
+        m.load_user_binary_with_syscalls(&obj, syscalls);
+        // the unregistered extern is left at address 0 which is not mapped
+        assert_eq!(m.run_until_abort(), TrapMode::Level2PageFault);
+        assert_eq!(m.cpu.fault_address, 0);
+        assert_eq!(m.cpu.read_wr(R1), Ok(42));
+    }
+
+    #[test]
+    fn error_handler_gets_fault() {
+        // writing to address 0 is illegal and goes to the error handler
+        let src = ".seg text\n.entry\nldi r1, 15\nldi r2, handler\nsyscall\nldi r4, 0x1234\nstore r0, 0, r4\nhandler:\nhalt\n";
+        let obj = ProcessedSource::assemble(src).unwrap().to_object();
+
+        let mut m = Machine::new(LazyMain::new(PanickingIO), Blf4::new());
+        m.load_user_binary(&obj);
+        assert_eq!(m.run_until_abort(), TrapMode::Halt);
+        assert_eq!(m.cpu.read_wr(R1), Ok(TrapMode::IllegalWrite as u16));
+        assert_eq!(m.cpu.read_wr(R2), Ok(0));
+        assert_eq!(m.cpu.read_wr(R3), Ok(1));
+    }
+
+    #[test]
+    fn extern_relocation_outside_segment() {
+        let src = ".extern double\n.seg text\n.entry\ncall double\n";
+        let mut syscalls = SyscallTable::empty();
+        syscalls.register_extern("double", Box::new(|_: &mut HandlerContext| Ok(())));
+
+        for location in [0, 0x7fff] {
+            let mut obj = ProcessedSource::assemble(src).unwrap().to_object();
+            obj.relocation_table.0[0].reference_location = location;
+            let segs = resolve_externs(&obj, &syscalls);
+            assert_eq!(segs, obj.segs);
+        }
+        let obj = ProcessedSource::assemble(src).unwrap().to_object();
+        assert_ne!(resolve_externs(&obj, &syscalls), obj.segs);
+    }
+}
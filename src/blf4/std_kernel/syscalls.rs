@@ -0,0 +1,222 @@
+use std::{
+    collections::HashMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::blf4::{HandlerContext, OpRes, TrapMode, R1, R1L, R2, R2L};
+
+/// Shows the memory mapping of the calling program (for debugging)
+pub const SYS_DEBUG_MMAP: u16 = 0;
+/// Stops the machine as if it halted
+pub const SYS_EXIT: u16 = 1;
+/// Reads a byte from stdin into r1l
+pub const SYS_READ: u16 = 3;
+/// Writes the byte in r2l to stdout
+pub const SYS_WRITE: u16 = 4;
+/// Gets the seconds since the UNIX epoch in r1 (lower half) and r2 (upper half)
+pub const SYS_GETTIME: u16 = 13;
+/// Sets the error handler to the address in r2, this is handled by the kernel itself
+///
+/// The error handler cannot return and should either halt or run a new program
+pub const SYS_SET_ERROR_HANDLER: u16 = 15;
+
+/// Address of the first extern, each registered extern gets the next wide after the last one
+///
+/// The addresses are never mapped as executable, so calling one faults into the kernel
+/// which runs the handler of the extern instead
+pub const EXTERN_START: u16 = 0xf000;
+/// Address of the last possible extern
+pub const EXTERN_END: u16 = 0xfffe;
+
+/// The port stdin and stdout are at
+const STDIO_PORT: u32 = 1;
+
+pub trait SyscallHandler {
+    /// Handles the syscall, an error is handled by the kernel like any other trap
+    fn handle(&mut self, ctx: &mut HandlerContext) -> OpRes<()>;
+}
+
+impl<F: FnMut(&mut HandlerContext) -> OpRes<()>> SyscallHandler for F {
+    fn handle(&mut self, ctx: &mut HandlerContext) -> OpRes<()> {
+        self(ctx)
+    }
+}
+
+/// Handlers of syscalls by their number which the program puts in r1
+///
+/// Also has the handlers of externs, the symbols declared by `.extern` that are called
+/// like any other subroutine
+pub struct SyscallTable {
+    handlers: HashMap<u16, Box<dyn SyscallHandler>>,
+    externs: Vec<(Box<str>, Box<dyn SyscallHandler>)>,
+}
+
+impl SyscallTable {
+    /// Table without any syscalls
+    pub fn empty() -> Self {
+        SyscallTable {
+            handlers: HashMap::new(),
+            externs: Vec::new(),
+        }
+    }
+    /// Sets the handler of a syscall, returning the handler it replaced if any
+    pub fn register(
+        &mut self,
+        num: u16,
+        handler: Box<dyn SyscallHandler>,
+    ) -> Option<Box<dyn SyscallHandler>> {
+        self.handlers.insert(num, handler)
+    }
+    /// Runs the handler of the syscall, unknown syscalls give [`TrapMode::SysCall`]
+    pub fn dispatch(&mut self, num: u16, ctx: &mut HandlerContext) -> OpRes<()> {
+        match self.handlers.get_mut(&num) {
+            Some(handler) => handler.handle(ctx),
+            None => Err(TrapMode::SysCall),
+        }
+    }
+    /// Sets the handler of an extern, returning the address references to it are loaded as
+    ///
+    /// Registering a name again replaces the handler but keeps the address
+    pub fn register_extern(&mut self, name: &str, handler: Box<dyn SyscallHandler>) -> u16 {
+        if let Some(i) = self.externs.iter().position(|(n, _)| **n == *name) {
+            self.externs[i].1 = handler;
+            return extern_address(i);
+        }
+        let i = self.externs.len();
+        assert!(
+            i <= ((EXTERN_END - EXTERN_START) / 2) as usize,
+            "no more room for externs"
+        );
+        self.externs.push((name.into(), handler));
+        extern_address(i)
+    }
+    /// The address of a registered extern
+    pub fn extern_address(&self, name: &str) -> Option<u16> {
+        self.externs
+            .iter()
+            .position(|(n, _)| **n == *name)
+            .map(extern_address)
+    }
+    /// Whether `addr` is the address of a registered extern
+    pub fn is_extern(&self, addr: u16) -> bool {
+        self.extern_index(addr).is_some()
+    }
+    /// Runs the handler of the extern at `addr`, other addresses give [`TrapMode::IllegalExecute`]
+    pub fn call_extern(&mut self, addr: u16, ctx: &mut HandlerContext) -> OpRes<()> {
+        match self.extern_index(addr) {
+            Some(i) => self.externs[i].1.handle(ctx),
+            None => Err(TrapMode::IllegalExecute),
+        }
+    }
+    fn extern_index(&self, addr: u16) -> Option<usize> {
+        if addr < EXTERN_START || addr & 1 != 0 {
+            return None;
+        }
+        let i = ((addr - EXTERN_START) / 2) as usize;
+        (i < self.externs.len()).then_some(i)
+    }
+}
+
+const fn extern_address(index: usize) -> u16 {
+    EXTERN_START + 2 * index as u16
+}
+
+/// Table with the built-in syscalls
+impl Default for SyscallTable {
+    fn default() -> Self {
+        let mut table = SyscallTable::empty();
+        table.register(SYS_DEBUG_MMAP, Box::new(sys_debug_mmap));
+        table.register(SYS_EXIT, Box::new(sys_exit));
+        table.register(SYS_READ, Box::new(sys_read));
+        table.register(SYS_WRITE, Box::new(sys_write));
+        table.register(SYS_GETTIME, Box::new(sys_gettime));
+        table
+    }
+}
+
+fn sys_debug_mmap(ctx: &mut HandlerContext) -> OpRes<()> {
+    ctx.print_mmap();
+    Ok(())
+}
+fn sys_exit(_ctx: &mut HandlerContext) -> OpRes<()> {
+    Err(TrapMode::Halt)
+}
+fn sys_read(ctx: &mut HandlerContext) -> OpRes<()> {
+    let b = ctx.physical_read(STDIO_PORT)?;
+    ctx.cpu.write_br(R1L, b);
+    Ok(())
+}
+fn sys_write(ctx: &mut HandlerContext) -> OpRes<()> {
+    let b = ctx.cpu.read_br(R2L);
+    ctx.physical_write(STDIO_PORT, b)
+}
+fn sys_gettime(ctx: &mut HandlerContext) -> OpRes<()> {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as u32)
+        .unwrap_or(0);
+    ctx.cpu.write_wr(R1, secs as u16)?;
+    ctx.cpu.write_wr(R2, (secs >> 16) as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        blf4::Blf4,
+        mem::{LazyMain, PanickingIO},
+    };
+
+    #[test]
+    fn dispatch() {
+        let mut mem = LazyMain::new(PanickingIO);
+        let mut cpu = Blf4::new();
+        let mut ctx = cpu.context(&mut mem);
+
+        let mut table = SyscallTable::default();
+        let replaced = table.register(
+            SYS_GETTIME,
+            Box::new(|ctx: &mut HandlerContext| ctx.cpu.write_wr(R1, 42)),
+        );
+        assert!(replaced.is_some());
+
+        assert_eq!(table.dispatch(SYS_GETTIME, &mut ctx), Ok(()));
+        assert_eq!(ctx.cpu.read_wr(R1), Ok(42));
+        assert_eq!(table.dispatch(SYS_EXIT, &mut ctx), Err(TrapMode::Halt));
+        assert_eq!(table.dispatch(0x1234, &mut ctx), Err(TrapMode::SysCall));
+    }
+
+    #[test]
+    fn externs() {
+        let mut mem = LazyMain::new(PanickingIO);
+        let mut cpu = Blf4::new();
+        let mut ctx = cpu.context(&mut mem);
+
+        let mut table = SyscallTable::empty();
+        let first = table.register_extern("first", Box::new(|_: &mut HandlerContext| Ok(())));
+        let second = table.register_extern(
+            "second",
+            Box::new(|ctx: &mut HandlerContext| ctx.cpu.write_wr(R1, 7)),
+        );
+        assert_eq!((first, second), (EXTERN_START, EXTERN_START + 2));
+        let replaced = table.register_extern(
+            "first",
+            Box::new(|ctx: &mut HandlerContext| ctx.cpu.write_wr(R1, 3)),
+        );
+        assert_eq!(replaced, first);
+        assert_eq!(table.extern_address("second"), Some(second));
+        assert_eq!(table.extern_address("third"), None);
+
+        assert!(table.is_extern(second));
+        assert!(!table.is_extern(second + 1));
+        assert!(!table.is_extern(second + 2));
+        assert_eq!(table.call_extern(second, &mut ctx), Ok(()));
+        assert_eq!(ctx.cpu.read_wr(R1), Ok(7));
+        assert_eq!(table.call_extern(first, &mut ctx), Ok(()));
+        assert_eq!(ctx.cpu.read_wr(R1), Ok(3));
+        assert_eq!(
+            table.call_extern(0x1234, &mut ctx),
+            Err(TrapMode::IllegalExecute)
+        );
+    }
+}
@@ -10,9 +10,31 @@ use crate::{
     },
     machine::Machine,
     mem::MainMemory,
+    source::ProcessedSource,
     PAGE_SIZE_P, U4,
 };
 
+/// Looks up the labels of a source for [`disassemble_instruction`]
+///
+/// An address inside a segment that is not at a label is shown as an offset from the
+/// nearest label before it in the same segment
+pub fn source_label_lookup<'a>(
+    src: &'a ProcessedSource,
+) -> impl Fn(u16) -> Option<(&'a str, u16)> + 'a {
+    move |addr| {
+        if let Some(lbl) = src.label_at(addr) {
+            return Some((lbl, 0));
+        }
+        let (lbl, lbl_addr) = src.nearest_label_before(addr)?;
+        // the address has to be in the segment of the label
+        let (_, _, st, _) = src.labels().iter().find(|l| &*l.0 == lbl)?;
+        let dls = src.dls.get(st)?;
+        (dls.start..dls.start.saturating_add(dls.size))
+            .contains(&addr)
+            .then_some((lbl, addr - lbl_addr))
+    }
+}
+
 struct StrictMemory<'a, M: MainMemory> {
     inner: &'a mut M,
 }
@@ -37,7 +59,10 @@ pub struct DisassembledInstruction {
     pub next_instruction_location: u16,
 }
 
-pub fn disassemble_instruction<'a, M: MainMemory, F: FnOnce(u16) -> Option<&'a str>>(
+/// Disassembles the instruction at the program counter
+///
+/// Wides are shown as the label `label_lookup` gives for them along with the offset from it
+pub fn disassemble_instruction<'a, M: MainMemory, F: FnOnce(u16) -> Option<(&'a str, u16)>>(
     machine: &mut Machine<M, Blf4>,
     label_lookup: F,
 ) -> Result<DisassembledInstruction, TrapMode> {
@@ -74,7 +99,9 @@ pub fn disassemble_instruction<'a, M: MainMemory, F: FnOnce(u16) -> Option<&'a s
             write!(f, "reth").unwrap();
             ends_block = true;
         }
+        CPUID => write!(f, "cpuid").unwrap(),
         NOP => write!(f, "nop").unwrap(),
+        WAIT => write!(f, "wait").unwrap(),
         PUSH_B => {
             let (r1, _) = arg_pair(&mut c, ByteRegister, identity)?;
             write!(f, "push {r1}").unwrap();
@@ -83,6 +110,14 @@ pub fn disassemble_instruction<'a, M: MainMemory, F: FnOnce(u16) -> Option<&'a s
             let (r1, _) = arg_pair(&mut c, WideRegister, identity)?;
             write!(f, "push {r1}").unwrap();
         }
+        PUSH_IMM_B => {
+            let b = arg_imm_byte(&mut c)?;
+            write!(f, "push {b}b").unwrap();
+        }
+        PUSH_IMM_W => {
+            let w = Operand::Wide(arg_imm_wide(&mut c)?).looked_up(label_lookup);
+            write!(f, "push {w}").unwrap();
+        }
         POP_B => {
             let (r1, _r2) = arg_pair(&mut c, ByteRegister, identity)?;
             write!(f, "pop {r1}").unwrap();
@@ -96,12 +131,23 @@ pub fn disassemble_instruction<'a, M: MainMemory, F: FnOnce(u16) -> Option<&'a s
             write!(f, "call {w}").unwrap();
             nesting_difference = 1;
         }
+        CALL_R => {
+            let (r1, _) = arg_pair(&mut c, WideRegister, identity)?;
+            write!(f, "call {r1}").unwrap();
+            nesting_difference = 1;
+        }
         RET => {
             let b = arg_imm_byte(&mut c)?;
             write!(f, "ret {b}").unwrap();
             nesting_difference = -1;
             ends_block = true;
         }
+        RET_W => {
+            let w = arg_imm_wide(&mut c)?;
+            write!(f, "ret {w}").unwrap();
+            nesting_difference = -1;
+            ends_block = true;
+        }
         STORE_BI => {
             let (r1, r2) = arg_pair(&mut c, WideRegister, ByteRegister)?;
             let offset = Operand::Wide(arg_imm_wide(&mut c)?).looked_up(label_lookup);
@@ -142,6 +188,21 @@ pub fn disassemble_instruction<'a, M: MainMemory, F: FnOnce(u16) -> Option<&'a s
             let (r3, _) = arg_pair(&mut c, WideRegister, identity)?;
             write!(f, "load {r1}, {r2}, {r3}").unwrap();
         }
+        LOAD_D => {
+            let (r1, r2) = arg_pair(&mut c, WideRegister, WideRegister)?;
+            let (r3, r4) = arg_pair(&mut c, WideRegister, WideRegister)?;
+            write!(f, "load {r1}, {r2}, {r3}, {r4}").unwrap();
+        }
+        STORE_D => {
+            let (r1, r2) = arg_pair(&mut c, WideRegister, WideRegister)?;
+            let (r3, r4) = arg_pair(&mut c, WideRegister, WideRegister)?;
+            write!(f, "store {r1}, {r2}, {r3}, {r4}").unwrap();
+        }
+        PMSET => {
+            let (r1, r2) = arg_pair(&mut c, ByteRegister, WideRegister)?;
+            let (r3, r4) = arg_pair(&mut c, WideRegister, ByteRegister)?;
+            write!(f, "pmset {r1}, {r2}, {r3}, {r4}").unwrap();
+        }
         JEZ => cjmp("jez", &mut c, label_lookup, f)?,
         JLT => cjmp("jlt", &mut c, label_lookup, f)?,
         JLE => cjmp("jle", &mut c, label_lookup, f)?,
@@ -162,30 +223,48 @@ pub fn disassemble_instruction<'a, M: MainMemory, F: FnOnce(u16) -> Option<&'a s
         }
         LDI_W => {
             let (r1, o) = arg_pair(&mut c, WideRegister, u8::from)?;
-            let w = Operand::Wide(arg_imm_wide(&mut c)?).looked_up(label_lookup);
+            if o == 2 {
+                // ldi with a sign-extended byte
+                let b = arg_imm_byte(&mut c)? as i8;
+                write!(f, "ldi {r1}, {b}").unwrap();
+            } else {
+                let w = Operand::Wide(arg_imm_wide(&mut c)?).looked_up(label_lookup);
 
-            match o {
-                // ldi
-                0 => write!(f, "ldi {r1}, {w}").unwrap(),
-                // jmp, jump
-                1 => {
-                    if r1 == R0 {
-                        // jmp imm
-                        write!(f, "jmp {w}").unwrap();
-                        ends_block = true;
-                    } else {
-                        // jmp r
-                        write!(f, "jmp {r1}").unwrap();
-                        ends_block = true;
+                match o {
+                    // ldi
+                    0 => write!(f, "ldi {r1}, {w}").unwrap(),
+                    // jmp, jump
+                    1 => {
+                        if r1 == R0 {
+                            // jmp imm
+                            write!(f, "jmp {w}").unwrap();
+                            ends_block = true;
+                        } else {
+                            // jmp r
+                            write!(f, "jmp {r1}").unwrap();
+                            ends_block = true;
+                        }
                     }
+                    n => write!(f, "invalid ldi{n}, {r1}, {w}").unwrap(),
                 }
-                n => write!(f, "invalid ldi{n}, {r1}, {w}").unwrap(),
             }
         }
         ADD_B => binop("add", ByteRegister, &mut c, f)?,
         ADD_W => binop("add", WideRegister, &mut c, f)?,
         SUB_B => binop("sub", ByteRegister, &mut c, f)?,
         SUB_W => binop("sub", WideRegister, &mut c, f)?,
+        ADD_B_IMM | SUB_B_IMM => {
+            let (r1, r2) = arg_pair(&mut c, ByteRegister, ByteRegister)?;
+            let b = Operand::Byte(arg_imm_byte(&mut c)?);
+            let name = if opcode == ADD_B_IMM { "add" } else { "sub" };
+            write!(f, "{name} {r1}, {r2}, {b}").unwrap();
+        }
+        ADD_W_IMM | SUB_W_IMM => {
+            let (r1, r2) = arg_pair(&mut c, WideRegister, WideRegister)?;
+            let w = Operand::Wide(arg_imm_wide(&mut c)?).looked_up(label_lookup);
+            let name = if opcode == ADD_W_IMM { "add" } else { "sub" };
+            write!(f, "{name} {r1}, {r2}, {w}").unwrap();
+        }
         AND_B => binop("and", ByteRegister, &mut c, f)?,
         AND_W => binop("and", WideRegister, &mut c, f)?,
         OR_B => binop("or", ByteRegister, &mut c, f)?,
@@ -198,10 +277,94 @@ pub fn disassemble_instruction<'a, M: MainMemory, F: FnOnce(u16) -> Option<&'a s
         ASR_W => binop("asr", WideRegister, &mut c, f)?,
         LSR_B => binop("lsr", ByteRegister, &mut c, f)?,
         LSR_W => binop("lsr", WideRegister, &mut c, f)?,
+        SHL_B_IMM => shift_imm("shl", ByteRegister, &mut c, f)?,
+        SHL_W_IMM => shift_imm("shl", WideRegister, &mut c, f)?,
+        ASR_B_IMM => shift_imm("asr", ByteRegister, &mut c, f)?,
+        ASR_W_IMM => shift_imm("asr", WideRegister, &mut c, f)?,
+        LSR_B_IMM => shift_imm("lsr", ByteRegister, &mut c, f)?,
+        LSR_W_IMM => shift_imm("lsr", WideRegister, &mut c, f)?,
         DIV_B => binop("div", ByteRegister, &mut c, f)?,
         DIV_W => binop("div", WideRegister, &mut c, f)?,
         MUL_B => binop("mul", ByteRegister, &mut c, f)?,
         MUL_W => binop("mul", WideRegister, &mut c, f)?,
+        ADC_B => binop("adc", ByteRegister, &mut c, f)?,
+        ADC_W => binop("adc", WideRegister, &mut c, f)?,
+        SBB_B => binop("sbb", ByteRegister, &mut c, f)?,
+        SBB_W => binop("sbb", WideRegister, &mut c, f)?,
+        CAS_W => binop("cas", WideRegister, &mut c, f)?,
+        MCPY => binop("mcpy", WideRegister, &mut c, f)?,
+        XCHG_B => {
+            let (r1, r2) = arg_pair(&mut c, ByteRegister, ByteRegister)?;
+            write!(f, "xchg {r1}, {r2}").unwrap();
+        }
+        XCHG_W => {
+            let (r1, r2) = arg_pair(&mut c, WideRegister, WideRegister)?;
+            write!(f, "xchg {r1}, {r2}").unwrap();
+        }
+        SXT_B => {
+            let (r1, r2) = arg_pair(&mut c, WideRegister, ByteRegister)?;
+            write!(f, "sxt {r1}, {r2}").unwrap();
+        }
+        ZXT_B => {
+            let (r1, r2) = arg_pair(&mut c, WideRegister, ByteRegister)?;
+            write!(f, "zxt {r1}, {r2}").unwrap();
+        }
+        MOVB_WR => {
+            let (r1, r2) = arg_pair(&mut c, ByteRegister, WideRegister)?;
+            write!(f, "movb {r1}, {r2}").unwrap();
+        }
+        IN_B => {
+            let (r1, r2) = arg_pair(&mut c, ByteRegister, ByteRegister)?;
+            write!(f, "in {r1}, {r2}").unwrap();
+        }
+        OUT_B => {
+            let (r1, r2) = arg_pair(&mut c, ByteRegister, ByteRegister)?;
+            write!(f, "out {r1}, {r2}").unwrap();
+        }
+        MOVBH_WR => {
+            let (r1, r2) = arg_pair(&mut c, ByteRegister, WideRegister)?;
+            write!(f, "movbh {r1}, {r2}").unwrap();
+        }
+        BSWAP_B => {
+            let (r1, r2) = arg_pair(&mut c, ByteRegister, ByteRegister)?;
+            write!(f, "bswap {r1}, {r2}").unwrap();
+        }
+        BSWAP_W => {
+            let (r1, r2) = arg_pair(&mut c, WideRegister, WideRegister)?;
+            write!(f, "bswap {r1}, {r2}").unwrap();
+        }
+        POPCNT_B => {
+            let (r1, r2) = arg_pair(&mut c, ByteRegister, ByteRegister)?;
+            write!(f, "popcnt {r1}, {r2}").unwrap();
+        }
+        POPCNT_W => {
+            let (r1, r2) = arg_pair(&mut c, WideRegister, WideRegister)?;
+            write!(f, "popcnt {r1}, {r2}").unwrap();
+        }
+        CLZ_B => {
+            let (r1, r2) = arg_pair(&mut c, ByteRegister, ByteRegister)?;
+            write!(f, "clz {r1}, {r2}").unwrap();
+        }
+        CLZ_W => {
+            let (r1, r2) = arg_pair(&mut c, WideRegister, WideRegister)?;
+            write!(f, "clz {r1}, {r2}").unwrap();
+        }
+        CTZ_B => {
+            let (r1, r2) = arg_pair(&mut c, ByteRegister, ByteRegister)?;
+            write!(f, "ctz {r1}, {r2}").unwrap();
+        }
+        CTZ_W => {
+            let (r1, r2) = arg_pair(&mut c, WideRegister, WideRegister)?;
+            write!(f, "ctz {r1}, {r2}").unwrap();
+        }
+        NEG_B => {
+            let (r1, r2) = arg_pair(&mut c, ByteRegister, ByteRegister)?;
+            write!(f, "neg {r1}, {r2}").unwrap();
+        }
+        NEG_W => {
+            let (r1, r2) = arg_pair(&mut c, WideRegister, WideRegister)?;
+            write!(f, "neg {r1}, {r2}").unwrap();
+        }
         b => {
             write!(f, "0x{b:02x}").unwrap();
             ends_block = true;
@@ -233,7 +396,7 @@ pub fn disassemble_instruction<'a, M: MainMemory, F: FnOnce(u16) -> Option<&'a s
     })
 }
 
-fn cjmp<'a, F: FnOnce(u16) -> Option<&'a str>>(
+fn cjmp<'a, F: FnOnce(u16) -> Option<(&'a str, u16)>>(
     name: &str,
     c: &mut HandlerContext,
     label_lookup: F,
@@ -262,21 +425,38 @@ fn binop<T: Display, RF: Fn(U4) -> T>(
     Ok(())
 }
 
+fn shift_imm<T: Display, RF: Fn(U4) -> T>(
+    name: &str,
+    rf: RF,
+    c: &mut HandlerContext,
+    f: &mut String,
+) -> Result<(), TrapMode> {
+    let (r1, r2) = arg_pair(c, &rf, &rf)?;
+    let (n, _o) = arg_pair(c, u8::from, identity)?;
+    write!(f, "{name} {r1}, {r2}, {n}").unwrap();
+
+    Ok(())
+}
+
 enum Operand<'a> {
     Byte(u8),
     Wide(u16),
-    Label(&'a str),
+    /// A label and an offset from it
+    Label(&'a str, u16),
 }
 
 impl<'a> Operand<'a> {
-    pub fn looked_up<F: FnOnce(u16) -> Option<&'a str>>(mut self, label_lookup: F) -> Self {
+    pub fn looked_up<F: FnOnce(u16) -> Option<(&'a str, u16)>>(mut self, label_lookup: F) -> Self {
         self.convert_wide_to_label(label_lookup);
         self
     }
-    pub fn convert_wide_to_label<F: FnOnce(u16) -> Option<&'a str>>(&mut self, label_lookup: F) {
+    pub fn convert_wide_to_label<F: FnOnce(u16) -> Option<(&'a str, u16)>>(
+        &mut self,
+        label_lookup: F,
+    ) {
         if let Operand::Wide(w) = *self {
-            if let Some(lbl) = label_lookup(w) {
-                *self = Operand::Label(lbl);
+            if let Some((lbl, offset)) = label_lookup(w) {
+                *self = Operand::Label(lbl, offset);
             }
         }
     }
@@ -287,7 +467,61 @@ impl Display for Operand<'_> {
         match self {
             Self::Byte(b) => write!(f, "0x{b:02x}"),
             Self::Wide(w) => write!(f, "0x{w:03x}"),
-            Self::Label(l) => l.fmt(f),
+            Self::Label(l, 0) => l.fmt(f),
+            Self::Label(l, offset) => write!(f, "{l}+{offset}"),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::{LazyMain, PanickingIO};
+
+    #[test]
+    fn recovered_labels() {
+        let src = "\
+.seg text
+.entry
+main:
+    ldi r1, table
+    jez foo
+    call bar
+    jmp main
+foo:
+    jez foo
+bar:
+    jez foo
+    halt
+.seg rodata
+table:
+    .wide 1, 2, 3
+";
+        let ps = ProcessedSource::assemble(src).unwrap();
+        let lookup = source_label_lookup(&ps);
+        let table = ps.find_label("table").unwrap();
+        assert_eq!(lookup(table + 4), Some(("table", 4)));
+        assert_eq!(lookup(table + 6), None);
+        assert_eq!(lookup(0x2), None);
+
+        let mut machine = Machine::new(LazyMain::new(PanickingIO), Blf4::new());
+        machine.load_user_binary(&ps.to_object());
+        let mut lines = Vec::new();
+        loop {
+            let dins = disassemble_instruction(&mut machine, &lookup).unwrap();
+            let halted = dins.annotated_source.ends_with("halt");
+            lines.push(dins.annotated_source);
+            if halted {
+                break;
+            }
+            machine.cpu.program_counter = dins.next_instruction_location;
+        }
+
+        let jez: Vec<_> = lines.iter().filter(|l| l.contains("jez")).collect();
+        assert_eq!(jez.len(), 3);
+        assert!(jez.iter().all(|l| l.contains("jez foo")), "{jez:?}");
+        assert!(lines.iter().any(|l| l.contains("ldi r1, table")));
+        assert!(lines.iter().any(|l| l.contains("call bar")));
+        assert!(lines.iter().any(|l| l.contains("jmp main")));
+    }
+}
@@ -0,0 +1,400 @@
+//! A stub for the GDB Remote Serial Protocol so a machine can be debugged with `gdb`
+//!
+//! Only what is needed for stepping through a program is supported: `?`, `g`/`G`, `m`/`M`,
+//! `s`, `c`, `Z0`/`z0`, `k` and `D`. Other packets get the empty reply meaning unsupported.
+//! `monitor line` shows the source line of the program counter if a source map was given.
+//! The registers are sent as the 16 wide registers followed by the flags and the program
+//! counter, each as a little-endian wide
+
+use std::{
+    collections::BTreeSet,
+    fmt::Write as _,
+    io::{self, BufReader, Read, Write},
+    net::{TcpListener, ToSocketAddrs},
+};
+
+use crate::{
+    blf4::{Blf4, TrapMode, WideRegister},
+    machine::Machine,
+    mem::MainMemory,
+    source::{lookup_source, SourceMapEntry},
+    PAGE_SIZE_P, U4,
+};
+
+/// The port `target remote :1234` connects to
+pub const DEFAULT_PORT: u16 = 1234;
+/// Number of wides in the reply to `g`
+const REGISTER_COUNT: usize = 18;
+
+pub struct GdbStub<M> {
+    pub machine: Machine<M, Blf4>,
+    breakpoints: BTreeSet<u16>,
+    source_map: Vec<SourceMapEntry>,
+}
+
+/// Why the machine stopped
+enum Stop {
+    Trap,
+    /// The program halted and cannot continue
+    Exited,
+    Signal(u8),
+}
+
+impl Stop {
+    fn from_trap(tm: TrapMode) -> Self {
+        match tm {
+            TrapMode::Halt => Stop::Exited,
+            // signal numbers as gdb knows them
+            TrapMode::Invalid | TrapMode::IllegalOperation => Stop::Signal(4),
+            TrapMode::ZeroDiv => Stop::Signal(8),
+            tm if tm.is_address_fault() => Stop::Signal(11),
+            _ => Stop::Signal(5),
+        }
+    }
+    fn reply(&self) -> String {
+        match *self {
+            Stop::Trap => "S05".to_owned(),
+            Stop::Exited => "W00".to_owned(),
+            Stop::Signal(n) => format!("S{n:02x}"),
+        }
+    }
+}
+
+impl<M: MainMemory> GdbStub<M> {
+    pub fn new(machine: Machine<M, Blf4>) -> Self {
+        GdbStub {
+            machine,
+            breakpoints: BTreeSet::new(),
+            source_map: Vec::new(),
+        }
+    }
+    /// Uses `source_map` to answer `monitor line`, the entries have to be sorted by address
+    pub fn with_source_map(mut self, source_map: Vec<SourceMapEntry>) -> Self {
+        self.source_map = source_map;
+        self
+    }
+    /// Waits for a debugger to connect at `addr` and serves it until it kills or detaches
+    pub fn listen(&mut self, addr: impl ToSocketAddrs) -> io::Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        let (stream, _) = listener.accept()?;
+        stream.set_nodelay(true)?;
+        self.serve(BufReader::new(stream.try_clone()?), stream)
+    }
+    /// Serves a debugger until it kills or detaches or the connection is closed
+    pub fn serve(&mut self, mut r: impl Read, mut w: impl Write) -> io::Result<()> {
+        while let Some(packet) = read_packet(&mut r)? {
+            w.write_all(b"+")?;
+            let (reply, stop) = match packet.as_bytes().first() {
+                Some(b'k') => (None, true),
+                Some(b'D') => (Some("OK".to_owned()), true),
+                _ => (Some(self.handle(&packet)), false),
+            };
+            if let Some(reply) = reply {
+                write_packet(&mut w, &reply)?;
+            }
+            if stop {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Gives the reply to a packet
+    fn handle(&mut self, packet: &str) -> String {
+        let (cmd, args) = packet.split_at(packet.len().min(1));
+        let reply = match cmd {
+            "?" => Some(Stop::Trap.reply()),
+            "g" => Some(self.read_registers()),
+            "G" => self.write_registers(args),
+            "m" => self.read_memory(args),
+            "M" => self.write_memory(args),
+            "s" => Some(self.step().reply()),
+            "c" => Some(self.resume().reply()),
+            "q" => match args.strip_prefix("Rcmd,") {
+                Some(cmd) => self.monitor(cmd),
+                None => Some(String::new()),
+            },
+            "Z" | "z" => match args.strip_prefix("0,") {
+                Some(args) => {
+                    let addr = args.split(',').next().and_then(parse_hex);
+                    addr.map(|addr| {
+                        if cmd == "Z" {
+                            self.breakpoints.insert(addr);
+                        } else {
+                            self.breakpoints.remove(&addr);
+                        }
+                        "OK".to_owned()
+                    })
+                }
+                // only software breakpoints are supported
+                None => Some(String::new()),
+            },
+            _ => Some(String::new()),
+        };
+        reply.unwrap_or_else(|| "E01".to_owned())
+    }
+
+    /// Runs a `monitor` command given hex encoded, replying with its hex encoded output
+    fn monitor(&self, cmd: &str) -> Option<String> {
+        let cmd = String::from_utf8(parse_hex_bytes(cmd)?).ok()?;
+        let output = match cmd.trim() {
+            "line" => match lookup_source(&self.source_map, self.machine.cpu.program_counter) {
+                Some(entry) => format!("{}:{}\n", entry.source, entry.line),
+                None => "no source line\n".to_owned(),
+            },
+            _ => return None,
+        };
+        let mut s = String::with_capacity(output.len() * 2);
+        write_hex(&mut s, output.as_bytes());
+        Some(s)
+    }
+
+    fn step(&mut self) -> Stop {
+        match self.machine.execute_once() {
+            Ok(()) => Stop::Trap,
+            Err(tm) => Stop::from_trap(tm),
+        }
+    }
+    /// Runs until a breakpoint is reached or the machine traps
+    fn resume(&mut self) -> Stop {
+        loop {
+            if let Err(tm) = self.machine.execute_once() {
+                break Stop::from_trap(tm);
+            }
+            if self.breakpoints.contains(&self.machine.cpu.program_counter) {
+                break Stop::Trap;
+            }
+        }
+    }
+
+    fn read_registers(&self) -> String {
+        let cpu = &self.machine.cpu;
+        let mut regs = [0; REGISTER_COUNT];
+        for (i, reg) in regs.iter_mut().enumerate().take(16) {
+            *reg = match i {
+                // read directly since they cannot be read from user mode
+                14 => cpu.page,
+                15 => cpu.trap_handler,
+                i => cpu
+                    .read_wr(WideRegister(U4::new(i as u8)))
+                    .expect("register can be read"),
+            };
+        }
+        regs[16] = cpu.flags.into();
+        regs[17] = cpu.program_counter;
+
+        let mut s = String::with_capacity(REGISTER_COUNT * 4);
+        for reg in regs {
+            write_hex(&mut s, &reg.to_le_bytes());
+        }
+        s
+    }
+    fn write_registers(&mut self, args: &str) -> Option<String> {
+        let bytes = parse_hex_bytes(args)?;
+        if bytes.len() != REGISTER_COUNT * 2 {
+            return None;
+        }
+        let cpu = &mut self.machine.cpu;
+        let mut regs = bytes.chunks(2).map(|b| u16::from_le_bytes([b[0], b[1]]));
+        for i in 0..16 {
+            let reg = regs.next()?;
+            match i {
+                14 => cpu.page = reg,
+                15 => cpu.trap_handler = reg,
+                i => cpu.write_wr(WideRegister(U4::new(i)), reg).ok()?,
+            }
+        }
+        cpu.flags = regs.next()?.into();
+        cpu.program_counter = regs.next()?;
+
+        Some("OK".to_owned())
+    }
+
+    fn read_memory(&mut self, args: &str) -> Option<String> {
+        let (addr, len) = args.split_once(',')?;
+        let (addr, len) = (parse_hex(addr)?, parse_hex(len)?);
+
+        let mut bytes = Vec::with_capacity(len as usize);
+        let mut ctx = self.machine.cpu.context(&mut self.machine.memory);
+        for i in 0..len {
+            let addr = ctx.translate_read(addr.wrapping_add(i)).ok()?;
+            // reading a port could have side effects
+            if addr < PAGE_SIZE_P {
+                break;
+            }
+            bytes.push(ctx.physical_read(addr).ok()?);
+        }
+        if bytes.is_empty() && len != 0 {
+            return None;
+        }
+
+        let mut s = String::with_capacity(bytes.len() * 2);
+        write_hex(&mut s, &bytes);
+        Some(s)
+    }
+    fn write_memory(&mut self, args: &str) -> Option<String> {
+        let (range, data) = args.split_once(':')?;
+        let (addr, len) = range.split_once(',')?;
+        let (addr, len) = (parse_hex(addr)?, parse_hex(len)?);
+        let bytes = parse_hex_bytes(data)?;
+        if bytes.len() != len as usize {
+            return None;
+        }
+
+        let mut ctx = self.machine.cpu.context(&mut self.machine.memory);
+        for (i, b) in (0..len).zip(bytes) {
+            let addr = ctx.translate_read(addr.wrapping_add(i)).ok()?;
+            if addr < PAGE_SIZE_P {
+                return None;
+            }
+            ctx.physical_write(addr, b).ok()?;
+        }
+
+        Some("OK".to_owned())
+    }
+}
+
+fn parse_hex(s: &str) -> Option<u16> {
+    u16::from_str_radix(s, 16).ok()
+}
+
+fn parse_hex_bytes(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn write_hex(s: &mut String, bytes: &[u8]) {
+    for b in bytes {
+        write!(s, "{b:02x}").unwrap();
+    }
+}
+
+/// Reads the next packet, skipping acknowledgements and anything else between packets
+///
+/// Returns `None` when the connection is closed
+fn read_packet(r: &mut impl Read) -> io::Result<Option<String>> {
+    let mut byte = [0];
+    loop {
+        if r.read(&mut byte)? == 0 {
+            return Ok(None);
+        }
+        if byte[0] == b'$' {
+            break;
+        }
+    }
+
+    let mut data = Vec::new();
+    loop {
+        r.read_exact(&mut byte)?;
+        if byte[0] == b'#' {
+            break;
+        }
+        data.push(byte[0]);
+    }
+    // the checksum is not checked since the connection is reliable
+    let mut checksum = [0; 2];
+    r.read_exact(&mut checksum)?;
+
+    String::from_utf8(data)
+        .map(Some)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "packet is not UTF-8"))
+}
+
+fn write_packet(w: &mut impl Write, data: &str) -> io::Result<()> {
+    let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+    write!(w, "${data}#{checksum:02x}")?;
+    w.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::BufRead, net::TcpStream, thread};
+
+    use super::*;
+    use crate::{
+        blf4::isa::*,
+        mem::{LazyMain, PanickingIO},
+    };
+
+    /// Sends a packet and gives the reply
+    fn request(stream: &mut BufReader<TcpStream>, packet: &str) -> String {
+        write_packet(stream.get_mut(), packet).unwrap();
+        let mut ack = [0];
+        stream.read_exact(&mut ack).unwrap();
+        assert_eq!(ack, *b"+");
+
+        let mut reply = Vec::new();
+        stream.read_until(b'#', &mut reply).unwrap();
+        let mut checksum = [0; 2];
+        stream.read_exact(&mut checksum).unwrap();
+        stream.get_mut().write_all(b"+").unwrap();
+
+        String::from_utf8(reply[1..reply.len() - 1].to_vec()).unwrap()
+    }
+
+    fn hex(s: &str) -> String {
+        let mut h = String::new();
+        write_hex(&mut h, s.as_bytes());
+        h
+    }
+
+    #[test]
+    fn breakpoint() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client = thread::spawn(move || {
+            let mut stream = BufReader::new(TcpStream::connect(addr).unwrap());
+            assert_eq!(request(&mut stream, "?"), "S05");
+            assert_eq!(request(&mut stream, "Z0,84,1"), "OK");
+            assert_eq!(request(&mut stream, "c"), "S05");
+
+            let regs = request(&mut stream, "g");
+            assert_eq!(regs.len(), REGISTER_COUNT * 4);
+            // r1 and the program counter
+            assert_eq!(&regs[4..8], "3412");
+            assert_eq!(&regs[68..], "8400");
+            // `monitor line`
+            assert_eq!(request(&mut stream, "qRcmd,6c696e65"), hex("main.tasm:2\n"));
+            assert_eq!(request(&mut stream, "qRcmd,6e6f6e65"), "E01");
+
+            assert_eq!(
+                request(&mut stream, "m84,2"),
+                format!("{NOP:02x}{HALT:02x}")
+            );
+            assert_eq!(request(&mut stream, "m0,2"), "E01");
+            assert_eq!(request(&mut stream, "Mffff,1:aa"), "OK");
+            assert_eq!(request(&mut stream, "mffff,1"), "aa");
+            assert_eq!(request(&mut stream, "s"), "S05");
+            assert_eq!(request(&mut stream, "vMustReplyEmpty"), "");
+            assert_eq!(request(&mut stream, "c"), "W00");
+            write_packet(stream.get_mut(), "k").unwrap();
+        });
+
+        let rom = [LDI_W, 0x10, 0x34, 0x12, NOP, HALT];
+        let machine = Machine::new(LazyMain::new(PanickingIO).with_rom(&rom), Blf4::new());
+        let mut stub = GdbStub::new(machine).with_source_map(vec![
+            SourceMapEntry {
+                address: 0x80,
+                source: "main.tasm".into(),
+                line: 1,
+            },
+            SourceMapEntry {
+                address: 0x84,
+                source: "main.tasm".into(),
+                line: 2,
+            },
+        ]);
+        let (stream, _) = listener.accept().unwrap();
+        stub.serve(BufReader::new(stream.try_clone().unwrap()), stream)
+            .unwrap();
+
+        client.join().unwrap();
+        assert_eq!(stub.machine.cpu.program_counter, 0x86);
+    }
+}
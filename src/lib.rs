@@ -1,12 +1,18 @@
 pub mod aalv;
+pub mod bin_format;
 pub mod blf4;
 pub mod disassemble;
+pub mod gdb_stub;
 pub mod machine;
 pub mod mem;
+pub mod object;
+pub mod opt;
+pub mod output_format;
 pub mod source;
+pub mod sym;
 pub mod u4;
 
-pub use self::u4::U4;
+pub use self::u4::{U4Error, U4};
 
 /// The size of a page
 pub const PAGE_SIZE: u16 = 128;
@@ -17,14 +23,22 @@ pub const fn align_start(addr: u16, alignment: u16) -> u16 {
     addr & mask
 }
 pub const fn align_end(addr: u16, alignment: u16) -> u16 {
+    match checked_align_end(addr, alignment) {
+        Some(aligned) => aligned,
+        None => panic!("aligned address does not fit in 16 bits"),
+    }
+}
+/// Like [`align_end`] but gives `None` if the aligned address would not fit in 16 bits
+pub const fn checked_align_end(addr: u16, alignment: u16) -> Option<u16> {
     let mask = alignment - 1;
-    let next_aligned = (addr + alignment) & !mask;
 
     // If the current end is already aligned, do nothing
     if addr & mask == 0 {
-        addr
-    } else {
-        next_aligned
+        return Some(addr);
+    }
+    match addr.checked_add(alignment) {
+        Some(next) => Some(next & !mask),
+        None => None,
     }
 }
 
@@ -38,6 +52,10 @@ fn test_align() {
     assert_eq!(align_end(0x63f, PAGE_SIZE), 0x680);
     assert_eq!(align_end(0x684, PAGE_SIZE), 0x700);
 
+    assert_eq!(checked_align_end(0xff80, PAGE_SIZE), Some(0xff80));
+    assert_eq!(checked_align_end(0xff81, PAGE_SIZE), None);
+    assert_eq!(checked_align_end(0xffff, 2), None);
+
     assert_eq!(align_start(0x124, PAGE_SIZE), 0x100);
     assert_eq!(align_start(0x180, PAGE_SIZE), 0x180);
     assert_eq!(align_start(0x000, PAGE_SIZE), 0x000);
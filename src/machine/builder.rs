@@ -0,0 +1,344 @@
+use std::{
+    fmt::{self, Display},
+    ops::{Range, RangeInclusive},
+};
+
+use crate::{
+    bin_format::{parse_telda_elf, ParseError, DATA_START, TEXT_START},
+    blf4::Blf4,
+    mem::{write_n, Io, LazyMain, HALF_CELL, ROM_SIZE},
+    PAGE_SIZE_P,
+};
+
+use super::Machine;
+
+/// Most memory a [`LazyMain`] can have
+pub const MAX_MEMORY_SIZE: u32 = 0x100_0000;
+
+/// Sets up a [`Machine`] with a [`LazyMain`] and a [`Blf4`], checking that the parts fit together
+///
+/// Bytes loaded below [`HALF_CELL`] go in ROM and the rest in RAM
+pub struct MachineBuilder<P> {
+    ports: P,
+    memory_size: u32,
+    entry: Option<u16>,
+    stack_top: Option<u16>,
+    peripherals: Vec<(RangeInclusive<u8>, Box<dyn Io>)>,
+    binaries: Vec<(u16, Vec<u8>)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    /// The memory size is bigger than [`MAX_MEMORY_SIZE`]
+    MemoryTooBig(u32),
+    /// A binary is loaded into the memory-mapped I/O or past the end of memory
+    BinaryOutOfRange(Range<u32>),
+    /// Two binaries are loaded into the same memory
+    OverlappingBinaries(Range<u32>, Range<u32>),
+    /// The entry point is not in any loaded binary
+    EntryNotLoaded(u16),
+    /// The stack top is not in RAM
+    StackOutOfRange(u16),
+    /// Two peripherals are attached to the same port
+    OverlappingPeripherals(RangeInclusive<u8>, RangeInclusive<u8>),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::MemoryTooBig(size) => write!(
+                f,
+                "memory size 0x{size:x} is bigger than 0x{MAX_MEMORY_SIZE:x}"
+            ),
+            ConfigError::BinaryOutOfRange(r) => write!(
+                f,
+                "binary at 0x{:04x}-0x{:04x} is not in memory",
+                r.start, r.end
+            ),
+            ConfigError::OverlappingBinaries(a, b) => write!(
+                f,
+                "binaries at 0x{:04x}-0x{:04x} and 0x{:04x}-0x{:04x} overlap",
+                a.start, a.end, b.start, b.end
+            ),
+            ConfigError::EntryNotLoaded(addr) => {
+                write!(f, "entry point 0x{addr:04x} is not in a loaded binary")
+            }
+            ConfigError::StackOutOfRange(addr) => {
+                write!(f, "stack top 0x{addr:04x} is not in RAM")
+            }
+            ConfigError::OverlappingPeripherals(a, b) => write!(
+                f,
+                "peripherals at ports {}-{} and {}-{} overlap",
+                a.start(),
+                a.end(),
+                b.start(),
+                b.end()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl<P: Io> MachineBuilder<P> {
+    /// A builder for a machine with `ports` as its memory-mapped I/O and 64 KiB of memory
+    pub fn new(ports: P) -> Self {
+        MachineBuilder {
+            ports,
+            memory_size: 0x1_0000,
+            entry: None,
+            stack_top: None,
+            peripherals: Vec::new(),
+            binaries: Vec::new(),
+        }
+    }
+    /// A builder with the text and data of a flat binary loaded and its entry point set
+    pub fn from_telf(ports: P, bytes: &[u8]) -> Result<Self, ParseError> {
+        let elf = parse_telda_elf(bytes)?;
+        let mut builder = Self::new(ports)
+            .entry_point(elf.entry)
+            .load_binary(&elf.text, TEXT_START);
+        if !elf.data.is_empty() {
+            builder = builder.load_binary(&elf.data, DATA_START);
+        }
+        Ok(builder)
+    }
+    /// Bytes of memory that binaries and the stack have to fit in
+    pub fn memory_size(mut self, bytes: u32) -> Self {
+        self.memory_size = bytes;
+        self
+    }
+    /// Where the program counter starts, it has to be inside a loaded binary
+    pub fn entry_point(mut self, addr: u16) -> Self {
+        self.entry = Some(addr);
+        self
+    }
+    /// See [`Blf4::with_stack_top`], 0 means the stack starts at the end of memory
+    pub fn stack_top(mut self, addr: u16) -> Self {
+        self.stack_top = Some(addr);
+        self
+    }
+    /// Puts a device in the port space used by the `in` and `out` instructions
+    pub fn attach_peripheral(mut self, ports: RangeInclusive<u8>, device: Box<dyn Io>) -> Self {
+        self.peripherals.push((ports, device));
+        self
+    }
+    /// Copies `data` into memory at `load_addr`
+    pub fn load_binary(mut self, data: &[u8], load_addr: u16) -> Self {
+        self.binaries.push((load_addr, data.to_vec()));
+        self
+    }
+    pub fn build(self) -> Result<Machine<LazyMain<P>, Blf4>, ConfigError> {
+        self.validate()?;
+        let MachineBuilder {
+            ports,
+            memory_size: _,
+            entry,
+            stack_top,
+            peripherals,
+            binaries,
+        } = self;
+
+        let mut rom = None;
+        let mut ram = Vec::new();
+        for (addr, data) in binaries {
+            // the part below HALF_CELL goes in ROM
+            let in_rom = HALF_CELL.saturating_sub(addr as usize).min(data.len());
+            let (rom_part, ram_part) = data.split_at(in_rom);
+            if !rom_part.is_empty() {
+                let rom = rom.get_or_insert_with(|| vec![0; ROM_SIZE]);
+                let start = addr as usize - PAGE_SIZE_P as usize;
+                rom[start..start + rom_part.len()].copy_from_slice(rom_part);
+            }
+            if !ram_part.is_empty() {
+                ram.push((addr as u32 + in_rom as u32, ram_part.to_vec()));
+            }
+        }
+
+        let mut memory = LazyMain::new(ports);
+        if let Some(rom) = rom {
+            memory = memory.with_rom(&rom);
+        }
+        for (ports, device) in peripherals {
+            memory = memory.with_port_device(ports, device);
+        }
+        for (addr, data) in ram {
+            write_n(&mut memory, addr, &data);
+        }
+
+        let mut cpu = Blf4::new();
+        if let Some(top) = stack_top {
+            cpu = cpu.with_stack_top(top);
+        }
+        if let Some(entry) = entry {
+            cpu.program_counter = entry;
+        }
+        Ok(Machine::new(memory, cpu))
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.memory_size > MAX_MEMORY_SIZE {
+            return Err(ConfigError::MemoryTooBig(self.memory_size));
+        }
+
+        let regions: Vec<Range<u32>> = self
+            .binaries
+            .iter()
+            .map(|(addr, data)| *addr as u32..*addr as u32 + data.len() as u32)
+            .collect();
+        let addressable = PAGE_SIZE_P..self.memory_size.min(0x1_0000);
+        for r in &regions {
+            if r.start < addressable.start || r.end > addressable.end {
+                return Err(ConfigError::BinaryOutOfRange(r.clone()));
+            }
+        }
+        for (i, a) in regions.iter().enumerate() {
+            if let Some(b) = regions[..i]
+                .iter()
+                .find(|b| a.start < b.end && b.start < a.end)
+            {
+                return Err(ConfigError::OverlappingBinaries(b.clone(), a.clone()));
+            }
+        }
+
+        if let Some(entry) = self.entry {
+            if !regions.iter().any(|r| r.contains(&(entry as u32))) {
+                return Err(ConfigError::EntryNotLoaded(entry));
+            }
+        }
+        if let Some(top) = self.stack_top {
+            let end = if top == 0 { 0x1_0000 } else { top as u32 };
+            if end <= HALF_CELL as u32 || end > self.memory_size {
+                return Err(ConfigError::StackOutOfRange(top));
+            }
+        }
+
+        for (i, (a, _)) in self.peripherals.iter().enumerate() {
+            if let Some((b, _)) = self.peripherals[..i]
+                .iter()
+                .find(|(b, _)| a.start() <= b.end() && b.start() <= a.end())
+            {
+                return Err(ConfigError::OverlappingPeripherals(b.clone(), a.clone()));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        bin_format::TeldaElf,
+        blf4::{isa::*, TrapMode},
+        mem::{MainMemory, PanickingIO},
+    };
+
+    struct Latch(u8);
+    impl Io for Latch {
+        fn read(&mut self, _port: u8) -> u8 {
+            self.0
+        }
+        fn write(&mut self, _port: u8, val: u8) {
+            self.0 = val;
+        }
+    }
+
+    #[test]
+    fn from_telf() {
+        let elf = TeldaElf::new(0x81, vec![NULL, NOP, HALT], vec![7, 8]);
+        let mut m = MachineBuilder::from_telf(PanickingIO, &elf.to_bytes())
+            .unwrap()
+            .stack_top(0xa000)
+            .attach_peripheral(4..=5, Box::new(Latch(0x33)))
+            .build()
+            .unwrap();
+        assert_eq!(m.cpu.program_counter, 0x81);
+        assert_eq!(m.cpu.stack, 0xa000);
+        assert_eq!(m.memory.read(DATA_START as u32 + 1), 8);
+        assert_eq!(m.memory.port_read(5), 0x33);
+        assert_eq!(m.run_until_abort(), TrapMode::Halt);
+
+        assert_eq!(
+            MachineBuilder::from_telf(PanickingIO, b"nope").err(),
+            Some(ParseError::TooShort)
+        );
+    }
+
+    #[test]
+    fn binary_across_rom_and_ram() {
+        let data: Vec<u8> = (0..16).collect();
+        let mut m = MachineBuilder::new(PanickingIO)
+            .load_binary(&data, 0x7ff8)
+            .entry_point(0x7ff8)
+            .build()
+            .unwrap();
+        for (i, &b) in data.iter().enumerate() {
+            assert_eq!(m.memory.read(0x7ff8 + i as u32), b);
+        }
+    }
+
+    #[test]
+    fn stack_at_end_of_memory() {
+        let mut m = MachineBuilder::new(PanickingIO)
+            .load_binary(&[PUSH_W, 0x10, POP_W, 0x10, HALT], 0x80)
+            .entry_point(0x80)
+            .stack_top(0)
+            .build()
+            .unwrap();
+        assert_eq!(m.run_until_abort(), TrapMode::Halt);
+        assert_eq!(m.cpu.stack, 0);
+
+        let mut m = MachineBuilder::new(PanickingIO)
+            .load_binary(&[POP_W, 0x10, HALT], 0x80)
+            .entry_point(0x80)
+            .stack_top(0)
+            .build()
+            .unwrap();
+        assert_eq!(m.run_until_abort(), TrapMode::StackUnderflow);
+    }
+
+    #[test]
+    fn inconsistent_configs() {
+        let err = |b: MachineBuilder<PanickingIO>| b.build().err().unwrap();
+        let new = || MachineBuilder::new(PanickingIO);
+
+        assert_eq!(
+            err(new().memory_size(0x200_0000)),
+            ConfigError::MemoryTooBig(0x200_0000)
+        );
+        assert_eq!(
+            err(new().load_binary(&[0; 4], 0x7e)),
+            ConfigError::BinaryOutOfRange(0x7e..0x82)
+        );
+        assert_eq!(
+            err(new().memory_size(0x9000).load_binary(&[0; 4], 0x9000)),
+            ConfigError::BinaryOutOfRange(0x9000..0x9004)
+        );
+        assert_eq!(
+            err(new().load_binary(&[0; 4], 0x80).load_binary(&[0; 4], 0x83)),
+            ConfigError::OverlappingBinaries(0x80..0x84, 0x83..0x87)
+        );
+        assert_eq!(
+            err(new().load_binary(&[0; 4], 0x80).entry_point(0x84)),
+            ConfigError::EntryNotLoaded(0x84)
+        );
+        assert_eq!(
+            err(new().stack_top(0x4000)),
+            ConfigError::StackOutOfRange(0x4000)
+        );
+        assert_eq!(
+            err(new().memory_size(0x9000).stack_top(0)),
+            ConfigError::StackOutOfRange(0)
+        );
+        assert_eq!(
+            err(new()
+                .attach_peripheral(0..=3, Box::new(Latch(0)))
+                .attach_peripheral(3..=4, Box::new(Latch(0)))),
+            ConfigError::OverlappingPeripherals(0..=3, 3..=4)
+        );
+
+        assert!(new().stack_top(0).build().is_ok());
+    }
+}
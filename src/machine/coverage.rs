@@ -0,0 +1,137 @@
+//! Which addresses have been executed, to find code that is never reached
+//!
+//! Only compiled in with the `coverage` feature so running without it costs nothing
+
+use std::io::{self, Read, Write};
+
+use crate::source::SourceMapEntry;
+
+/// Magic number at the start of a saved coverage map
+pub const COVERAGE_MAGIC: [u8; 4] = *b"TCOV";
+/// Extension of saved coverage maps
+pub const COVERAGE_EXT: &str = "tcov";
+
+const ADDRESSES: usize = 0x1_0000;
+
+/// One bit for every virtual address, set when an instruction or operand byte was fetched from it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoverageMap {
+    executed: Box<[u8]>,
+}
+
+impl Default for CoverageMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CoverageMap {
+    pub fn new() -> Self {
+        CoverageMap {
+            executed: vec![0; ADDRESSES / 8].into_boxed_slice(),
+        }
+    }
+    #[inline]
+    pub fn mark(&mut self, addr: u16) {
+        self.executed[addr as usize / 8] |= 1 << (addr % 8);
+    }
+    pub fn is_executed(&self, addr: u16) -> bool {
+        self.executed[addr as usize / 8] & (1 << (addr % 8)) != 0
+    }
+    pub fn clear(&mut self) {
+        self.executed.fill(0);
+    }
+
+    /// Writes the magic number followed by the bitmap, the lowest bit of the first byte is address 0
+    pub fn write(&self, w: &mut dyn Write) -> io::Result<()> {
+        w.write_all(&COVERAGE_MAGIC)?;
+        w.write_all(&self.executed)
+    }
+    pub fn read(r: &mut dyn Read) -> io::Result<Self> {
+        let mut magic = [0; 4];
+        r.read_exact(&mut magic)?;
+        if magic != COVERAGE_MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a telda coverage map",
+            ));
+        }
+        let mut map = Self::new();
+        r.read_exact(&mut map.executed)?;
+        Ok(map)
+    }
+}
+
+/// Percentage of the addresses from `text_start` up to `text_end` that were executed
+///
+/// An empty range gives 0
+pub fn coverage_percentage(map: &CoverageMap, text_start: u16, text_end: u16) -> f64 {
+    if text_end <= text_start {
+        return 0.;
+    }
+    let hit = (text_start..text_end)
+        .filter(|&addr| map.is_executed(addr))
+        .count();
+    100. * hit as f64 / (text_end - text_start) as f64
+}
+
+/// Gives every line of the source map with whether any of its bytes were executed
+///
+/// The last entry only covers its first address since where it ends is not known
+pub fn coverage_report(
+    map: &CoverageMap,
+    source_map: &[SourceMapEntry],
+) -> Vec<(String, u32, bool)> {
+    source_map
+        .iter()
+        .enumerate()
+        .map(|(i, entry)| {
+            let end = source_map
+                .get(i + 1)
+                .map(|next| next.address)
+                .unwrap_or(entry.address.saturating_add(1))
+                .max(entry.address.saturating_add(1));
+            let hit = (entry.address..end).any(|addr| map.is_executed(addr));
+            (entry.source.to_string(), entry.line, hit)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        blf4::{isa::*, Blf4, TrapMode},
+        machine::Machine,
+        mem::{LazyMain, PanickingIO},
+    };
+
+    #[test]
+    fn marks_executed_bytes() {
+        // skips over the nop
+        let rom = [LDI_W, 0x01, 0x85, 0x00, NOP, HALT, NOP];
+        let mut m = Machine::new(LazyMain::new(PanickingIO).with_rom(&rom), Blf4::new());
+        assert_eq!(m.run_until_abort(), TrapMode::Halt);
+
+        let map = &m.cpu.coverage;
+        let executed: Vec<_> = (0x80..0x87).map(|a| map.is_executed(a)).collect();
+        assert_eq!(executed, [true, true, true, true, false, true, false]);
+        assert_eq!(coverage_percentage(map, 0x80, 0x87), 500. / 7.);
+
+        let entries = [0x80, 0x84, 0x85].map(|address| SourceMapEntry {
+            address,
+            source: "a.telda".into(),
+            line: address as u32,
+        });
+        let report: Vec<_> = coverage_report(map, &entries)
+            .into_iter()
+            .map(|(_, line, hit)| (line, hit))
+            .collect();
+        assert_eq!(report, [(0x80, true), (0x84, false), (0x85, true)]);
+
+        let mut buf = Vec::new();
+        map.write(&mut buf).unwrap();
+        assert_eq!(buf.len(), 4 + 0x2000);
+        assert_eq!(&CoverageMap::read(&mut &buf[..]).unwrap(), map);
+    }
+}
@@ -1,7 +1,19 @@
-use crate::mem::MainMemory;
+use crate::mem::{MainMemory, Notifier};
 
+mod builder;
+#[cfg(feature = "coverage")]
+pub mod coverage;
 mod ekernel;
+#[cfg(feature = "profiling")]
+pub mod profile;
+pub mod snapshot;
+#[cfg(feature = "coverage")]
+pub use self::coverage::CoverageMap;
+pub use self::builder::*;
 pub use self::ekernel::*;
+#[cfg(feature = "profiling")]
+pub use self::profile::Profile;
+pub use self::snapshot::CpuSnapshot;
 
 pub trait Cpu {
     type TrapMode;
@@ -10,23 +22,38 @@ pub trait Cpu {
         &mut self,
         main_memory: &mut M,
     ) -> Result<(), Self::TrapMode>;
+    /// Whether the trap means the cpu is waiting to be notified by a peripheral
+    fn is_waiting(_tm: &Self::TrapMode) -> bool {
+        false
+    }
 }
 
 pub struct Machine<M, C> {
     pub memory: M,
     pub cpu: C,
+    /// Total number of instructions executed by this machine
+    pub cycles: u64,
 
     ekernel: Option<Box<dyn EmulatedKernel<C>>>,
+    notifier: Notifier,
 }
 
-impl<M, C> Machine<M, C> {
-    pub fn new(memory: M, cpu: C) -> Self {
+impl<M: MainMemory, C> Machine<M, C> {
+    pub fn new(mut memory: M, cpu: C) -> Self {
+        let notifier = Notifier::default();
+        memory.set_notifier(notifier.clone());
         Machine {
             memory,
             cpu,
+            cycles: 0,
             ekernel: None,
+            notifier,
         }
     }
+    /// Notifier that wakes up the machine when it is waiting
+    pub fn notifier(&self) -> Notifier {
+        self.notifier.clone()
+    }
 }
 
 impl<M: MainMemory, C: Cpu> Machine<M, C> {
@@ -39,8 +66,16 @@ impl<M: MainMemory, C: Cpu> Machine<M, C> {
         installed_alreday
     }
     pub fn execute_once(&mut self) -> Result<(), C::TrapMode> {
+        self.cycles += 1;
         match self.cpu.execute_instruction(&mut self.memory) {
             Ok(()) => Ok(()),
+            Err(tm) if C::is_waiting(&tm) => {
+                match self.memory.idle() {
+                    Some(timeout) => self.notifier.wait_timeout(timeout),
+                    None => self.notifier.wait(),
+                }
+                Ok(())
+            }
             Err(tm) => {
                 let Some(k) = self.ekernel.as_deref_mut() else {
                     return Err(tm);
@@ -60,4 +95,46 @@ impl<M: MainMemory, C: Cpu> Machine<M, C> {
             }
         }
     }
+    /// Runs at most `max` instructions or until an unhandled trap
+    ///
+    /// Returns how many instructions were executed (including the one that trapped)
+    /// and the trap if one occurred
+    pub fn run_for_cycles(&mut self, max: u64) -> (u64, Option<C::TrapMode>) {
+        let start = self.cycles;
+        while self.cycles - start < max {
+            if let Err(tm) = self.execute_once() {
+                return (self.cycles - start, Some(tm));
+            }
+        }
+        (self.cycles - start, None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Machine;
+    use crate::{
+        blf4::{isa::*, Blf4, TrapMode},
+        mem::{LazyMain, PanickingIO},
+    };
+
+    fn machine(rom: &[u8]) -> Machine<LazyMain<PanickingIO>, Blf4> {
+        Machine::new(LazyMain::new(PanickingIO).with_rom(rom), Blf4::new())
+    }
+
+    #[test]
+    fn infinite_loop_is_bounded() {
+        // jmp 0x0080
+        let mut m = machine(&[LDI_W, 0x01, 0x80, 0x00]);
+        assert_eq!(m.run_for_cycles(1000), (1000, None));
+        assert_eq!(m.run_for_cycles(24), (24, None));
+        assert_eq!(m.cycles, 1024);
+    }
+
+    #[test]
+    fn halt_stops_early() {
+        let mut m = machine(&[NOP, NOP, NOP, NOP, HALT, NOP]);
+        assert_eq!(m.run_for_cycles(100), (5, Some(TrapMode::Halt)));
+        assert_eq!(m.cycles, 5);
+    }
 }
@@ -0,0 +1,89 @@
+//! How many times each opcode has been executed, to find the hot instructions
+//!
+//! Only compiled in with the `profiling` feature so running without it costs nothing
+
+use crate::blf4::isa::mnemonic;
+
+/// Number of times each opcode has been executed, indexed by opcode
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Profile {
+    pub counts: [u64; 256],
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Profile {
+    pub fn new() -> Self {
+        Profile { counts: [0; 256] }
+    }
+    #[inline]
+    pub fn count(&mut self, opcode: u8) {
+        self.counts[opcode as usize] += 1;
+    }
+    /// Total number of instructions executed
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+    pub fn clear(&mut self) {
+        self.counts.fill(0);
+    }
+}
+
+/// Gives every executed opcode with its mnemonic and count, most executed first
+///
+/// Opcodes with the same count are in order of opcode
+pub fn profile_report(p: &Profile) -> Vec<(u8, &'static str, u64)> {
+    let mut report: Vec<_> = (0..=255)
+        .filter(|&opcode| p.counts[opcode as usize] != 0)
+        .map(|opcode| {
+            let name = mnemonic(opcode).unwrap_or("invalid");
+            (opcode, name, p.counts[opcode as usize])
+        })
+        .collect();
+    report.sort_by_key(|&(_, _, count)| std::cmp::Reverse(count));
+    report
+}
+
+/// Like [`profile_report`] but only the `n` most executed opcodes
+pub fn profile_report_top_n(p: &Profile, n: usize) -> Vec<(u8, &'static str, u64)> {
+    let mut report = profile_report(p);
+    report.truncate(n);
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        blf4::{isa::*, Blf4, TrapMode},
+        machine::Machine,
+        mem::{LazyMain, PanickingIO},
+    };
+
+    #[test]
+    fn counts_opcodes() {
+        // r1 = 3; loop: r1 -= 1; jnz loop
+        let rom = [
+            LDI_W, 0x10, 0x03, 0x00, SUB_W_IMM, 0x11, 0x01, 0x00, JNZ, 0x84, 0x00, HALT,
+        ];
+        let mut m = Machine::new(LazyMain::new(PanickingIO).with_rom(&rom), Blf4::new());
+        assert_eq!(m.run_until_abort(), TrapMode::Halt);
+
+        let p = &m.cpu.profile;
+        assert_eq!(p.total(), 8);
+        assert_eq!(
+            profile_report(p),
+            [
+                (JNZ, "jnz", 3),
+                (SUB_W_IMM, "sub", 3),
+                (HALT, "halt", 1),
+                (LDI_W, "ldi", 1),
+            ]
+        );
+        assert_eq!(profile_report_top_n(p, 1), [(JNZ, "jnz", 3)]);
+    }
+}
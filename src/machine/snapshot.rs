@@ -0,0 +1,153 @@
+//! Checkpointing of a machine so it can be resumed later
+
+use std::io::{self, Read, Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    blf4::Blf4,
+    mem::{LazyMain, MemoryImage},
+};
+
+use super::Machine;
+
+/// Magic number at the start of a saved snapshot
+pub const SNAPSHOT_MAGIC: [u8; 4] = *b"TSNP";
+/// Version of the format of saved snapshots, bumped whenever it changes
+///
+/// 2 added the trap table of the CPU, 3 the number of its last interrupt request, 4 its system
+/// call handler
+pub const SNAPSHOT_VERSION: u16 = 4;
+
+/// All registers, flags and memory of a machine
+///
+/// The ports and any emulated kernel are not part of the snapshot
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CpuSnapshot {
+    pub cpu: Blf4,
+    pub cycles: u64,
+    pub memory: MemoryImage,
+}
+
+/// Writes the snapshot preceded by the magic number and format version
+pub fn save(snapshot: &CpuSnapshot, mut w: impl Write) -> io::Result<()> {
+    w.write_all(&SNAPSHOT_MAGIC)?;
+    w.write_all(&SNAPSHOT_VERSION.to_le_bytes())?;
+    bincode::serialize_into(w, snapshot).map_err(|e| into_io_error(*e))
+}
+
+pub fn load(mut r: impl Read) -> io::Result<CpuSnapshot> {
+    let mut header = [0; 6];
+    r.read_exact(&mut header)?;
+    if header[..4] != SNAPSHOT_MAGIC {
+        return Err(invalid_data("not a telda snapshot".to_owned()));
+    }
+    let version = u16::from_le_bytes([header[4], header[5]]);
+    if version != SNAPSHOT_VERSION {
+        return Err(invalid_data(format!(
+            "snapshot has version {version} but only version {SNAPSHOT_VERSION} is supported"
+        )));
+    }
+
+    let snapshot: CpuSnapshot = bincode::deserialize_from(r).map_err(|e| into_io_error(*e))?;
+    if !snapshot.memory.is_valid() {
+        return Err(invalid_data("snapshot has invalid memory".to_owned()));
+    }
+    Ok(snapshot)
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn into_io_error(e: bincode::ErrorKind) -> io::Error {
+    match e {
+        bincode::ErrorKind::Io(e) => e,
+        e => invalid_data(e.to_string()),
+    }
+}
+
+impl<P> Machine<LazyMain<P>, Blf4> {
+    pub fn snapshot(&self) -> CpuSnapshot {
+        CpuSnapshot {
+            cpu: self.cpu.clone(),
+            cycles: self.cycles,
+            memory: self.memory.image(),
+        }
+    }
+    /// Puts the machine back in the state of the snapshot
+    ///
+    /// Panics if the memory of the snapshot is not valid
+    pub fn restore_snapshot(&mut self, snap: CpuSnapshot) {
+        let CpuSnapshot {
+            cpu,
+            cycles,
+            memory,
+        } = snap;
+        self.memory.restore_image(memory);
+        self.cpu = cpu;
+        self.cycles = cycles;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        blf4::{isa::*, TrapMode, R1},
+        mem::{MainMemory, PanickingIO},
+    };
+
+    #[test]
+    #[rustfmt::skip]
+    fn resuming_gives_same_result() {
+        let rom = [
+            // r1 = 0, r2 = 1, r3 = 0x8000, r4 = 50
+            LDI_W, 0x10, 0x00, 0x00,
+            LDI_W, 0x20, 0x01, 0x00,
+            LDI_W, 0x30, 0x00, 0x80,
+            LDI_W, 0x40, 0x32, 0x00,
+            // loop: r1 += r2; [r3 + r1] = r1; r5 = r4 - r1; jnz loop
+            ADD_W, 0x11, 0x20,
+            STORE_WR, 0x31, 0x10,
+            SUB_W, 0x54, 0x10,
+            JNZ, 0x90, 0x00,
+            HALT,
+        ];
+        let mut m = Machine::new(LazyMain::new(PanickingIO).with_rom(&rom), Blf4::new());
+        assert_eq!(m.run_for_cycles(40), (40, None));
+
+        let mut saved = Vec::new();
+        save(&m.snapshot(), &mut saved).unwrap();
+
+        assert_eq!(m.run_until_abort(), TrapMode::Halt);
+        let result = (m.cpu.read_wr(R1), m.memory.read(0x8032), m.cycles);
+
+        m.restore_snapshot(load(&saved[..]).unwrap());
+        assert_eq!(m.cycles, 40);
+        assert_eq!(m.memory.read(0x8032), 0);
+        assert_eq!(m.run_until_abort(), TrapMode::Halt);
+        assert_eq!((m.cpu.read_wr(R1), m.memory.read(0x8032), m.cycles), result);
+        assert_eq!(result.0, Ok(50));
+        assert_eq!(result.1, 50);
+    }
+
+    #[test]
+    fn cpu_layout_matches_version() {
+        // changing what is saved of the CPU needs a new SNAPSHOT_VERSION and then a new size here
+        assert_eq!(SNAPSHOT_VERSION, 4);
+        assert_eq!(bincode::serialized_size(&Blf4::new()).unwrap(), 49);
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let m = Machine::new(LazyMain::new(PanickingIO), Blf4::new());
+        let mut saved = Vec::new();
+        save(&m.snapshot(), &mut saved).unwrap();
+
+        saved[4] += 1;
+        let e = load(&saved[..]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(load(&b"not a snapshot"[..]).is_err());
+    }
+}
@@ -1,10 +1,73 @@
-use std::io::{stdin, stdout, Read, Write};
+use std::{
+    io::{stdin, stdout, Read, Write},
+    ops::RangeInclusive,
+    sync::{Arc, Condvar, Mutex},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
 
 use crate::PAGE_SIZE_P;
 
+mod ports;
+mod timer;
+mod uart;
+pub use self::ports::*;
+pub use self::timer::*;
+pub use self::uart::*;
+
 pub trait MainMemory {
     fn read(&mut self, addr: u32) -> u8;
     fn write(&mut self, addr: u32, byte: u8);
+    /// Gives the memory a way to wake up the machine it is in
+    fn set_notifier(&mut self, _notifier: Notifier) {}
+    /// Called once before every instruction, returns the number of a requested interrupt
+    fn tick(&mut self) -> Option<u8> {
+        None
+    }
+    /// Called when the machine starts waiting, returns how long it may wait at most before
+    /// the next [`Self::tick`] requests an interrupt, or `None` if only a notification can wake it
+    fn idle(&mut self) -> Option<Duration> {
+        None
+    }
+    /// Reads from the port space, which has nothing unless the memory has a [`PortBus`]
+    fn port_read(&mut self, _port: u8) -> u8 {
+        0xff
+    }
+    fn port_write(&mut self, _port: u8, _byte: u8) {}
+}
+
+/// Interrupt requested by a [`Timer`] when it expires
+pub const IRQ_TIMER: u8 = 0;
+/// Interrupt requested by a [`Uart`] when a byte is received
+pub const IRQ_UART: u8 = 1;
+
+/// Wakes up a machine waiting for an interrupt, can be used from any thread
+#[derive(Debug, Clone, Default)]
+pub struct Notifier(Arc<(Mutex<bool>, Condvar)>);
+
+impl Notifier {
+    pub fn notify(&self) {
+        let (notified, condvar) = &*self.0;
+        *notified.lock().unwrap() = true;
+        condvar.notify_all();
+    }
+    /// Blocks until notified, returns immediately if notified since the last wait
+    pub fn wait(&self) {
+        let (notified, condvar) = &*self.0;
+        let mut notified = condvar
+            .wait_while(notified.lock().unwrap(), |notified| !*notified)
+            .unwrap();
+        *notified = false;
+    }
+    /// Like [`Self::wait`] but gives up after `timeout`
+    pub fn wait_timeout(&self, timeout: Duration) {
+        let (notified, condvar) = &*self.0;
+        let (mut notified, _) = condvar
+            .wait_timeout_while(notified.lock().unwrap(), timeout, |notified| !*notified)
+            .unwrap();
+        *notified = false;
+    }
 }
 
 pub fn read_n<M: MainMemory + ?Sized, const N: usize>(m: &mut M, addr: u32) -> [u8; N] {
@@ -25,6 +88,7 @@ pub struct LazyMain<P> {
     ram0: [u8; HALF_CELL],
     cells: [Option<Box<[u8; 256 * 256]>>; 255],
     ports: P,
+    port_bus: PortBus,
 }
 
 impl<P: Io> MainMemory for LazyMain<P> {
@@ -70,6 +134,26 @@ impl<P: Io> MainMemory for LazyMain<P> {
             }
         }
     }
+    fn set_notifier(&mut self, notifier: Notifier) {
+        self.port_bus.set_notifier(notifier.clone());
+        self.ports.set_notifier(notifier);
+    }
+    fn tick(&mut self) -> Option<u8> {
+        let irq = self.port_bus.tick();
+        self.ports.tick().or(irq)
+    }
+    fn idle(&mut self) -> Option<Duration> {
+        match (self.port_bus.idle(), self.ports.idle()) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+    fn port_read(&mut self, port: u8) -> u8 {
+        self.port_bus.read(port)
+    }
+    fn port_write(&mut self, port: u8, byte: u8) {
+        self.port_bus.write(port, byte);
+    }
 }
 
 impl<P> LazyMain<P> {
@@ -79,17 +163,101 @@ impl<P> LazyMain<P> {
             ram0: [0; HALF_CELL],
             ports,
             cells: ([(); 255]).map(|()| None),
+            port_bus: PortBus::new(),
         }
     }
+    /// Puts a device in the port space used by the `in` and `out` instructions
+    pub fn with_port_device(mut self, ports: RangeInclusive<u8>, device: impl Io + 'static) -> Self {
+        self.port_bus.register(ports, device);
+        self
+    }
     pub fn with_rom(mut self, bytes: &[u8]) -> Self {
         assert!(bytes.len() <= ROM_SIZE, "bytes cannot be bigger than ROM");
-        self.rom = Some(std::array::from_fn(|i| bytes[i]));
+        self.rom = Some(std::array::from_fn(|i| bytes.get(i).copied().unwrap_or(0)));
         self
     }
+    /// Copies out everything but the ports
+    pub fn image(&self) -> MemoryImage {
+        MemoryImage {
+            rom: self.rom.map(|rom| rom.to_vec()),
+            ram0: self.ram0.to_vec(),
+            cells: self
+                .cells
+                .iter()
+                .enumerate()
+                .filter_map(|(i, cell)| Some((i as u8, cell.as_ref()?.to_vec())))
+                .collect(),
+        }
+    }
+    /// Replaces everything but the ports with the contents of `image`
+    ///
+    /// Panics if the image is not valid
+    pub fn restore_image(&mut self, image: MemoryImage) {
+        assert!(image.is_valid(), "invalid memory image");
+        let MemoryImage { rom, ram0, cells } = image;
+
+        self.rom = rom.map(|rom| rom.try_into().unwrap());
+        self.ram0.copy_from_slice(&ram0);
+        self.cells = ([(); 255]).map(|()| None);
+        for (i, cell) in cells {
+            self.cells[i as usize] = Some(cell.into_boxed_slice().try_into().unwrap());
+        }
+    }
+}
+
+/// Contents of a [`LazyMain`] without its ports
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MemoryImage {
+    pub rom: Option<Vec<u8>>,
+    /// RAM of the first cell
+    pub ram0: Vec<u8>,
+    /// The cells that have been written to, by their index minus one
+    pub cells: Vec<(u8, Vec<u8>)>,
+}
+
+impl MemoryImage {
+    /// Whether every part has the right size and no cell is out of range
+    pub fn is_valid(&self) -> bool {
+        self.rom.as_ref().is_none_or(|rom| rom.len() == ROM_SIZE)
+            && self.ram0.len() == HALF_CELL
+            && self
+                .cells
+                .iter()
+                .all(|(i, cell)| *i < 255 && cell.len() == 256 * 256)
+    }
 }
 pub trait Io {
     fn read(&mut self, addr: u8) -> u8;
     fn write(&mut self, addr: u8, val: u8);
+    /// Gives the peripherals a way to wake up the machine when it is waiting
+    fn set_notifier(&mut self, _notifier: Notifier) {}
+    /// Called once before every instruction, returns the number of a requested interrupt
+    fn tick(&mut self) -> Option<u8> {
+        None
+    }
+    /// Called when the machine starts waiting, returns how long it may wait at most before
+    /// the next [`Self::tick`] requests an interrupt, or `None` if only a notification can wake it
+    fn idle(&mut self) -> Option<Duration> {
+        None
+    }
+}
+
+impl<I: Io + ?Sized> Io for Box<I> {
+    fn read(&mut self, addr: u8) -> u8 {
+        (**self).read(addr)
+    }
+    fn write(&mut self, addr: u8, val: u8) {
+        (**self).write(addr, val)
+    }
+    fn set_notifier(&mut self, notifier: Notifier) {
+        (**self).set_notifier(notifier)
+    }
+    fn tick(&mut self) -> Option<u8> {
+        (**self).tick()
+    }
+    fn idle(&mut self) -> Option<Duration> {
+        (**self).idle()
+    }
 }
 
 pub struct PanickingIO;
@@ -0,0 +1,105 @@
+use std::{cell::RefCell, fmt, ops::RangeInclusive, rc::Rc, time::Duration};
+
+use super::{Io, Notifier};
+
+/// A device shared between the clones of a [`PortBus`]
+type SharedDevice = Rc<RefCell<dyn Io>>;
+
+/// Port space separate from memory, used by the `in` and `out` instructions
+///
+/// Devices are given the port number itself, like they are given the address when mapped
+/// into memory. Ports without a device read as `0xff` and ignore writes.
+/// A clone of the bus shares its devices with the original
+#[derive(Default, Clone)]
+pub struct PortBus {
+    devices: Vec<(RangeInclusive<u8>, SharedDevice)>,
+}
+
+impl PortBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Puts `device` at `ports`, taking precedence over devices registered before it
+    pub fn register(&mut self, ports: RangeInclusive<u8>, device: impl Io + 'static) {
+        self.devices.push((ports, Rc::new(RefCell::new(device))));
+    }
+    fn device(&self, port: u8) -> Option<&RefCell<dyn Io>> {
+        self.devices
+            .iter()
+            .rev()
+            .find(|(ports, _)| ports.contains(&port))
+            .map(|(_, device)| &**device)
+    }
+    pub fn read(&mut self, port: u8) -> u8 {
+        match self.device(port) {
+            Some(device) => device.borrow_mut().read(port),
+            None => 0xff,
+        }
+    }
+    pub fn write(&mut self, port: u8, val: u8) {
+        if let Some(device) = self.device(port) {
+            device.borrow_mut().write(port, val);
+        }
+    }
+    pub fn set_notifier(&mut self, notifier: Notifier) {
+        for (_, device) in &self.devices {
+            device.borrow_mut().set_notifier(notifier.clone());
+        }
+    }
+    /// Ticks every device, giving the first requested interrupt
+    pub fn tick(&mut self) -> Option<u8> {
+        self.devices
+            .iter()
+            .fold(None, |irq, (_, device)| irq.or(device.borrow_mut().tick()))
+    }
+    /// The shortest time any device may wait before requesting an interrupt
+    pub fn idle(&mut self) -> Option<Duration> {
+        self.devices
+            .iter()
+            .filter_map(|(_, device)| device.borrow_mut().idle())
+            .min()
+    }
+}
+
+impl fmt::Debug for PortBus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.devices.iter().map(|(ports, _)| ports))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Requests its interrupt on every tick and counts them
+    struct Requester(u8, Rc<RefCell<u32>>);
+    impl Io for Requester {
+        fn read(&mut self, _port: u8) -> u8 {
+            self.0
+        }
+        fn write(&mut self, _port: u8, _val: u8) {}
+        fn tick(&mut self) -> Option<u8> {
+            *self.1.borrow_mut() += 1;
+            Some(self.0)
+        }
+    }
+
+    #[test]
+    fn first_interrupt_wins() {
+        let ticks = Rc::new(RefCell::new(0));
+        let mut bus = PortBus::new();
+        bus.register(0..=0, Requester(3, ticks.clone()));
+        bus.register(1..=1, Requester(5, ticks.clone()));
+
+        assert_eq!(bus.tick(), Some(3));
+        // every device is ticked
+        assert_eq!(*ticks.borrow(), 2);
+
+        let mut clone = bus.clone();
+        assert_eq!(clone.read(1), 5);
+        assert_eq!(clone.tick(), Some(3));
+        assert_eq!(*ticks.borrow(), 4);
+    }
+}
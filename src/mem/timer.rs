@@ -0,0 +1,247 @@
+use std::time::{Duration, Instant};
+
+use super::{Io, IRQ_TIMER};
+
+/// Offset of the low byte of the reload value in microseconds
+pub const TIMER_LOAD_LO: u8 = 0;
+/// Offset of the high byte of the reload value in microseconds
+pub const TIMER_LOAD_HI: u8 = 1;
+/// Offset of the control register, see the `TIMER_CTRL_` constants
+pub const TIMER_CTRL: u8 = 2;
+/// Offset of the status register, see the `TIMER_STATUS_` constants
+pub const TIMER_STATUS: u8 = 3;
+
+pub const TIMER_CTRL_ENABLE: u8 = 0b001;
+/// Starts the timer again when it expires, otherwise it disables itself
+pub const TIMER_CTRL_AUTO_RELOAD: u8 = 0b010;
+/// Requests an interrupt while the timer has expired
+pub const TIMER_CTRL_INTERRUPT: u8 = 0b100;
+
+/// Set when the timer expires, reading the status register clears it
+pub const TIMER_STATUS_EXPIRED: u8 = 0b1;
+
+/// What a [`Timer`] counts the passing of time in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerClock {
+    /// Every instruction counts as one microsecond, so the timer is deterministic
+    Instructions,
+    WallClock,
+}
+
+/// Timer that counts down from its reload value and can request an interrupt when it expires
+///
+/// Occupies four ports starting at its base port.
+/// A machine waiting for an interrupt is woken up when the timer expires. In
+/// [`TimerClock::Instructions`] mode no instructions run while waiting, so the wait counts as
+/// the instructions that were left until it expires
+pub struct Timer {
+    base: u8,
+    clock: TimerClock,
+    load: u16,
+    ctrl: u8,
+    expired: bool,
+    /// Microseconds until the timer expires
+    remaining: u64,
+    last_tick: Instant,
+}
+
+impl Timer {
+    pub fn new(base: u8, clock: TimerClock) -> Self {
+        Timer {
+            base,
+            clock,
+            load: 0,
+            ctrl: 0,
+            expired: false,
+            remaining: 0,
+            last_tick: Instant::now(),
+        }
+    }
+    fn elapsed(&mut self) -> u64 {
+        match self.clock {
+            TimerClock::Instructions => 1,
+            TimerClock::WallClock => {
+                let micros = self.last_tick.elapsed().as_micros() as u64;
+                // keep the fraction of a microsecond for the next tick
+                self.last_tick += Duration::from_micros(micros);
+                micros
+            }
+        }
+    }
+    fn start(&mut self) {
+        self.remaining = self.load as u64;
+        self.last_tick = Instant::now();
+    }
+}
+
+impl Io for Timer {
+    fn read(&mut self, addr: u8) -> u8 {
+        match addr.wrapping_sub(self.base) {
+            TIMER_LOAD_LO => self.load as u8,
+            TIMER_LOAD_HI => (self.load >> 8) as u8,
+            TIMER_CTRL => self.ctrl,
+            TIMER_STATUS => {
+                let expired = self.expired;
+                self.expired = false;
+                if expired {
+                    TIMER_STATUS_EXPIRED
+                } else {
+                    0
+                }
+            }
+            _ => 0,
+        }
+    }
+    fn write(&mut self, addr: u8, val: u8) {
+        match addr.wrapping_sub(self.base) {
+            TIMER_LOAD_LO => self.load = self.load & 0xff00 | val as u16,
+            TIMER_LOAD_HI => self.load = self.load & 0x00ff | (val as u16) << 8,
+            TIMER_CTRL => {
+                let was_enabled = self.ctrl & TIMER_CTRL_ENABLE != 0;
+                self.ctrl = val;
+                if !was_enabled && val & TIMER_CTRL_ENABLE != 0 {
+                    self.start();
+                }
+            }
+            _ => (),
+        }
+    }
+    fn tick(&mut self) -> Option<u8> {
+        if self.ctrl & TIMER_CTRL_ENABLE != 0 {
+            let elapsed = self.elapsed();
+            self.remaining = self.remaining.saturating_sub(elapsed);
+            if self.remaining == 0 {
+                self.expired = true;
+                if self.ctrl & TIMER_CTRL_AUTO_RELOAD != 0 {
+                    self.start();
+                } else {
+                    self.ctrl &= !TIMER_CTRL_ENABLE;
+                }
+            }
+        }
+
+        (self.expired && self.ctrl & TIMER_CTRL_INTERRUPT != 0).then_some(IRQ_TIMER)
+    }
+    fn idle(&mut self) -> Option<Duration> {
+        if self.ctrl & TIMER_CTRL_INTERRUPT == 0 {
+            return None;
+        }
+        if self.expired {
+            return Some(Duration::ZERO);
+        }
+        if self.ctrl & TIMER_CTRL_ENABLE == 0 {
+            return None;
+        }
+        match self.clock {
+            TimerClock::Instructions => {
+                // the next tick expires the timer
+                self.remaining = 1;
+                Some(Duration::ZERO)
+            }
+            TimerClock::WallClock => {
+                let remaining = Duration::from_micros(self.remaining);
+                Some(remaining.saturating_sub(self.last_tick.elapsed()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        blf4::{isa::*, Blf4, TrapMode},
+        machine::Machine,
+        mem::{LazyMain, MainMemory},
+    };
+
+    #[test]
+    fn one_shot() {
+        let mut timer = Timer::new(0x10, TimerClock::Instructions);
+        timer.write(0x10 + TIMER_LOAD_LO, 3);
+        timer.write(0x10 + TIMER_CTRL, TIMER_CTRL_ENABLE);
+
+        assert_eq!(timer.tick(), None);
+        assert_eq!(timer.read(0x10 + TIMER_STATUS), 0);
+        assert_eq!(timer.tick(), None);
+        // expires without an interrupt since it is not enabled
+        assert_eq!(timer.tick(), None);
+        assert_eq!(timer.read(0x10 + TIMER_CTRL), 0);
+        assert_eq!(timer.read(0x10 + TIMER_STATUS), TIMER_STATUS_EXPIRED);
+        assert_eq!(timer.read(0x10 + TIMER_STATUS), 0);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn interrupts_handled() {
+        let rom = [
+            // rh = handler, r8 = 0x8000 (interrupt count), r3 = 3
+            LDI_W, 0xf0, 0xae, 0x00,
+            LDI_W, 0x80, 0x00, 0x80,
+            LDI_W, 0x30, 0x03, 0x00,
+            // load = 20, long enough for the handler and the loop body to run in between
+            LDI_W, 0x40, TIMER_LOAD_LO, 0x00,
+            LDI_B, 0x10, 20,
+            STORE_BR, 0x40, 0x10,
+            // enable with auto-reload and interrupt
+            LDI_W, 0x40, TIMER_CTRL, 0x00,
+            LDI_B, 0x10, TIMER_CTRL_ENABLE | TIMER_CTRL_AUTO_RELOAD | TIMER_CTRL_INTERRUPT,
+            STORE_BR, 0x40, 0x10,
+            // loop until r5 = [r8] is 3
+            LOAD_WR, 0x58, 0x00,
+            SUB_W, 0x63, 0x50,
+            JNZ, 0xa0, 0x00,
+            // halting would otherwise trap to the handler
+            LDI_W, 0xf0, 0x00, 0x00,
+            HALT,
+            // handler: [r8] += 1 and read status to acknowledge
+            LOAD_WR, 0x58, 0x00,
+            LDI_W, 0x70, 0x01, 0x00,
+            ADD_W, 0x55, 0x70,
+            STORE_WR, 0x80, 0x50,
+            LDI_W, 0x40, TIMER_STATUS, 0x00,
+            LOAD_BR, 0x14, 0x00,
+            RETH,
+        ];
+        let memory = LazyMain::new(Timer::new(0, TimerClock::Instructions)).with_rom(&rom);
+        let mut m = Machine::new(memory, Blf4::new().with_stack_top(0xa000));
+
+        assert_eq!(m.run_until_abort(), TrapMode::Halt);
+        assert_eq!(m.memory.read(0x8000), 3);
+        assert_eq!(m.cpu.stack, 0xa000);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn wakes_up_waiting_machine() {
+        let rom = [
+            // rh = handler, load = 0x800
+            LDI_W, 0xf0, 0x9e, 0x00,
+            LDI_W, 0x40, TIMER_LOAD_HI, 0x00,
+            LDI_B, 0x10, 0x08,
+            STORE_BR, 0x40, 0x10,
+            // enable with interrupt
+            LDI_W, 0x40, TIMER_CTRL, 0x00,
+            LDI_B, 0x10, TIMER_CTRL_ENABLE | TIMER_CTRL_INTERRUPT,
+            STORE_BR, 0x40, 0x10,
+            WAIT,
+            // halting would otherwise trap to the handler
+            LDI_W, 0xf0, 0x00, 0x00,
+            HALT,
+            // handler: [0x8000] = 1 and read status to acknowledge
+            LDI_W, 0x80, 0x00, 0x80,
+            LDI_B, 0x10, 0x01,
+            STORE_BR, 0x80, 0x10,
+            LDI_W, 0x40, TIMER_STATUS, 0x00,
+            LOAD_BR, 0x14, 0x00,
+            RETH,
+        ];
+        for clock in [TimerClock::Instructions, TimerClock::WallClock] {
+            let memory = LazyMain::new(Timer::new(0, clock)).with_rom(&rom);
+            let mut m = Machine::new(memory, Blf4::new().with_stack_top(0xa000));
+
+            assert_eq!(m.run_until_abort(), TrapMode::Halt, "{clock:?}");
+            assert_eq!(m.memory.read(0x8000), 1, "{clock:?}");
+        }
+    }
+}
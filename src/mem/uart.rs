@@ -0,0 +1,113 @@
+use std::{
+    io::{stdin, stdout, BufReader, Read, Write},
+    sync::{
+        mpsc::{self, Receiver},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+use super::{Io, Notifier, IRQ_UART};
+
+/// Offset of the status register, see the `STATUS_` constants
+pub const UART_STATUS: u8 = 0;
+/// Offset of the transmit register, writing a byte here outputs it
+pub const UART_TX: u8 = 1;
+/// Offset of the receive register, reading gives the next received byte or 0 if there is none
+pub const UART_RX: u8 = 2;
+/// Offset of the control register, see the `CTRL_` constants
+pub const UART_CTRL: u8 = 3;
+
+pub const STATUS_TX_READY: u8 = 0b01;
+pub const STATUS_RX_AVAILABLE: u8 = 0b10;
+
+/// Enables the receive interrupt
+pub const CTRL_RX_INTERRUPT: u8 = 0b01;
+
+/// Serial terminal connected to stdin and stdout
+///
+/// Occupies four ports starting at its base port.
+/// Stdin is read on a separate thread so that reads never block the machine.
+pub struct Uart {
+    base: u8,
+    ctrl: u8,
+    rx: Receiver<u8>,
+    received: Option<u8>,
+    /// Notified by the reading thread whenever a byte is received
+    notifier: Arc<Mutex<Option<Notifier>>>,
+}
+
+impl Uart {
+    pub fn new(base: u8) -> Self {
+        let (tx, rx) = mpsc::channel();
+        let notifier: Arc<Mutex<Option<Notifier>>> = Arc::default();
+        let thread_notifier = notifier.clone();
+        thread::spawn(move || {
+            for b in BufReader::new(stdin()).bytes() {
+                let Ok(b) = b else { break };
+                if tx.send(b).is_err() {
+                    break;
+                }
+                if let Some(notifier) = &*thread_notifier.lock().unwrap() {
+                    notifier.notify();
+                }
+            }
+        });
+
+        Uart {
+            base,
+            ctrl: 0,
+            rx,
+            received: None,
+            notifier,
+        }
+    }
+    fn poll(&mut self) -> Option<u8> {
+        if self.received.is_none() {
+            self.received = self.rx.try_recv().ok();
+        }
+        self.received
+    }
+    /// Whether the receive interrupt is enabled and a byte is waiting to be read
+    pub fn interrupt_pending(&mut self) -> bool {
+        self.ctrl & CTRL_RX_INTERRUPT != 0 && self.poll().is_some()
+    }
+}
+
+impl Io for Uart {
+    fn read(&mut self, addr: u8) -> u8 {
+        match addr.wrapping_sub(self.base) {
+            UART_STATUS => {
+                let rx_available = if self.poll().is_some() {
+                    STATUS_RX_AVAILABLE
+                } else {
+                    0
+                };
+                STATUS_TX_READY | rx_available
+            }
+            UART_RX => {
+                self.poll();
+                self.received.take().unwrap_or(0)
+            }
+            UART_CTRL => self.ctrl,
+            _ => 0,
+        }
+    }
+    fn write(&mut self, addr: u8, val: u8) {
+        match addr.wrapping_sub(self.base) {
+            UART_TX => {
+                let mut stdout = stdout();
+                stdout.write_all(&[val]).expect("stdout failed");
+                stdout.flush().expect("stdout failed");
+            }
+            UART_CTRL => self.ctrl = val,
+            _ => (),
+        }
+    }
+    fn set_notifier(&mut self, notifier: Notifier) {
+        *self.notifier.lock().unwrap() = Some(notifier);
+    }
+    fn tick(&mut self) -> Option<u8> {
+        self.interrupt_pending().then_some(IRQ_UART)
+    }
+}
@@ -0,0 +1,292 @@
+//! Saving of processed sources, as object files or as caches so unchanged sources need not be
+//! assembled again
+
+use std::io::{self, BufRead, Read, Seek, Write};
+
+use crate::{
+    aalv::{
+        obj::Object, read_archive_from, write_archive_magic, AalvReader, AalvWriter, MemberName,
+    },
+    source::{line_number_table, ProcessedSource, Warning},
+};
+
+/// Extension of cached processed sources
+pub const CACHE_EXT: &str = "tobj";
+
+pub fn save_object(src: &ProcessedSource, w: impl Write) -> bincode::Result<()> {
+    bincode::serialize_into(w, src)
+}
+
+pub fn load_object(r: impl Read) -> bincode::Result<ProcessedSource> {
+    bincode::deserialize_from(r)
+}
+
+/// Start of a cache file, followed by [`CACHE_VERSION`]
+const CACHE_MAGIC: [u8; 4] = *b"tobj";
+/// Bumped whenever a change to [`ProcessedSource`] or [`Warning`] changes how they are serialised
+pub const CACHE_VERSION: u32 = 1;
+
+/// Saves a processed source with the warnings it was assembled with as a cache
+pub fn save_cache(
+    src: &ProcessedSource,
+    warnings: &[Warning],
+    mut w: impl Write,
+) -> bincode::Result<()> {
+    w.write_all(&CACHE_MAGIC)?;
+    w.write_all(&CACHE_VERSION.to_le_bytes())?;
+    bincode::serialize_into(w, &(src, warnings))
+}
+
+/// Loads a cache saved by [`save_cache`], failing if it is of another version
+pub fn load_cache(mut r: impl Read) -> bincode::Result<(ProcessedSource, Vec<Warning>)> {
+    let mut header = [0; 8];
+    r.read_exact(&mut header)?;
+    if header[..4] != CACHE_MAGIC || header[4..] != CACHE_VERSION.to_le_bytes() {
+        return Err(Box::new(bincode::ErrorKind::Custom(
+            "not a cache file of this version".to_owned(),
+        )));
+    }
+    bincode::deserialize_from(r)
+}
+
+/// Writes the source as an object file with a line number table if it fits
+///
+/// Seeking is needed to write the lengths of the sections
+pub fn write_obj(src: &ProcessedSource, w: impl Write + Seek) -> io::Result<()> {
+    let mut obj = src.to_object();
+    obj.line_numbers = line_number_table(&src.source_map);
+    obj.write(&mut AalvWriter::new(w, 0)?)
+}
+
+/// Reads an object file as a processed source, see [`ProcessedSource::from_object`]
+pub fn read_obj(r: impl BufRead + Seek) -> io::Result<ProcessedSource> {
+    let obj = Object::from_aalv_reader(&mut AalvReader::new(r)?)?;
+    ProcessedSource::from_object(obj)
+}
+
+/// Writes the sources as an archive of object files with the names as their member names
+pub fn create_archive(
+    objects: &[(&str, ProcessedSource)],
+    mut w: impl Write + Seek,
+) -> io::Result<()> {
+    write_archive_magic(&mut w)?;
+    for (name, src) in objects {
+        let mut obj = src.to_object();
+        obj.line_numbers = line_number_table(&src.source_map);
+
+        let offset = w.stream_position()?;
+        let mut aalvur = AalvWriter::new(&mut w, offset)?;
+        aalvur.write_section(&MemberName((*name).into()))?;
+        obj.write(&mut aalvur)?;
+    }
+    Ok(())
+}
+
+/// Names of the members of an archive in order
+///
+/// Members without a name are named by their position starting from 1
+pub fn list_archive(r: impl BufRead + Seek) -> io::Result<Vec<String>> {
+    Ok(extract_members(r, |_| Ok(()))?
+        .into_iter()
+        .map(|(name, ())| name)
+        .collect())
+}
+
+/// Reads every member of an archive as a processed source along with its name
+///
+/// Members without a name are named by their position starting from 1
+pub fn extract_archive(r: impl BufRead + Seek) -> io::Result<Vec<(String, ProcessedSource)>> {
+    extract_members(r, |aalvur| {
+        let obj = Object::from_aalv_reader(aalvur)?;
+        ProcessedSource::from_object(obj)
+    })
+}
+
+fn extract_members<R: BufRead + Seek, T>(
+    r: R,
+    mut load: impl FnMut(&mut AalvReader<R>) -> io::Result<T>,
+) -> io::Result<Vec<(String, T)>> {
+    let mut archive = read_archive_from(r)?;
+    let mut members = Vec::new();
+    while let Some((name, member)) = archive.next(|aalvur| {
+        let name = aalvur.read_section::<MemberName>().transpose()?;
+        Ok((name, load(aalvur)?))
+    })? {
+        let name = match name {
+            Some(MemberName(name)) => name.into(),
+            None => (members.len() + 1).to_string(),
+        };
+        members.push((name, member));
+    }
+    Ok(members)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fmt::Write as _, io::Cursor};
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+    use crate::{
+        aalv::obj::{RelocationEntry, SegmentType},
+        source::{process, ProcessedSource, Recovery, SourceLines, SymbolType},
+    };
+
+    #[test]
+    fn round_trip() {
+        let src = ".seg text\n.entry\nmain:\nldi r1, msg\nhalt\n.seg rodata\nmsg:\n.byte 1, 2\n";
+        let ps = ProcessedSource::assemble(src).unwrap();
+
+        let mut buf = Vec::new();
+        save_object(&ps, &mut buf).unwrap();
+        let loaded = load_object(&buf[..]).unwrap();
+
+        assert_eq!(loaded.find_label("msg"), ps.find_label("msg"));
+        assert_eq!(loaded.dls.len(), ps.dls.len());
+        assert_eq!(format!("{:?}", loaded.dls), format!("{:?}", ps.dls));
+    }
+
+    #[test]
+    fn cache_keeps_warnings() {
+        let (ps, _, warnings) = process(
+            SourceLines::from_str(".seg text\nunused:\nhalt\n"),
+            Recovery::Continue,
+        );
+        let ps = ps.unwrap();
+        assert!(!warnings.is_empty());
+
+        let mut buf = Vec::new();
+        save_cache(&ps, &warnings, &mut buf).unwrap();
+        let (loaded, loaded_warnings) = load_cache(&buf[..]).unwrap();
+        assert_eq!(format!("{:?}", loaded.dls), format!("{:?}", ps.dls));
+        assert_eq!(format!("{loaded_warnings:?}"), format!("{warnings:?}"));
+
+        // another version is not read
+        buf[4] ^= 0xff;
+        assert!(load_cache(&buf[..]).is_err());
+    }
+
+    /// Makes a random source with labels used across segments
+    fn random_source(rng: &mut StdRng) -> String {
+        let mut src = String::from(".ref ext\n.global main\n.seg text\n.entry\nmain:\n");
+        let mut labels = vec!["main".to_owned(), "ext".to_owned()];
+        for i in 0..rng.gen_range(1..40) {
+            let label = &labels[rng.gen_range(0..labels.len())];
+            match rng.gen_range(0..8) {
+                0 => writeln!(
+                    src,
+                    ".seg {}",
+                    ["text", "rodata", "data"][rng.gen_range(0..3)]
+                ),
+                1 => {
+                    labels.push(format!("l{i}"));
+                    writeln!(src, "l{i}:")
+                }
+                2 => writeln!(src, "    ldi r{}, {label}", rng.gen_range(1..10)),
+                3 => writeln!(src, "    call {label}"),
+                4 => writeln!(src, "    .wide {label}, {}", rng.gen::<u16>()),
+                5 => writeln!(src, "    .byte {}", rng.gen::<u8>()),
+                6 => writeln!(src, "    add r1, r2, {}", rng.gen::<u8>()),
+                _ => writeln!(src, "    halt"),
+            }
+            .unwrap();
+        }
+        if rng.gen() {
+            src.push_str(".seg stack\n.byte 0, 0, 0, 0\n");
+        }
+        src
+    }
+
+    #[test]
+    fn object_round_trip() {
+        let mut rng = StdRng::seed_from_u64(0x7e1da);
+        for _ in 0..64 {
+            let src = random_source(&mut rng);
+            let ps = ProcessedSource::assemble(&src).unwrap_or_else(|e| panic!("{e}\nin:\n{src}"));
+
+            let mut buf = Cursor::new(Vec::new());
+            write_obj(&ps, &mut buf).unwrap();
+            buf.set_position(0);
+            let read = read_obj(buf).unwrap();
+
+            assert_eq!(read.labels(), ps.labels(), "{src}");
+            assert_eq!(read.relocations, ps.relocations, "{src}");
+            assert_eq!(read.source_map, ps.source_map, "{src}");
+            assert_eq!(read.entry.map(|e| (e.0, e.1)), ps.entry.map(|e| (e.0, e.1)));
+            assert_eq!(read.stack_size(), ps.stack_size());
+            for st in [SegmentType::Text, SegmentType::RoData, SegmentType::Data] {
+                assert_eq!(read.encode_segment(st).0, ps.encode_segment(st).0, "{src}");
+            }
+            assert_eq!(read.checksum(), ps.checksum(), "{src}");
+        }
+    }
+
+    #[test]
+    fn weak_symbols() {
+        let src =
+            ".weak handler\n.weak main\n.global main\n.seg text\n.entry\nmain:\nhandler:\nhalt\n";
+        let ps = ProcessedSource::assemble(src).unwrap();
+        let types: Vec<_> = ps.labels().iter().map(|l| (&*l.0, l.1)).collect();
+        // the global declaration wins over the weak one
+        assert_eq!(
+            types,
+            [("handler", SymbolType::Weak), ("main", SymbolType::Global)]
+        );
+
+        let obj = ps.to_object();
+        assert_eq!(
+            obj.weak.as_ref().map(|w| &*w.0),
+            Some(&["handler".into()][..])
+        );
+        assert!(obj.symbols.iter().all(|s| s.is_global));
+
+        let mut buf = Cursor::new(Vec::new());
+        write_obj(&ps, &mut buf).unwrap();
+        buf.set_position(0);
+        assert_eq!(read_obj(buf).unwrap().labels(), ps.labels());
+    }
+
+    #[test]
+    fn malformed_relocation() {
+        let ps = ProcessedSource::assemble(".seg text\n.entry\nmain:\ncall main\n").unwrap();
+        assert_eq!(ps.to_object().relocation_table.0.len(), 1);
+
+        let breakages: [fn(&mut RelocationEntry); 3] = [
+            |r| r.reference_location = 0,
+            |r| r.reference_location += 2,
+            |r| r.symbol_index = 1,
+        ];
+        for (i, break_reloc) in breakages.into_iter().enumerate() {
+            let mut obj = ps.to_object();
+            break_reloc(&mut obj.relocation_table.0[0]);
+            let e = ProcessedSource::from_object(obj).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData, "{i}");
+        }
+        assert!(ProcessedSource::from_object(ps.to_object()).is_ok());
+    }
+
+    #[test]
+    fn archive() {
+        let a = ProcessedSource::assemble(".global f\n.seg text\nf:\nret 0\n").unwrap();
+        let b = ProcessedSource::assemble(".ref f\n.seg text\n.entry\ncall f\nhalt\n").unwrap();
+
+        let mut buf = Cursor::new(Vec::new());
+        create_archive(&[("a.to", a.clone()), ("b.to", b.clone())], &mut buf).unwrap();
+        // a member from an object file without a name like `tar` used to make
+        let mut obj = Cursor::new(Vec::new());
+        write_obj(&b, &mut obj).unwrap();
+        buf.get_mut().extend(obj.into_inner());
+
+        buf.set_position(0);
+        assert_eq!(list_archive(&mut buf).unwrap(), ["a.to", "b.to", "3"]);
+        buf.set_position(0);
+        let members = extract_archive(&mut buf).unwrap();
+        assert_eq!(members.len(), 3);
+        assert_eq!(members[0].1.labels(), a.labels());
+        assert_eq!(members[1].1.labels(), b.labels());
+        assert_eq!(members[2].1.checksum(), b.checksum());
+
+        assert!(list_archive(Cursor::new(b"not an archive")).is_err());
+    }
+}
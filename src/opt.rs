@@ -0,0 +1,255 @@
+//! Peephole optimisation of assembled instructions
+//!
+//! The patterns look at a few instructions at a time in the data lines of a [`ProcessedSource`].
+//! When a replacement makes the code shorter, the labels and entry point after it are moved back,
+//! except in segments with an alignment where it is padded with `nop`s to keep the addresses
+
+use crate::{
+    aalv::obj::SegmentType,
+    blf4::{isa::*, R0, R0B, R1},
+    source::{DataLine, DataOperand, ProcessedSource, Wide},
+    U4,
+};
+
+/// A replacement the optimiser can do
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pattern {
+    /// `push r` right before `pop r` is removed
+    PushPop,
+    /// A `jmp` to the instruction right after it is removed
+    JmpNext,
+    /// `ldi r, 0` becomes `xor r, r, r`, which sets the flags unlike `ldi`
+    LdiZero,
+    /// `add r, r, 0` and `sub r, r, 0` are removed, so the flags are not set
+    AddZero,
+}
+
+impl Pattern {
+    pub const ALL: [Pattern; 4] = [
+        Pattern::PushPop,
+        Pattern::JmpNext,
+        Pattern::LdiZero,
+        Pattern::AddZero,
+    ];
+
+    /// The optimisation level that enables the pattern
+    ///
+    /// Patterns of level 1 never change what the program does, those of level 2 can change
+    /// the flags which only matters to code that reads flags set before the replaced instruction
+    pub const fn level(self) -> u8 {
+        match self {
+            Pattern::PushPop | Pattern::JmpNext => 1,
+            Pattern::LdiZero | Pattern::AddZero => 2,
+        }
+    }
+    /// The patterns enabled by an optimisation level
+    pub fn for_level(level: u8) -> Vec<Pattern> {
+        Self::ALL
+            .into_iter()
+            .filter(|p| p.level() <= level)
+            .collect()
+    }
+}
+
+/// What a pattern found at a line
+struct Replacement {
+    /// How many lines are replaced
+    lines: usize,
+    with: Vec<DataLine>,
+}
+
+/// Applies the patterns to every instruction of the source, giving how many replacements were made
+pub fn optimize(src: &mut ProcessedSource, patterns: &[Pattern]) -> usize {
+    let mut replacements = 0;
+    let segments: Vec<_> = src.dls.keys().copied().collect();
+
+    for st in segments {
+        let mut i = 0;
+        let mut offset = 0;
+        while i < src.dls[&st].lines.len() {
+            let addr = src.dls[&st].start + offset;
+            let found = patterns.iter().find_map(|&p| find(p, src, st, i, addr));
+            let Some(Replacement { lines, mut with }) = found else {
+                offset += src.dls[&st].lines[i].size();
+                i += 1;
+                continue;
+            };
+            replacements += 1;
+
+            let dls = src.dls.get_mut(&st).expect("segment exists");
+            let old_size: u16 = dls.lines[i..i + lines].iter().map(DataLine::size).sum();
+            let mut new_size: u16 = with.iter().map(DataLine::size).sum();
+            if dls.layout.alignment > 1 {
+                for _ in new_size..old_size {
+                    with.push(DataLine::Ins(NOP, DataOperand::Nothing));
+                }
+                new_size = old_size;
+            }
+
+            let location = dls.locations[i].clone();
+            let count = with.len();
+            dls.lines.splice(i..i + lines, with);
+            dls.locations
+                .splice(i..i + lines, std::iter::repeat_n(location, count));
+            dls.size -= old_size - new_size;
+
+            // anything after the replaced lines moves back by what they shrank
+            let moved = |a: &mut u16| {
+                if *a > addr {
+                    *a = (*a).max(addr + old_size) - (old_size - new_size);
+                }
+            };
+            for label in src.labels_mut() {
+                if label.2 == st {
+                    moved(&mut label.3);
+                }
+            }
+            if let Some(entry) = &mut src.entry {
+                if entry.0 == st {
+                    moved(&mut entry.1);
+                }
+            }
+            // the replacement is looked at again in case it makes another pattern fit
+        }
+    }
+
+    if replacements > 0 {
+        src.lines_changed();
+    }
+    replacements
+}
+
+/// Whether a label or the entry point is at the address
+fn is_target(src: &ProcessedSource, st: SegmentType, addr: u16) -> bool {
+    src.labels().iter().any(|l| l.2 == st && l.3 == addr)
+        || src.entry.is_some_and(|e| e.0 == st && e.1 == addr)
+}
+
+fn find(
+    pattern: Pattern,
+    src: &ProcessedSource,
+    st: SegmentType,
+    i: usize,
+    addr: u16,
+) -> Option<Replacement> {
+    use self::DataOperand::*;
+
+    let lines = &src.dls[&st].lines;
+    let DataLine::Ins(opcode, dat_op) = lines[i] else {
+        return None;
+    };
+    let removed = |lines| {
+        Some(Replacement {
+            lines,
+            with: Vec::new(),
+        })
+    };
+
+    match pattern {
+        Pattern::PushPop => {
+            let &DataLine::Ins(next, next_op) = lines.get(i + 1)? else {
+                return None;
+            };
+            let pair = matches!(
+                (opcode, dat_op, next),
+                (PUSH_B, ByteRegister(_), POP_B) | (PUSH_W, WideRegister(_), POP_W)
+            );
+            // the pop could be jumped to on its own
+            let pop_addr = addr + 1 + dat_op.size();
+            if pair && dat_op == next_op && !is_target(src, st, pop_addr) {
+                removed(2)
+            } else {
+                None
+            }
+        }
+        Pattern::JmpNext => match (opcode, dat_op) {
+            (LDI_W, TwoWideImm(R0, R1, Wide::Label(id))) => {
+                let (_, _, label_st, label_addr) = &src.labels()[id];
+                let next = addr + 1 + dat_op.size();
+                if *label_st == st && *label_addr == next {
+                    removed(1)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        },
+        Pattern::LdiZero => {
+            let xor = match (opcode, dat_op) {
+                (LDI_B, ByteImm(r, 0)) if r != R0B => DataLine::Ins(XOR_B, ThreeByte(r, r, r)),
+                (LDI_W, WideNibbleByte(r, n, 0)) if r != R0 && n == U4::new(2) => {
+                    DataLine::Ins(XOR_W, ThreeWide(r, r, r))
+                }
+                (LDI_W, TwoWideImm(r, R0, Wide::Number(0))) if r != R0 => {
+                    DataLine::Ins(XOR_W, ThreeWide(r, r, r))
+                }
+                _ => return None,
+            };
+            Some(Replacement {
+                lines: 1,
+                with: vec![xor],
+            })
+        }
+        Pattern::AddZero => match (opcode, dat_op) {
+            (ADD_B_IMM | SUB_B_IMM, TwoByteImm(r1, r2, 0)) if r1 == r2 => removed(1),
+            (ADD_W_IMM | SUB_W_IMM, TwoWideImm(r1, r2, Wide::Number(0))) if r1 == r2 => removed(1),
+            _ => None,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn optimized(src: &str, level: u8) -> (ProcessedSource, usize) {
+        let mut ps = ProcessedSource::assemble(src).unwrap();
+        let n = optimize(&mut ps, &Pattern::for_level(level));
+        (ps, n)
+    }
+
+    fn text(ps: &ProcessedSource) -> Vec<u8> {
+        ps.encode_segment(SegmentType::Text).0
+    }
+
+    #[test]
+    fn removes_dead_code() {
+        let src =
+            ".seg text\n.entry\nmain:\npush r1\npop r1\njmp next\nnext:\nldi r2, 0\njmp main\n";
+        let (ps, n) = optimized(src, 1);
+        assert_eq!(n, 2);
+        assert_eq!(ps.find_label("next"), Some(0x80));
+        assert_eq!(ps.entry.map(|e| e.1), Some(0x80));
+        assert_eq!(ps.dls[&SegmentType::Text].size, 7);
+        assert_eq!(text(&ps), [LDI_W, 0x22, 0, LDI_W, 0x01, 0x80, 0x00]);
+        // the jump back to main is relocated to its new address
+        assert_eq!(ps.relocations.len(), 1);
+        assert_eq!(ps.relocations[0].offset, 5);
+        assert_eq!(ps.source_map[1].address, 0x83);
+    }
+
+    #[test]
+    fn keeps_jump_targets() {
+        let src = ".seg text\npush r1\nagain:\npop r1\njmp again\n";
+        let (_, n) = optimized(src, 1);
+        assert_eq!(n, 0);
+    }
+
+    #[test]
+    fn aggressive() {
+        let src = ".seg text\nldi r1l, 0\nldi r2, 0\nadd r3, r3, 0\nsub r4l, r4l, 0\nhalt\n";
+        assert_eq!(optimized(src, 1).1, 0);
+        let (ps, n) = optimized(src, 2);
+        assert_eq!(n, 4);
+        assert_eq!(text(&ps), [XOR_B, 0x11, 0x10, XOR_W, 0x22, 0x20, HALT]);
+    }
+
+    #[test]
+    fn aligned_segment_is_padded() {
+        let src = ".seg text\n.align 4\npush r1\npop r1\nend:\nhalt\n";
+        let (ps, n) = optimized(src, 1);
+        assert_eq!(n, 1);
+        assert_eq!(ps.find_label("end"), Some(0x84));
+        assert_eq!(text(&ps), [NOP, NOP, NOP, NOP, HALT]);
+    }
+}
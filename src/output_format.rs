@@ -0,0 +1,254 @@
+//! Formats for writing the assembled segments of a program without the symbols of an object file
+//!
+//! Segments are given as their start address and bytes, sorted by address
+
+use std::io::{self, Write};
+
+use crate::bin_format::{TeldaElf, DATA_START, TEXT_START};
+
+/// Extension of raw binaries
+pub const RAW_EXT: &str = "bin";
+/// Extension of Intel HEX files
+pub const IHEX_EXT: &str = "hex";
+/// Extension of Motorola S-record files
+pub const SREC_EXT: &str = "srec";
+
+pub trait OutputFormat {
+    fn write(
+        &self,
+        entry: Option<u16>,
+        segments: &[(u16, &[u8])],
+        w: &mut dyn Write,
+    ) -> io::Result<()>;
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Memory from `origin` up to the end of the last segment with the gaps filled with zeroes
+fn image(origin: u16, segments: &[(u16, &[u8])]) -> io::Result<Vec<u8>> {
+    let mut mem = Vec::new();
+    for &(start, bytes) in segments {
+        if bytes.is_empty() {
+            continue;
+        }
+        let offset = start
+            .checked_sub(origin)
+            .ok_or_else(|| invalid(format!("segment at 0x{start:04x} is below 0x{origin:04x}")))?
+            as usize;
+        if mem.len() < offset + bytes.len() {
+            mem.resize(offset + bytes.len(), 0);
+        }
+        mem[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+    Ok(mem)
+}
+
+/// Memory from 0x0080 as it is loaded by `t -r`, execution starts at the beginning
+///
+/// The entry point has to be at 0x0080 if there is one
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RawBinary;
+
+impl OutputFormat for RawBinary {
+    fn write(
+        &self,
+        entry: Option<u16>,
+        segments: &[(u16, &[u8])],
+        w: &mut dyn Write,
+    ) -> io::Result<()> {
+        if let Some(entry) = entry.filter(|&e| e != TEXT_START) {
+            return Err(invalid(format!(
+                "raw binaries start at 0x{TEXT_START:04x}, not at the entry point 0x{entry:04x}"
+            )));
+        }
+        w.write_all(&image(TEXT_START, segments)?)
+    }
+}
+
+/// Intel HEX with 16 bytes per data record and the entry point as a start segment address
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IntelHex;
+
+impl IntelHex {
+    fn record(w: &mut dyn Write, addr: u16, record_type: u8, data: &[u8]) -> io::Result<()> {
+        let mut bytes = vec![data.len() as u8];
+        bytes.extend(addr.to_be_bytes());
+        bytes.push(record_type);
+        bytes.extend_from_slice(data);
+        let sum = bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+        bytes.push(sum.wrapping_neg());
+
+        write!(w, ":")?;
+        for b in bytes {
+            write!(w, "{b:02X}")?;
+        }
+        writeln!(w)
+    }
+}
+
+impl OutputFormat for IntelHex {
+    fn write(
+        &self,
+        entry: Option<u16>,
+        segments: &[(u16, &[u8])],
+        w: &mut dyn Write,
+    ) -> io::Result<()> {
+        for &(start, bytes) in segments {
+            for (i, chunk) in bytes.chunks(16).enumerate() {
+                Self::record(w, start + 16 * i as u16, 0x00, chunk)?;
+            }
+        }
+        if let Some(entry) = entry {
+            let [hi, lo] = entry.to_be_bytes();
+            Self::record(w, 0, 0x03, &[0, 0, hi, lo])?;
+        }
+        Self::record(w, 0, 0x01, &[])
+    }
+}
+
+/// Motorola S-records with 16-bit addresses and 16 bytes per data record
+///
+/// The termination record has the entry point, or 0 if there is none
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MotorolaSRec;
+
+impl MotorolaSRec {
+    fn record(w: &mut dyn Write, record_type: u8, addr: u16, data: &[u8]) -> io::Result<()> {
+        let mut bytes = vec![data.len() as u8 + 3];
+        bytes.extend(addr.to_be_bytes());
+        bytes.extend_from_slice(data);
+        let sum = bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+        bytes.push(!sum);
+
+        write!(w, "S{record_type}")?;
+        for b in bytes {
+            write!(w, "{b:02X}")?;
+        }
+        writeln!(w)
+    }
+}
+
+impl OutputFormat for MotorolaSRec {
+    fn write(
+        &self,
+        entry: Option<u16>,
+        segments: &[(u16, &[u8])],
+        w: &mut dyn Write,
+    ) -> io::Result<()> {
+        Self::record(w, 0, 0, b"telda")?;
+        for &(start, bytes) in segments {
+            for (i, chunk) in bytes.chunks(16).enumerate() {
+                Self::record(w, 1, start + 16 * i as u16, chunk)?;
+            }
+        }
+        Self::record(w, 9, entry.unwrap_or(0), &[])
+    }
+}
+
+/// Flat binary with a header, see [`TeldaElf`]
+///
+/// Segments below [`DATA_START`] go in ROM and the rest in RAM
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Telf {
+    /// Checksum of the source the segments were assembled from, or 0 if it is not known
+    pub checksum: u32,
+}
+
+impl OutputFormat for Telf {
+    fn write(
+        &self,
+        entry: Option<u16>,
+        segments: &[(u16, &[u8])],
+        w: &mut dyn Write,
+    ) -> io::Result<()> {
+        let (rom, ram): (Vec<_>, Vec<_>) = segments
+            .iter()
+            .filter(|(_, bytes)| !bytes.is_empty())
+            .partition(|&&(start, _)| start < DATA_START);
+        if let Some(&(start, bytes)) = rom
+            .iter()
+            .find(|&&(start, bytes)| start as usize + bytes.len() > DATA_START as usize)
+        {
+            return Err(invalid(format!(
+                "segment at 0x{start:04x} of 0x{:x} bytes crosses from ROM into RAM at 0x{DATA_START:04x}",
+                bytes.len()
+            )));
+        }
+
+        let elf = TeldaElf::new(
+            entry.unwrap_or(TEXT_START),
+            image(TEXT_START, &rom)?,
+            image(DATA_START, &ram)?,
+        )
+        .with_checksum(self.checksum);
+        if !elf.fits() {
+            return Err(invalid("segments are too big for a flat binary".to_owned()));
+        }
+        w.write_all(&elf.to_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn written(format: &dyn OutputFormat, entry: Option<u16>, segments: &[(u16, &[u8])]) -> String {
+        let mut out = Vec::new();
+        format.write(entry, segments, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn intel_hex() {
+        let out = written(&IntelHex, None, &[(0x0030, &[0x02, 0x33, 0x7a])]);
+        assert_eq!(out, ":0300300002337A1E\n:00000001FF\n");
+
+        let bytes: Vec<u8> = (0..20).collect();
+        let out = written(&IntelHex, Some(0x0080), &[(0x0080, &bytes)]);
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with(":10008000000102"));
+        assert!(lines[1].starts_with(":04009000101112"));
+        assert_eq!(lines[2], ":040000030000008079");
+    }
+
+    #[test]
+    fn s_records() {
+        let mut data = [0; 16];
+        data[..3].copy_from_slice(&[0x0a, 0x0a, 0x0d]);
+        let out = written(&MotorolaSRec, None, &[(0x7af0, &data)]);
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "S008000074656C6461ED",
+                "S1137AF00A0A0D0000000000000000000000000061",
+                "S9030000FC"
+            ]
+        );
+    }
+
+    #[test]
+    fn raw_and_telf() {
+        let segments: &[(u16, &[u8])] = &[(0x0080, &[1, 2]), (0x0084, &[3]), (0x8000, &[4])];
+        let mut out = Vec::new();
+        RawBinary.write(None, &segments[..2], &mut out).unwrap();
+        assert_eq!(out, [1, 2, 0, 0, 3]);
+        assert!(RawBinary.write(Some(0x84), segments, &mut out).is_err());
+
+        let mut out = Vec::new();
+        Telf { checksum: 5 }
+            .write(Some(0x84), segments, &mut out)
+            .unwrap();
+        let elf = crate::bin_format::parse_telda_elf(&out).unwrap();
+        assert_eq!(
+            elf,
+            TeldaElf::new(0x84, vec![1, 2, 0, 0, 3], vec![4]).with_checksum(5)
+        );
+
+        let crossing: &[(u16, &[u8])] = &[(0x7fff, &[1, 2])];
+        assert!(Telf::default().write(None, crossing, &mut out).is_err());
+    }
+}
@@ -0,0 +1,137 @@
+use crate::aalv::obj::Entry;
+
+use super::{ProcessedSource, SymbolType};
+
+/// Lookup table of the reflected CRC-32/ISO-HDLC polynomial
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// Running CRC-32/ISO-HDLC, the one used by zip and PNG
+#[derive(Debug, Clone, Copy)]
+pub struct Crc32(u32);
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Crc32 {
+    pub const fn new() -> Self {
+        Crc32(!0)
+    }
+    pub fn update(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 = CRC32_TABLE[((self.0 ^ b as u32) & 0xff) as usize] ^ (self.0 >> 8);
+        }
+    }
+    pub const fn finish(self) -> u32 {
+        !self.0
+    }
+}
+
+/// CRC-32/ISO-HDLC of the bytes
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(bytes);
+    crc.finish()
+}
+
+impl ProcessedSource {
+    /// CRC-32 of the assembled bytes, the entry point and the labels
+    ///
+    /// Segments are taken in segment type order with labels read as their addresses,
+    /// then the entry point and then the labels sorted by name.
+    /// All numbers are little-endian, so the checksum is the same on every platform
+    pub fn checksum(&self) -> u32 {
+        let mut crc = Crc32::new();
+
+        for (&st, dls) in &self.dls {
+            crc.update(&[st as u8]);
+            crc.update(&dls.start.to_le_bytes());
+            crc.update(&dls.size.to_le_bytes());
+            crc.update(&self.encode_segment(st).0);
+        }
+
+        match self.entry {
+            Some(Entry(st, addr)) => {
+                crc.update(&[1, st as u8]);
+                crc.update(&addr.to_le_bytes());
+            }
+            None => crc.update(&[0]),
+        }
+
+        let mut labels: Vec<_> = self.labels.iter().collect();
+        labels.sort_by(|a, b| a.0.cmp(&b.0));
+        for (name, sym_type, st, addr) in labels {
+            // names cannot contain a nul so it ends them unambiguously
+            crc.update(name.as_bytes());
+            crc.update(&[0]);
+            let sym_type = match sym_type {
+                SymbolType::Internal => 0,
+                SymbolType::Global => 1,
+                SymbolType::Reference => 2,
+                SymbolType::Weak => 3,
+            };
+            crc.update(&[sym_type, *st as u8]);
+            crc.update(&addr.to_le_bytes());
+        }
+
+        crc.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::crc32;
+    use crate::source::ProcessedSource;
+
+    #[test]
+    fn check_value() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(
+            crc32(b"The quick brown fox jumps over the lazy dog"),
+            0x414f_a339
+        );
+    }
+
+    #[test]
+    fn known_sources() {
+        let checksum = |src: &str| ProcessedSource::assemble(src).unwrap().checksum();
+
+        let hello = "\
+.seg text
+.entry
+    ldi r1, msg
+    halt
+.seg rodata
+msg:
+    .string \"hi\"
+";
+        assert_eq!(checksum(hello), 0xa27a_542b);
+        assert_eq!(checksum(".seg text\nhalt\n"), 0x0e32_7dd5);
+        // the same bytes with a different label differ
+        assert_ne!(
+            checksum(".seg text\nstart:\nhalt\n"),
+            checksum(".seg text\nbegin:\nhalt\n")
+        );
+    }
+}
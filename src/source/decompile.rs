@@ -0,0 +1,308 @@
+//! Printing processed source back as assembly that assembles to the same bytes
+
+use std::fmt::Write;
+
+use crate::{
+    aalv::obj::{SegmentLayout, SegmentType},
+    blf4::{R0, R1},
+};
+
+use super::{DataLine, DataOperand, ProcessedSource, SymbolType, Wide};
+
+type Label = (Box<str>, SymbolType, SegmentType, u16);
+
+/// Prints the processed source as assembly
+///
+/// Labels are printed by name at the addresses they were defined at and reads of them use
+/// their names. Instructions without a mnemonic are printed as their raw bytes
+pub fn decompile(src: &ProcessedSource, labels: &[Label]) -> String {
+    let mut out = String::new();
+
+    for (name, st, stype, addr) in labels {
+        match (st, stype) {
+            (SymbolType::Reference, _) => writeln!(out, ".ref {name}"),
+            (_, SegmentType::Zero) => writeln!(out, ".equ {name}, 0x{addr:04x}"),
+            (SymbolType::Global, _) => writeln!(out, ".global {name}"),
+            (SymbolType::Weak, _) => writeln!(out, ".weak {name}"),
+            _ => Ok(()),
+        }
+        .unwrap();
+    }
+
+    for (&stype, dls) in &src.dls {
+        let SegmentLayout {
+            alignment,
+            min_addr,
+            max_addr,
+        } = dls.layout;
+        write!(out, "\n.seg {stype}").unwrap();
+        if min_addr != 0 || max_addr.is_some() {
+            write!(out, " 0x{min_addr:04x}").unwrap();
+        }
+        if let Some(max_addr) = max_addr {
+            write!(out, " 0x{max_addr:04x}").unwrap();
+        }
+        writeln!(out).unwrap();
+        if alignment > 1 {
+            writeln!(out, ".align {alignment}").unwrap();
+        }
+
+        let mut addr = dls.start;
+        for line in &dls.lines {
+            // `.align' leaves an empty line when already aligned
+            if matches!(line, DataLine::Raw(bytes) if bytes.is_empty()) {
+                continue;
+            }
+            write_labels_at(&mut out, src, labels, stype, addr);
+            match *line {
+                DataLine::Ins(opcode, dat_op) => {
+                    write_ins(&mut out, labels, opcode, dat_op);
+                    addr += 1 + dat_op.size();
+                }
+                DataLine::Wide(w) => {
+                    writeln!(out, "    .wide {}", wide(labels, w)).unwrap();
+                    addr += 2;
+                }
+                DataLine::Raw(ref bytes) => {
+                    for chunk in bytes.chunks(16) {
+                        write_bytes(&mut out, chunk);
+                    }
+                    addr += bytes.len() as u16;
+                }
+            }
+        }
+        write_labels_at(&mut out, src, labels, stype, addr);
+    }
+
+    out
+}
+
+fn write_labels_at(
+    out: &mut String,
+    src: &ProcessedSource,
+    labels: &[Label],
+    stype: SegmentType,
+    addr: u16,
+) {
+    if src.entry.is_some_and(|e| e.0 == stype && e.1 == addr) {
+        writeln!(out, ".entry").unwrap();
+    }
+    for (name, _, _, _) in labels.iter().filter(|&&(_, st, lst, laddr)| {
+        !matches!(st, SymbolType::Reference) && lst == stype && laddr == addr
+    }) {
+        writeln!(out, "{name}:").unwrap();
+    }
+}
+
+fn write_bytes(out: &mut String, bytes: &[u8]) {
+    out.push_str("    .byte ");
+    for (i, b) in bytes.iter().enumerate() {
+        if i != 0 {
+            out.push_str(", ");
+        }
+        write!(out, "0x{b:02x}").unwrap();
+    }
+    out.push('\n');
+}
+
+fn wide(labels: &[Label], w: Wide) -> String {
+    match w {
+        Wide::Number(n) => format!("0x{n:04x}"),
+        Wide::Label(id) => labels[id].0.to_string(),
+    }
+}
+
+fn write_ins(out: &mut String, labels: &[Label], opcode: u8, dat_op: DataOperand) {
+    use self::DataOperand::*;
+    use crate::blf4::isa::*;
+
+    let mnemonic = match (opcode, dat_op) {
+        (LDI_W, TwoWideImm(r, r2, w)) if r2 == R0 => {
+            writeln!(out, "    ldi {r}, {}", wide(labels, w)).unwrap();
+            return;
+        }
+        (LDI_W, TwoWideImm(r, r2, w)) if r == R0 && r2 == R1 => {
+            writeln!(out, "    jmp {}", wide(labels, w)).unwrap();
+            return;
+        }
+        (LDI_W, TwoWideImm(r, r2, Wide::Number(0))) if r2 == R1 => {
+            writeln!(out, "    jmp {r}").unwrap();
+            return;
+        }
+        (PUSH_IMM_B, ImmediateByte(b)) => {
+            writeln!(out, "    push {b}b").unwrap();
+            return;
+        }
+        (LDI_W, WideNibbleByte(r, n, b)) if u8::from(n) == 2 => {
+            writeln!(out, "    ldi {r}, {}", b as i8).unwrap();
+            return;
+        }
+        // these cannot be written in assembly
+        (LDI_W | USR | VMON | VMOFF | PSTORE | PLOAD | PMSET, _) => None,
+        (opcode, _) => mnemonic(opcode),
+    };
+    let Some(mnemonic) = mnemonic else {
+        // labels resolve to the same addresses when reassembled
+        let mut bytes = vec![opcode];
+        bytes.extend(dat_op.encode(SegmentType::Unknown, 0, &mut |id, _| labels[id].3));
+        write_bytes(out, &bytes);
+        return;
+    };
+
+    let ops = match dat_op {
+        ImmediateWide(w) => wide(labels, w),
+        WideImm(r, w) => format!("{r}, {}", wide(labels, w)),
+        WideImmByte(r1, w, r2) => format!("{r1}, {}, {r2}", wide(labels, w)),
+        WideImmWide(r1, w, r2) => format!("{r1}, {}, {r2}", wide(labels, w)),
+        ByteWideImm(r1, r2, w) => format!("{r1}, {r2}, {}", wide(labels, w)),
+        TwoWideImm(r1, r2, w) => format!("{r1}, {r2}, {}", wide(labels, w)),
+        dat_op => dat_op.to_string(),
+    };
+    if ops.is_empty() {
+        writeln!(out, "    {mnemonic}").unwrap();
+    } else {
+        writeln!(out, "    {mnemonic} {ops}").unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    /// Bytes of each segment with the labels filled in
+    fn segments(ps: &ProcessedSource) -> BTreeMap<SegmentType, (u16, Vec<u8>)> {
+        ps.dls
+            .iter()
+            .map(|(&st, dls)| {
+                let mut mem = Vec::new();
+                for line in &dls.lines {
+                    match *line {
+                        DataLine::Ins(opcode, dat_op) => {
+                            mem.push(opcode);
+                            mem.extend(dat_op.encode(st, 0, &mut |id, _| ps.labels[id].3));
+                        }
+                        DataLine::Wide(Wide::Number(n)) => mem.extend(n.to_le_bytes()),
+                        DataLine::Wide(Wide::Label(id)) => {
+                            mem.extend(ps.labels[id].3.to_le_bytes())
+                        }
+                        DataLine::Raw(ref bytes) => mem.extend(bytes),
+                    }
+                }
+                (st, (dls.start, mem))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let src = "\
+.equ SIZE, 0x10
+.ref ext
+.global main
+.seg text
+.entry
+main:
+    ldi r1, msg
+    ldi r2l, 'a'
+    ldi r3, SIZE
+    ldi r4, -1
+    ldi r4, 0xff
+.loop:
+    load r4l, r1, 0
+    load r4, r1, r2
+    load r5, r4, r1, r3
+    store r1, r3, r5, r4
+    load r5l, r1, r3
+    store r1, 2, r4l
+    store r1, r2, r4
+    store r1, msg, r5
+    add r4l, r4l, r2l
+    sub r4, r4, r3
+    add r1l, r2l, 5
+    sub r1, r1, 0x300
+    add r2, r2, msg
+    and r1, r2, r3
+    or r1l, r1h, r2l
+    xor r1, r1, r1
+    shl r1, r1, r2
+    shl r1l, r1l, 3
+    asr r2, r2, 15
+    lsr r3h, r3h, r1l
+    adc r1, r2, r3
+    sbb r1l, r2l, r3l
+    mul r1, r2, r3, r4
+    div r1l, r2l, r3l, r4l
+    sxt r1, r2l
+    zxt r2, r3h
+    movw r2, r3l
+    movb r1l, r5
+    movbh r1h, r5
+    in r1l, r2l
+    out r2l, r1h
+    bswap r1, r2
+    popcnt r1l, r2l
+    clz r1, r2
+    ctz r1l, r2l
+    neg r1, r2
+    xchg r1l, r2l
+    cas r1, r2, r3
+    mcpy r1, r2, r3
+    push r1
+    push r1l
+    push 0x2ab
+    push -1b
+    push 'é'
+    push msg
+    push 300
+    pop r2
+    pop r2l
+    jez .loop
+    jnz .loop
+    jlt .end
+    jle .end
+    jgt .end
+    jge .end
+    jo .end
+    jno .end
+    jb .end
+    jae .end
+    ja .end
+    jbe .end
+    call ext
+    call r5
+    jmp r3
+.end:
+    nop
+    cpuid
+    wait
+    syscall
+    ret
+    ret 4
+    jmp main
+    halt
+.seg rodata
+.align 4
+table:
+.wide main, 0x1234, table
+.seg data 0x8000
+msg:
+.string hello\\n\\0
+.byte 1, 2, 3
+";
+        let ps = ProcessedSource::assemble(src).unwrap();
+        let printed = decompile(&ps, &ps.labels);
+        let reassembled =
+            ProcessedSource::assemble(&printed).unwrap_or_else(|e| panic!("{e}\nin:\n{printed}"));
+
+        assert_eq!(segments(&ps), segments(&reassembled), "{printed}");
+        assert_eq!(
+            ps.entry.map(|e| (e.0, e.1)),
+            reassembled.entry.map(|e| (e.0, e.1))
+        );
+        assert!(printed.contains("ldi r1, msg\n"));
+        assert!(printed.contains("jez main$.loop\n"));
+        assert!(printed.contains(".wide main\n"));
+    }
+}
@@ -0,0 +1,254 @@
+//! Dumping the parsed source lines as JSON for debugging the assembler
+
+use std::{
+    fmt::Write as _,
+    io::{self, BufRead, Write},
+    path::Path,
+};
+
+use super::{
+    inner_process, CondExpr, Error, LineNumber, ProcessState, Recovery, SegmentType, SourceLine,
+    SourceLines, SourceOperand, Symbols,
+};
+
+/// A line that gets assembled with the name of the source it came from
+pub type ExpandedLine = (Box<str>, LineNumber, SourceLine);
+
+/// Parses all the lines without processing them, so nothing is expanded
+pub fn parse_all<B: BufRead>(mut lines: SourceLines<B>) -> (Vec<ExpandedLine>, Vec<Error>) {
+    let mut parsed = Vec::new();
+    while let Some((ln, line)) = lines.parse_next_line() {
+        parsed.push((lines.source.clone(), ln, line));
+    }
+
+    (
+        parsed,
+        lines.errors.map(Error::into_vec).unwrap_or_default(),
+    )
+}
+
+/// Processes the lines and gives the ones that get assembled in order
+///
+/// Included files are expanded in place of their `.include`, repetitions are repeated and
+/// lines in false conditions are left out. The conditions and repetitions themselves are left out
+pub fn expand<B: BufRead>(
+    lines: SourceLines<B>,
+    recovery: Recovery,
+) -> (Vec<ExpandedLine>, Vec<Error>) {
+    let mut symbols = Symbols::new();
+    let mut state = ProcessState::new(recovery);
+    state.expanded = Some(Vec::new());
+    if let Ok(path) = Path::new(&*lines.source).canonicalize() {
+        state.includes.push(path);
+    }
+
+    let errors = inner_process(lines, &mut SegmentType::Unknown, &mut state, &mut symbols);
+
+    (
+        state.expanded.unwrap_or_default(),
+        errors.map(Error::into_vec).unwrap_or_default(),
+    )
+}
+
+/// Writes the lines as a JSON array with an object for each line
+///
+/// The objects have the source, the line number, the kind of line and then its fields
+pub fn write_lines_json(w: &mut dyn Write, lines: &[ExpandedLine]) -> io::Result<()> {
+    writeln!(w, "[")?;
+    for (i, (source, ln, line)) in lines.iter().enumerate() {
+        let comma = if i + 1 < lines.len() { "," } else { "" };
+        writeln!(
+            w,
+            "  {{\"source\": {}, \"line\": {ln}, {}}}{comma}",
+            string(source),
+            line_fields(line)
+        )?;
+    }
+    writeln!(w, "]")
+}
+
+fn string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn optional(n: Option<u16>) -> String {
+    n.map_or_else(|| "null".to_owned(), |n| n.to_string())
+}
+
+fn bytes(bytes: &[u8]) -> String {
+    let bytes: Vec<_> = bytes.iter().map(u8::to_string).collect();
+    format!("[{}]", bytes.join(", "))
+}
+
+fn operand(op: &SourceOperand) -> String {
+    let (kind, value) = match op {
+        SourceOperand::Byte(n) => ("Byte", n.to_string()),
+        SourceOperand::Wide(n) => ("Wide", n.to_string()),
+        SourceOperand::Number(n) => ("Number", n.to_string()),
+        SourceOperand::Char(c) => ("Char", string(&c.to_string())),
+        SourceOperand::ByteReg(r) => ("ByteReg", string(&r.to_string())),
+        SourceOperand::WideReg(r) => ("WideReg", string(&r.to_string())),
+        SourceOperand::Label(l) => ("Label", string(l)),
+    };
+    format!("{{\"type\": \"{kind}\", \"value\": {value}}}")
+}
+
+fn condition(cond: &CondExpr) -> String {
+    match cond {
+        CondExpr::Defined(l) => format!("{{\"kind\": \"Defined\", \"symbol\": {}}}", string(l)),
+        CondExpr::Not(c) => format!("{{\"kind\": \"Not\", \"condition\": {}}}", condition(c)),
+        CondExpr::Compare(a, cmp, b) => format!(
+            "{{\"kind\": \"Compare\", \"left\": {}, \"comparison\": \"{cmp:?}\", \"right\": {}}}",
+            string(a),
+            string(b)
+        ),
+        CondExpr::Value(v) => format!("{{\"kind\": \"Value\", \"value\": {}}}", string(v)),
+    }
+}
+
+/// The kind of line and its fields as JSON object members
+fn line_fields(line: &SourceLine) -> String {
+    match line {
+        SourceLine::Label(l) => format!("\"kind\": \"Label\", \"name\": {}", string(l)),
+        SourceLine::Ins(ins, ops) => {
+            let ops: Vec<_> = ops.iter().map(operand).collect();
+            format!(
+                "\"kind\": \"Ins\", \"instruction\": {}, \"operands\": [{}]",
+                string(ins),
+                ops.join(", ")
+            )
+        }
+        SourceLine::Comment => "\"kind\": \"Comment\"".to_owned(),
+        SourceLine::DirInclude(path) => {
+            format!("\"kind\": \"DirInclude\", \"path\": {}", string(path))
+        }
+        SourceLine::DirIncludeBin(path, offset, length) => format!(
+            "\"kind\": \"DirIncludeBin\", \"path\": {}, \"offset\": {offset}, \"length\": {}",
+            string(path),
+            optional(*length)
+        ),
+        SourceLine::DirString(s) => format!("\"kind\": \"DirString\", \"bytes\": {}", bytes(s)),
+        SourceLine::DirByte(b) => format!("\"kind\": \"DirByte\", \"bytes\": {}", bytes(b)),
+        SourceLine::DirWide(ws) => {
+            let ws: Vec<_> = ws
+                .iter()
+                .map(|w| match w {
+                    Ok(n) => n.to_string(),
+                    Err(l) => string(l),
+                })
+                .collect();
+            format!("\"kind\": \"DirWide\", \"wides\": [{}]", ws.join(", "))
+        }
+        SourceLine::DirGlobal(l) => format!("\"kind\": \"DirGlobal\", \"symbol\": {}", string(l)),
+        SourceLine::DirWeak(l) => format!("\"kind\": \"DirWeak\", \"symbol\": {}", string(l)),
+        SourceLine::DirReference(l) => {
+            format!("\"kind\": \"DirReference\", \"symbol\": {}", string(l))
+        }
+        SourceLine::DirExtern(l) => format!("\"kind\": \"DirExtern\", \"symbol\": {}", string(l)),
+        SourceLine::DirSeg(seg, min, max) => format!(
+            "\"kind\": \"DirSeg\", \"segment\": {}, \"min_addr\": {}, \"max_addr\": {}",
+            string(seg),
+            optional(*min),
+            optional(*max)
+        ),
+        SourceLine::DirAlign(n, padding) => {
+            format!("\"kind\": \"DirAlign\", \"alignment\": {n}, \"padding\": {padding}")
+        }
+        SourceLine::DirEntry => "\"kind\": \"DirEntry\"".to_owned(),
+        SourceLine::DirEqu(name, value) => format!(
+            "\"kind\": \"DirEqu\", \"name\": {}, \"value\": {value}",
+            string(name)
+        ),
+        SourceLine::DirIf(cond) => {
+            format!("\"kind\": \"DirIf\", \"condition\": {}", condition(cond))
+        }
+        SourceLine::DirElse => "\"kind\": \"DirElse\"".to_owned(),
+        SourceLine::DirEndif => "\"kind\": \"DirEndif\"".to_owned(),
+        SourceLine::DirRep(count) => format!("\"kind\": \"DirRep\", \"count\": {}", string(count)),
+        SourceLine::DirEndrep => "\"kind\": \"DirEndrep\"".to_owned(),
+        SourceLine::DirStruct(name) => {
+            format!("\"kind\": \"DirStruct\", \"name\": {}", string(name))
+        }
+        SourceLine::DirField(name, size) => format!(
+            "\"kind\": \"DirField\", \"name\": {}, \"size\": {size}",
+            string(name)
+        ),
+        SourceLine::DirEnds => "\"kind\": \"DirEnds\"".to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SRC: &str = "\
+.seg text
+.if 0
+nop
+.endif
+.rep 2
+add r1, r1, $REPIDX
+.endrep
+push r1l, \"a\\b\"
+";
+
+    fn json(lines: &[ExpandedLine]) -> String {
+        let mut out = Vec::new();
+        write_lines_json(&mut out, lines).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn ast() {
+        let (lines, errors) = parse_all(SourceLines::from_str(SRC));
+        assert!(errors.is_empty());
+        assert_eq!(lines.len(), 8);
+        let json = json(&lines);
+        assert!(
+            json.starts_with("[\n  {\"source\": \"<input>\", \"line\": 1, \"kind\": \"DirSeg\"")
+        );
+        assert!(json.contains(
+            "\"line\": 2, \"kind\": \"DirIf\", \"condition\": {\"kind\": \"Value\", \"value\": \"0\"}}"
+        ));
+        assert!(json.contains(
+            "\"instruction\": \"push\", \"operands\": [{\"type\": \"ByteReg\", \"value\": \"r1l\"}, \
+             {\"type\": \"Label\", \"value\": \"\\\"a\\\\b\\\"\"}]"
+        ));
+    }
+
+    #[test]
+    fn expanded() {
+        let (lines, errors) = expand(SourceLines::from_str(SRC), Recovery::Continue);
+        // the operands of the push are wrong, but the line is still there
+        assert_eq!(errors.len(), 1);
+        let ops: Vec<_> = lines
+            .iter()
+            .map(|(_, ln, l)| match l {
+                SourceLine::Ins(_, ops) => (*ln, ops.get(2).cloned()),
+                _ => (*ln, None),
+            })
+            .collect();
+        assert_eq!(
+            ops,
+            [
+                (1, None),
+                (6, Some(SourceOperand::Number(0))),
+                (6, Some(SourceOperand::Number(1))),
+                (8, None),
+            ]
+        );
+    }
+}
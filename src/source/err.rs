@@ -3,10 +3,22 @@ use std::fmt::{self, Display};
 pub(super) use std::io::Error as IoError;
 pub(super) use std::result::Result as StdResult;
 
+use serde::{Deserialize, Serialize};
+
+use super::SourceLocation;
+use crate::aalv::obj::SegmentType;
+
 pub type Result<T> = StdResult<T, Error>;
 
 pub type LineNumber = u32;
 
+/// What a literal is put in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LiteralSize {
+    Byte,
+    Wide,
+}
+
 #[derive(Debug)]
 pub enum ErrorType {
     UnknownSegment(Box<str>),
@@ -19,7 +31,42 @@ pub enum ErrorType {
     EscapeCharacterAtEnd,
     DoubleEntry,
     CharacterLiteralTooLong,
+    /// A character literal was used as a byte or wide but its code point does not fit in it
+    CharacterDoesNotFit(char, LiteralSize),
+    /// The operands of an instruction were wrong in a way not covered by the other variants
     IncorrectOperands(&'static str),
+    /// An instruction was given a number of operands it does not take any form with
+    ///
+    /// `expected` is the closest number of operands the instruction does take
+    WrongOperandCount {
+        instruction: Box<str>,
+        expected: u8,
+        got: u8,
+    },
+    /// A number operand is of the right kind but not within `min..=max`
+    OperandOutOfRange {
+        instruction: Box<str>,
+        operand_index: u8,
+        value: i32,
+        min: i32,
+        max: i32,
+    },
+    /// An operand is of a kind the instruction does not take there, like a register for a number
+    WrongOperandType {
+        instruction: Box<str>,
+        operand_index: u8,
+        expected: &'static str,
+        got: &'static str,
+    },
+    /// A file includes itself, the chain goes from the first inclusion of the file back to it
+    CircularInclude(Box<str>, Vec<Box<str>>),
+    IncludeTooDeep(usize),
+    /// A segment would go past its maximum address or the end of memory
+    SegmentDoesNotFit(SegmentType),
+    /// An `.incbin` range ended at the first value but the file only had the second value of bytes
+    IncbinOutOfBounds(usize, usize),
+    /// An `.align` that is not a power of two
+    InvalidAlignment(u16),
     Other(Box<str>),
 }
 
@@ -28,6 +75,8 @@ pub struct Error {
     source: Box<str>,
     ln: LineNumber,
     error: ErrorType,
+    /// Where the file of the error was included from, the innermost `.include` first
+    included_from: Vec<SourceLocation>,
     next: Option<Box<Self>>,
 }
 
@@ -37,6 +86,7 @@ impl Error {
             source: s.into(),
             ln,
             error,
+            included_from: Vec::new(),
             next: None,
         }
     }
@@ -50,6 +100,40 @@ impl Error {
             a @ None => *a = Some(Box::new(second)),
         }
     }
+    /// Name of the source the error is in
+    pub fn file(&self) -> &str {
+        &self.source
+    }
+    /// Line of the error, 0 if it is not about a specific line
+    pub fn line_number(&self) -> LineNumber {
+        self.ln
+    }
+    pub fn error_type(&self) -> &ErrorType {
+        &self.error
+    }
+    /// The `.include` lines that led to the file of the error, the innermost first
+    pub fn include_chain(&self) -> &[SourceLocation] {
+        &self.included_from
+    }
+    /// Records that the file of this and the chained errors was included at `location`
+    pub(super) fn included_from(mut self, location: SourceLocation) -> Self {
+        let mut cur = Some(&mut self);
+        while let Some(e) = cur {
+            e.included_from.push(location.clone());
+            cur = e.next.as_deref_mut();
+        }
+        self
+    }
+    /// Splits the chained errors into separate errors
+    pub fn into_vec(self) -> Vec<Self> {
+        let mut errors = Vec::new();
+        let mut cur = Some(Box::new(self));
+        while let Some(mut e) = cur {
+            cur = e.next.take();
+            errors.push(*e);
+        }
+        errors
+    }
     // pub(super) fn set_line_number(self, ln: LineNumber) -> Self {
     //     Self { ln, .. self }
     // }
@@ -76,6 +160,7 @@ impl Display for Error {
                 source,
                 ln,
                 error,
+                included_from,
                 next,
             } = e;
 
@@ -84,6 +169,14 @@ impl Display for Error {
             } else {
                 write!(f, "{source}:{ln}: ")?;
             }
+            for location in included_from {
+                write!(
+                    f,
+                    "included from {}:{}: ",
+                    location.source(),
+                    location.line_number()
+                )?;
+            }
             match error {
                 ErrorType::DoubleEntry => write!(f, "entry point defined twice"),
                 ErrorType::UnknownSegment(s) => write!(f, "unsupported segment `{s}'"),
@@ -97,7 +190,63 @@ impl Display for Error {
                 }
                 ErrorType::EscapeCharacterAtEnd => write!(f, "unfinished escape at end"),
                 ErrorType::CharacterLiteralTooLong => write!(f, "character literal too long"),
+                ErrorType::CharacterDoesNotFit(c, LiteralSize::Byte) => write!(
+                    f,
+                    "character {c:?} does not fit in a byte, only ASCII characters do"
+                ),
+                ErrorType::CharacterDoesNotFit(c, LiteralSize::Wide) => {
+                    write!(f, "character {c:?} does not fit in a wide")
+                }
                 ErrorType::IncorrectOperands(s) => write!(f, "incorrect operands, expected {s}"),
+                ErrorType::WrongOperandCount {
+                    instruction,
+                    expected,
+                    got,
+                } => {
+                    let plural = if *expected == 1 { "" } else { "s" };
+                    write!(
+                        f,
+                        "incorrect operands, `{instruction}' takes {expected} operand{plural} but was given {got}"
+                    )
+                }
+                ErrorType::OperandOutOfRange {
+                    instruction,
+                    operand_index,
+                    value,
+                    min,
+                    max,
+                } => write!(
+                    f,
+                    "incorrect operands, operand {} of `{instruction}' is {value} but has to be from {min} to {max}",
+                    operand_index + 1
+                ),
+                ErrorType::WrongOperandType {
+                    instruction,
+                    operand_index,
+                    expected,
+                    got,
+                } => write!(
+                    f,
+                    "incorrect operands, operand {} of `{instruction}' has to be {expected} but is {got}",
+                    operand_index + 1
+                ),
+                ErrorType::CircularInclude(s, chain) => {
+                    write!(f, "circular include of `{s}': ")?;
+                    for p in chain {
+                        write!(f, "{p} -> ")?;
+                    }
+                    write!(f, "{s}")
+                }
+                ErrorType::IncludeTooDeep(n) => {
+                    write!(f, "includes nested more than {n} levels deep")
+                }
+                ErrorType::SegmentDoesNotFit(st) => {
+                    write!(f, "segment {st} does not fit below its maximum address")
+                }
+                ErrorType::IncbinOutOfBounds(end, len) => {
+                    write!(f, "included bytes end at {end} but the file is only {len} bytes")
+                }
+                ErrorType::InvalidAlignment(n) => write!(f, "alignment {n} is not a power of two"),
                 ErrorType::Other(s) => write!(f, "{s}"),
             }?;
             if next.is_some() {
@@ -111,12 +260,85 @@ impl Display for Error {
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WarningType {
+    /// A byte literal was outside of both the signed and unsigned range and got truncated
+    ByteOverflow(i32),
+    /// A wide literal was outside of both the signed and unsigned range and got truncated
+    WideOverflow(i32),
+    UnusedLabel(Box<str>),
+    /// Neither an entry point nor any global symbols, so the output can neither be run nor linked against
+    NoEntryPoint,
+    /// A `/*` inside of a block comment, which does not start a nested comment
+    NestedBlockComment,
+    /// Instructions after an unconditional jump, `ret` or `halt` that no label leads to
+    UnreachableCode {
+        after: SourceLocation,
+        count: usize,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Warning {
+    location: SourceLocation,
+    warning: WarningType,
+}
+
+impl Warning {
+    pub(super) fn new(location: SourceLocation, warning: WarningType) -> Self {
+        Warning { location, warning }
+    }
+    pub fn warning_type(&self) -> &WarningType {
+        &self.warning
+    }
+    pub fn location(&self) -> &SourceLocation {
+        &self.location
+    }
+}
+
+impl Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Self { location, warning } = self;
+
+        if location.line_number == 0 {
+            write!(f, "{}: warning: ", location.source)?;
+        } else {
+            write!(f, "{}:{}: warning: ", location.source, location.line_number)?;
+        }
+        match warning {
+            &WarningType::ByteOverflow(n) if n < 0 => write!(f, "byte literal underflow ({n})"),
+            WarningType::ByteOverflow(n) => write!(f, "byte literal overflow ({n})"),
+            &WarningType::WideOverflow(n) if n < 0 => write!(f, "wide literal underflow ({n})"),
+            WarningType::WideOverflow(n) => write!(f, "wide literal overflow ({n})"),
+            WarningType::UnusedLabel(l) => write!(f, "label `{l}' is never used"),
+            WarningType::NoEntryPoint => {
+                write!(
+                    f,
+                    "no entry point and no global symbols, output is unusable"
+                )
+            }
+            WarningType::NestedBlockComment => {
+                write!(
+                    f,
+                    "`/*' inside of a block comment, block comments do not nest"
+                )
+            }
+            WarningType::UnreachableCode { after, count } => write!(
+                f,
+                "{count} unreachable instruction(s) after {}:{}",
+                after.source, after.line_number
+            ),
+        }
+    }
+}
+
 impl From<IoError> for Error {
     fn from(e: IoError) -> Self {
         Self {
             ln: 0,
             source: "".to_owned().into_boxed_str(),
             error: ErrorType::IoError(e),
+            included_from: Vec::new(),
             next: None,
         }
     }
@@ -1,32 +1,61 @@
 use std::{
-    collections::BTreeMap,
+    cell::OnceCell,
+    collections::{BTreeMap, HashMap, VecDeque},
+    fmt::{self, Display},
     fs::File,
-    io::{BufRead, BufReader, Lines},
-    path::Path,
+    io::{self, BufRead, BufReader, Lines, Write},
+    iter,
+    path::{Path, PathBuf},
     slice::Iter,
 };
 
+use serde::{Deserialize, Serialize};
+
 use crate::{
     aalv::obj::Entry,
-    aalv::obj::SegmentType,
-    align_end,
+    aalv::obj::{SegmentLayout, SegmentType},
+    checked_align_end,
     blf4::{ByteRegister as BReg, WideRegister as WReg, *},
     PAGE_SIZE, U4,
 };
 
 mod err;
 pub use self::err::*;
+mod checksum;
+pub use self::checksum::*;
+mod decompile;
+pub use self::decompile::*;
+mod emit;
+pub use self::emit::*;
+mod obj;
+mod operands;
+#[cfg(feature = "parallel")]
+mod parallel;
+#[cfg(feature = "parallel")]
+pub use self::parallel::*;
+mod query;
+mod source_map;
+pub use self::source_map::*;
 mod symbols;
-use self::symbols::*;
-pub use self::symbols::{LabelRead, SymbolType};
+pub use self::symbols::{Address, LabelRead, SymbolType, Symbols, SymbolsIter};
+mod unreachable;
 
 type Opcode = u8;
 
+/// How deeply `.include`s may be nested before giving up
+pub const MAX_INCLUDE_DEPTH: usize = 256;
+/// Most times a `.rep` block may be repeated
+pub const MAX_REPETITIONS: u32 = 65536;
+/// Replaced by the number of the current repetition inside of a `.rep` block
+pub const REP_INDEX: &str = "$REPIDX";
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum SourceOperand {
     Byte(u8),
     Wide(u16),
     Number(i32),
+    /// A character literal, which is a byte when it is ASCII and otherwise a wide
+    Char(char),
     ByteReg(BReg),
     WideReg(WReg),
     Label(String),
@@ -38,20 +67,197 @@ pub enum SourceLine {
     Ins(String, Vec<SourceOperand>),
     Comment,
     DirInclude(String),
+    /// Path, offset into the file and how many bytes to include, the rest of the file if none
+    DirIncludeBin(String, u16, Option<u16>),
     DirString(Vec<u8>),
-    DirByte(u8),
-    DirWide(StdResult<u16, String>),
+    DirByte(Vec<u8>),
+    DirWide(Vec<StdResult<u16, String>>),
     DirGlobal(String),
+    /// Makes a label global but lets a global definition in another object override it
+    DirWeak(String),
     DirReference(String),
-    DirSeg(String),
+    /// Declares a reference to a symbol that the environment loading the program provides,
+    /// see [`crate::blf4::SyscallTable::register_extern`]
+    DirExtern(String),
+    /// Segment name with an optional minimum and maximum address
+    DirSeg(String, Option<u16>, Option<u16>),
+    /// Alignment, which is a power of two, and the byte to pad with
+    DirAlign(u16, u8),
     DirEntry,
+    /// Defines a constant usable in conditions and as a label with an absolute value
+    DirEqu(String, u16),
+    DirIf(CondExpr),
+    DirElse,
+    DirEndif,
+    /// Repeats the lines up to the matching `.endrep` a number or `.equ` constant of times
+    DirRep(String),
+    DirEndrep,
+    /// Starts a structure whose fields up to the matching `.ends` become constants
+    DirStruct(String),
+    /// Field of a structure with its size in bytes
+    DirField(String, u16),
+    DirEnds,
+}
+
+/// Condition of an `.if` directive
+#[derive(Debug, Clone)]
+pub enum CondExpr {
+    /// Whether a label or constant has been defined before the condition
+    Defined(String),
+    Not(Box<CondExpr>),
+    /// Compares two numbers or `.equ` constants
+    Compare(String, Comparison, String),
+    /// Whether a number or `.equ` constant is not zero
+    Value(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+}
+
+impl CondExpr {
+    fn parse(s: &str) -> Option<Self> {
+        use self::Comparison::*;
+
+        let s = s.trim();
+        if let Some(rest) = s.strip_prefix('!') {
+            return Some(CondExpr::Not(Box::new(Self::parse(rest)?)));
+        }
+        if let Some(sym) = s.strip_prefix("defined(").and_then(|s| s.strip_suffix(')')) {
+            return Some(CondExpr::Defined(sym.trim().to_owned()));
+        }
+        for (op, cmp) in [("==", Eq), ("!=", Ne), (">=", Ge), ("<=", Le), (">", Gt), ("<", Lt)] {
+            if let Some((a, b)) = s.split_once(op) {
+                return Some(CondExpr::Compare(a.trim().to_owned(), cmp, b.trim().to_owned()));
+            }
+        }
+
+        (!s.is_empty() && !s.contains(char::is_whitespace)).then(|| CondExpr::Value(s.to_owned()))
+    }
+    fn evaluate(
+        &self,
+        src: &str,
+        ln: LineNumber,
+        state: &ProcessState,
+        symbols: &mut Symbols,
+    ) -> Result<bool> {
+        let mut value = |operand: &str| constant_value(operand, src, ln, state, symbols);
+
+        Ok(match self {
+            CondExpr::Defined(l) => symbols.is_defined(l),
+            CondExpr::Not(c) => !c.evaluate(src, ln, state, symbols)?,
+            CondExpr::Compare(a, cmp, b) => {
+                let (a, b) = (value(a)?, value(b)?);
+                match cmp {
+                    Comparison::Eq => a == b,
+                    Comparison::Ne => a != b,
+                    Comparison::Lt => a < b,
+                    Comparison::Gt => a > b,
+                    Comparison::Le => a <= b,
+                    Comparison::Ge => a >= b,
+                }
+            }
+            CondExpr::Value(a) => value(a)? != 0,
+        })
+    }
+}
+
+/// Gets the value of a number or a constant defined with `.equ`
+fn constant_value(
+    operand: &str,
+    src: &str,
+    ln: LineNumber,
+    state: &ProcessState,
+    symbols: &mut Symbols,
+) -> Result<i32> {
+    match parse_number(operand).map_err(|et| Error::new(src, ln, et))? {
+        SourceOperand::Byte(n) => Ok(n as i32),
+        SourceOperand::Wide(n) => Ok(n as i32),
+        SourceOperand::Number(n) => Ok(n),
+        SourceOperand::Char(c) => Ok(c as i32),
+        SourceOperand::Label(l) if state.constants.contains_key(&*l) => {
+            symbols.get_label(&l, SourceLocation::new(src, ln));
+            Ok(state.constants[&*l] as i32)
+        }
+        _ => Err(Error::new(
+            src,
+            ln,
+            ErrorType::Other(
+                format!("`{operand}' is not a number or a constant defined with `.equ'")
+                    .into_boxed_str(),
+            ),
+        )),
+    }
+}
+
+/// An `.if` whose `.endif` has not been reached yet
+struct Condition {
+    /// Where the `.if` was
+    ln: LineNumber,
+    /// Whether the lines around the `.if` are assembled
+    parent_active: bool,
+    value: bool,
+    in_else: bool,
+}
+
+impl Condition {
+    fn active(&self) -> bool {
+        self.parent_active && self.value != self.in_else
+    }
+}
+
+/// A `.struct` whose `.ends` has not been reached yet
+struct StructBuilder {
+    name: String,
+    /// Where the `.struct` was
+    ln: LineNumber,
+    /// Offset of the next field
+    offset: u16,
+}
+
+impl StructBuilder {
+    /// Turns a line inside the structure into the line defining its constant, if it has one
+    fn take_line(&mut self, src: &str, ln: LineNumber, line: SourceLine) -> Result<SourceLine> {
+        match line {
+            SourceLine::DirField(field, size) => {
+                let offset = self.offset;
+                self.offset = offset.checked_add(size).ok_or_else(|| {
+                    let msg = format!("structure `{}' is bigger than 0xffff bytes", self.name);
+                    Error::new(src, ln, ErrorType::Other(msg.into_boxed_str()))
+                })?;
+                Ok(SourceLine::DirEqu(format!("{}.{field}", self.name), offset))
+            }
+            SourceLine::Comment => Ok(SourceLine::Comment),
+            _ => Err(Error::new(
+                src,
+                ln,
+                ErrorType::Other("only fields can be in a `.struct'".into()),
+            )),
+        }
+    }
 }
 
+/// A line parsed ahead of time, a `.rep` line has its body and the line number it ends at
+type ParsedLine = (LineNumber, SourceLine, Option<(Result<Vec<String>>, LineNumber)>);
+
 pub struct SourceLines<B> {
     lines: Lines<B>,
     ln: LineNumber,
     source: Box<str>,
     errors: Option<Error>,
+    warnings: Vec<Warning>,
+    /// Whether a `/*` has been read without its `*/` yet
+    in_block_comment: bool,
+    /// Lines parsed ahead of time, they are read before `lines`
+    preparsed: VecDeque<ParsedLine>,
+    /// Body of the last `.rep` line read from `preparsed`
+    pending_rep: Option<(Result<Vec<String>>, LineNumber)>,
 }
 
 fn add_error_opt(errors: &mut Option<Error>, error: Error) {
@@ -67,6 +273,233 @@ impl<B> SourceLines<B> {
     fn add_error(&mut self, error: Error) {
         add_error_opt(&mut self.errors, error)
     }
+    /// Splits the comma separated arguments of a directive that needs at least one
+    ///
+    /// Commas in character literals do not split
+    fn directive_args<'a>(&self, directive: &str, arg: &'a str) -> Result<Vec<&'a str>> {
+        if arg.trim().is_empty() {
+            return Err(Error::new(
+                self.source.clone(),
+                self.ln,
+                ErrorType::Other(
+                    format!("`.{directive}' needs at least one argument").into_boxed_str(),
+                ),
+            ));
+        }
+
+        let mut args = Vec::new();
+        let mut in_char = false;
+        let mut escaped = false;
+        let mut start = 0;
+        for (i, c) in arg.char_indices() {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' if in_char => escaped = true,
+                '\'' => in_char = !in_char,
+                ',' if !in_char => {
+                    args.push(arg[start..i].trim());
+                    start = i + 1;
+                }
+                _ => (),
+            }
+        }
+        args.push(arg[start..].trim());
+
+        Ok(args)
+    }
+    fn parse_byte_literal(&mut self, arg: &str) -> Result<u8> {
+        match parse_number(arg).map_err(|et| Error::new(self.source.clone(), self.ln, et))? {
+            SourceOperand::Byte(n) => Ok(n),
+            SourceOperand::Number(n) => {
+                if n > u8::MAX as i32 || n < i8::MIN as i32 {
+                    self.warnings.push(Warning::new(
+                        SourceLocation::new(&self.source, self.ln),
+                        WarningType::ByteOverflow(n),
+                    ));
+                }
+
+                Ok(n as u8)
+            }
+            SourceOperand::Char(c) if c.is_ascii() => Ok(c as u8),
+            SourceOperand::Char(c) => Err(Error::new(
+                self.source.clone(),
+                self.ln,
+                ErrorType::CharacterDoesNotFit(c, LiteralSize::Byte),
+            )),
+            // addresses are only ever put in wides, so they are never truncated
+            SourceOperand::Label(l) => Err(Error::new(
+                self.source.clone(),
+                self.ln,
+                ErrorType::Other(
+                    format!("label `{l}' cannot be a byte, an address only fits in a wide")
+                        .into_boxed_str(),
+                ),
+            )),
+            _ => Err(Error::new(
+                self.source.clone(),
+                self.ln,
+                ErrorType::Other(format!("invalid byte literal \'{arg}\'").into_boxed_str()),
+            )),
+        }
+    }
+    fn parse_wide_literal(&mut self, arg: &str) -> Result<StdResult<u16, String>> {
+        match parse_number(arg).map_err(|et| Error::new(self.source.clone(), self.ln, et))? {
+            SourceOperand::Wide(n) => Ok(Ok(n)),
+            SourceOperand::Number(n) => {
+                if n > u16::MAX as i32 || n < i16::MIN as i32 {
+                    self.warnings.push(Warning::new(
+                        SourceLocation::new(&self.source, self.ln),
+                        WarningType::WideOverflow(n),
+                    ));
+                }
+
+                Ok(Ok(n as u16))
+            }
+            SourceOperand::Char(c) => u16::try_from(c as u32).map(Ok).map_err(|_| {
+                Error::new(
+                    self.source.clone(),
+                    self.ln,
+                    ErrorType::CharacterDoesNotFit(c, LiteralSize::Wide),
+                )
+            }),
+            SourceOperand::Label(l) => Ok(Err(l)),
+            _ => Err(Error::new(
+                self.source.clone(),
+                self.ln,
+                ErrorType::Other(format!("invalid wide literal \'{arg}\'").into_boxed_str()),
+            )),
+        }
+    }
+    fn invalid_count(&self, directive: &str, arg: &str) -> Error {
+        Error::new(
+            self.source.clone(),
+            self.ln,
+            ErrorType::Other(format!("invalid `.{directive}' count `{arg}'").into_boxed_str()),
+        )
+    }
+    /// Name of the field of a `.byte_field` or `.wide_field`
+    fn parse_field_name(&self, directive: &str, arg: &str) -> Result<String> {
+        match *self.directive_args(directive, arg)? {
+            [name] if !name.is_empty() => Ok(name.to_string()),
+            _ => Err(Error::new(
+                self.source.clone(),
+                self.ln,
+                ErrorType::Other(
+                    format!("`.{directive}' expects a name, got `{arg}'").into_boxed_str(),
+                ),
+            )),
+        }
+    }
+    /// Bytes of `.zero count[, fill]`, which are zero unless a fill byte is given
+    fn parse_zero(&mut self, arg: &str) -> Result<Vec<u8>> {
+        let args = self.directive_args("zero", arg)?;
+        let (count, fill) = match *args {
+            [count] => (count, None),
+            [count, fill] => (count, Some(fill)),
+            _ => {
+                return Err(Error::new(
+                    self.source.clone(),
+                    self.ln,
+                    ErrorType::Other(
+                        format!("`.zero' expects a count and an optional fill byte, got `{arg}'")
+                            .into_boxed_str(),
+                    ),
+                ))
+            }
+        };
+        let count = parse_wide_arg(count).ok_or_else(|| self.invalid_count("zero", count))?;
+        let fill = fill.map(|f| self.parse_byte_literal(f)).transpose()?;
+        Ok(vec![fill.unwrap_or(0); count as usize])
+    }
+    /// Alignment and padding byte of `.align alignment[, padding]`, the padding is zero unless
+    /// given so that padding in code traps if it is run
+    fn parse_align(&mut self, arg: &str) -> Result<(u16, u8)> {
+        let args = self.directive_args("align", arg)?;
+        let (alignment, padding) = match *args {
+            [alignment] => (alignment, None),
+            [alignment, padding] => (alignment, Some(padding)),
+            _ => {
+                return Err(Error::new(
+                    self.source.clone(),
+                    self.ln,
+                    ErrorType::Other(
+                        format!("`.align' expects an alignment and an optional padding byte, \
+                                 got `{arg}'")
+                        .into_boxed_str(),
+                    ),
+                ))
+            }
+        };
+        let alignment = parse_wide_arg(alignment).ok_or_else(|| {
+            Error::new(
+                self.source.clone(),
+                self.ln,
+                ErrorType::Other(format!("invalid alignment `{alignment}'").into_boxed_str()),
+            )
+        })?;
+        if !alignment.is_power_of_two() {
+            return Err(Error::new(
+                self.source.clone(),
+                self.ln,
+                ErrorType::InvalidAlignment(alignment),
+            ));
+        }
+        let padding = padding.map(|p| self.parse_byte_literal(p)).transpose()?;
+        Ok((alignment, padding.unwrap_or(0)))
+    }
+    /// Bytes of `.fill count, size, value`, `count` times the `size` lowest bytes of `value` in
+    /// little-endian, zero-extended past two bytes
+    fn parse_fill(&mut self, arg: &str) -> Result<Vec<u8>> {
+        let args = self.directive_args("fill", arg)?;
+        let &[count, size, value] = &*args else {
+            return Err(Error::new(
+                self.source.clone(),
+                self.ln,
+                ErrorType::Other(
+                    format!("`.fill' expects a count, a size and a value, got `{arg}'")
+                        .into_boxed_str(),
+                ),
+            ));
+        };
+        let count = parse_wide_arg(count).ok_or_else(|| self.invalid_count("fill", count))?;
+        let size = parse_wide_arg(size).ok_or_else(|| {
+            Error::new(
+                self.source.clone(),
+                self.ln,
+                ErrorType::Other(format!("invalid `.fill' size `{size}'").into_boxed_str()),
+            )
+        })?;
+        let value = self.parse_wide_literal(value)?.map_err(|l| {
+            Error::new(
+                self.source.clone(),
+                self.ln,
+                ErrorType::Other(
+                    format!("label `{l}' cannot be a `.fill' value").into_boxed_str(),
+                ),
+            )
+        })?;
+        let Some(len) = count.checked_mul(size) else {
+            return Err(Error::new(
+                self.source.clone(),
+                self.ln,
+                ErrorType::Other(
+                    format!("`.fill' of {count} times {size} bytes is too big").into_boxed_str(),
+                ),
+            ));
+        };
+
+        let pattern: Vec<u8> = value
+            .to_le_bytes()
+            .into_iter()
+            .chain(iter::repeat(0))
+            .take(size as usize)
+            .collect();
+        let mut bytes = Vec::with_capacity(len as usize);
+        for _ in 0..count {
+            bytes.extend(&pattern);
+        }
+        Ok(bytes)
+    }
 }
 
 impl SourceLines<BufReader<File>> {
@@ -80,24 +513,70 @@ impl SourceLines<BufReader<File>> {
             ln: 0,
             source,
             errors: None,
+            warnings: Vec::new(),
+            in_block_comment: false,
+            preparsed: VecDeque::new(),
+            pending_rep: None,
         })
     }
 }
 
+/// Where a `/*` starts a block comment, which it does not inside of a string or character literal
+/// or after the start of a line comment
+fn block_comment_start(line: &str) -> Option<usize> {
+    let mut quote = None;
+    let mut escaped = false;
+    let mut chars = line.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let next = chars.peek().map(|&(_, c)| c);
+        match (quote, c) {
+            (Some(_), _) if escaped => escaped = false,
+            (Some(_), '\\') => escaped = true,
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => (),
+            (None, '"' | '\'') => quote = Some(c),
+            (None, ';' | '#') => return None,
+            (None, '/') if next == Some('/') => return None,
+            (None, '/') if next == Some('*') => return Some(i),
+            (None, _) => (),
+        }
+    }
+    None
+}
+
+/// Parses a number that has to fit in a wide
+fn parse_wide_arg(arg: &str) -> Option<u16> {
+    match parse_number(arg).ok()? {
+        SourceOperand::Byte(n) => Some(n as u16),
+        SourceOperand::Wide(n) => Some(n),
+        SourceOperand::Number(n) => u16::try_from(n).ok(),
+        SourceOperand::Char(c) => u16::try_from(c as u32).ok(),
+        _ => None,
+    }
+}
+
+/// Parses a number literal, character literal or otherwise a label
+///
+/// Numbers can have a `0x`, `0b` or `0o` prefix for hexadecimal, binary or octal after an optional sign.
+/// A `b` or `w` suffix makes the number a byte or wide, otherwise it is just a number.
+/// A character literal of a `\x` or octal escape is a byte, otherwise it is a character
 fn parse_number(arg: &str) -> StdResult<SourceOperand, ErrorType> {
     let so;
+    let (sign, unsigned) = match arg.strip_prefix('-') {
+        Some(unsigned) => ("-", unsigned),
+        None => ("", arg),
+    };
     let mut radix = 10;
-    let mut num = arg;
-    if let Some(new_num) = arg.strip_prefix("0x") {
-        radix = 16;
-        num = new_num;
-    } else if let Some(new_num) = arg.strip_prefix("0b") {
-        radix = 2;
-        num = new_num;
-    } else if let Some(new_num) = arg.strip_prefix("0o") {
-        radix = 8;
-        num = new_num;
+    let mut digits = unsigned;
+    for (prefix, prefix_radix) in [("0x", 16), ("0b", 2), ("0o", 8)] {
+        // `0b' on its own is a zero byte
+        if let Some(rest) = unsigned.strip_prefix(prefix).filter(|r| !r.is_empty()) {
+            radix = prefix_radix;
+            digits = rest;
+            break;
+        }
     }
+    let num = &*format!("{sign}{digits}");
 
     if let Some(num) = num.strip_suffix('b') {
         so = u8::from_str_radix(num, radix)
@@ -110,12 +589,20 @@ fn parse_number(arg: &str) -> StdResult<SourceOperand, ErrorType> {
             .or_else(|| i16::from_str_radix(num, radix).ok().map(|w| w as u16))
             .map(SourceOperand::Wide);
     } else if let Some(arg) = arg.strip_prefix('\'').and_then(|a| a.strip_suffix('\'')) {
-        let (byte, rest) = parse_bytechar(arg.as_bytes())?;
+        let (c, rest) = parse_char(arg)?;
         if !rest.is_empty() {
             return Err(ErrorType::CharacterLiteralTooLong);
         }
 
-        so = Some(SourceOperand::Byte(byte));
+        let numeric_escape = matches!(
+            arg.as_bytes(),
+            [b'\\', b'x', ..] | [b'\\', b'1'..=b'7', ..] | [b'\\', b'0', b'0'..=b'7', ..]
+        );
+        so = Some(if numeric_escape {
+            SourceOperand::Byte(c as u8)
+        } else {
+            SourceOperand::Char(c)
+        });
     } else {
         so = i32::from_str_radix(num, radix)
             .ok()
@@ -129,6 +616,15 @@ fn parse_number(arg: &str) -> StdResult<SourceOperand, ErrorType> {
     })
 }
 
+impl<'a> SourceLines<&'a [u8]> {
+    /// Reads the lines of a string, mostly useful for tests
+    // `FromStr` cannot be used since it cannot borrow the string
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(s: &'a str) -> Self {
+        Self::from_reader(s.as_bytes())
+    }
+}
+
 impl<B: BufRead> SourceLines<B> {
     pub fn from_reader(r: B) -> Self {
         SourceLines {
@@ -136,11 +632,31 @@ impl<B: BufRead> SourceLines<B> {
             ln: 0,
             source: "<input>".into(),
             errors: None,
+            warnings: Vec::new(),
+            in_block_comment: false,
+            preparsed: VecDeque::new(),
+            pending_rep: None,
         }
     }
     pub fn parse_next_line(&mut self) -> Option<(u32, SourceLine)> {
+        if let Some((ln, line, rep)) = self.preparsed.pop_front() {
+            self.ln = ln;
+            self.pending_rep = rep;
+            return Some((ln, line));
+        }
         loop {
-            let line = self.lines.next()?;
+            let Some(line) = self.lines.next() else {
+                if self.in_block_comment {
+                    self.in_block_comment = false;
+                    let e = Error::new(
+                        self.source.clone(),
+                        self.ln,
+                        ErrorType::Other("block comment is never closed".into()),
+                    );
+                    self.add_error(e);
+                }
+                return None;
+            };
             self.ln += 1;
             match self.inner_parse_line(line) {
                 Ok(sl) => break Some((self.ln, sl)),
@@ -150,9 +666,69 @@ impl<B: BufRead> SourceLines<B> {
             }
         }
     }
+    /// Reads the lines of a `.rep` block up to its `.endrep` with block comments stripped
+    fn read_rep_body(&mut self) -> Result<Vec<String>> {
+        if let Some((body, end)) = self.pending_rep.take() {
+            self.ln = end;
+            return body;
+        }
+        let start = self.ln;
+        let mut body = Vec::new();
+        let mut depth = 0;
+        loop {
+            let Some(line) = self.lines.next() else {
+                return Err(Error::new(
+                    self.source.clone(),
+                    start,
+                    ErrorType::Other("`.rep' without `.endrep'".into()),
+                ));
+            };
+            self.ln += 1;
+            let line =
+                line.map_err(|e| Error::new(self.source.clone(), self.ln, ErrorType::IoError(e)))?;
+            let line = self.strip_block_comments(&line);
+            match rep_nesting(&line) {
+                Some(true) => depth += 1,
+                Some(false) if depth == 0 => break Ok(body),
+                Some(false) => depth -= 1,
+                None => (),
+            }
+            body.push(line);
+        }
+    }
+    /// Replaces block comments with a space, they may span several lines
+    ///
+    /// Block comments do not nest, a `/*` inside of one is warned about
+    fn strip_block_comments(&mut self, mut line: &str) -> String {
+        let mut stripped = String::with_capacity(line.len());
+        loop {
+            if self.in_block_comment {
+                let end = line.find("*/");
+                if line[..end.unwrap_or(line.len())].contains("/*") {
+                    self.warnings.push(Warning::new(
+                        SourceLocation::new(&self.source, self.ln),
+                        WarningType::NestedBlockComment,
+                    ));
+                }
+                let Some(end) = end else { break };
+                self.in_block_comment = false;
+                stripped.push(' ');
+                line = &line[end + 2..];
+            } else {
+                let Some(start) = block_comment_start(line) else {
+                    stripped.push_str(line);
+                    break;
+                };
+                stripped.push_str(&line[..start]);
+                self.in_block_comment = true;
+                line = &line[start + 2..];
+            }
+        }
+        stripped
+    }
     fn inner_parse_line(&mut self, line: StdResult<String, IoError>) -> Result<SourceLine> {
         Ok({
-            let line = line?;
+            let line = self.strip_block_comments(&line?);
             let line = line.trim();
 
             if line.is_empty()
@@ -161,6 +737,12 @@ impl<B: BufRead> SourceLines<B> {
                 || line.starts_with('#')
             {
                 SourceLine::Comment
+            } else if let Some(lbl) = line
+                .strip_suffix(':')
+                .filter(|l| l.starts_with('.') && !l.contains(char::is_whitespace))
+            {
+                // local label
+                SourceLine::Label(lbl.to_owned())
             } else if let Some(line) = line.strip_prefix('.') {
                 let (line_i, arg_i) = line
                     .find(' ')
@@ -179,65 +761,178 @@ impl<B: BufRead> SourceLines<B> {
                         }
                         string
                     }),
-                    "byte" => {
-                        let b;
-                        match parse_number(arg)
-                            .map_err(|et| Error::new(self.source.clone(), self.ln, et))?
-                        {
-                            SourceOperand::Byte(n) => b = n,
-                            SourceOperand::Number(n) => {
-                                if n > u8::MAX as i32 {
-                                    eprintln!("warning: byte literal overflow");
-                                } else if n < i8::MIN as i32 {
-                                    eprintln!("warning: byte literal underflow");
-                                }
-
-                                b = n as u8
+                    "string16" => SourceLine::DirString({
+                        let mut string = Vec::with_capacity(2 * arg.len());
+                        let mut arg = arg;
+                        while !arg.is_empty() {
+                            let (c, rest) = parse_char(arg)
+                                .map_err(|et| Error::new(self.source.clone(), self.ln, et))?;
+                            arg = rest;
+                            for unit in c.encode_utf16(&mut [0; 2]) {
+                                string.extend(unit.to_le_bytes());
                             }
-                            _ => {
-                                return Err(Error::new(
+                        }
+                        string
+                    }),
+                    "byte" => {
+                        let mut bytes = Vec::new();
+                        for arg in self.directive_args("byte", arg)? {
+                            bytes.push(self.parse_byte_literal(arg)?);
+                        }
+                        SourceLine::DirByte(bytes)
+                    }
+                    "zero" => SourceLine::DirByte(self.parse_zero(arg)?),
+                    "fill" => SourceLine::DirByte(self.parse_fill(arg)?),
+                    "float" => {
+                        let mut bytes = Vec::new();
+                        for arg in self.directive_args("float", arg)? {
+                            let f: f32 = arg.parse().map_err(|_| {
+                                Error::new(
                                     self.source.clone(),
                                     self.ln,
                                     ErrorType::Other(
-                                        format!("invalid byte literal \'{arg}\'").into_boxed_str(),
+                                        format!("invalid float `{arg}'").into_boxed_str(),
                                     ),
-                                ))
-                            }
+                                )
+                            })?;
+                            bytes.extend(f.to_le_bytes());
                         }
-                        SourceLine::DirByte(b)
+                        SourceLine::DirByte(bytes)
                     }
                     "wide" | "word" => {
-                        let w;
-                        match parse_number(arg)
-                            .map_err(|et| Error::new(self.source.clone(), self.ln, et))?
-                        {
-                            SourceOperand::Wide(n) => w = Ok(n),
-                            SourceOperand::Number(n) => {
-                                if n > u16::MAX as i32 {
-                                    eprintln!("warning: wide literal overflow");
-                                } else if n < i16::MIN as i32 {
-                                    eprintln!("warning: wide literal underflow");
-                                }
-
-                                w = Ok(n as u16)
-                            }
-                            SourceOperand::Label(l) => w = Err(l),
-                            _ => {
-                                return Err(Error::new(
+                        let mut wides = Vec::new();
+                        for arg in self.directive_args("wide", arg)? {
+                            wides.push(self.parse_wide_literal(arg)?);
+                        }
+                        SourceLine::DirWide(wides)
+                    }
+                    "include" => SourceLine::DirInclude(arg.to_string()),
+                    "equ" => {
+                        let args = self.directive_args("equ", arg)?;
+                        let value = match &*args {
+                            &[_, value] => parse_wide_arg(value),
+                            _ => None,
+                        };
+                        let Some(value) = value else {
+                            return Err(Error::new(
+                                self.source.clone(),
+                                self.ln,
+                                ErrorType::Other(
+                                    format!("`.equ' expects a name and a number, got `{arg}'")
+                                        .into_boxed_str(),
+                                ),
+                            ));
+                        };
+                        SourceLine::DirEqu(args[0].to_string(), value)
+                    }
+                    "if" => SourceLine::DirIf(CondExpr::parse(arg).ok_or_else(|| {
+                        Error::new(
+                            self.source.clone(),
+                            self.ln,
+                            ErrorType::Other(
+                                format!("invalid condition `{arg}'").into_boxed_str(),
+                            ),
+                        )
+                    })?),
+                    "ifdef" => SourceLine::DirIf(CondExpr::Defined(arg.trim().to_string())),
+                    "ifndef" => SourceLine::DirIf(CondExpr::Not(Box::new(CondExpr::Defined(
+                        arg.trim().to_string(),
+                    )))),
+                    "else" => SourceLine::DirElse,
+                    "endif" => SourceLine::DirEndif,
+                    "rep" => SourceLine::DirRep(arg.trim().to_string()),
+                    "endrep" => SourceLine::DirEndrep,
+                    "struct" => SourceLine::DirStruct(arg.trim().to_string()),
+                    "field" => {
+                        let args = self.directive_args("field", arg)?;
+                        let size = match &*args {
+                            &[name, size] if !name.is_empty() => parse_wide_arg(size),
+                            _ => None,
+                        };
+                        let Some(size) = size else {
+                            return Err(Error::new(
+                                self.source.clone(),
+                                self.ln,
+                                ErrorType::Other(
+                                    format!("`.field' expects a name and a size, got `{arg}'")
+                                        .into_boxed_str(),
+                                ),
+                            ));
+                        };
+                        SourceLine::DirField(args[0].to_string(), size)
+                    }
+                    "byte_field" => {
+                        SourceLine::DirField(self.parse_field_name("byte_field", arg)?, 1)
+                    }
+                    "wide_field" => {
+                        SourceLine::DirField(self.parse_field_name("wide_field", arg)?, 2)
+                    }
+                    "ends" => SourceLine::DirEnds,
+                    "incbin" => {
+                        let args = self.directive_args("incbin", arg)?;
+                        let path = args[0];
+                        let path = path
+                            .strip_prefix('"')
+                            .and_then(|p| p.strip_suffix('"'))
+                            .unwrap_or(path);
+                        let mut numbers = args[1..].iter().map(|&a| {
+                            parse_wide_arg(a).ok_or_else(|| {
+                                Error::new(
                                     self.source.clone(),
                                     self.ln,
                                     ErrorType::Other(
-                                        format!("invalid wide literal \'{arg}\'").into_boxed_str(),
+                                        format!("invalid incbin offset or length `{a}'")
+                                            .into_boxed_str(),
                                     ),
-                                ))
-                            }
+                                )
+                            })
+                        });
+                        let offset = numbers.next().transpose()?.unwrap_or(0);
+                        let length = numbers.next().transpose()?;
+                        if numbers.next().is_some() {
+                            return Err(Error::new(
+                                self.source.clone(),
+                                self.ln,
+                                ErrorType::Other(
+                                    "`.incbin' takes a path, an offset and a length"
+                                        .to_string()
+                                        .into_boxed_str(),
+                                ),
+                            ));
                         }
-                        SourceLine::DirWide(w)
+                        SourceLine::DirIncludeBin(path.to_string(), offset, length)
                     }
-                    "include" => SourceLine::DirInclude(arg.to_string()),
                     "global" | "globl" => SourceLine::DirGlobal(arg.to_string()),
+                    "weak" => SourceLine::DirWeak(arg.to_string()),
                     "ref" | "reference" => SourceLine::DirReference(arg.to_string()),
-                    "seg" => SourceLine::DirSeg(arg.to_string()),
+                    "extern" => SourceLine::DirExtern(arg.to_string()),
+                    "seg" => {
+                        let mut args = arg.split_whitespace();
+                        let seg = args.next().unwrap_or("").to_string();
+                        let mut addr = || {
+                            args.next()
+                                .map(|a| {
+                                    parse_wide_arg(a).ok_or_else(|| {
+                                        Error::new(
+                                            self.source.clone(),
+                                            self.ln,
+                                            ErrorType::Other(
+                                                format!("invalid segment address `{a}'")
+                                                    .into_boxed_str(),
+                                            ),
+                                        )
+                                    })
+                                })
+                                .transpose()
+                        };
+                        let min_addr = addr()?;
+                        let max_addr = addr()?;
+                        SourceLine::DirSeg(seg, min_addr, max_addr)
+                    }
+                    "align" => {
+                        let (alignment, padding) = self.parse_align(arg)?;
+                        SourceLine::DirAlign(alignment, padding)
+                    }
                     "entry" => SourceLine::DirEntry,
                     s => {
                         return Err(Error::new(
@@ -311,13 +1006,32 @@ fn parse_bytechar(s: &[u8]) -> StdResult<(u8, &[u8]), ErrorType> {
             b'r' => (b'\r', &s[2..]),
             b't' => (b'\t', &s[2..]),
             b'n' => (b'\n', &s[2..]),
-            b'0' => (b'\0', &s[2..]),
+            // up to three octal digits, `\0' on its own is NUL
+            b'0'..=b'7' => {
+                let digits = s[1..]
+                    .iter()
+                    .take(3)
+                    .take_while(|d| matches!(d, b'0'..=b'7'))
+                    .count();
+                let octal =
+                    std::str::from_utf8(&s[1..1 + digits]).expect("octal digits are ASCII");
+                (
+                    u8::from_str_radix(octal, 8).map_err(|_| InvalidEscapeSequence)?,
+                    &s[1 + digits..],
+                )
+            }
+            b'a' => (0x07, &s[2..]),
+            b'b' => (0x08, &s[2..]),
+            b'e' => (0x1b, &s[2..]),
+            b'f' => (0x0c, &s[2..]),
+            b'v' => (0x0b, &s[2..]),
             b'\\' => (b'\\', &s[2..]),
             b'\'' => (b'\'', &s[2..]),
             b'\"' => (b'\"', &s[2..]),
             b'x' => (
-                u8::from_str_radix(String::from_utf8_lossy(&s[2..4]).as_ref(), 16)
-                    .map_err(|_| InvalidEscapeSequence)?,
+                s.get(2..4)
+                    .and_then(|hex| u8::from_str_radix(&String::from_utf8_lossy(hex), 16).ok())
+                    .ok_or(InvalidEscapeSequence)?,
                 &s[4..],
             ),
             c => return Err(InvalidEscapeCharacter(*c)),
@@ -326,7 +1040,21 @@ fn parse_bytechar(s: &[u8]) -> StdResult<(u8, &[u8]), ErrorType> {
     })
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+/// Parses a character that can be an escape sequence like [`parse_bytechar`]
+///
+/// A `\x` escape gives the character with that code point
+fn parse_char(s: &str) -> StdResult<(char, &str), ErrorType> {
+    match s.chars().next().ok_or(ErrorType::UnexpectedEndOfString)? {
+        '\\' => {
+            let (b, rest) = parse_bytechar(s.as_bytes())?;
+            // escapes are ASCII, so the rest starts at a character boundary
+            Ok((b as char, &s[s.len() - rest.len()..]))
+        }
+        c => Ok((c, &s[c.len_utf8()..])),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct SourceLocation {
     source: Box<str>,
     line_number: LineNumber,
@@ -338,71 +1066,203 @@ impl SourceLocation {
             line_number: ln,
         }
     }
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+    pub fn line_number(&self) -> LineNumber {
+        self.line_number
+    }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum DataLine {
     Ins(Opcode, DataOperand),
     Wide(Wide),
     Raw(Vec<u8>),
 }
 
-#[derive(Debug, Clone)]
+impl DataLine {
+    /// Number of bytes the line takes up
+    pub fn size(&self) -> u16 {
+        match *self {
+            DataLine::Ins(_, dat_op) => 1 + dat_op.size(),
+            DataLine::Wide(_) => 2,
+            DataLine::Raw(ref bytes) => bytes.len() as u16,
+        }
+    }
+}
+
+/// A place in a segment where the address of a label is written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Relocation {
+    /// Index of the label in [`ProcessedSource::labels`]
+    pub label: usize,
+    pub segment: SegmentType,
+    /// Where in the segment the wide is
+    pub offset: u16,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ProcessedSource {
-    pub labels: Vec<(Box<str>, SymbolType, SegmentType, u16)>,
+    /// Every label with its type, segment and address, see [`Self::labels`]
+    labels: Vec<(Box<str>, SymbolType, SegmentType, u16)>,
     pub dls: BTreeMap<SegmentType, DataLineSegment>,
     pub entry: Option<Entry>,
+    /// Every read of a label in the data lines, in order of where they are
+    pub relocations: Vec<Relocation>,
+    /// Which source line each address came from, sorted by address
+    pub source_map: Vec<SourceMapEntry>,
+    /// References declared by `.extern` that the host provides
+    #[serde(default)]
+    pub externs: Vec<Box<str>>,
+    /// Every file that was read, the source itself, its includes and included binaries
+    #[serde(default)]
+    pub inputs: Vec<PathBuf>,
+    /// Address to label index, built on first lookup
+    #[serde(skip)]
+    by_address: OnceCell<BTreeMap<u16, usize>>,
 }
 
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct DataLineSegment {
     pub lines: Vec<DataLine>,
+    /// Where each of the lines came from
+    pub locations: Vec<SourceLocation>,
     pub size: u16,
     pub start: u16,
+    /// Placement constraints from `.seg` and `.align` directives
+    pub layout: SegmentLayout,
+}
+
+/// What the assembler does after an error
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Recovery {
+    /// Keeps going to find as many errors as possible
+    ///
+    /// Instructions that could not be assembled are replaced by a single byte, so the addresses
+    /// after them can be off by the size of the instruction that was meant
+    #[default]
+    Continue,
+    /// Stops at the first error
+    Stop,
 }
 
 struct ProcessState {
     dls: BTreeMap<SegmentType, DataLineSegment>,
+    recovery: Recovery,
     pub entry: Option<Address>,
+    warnings: Vec<Warning>,
+    /// Files currently being processed, outermost first
+    includes: Vec<PathBuf>,
+    /// Every file that has been read
+    inputs: Vec<PathBuf>,
+    /// Constants defined by `.equ`
+    constants: HashMap<Box<str>, u16>,
+    /// Symbols declared by `.extern` in the order they were first declared
+    externs: Vec<Box<str>>,
+    /// The lines that get assembled when recording them for [`expand`]
+    expanded: Option<Vec<ExpandedLine>>,
 }
 
 impl ProcessState {
-    fn new() -> Self {
+    fn new(recovery: Recovery) -> Self {
         Self {
             dls: BTreeMap::new(),
+            recovery,
             entry: None,
+            warnings: Vec::new(),
+            includes: Vec::new(),
+            inputs: Vec::new(),
+            constants: HashMap::new(),
+            externs: Vec::new(),
+            expanded: None,
         }
     }
     fn get_size(&self, st: SegmentType) -> u16 {
         self.dls.get(&st).map(|dls| dls.size).unwrap_or(0)
     }
-    fn add_line(&mut self, st: SegmentType, line: DataLine, size: u16) {
+    /// Adds the line to the end of the segment unless the segment would get too big
+    fn add_line(
+        &mut self,
+        st: SegmentType,
+        line: DataLine,
+        size: u16,
+        loc: SourceLocation,
+    ) -> Result<()> {
         let dls = self.dls.entry(st).or_default();
+        let Some(new_size) = dls.size.checked_add(size) else {
+            return Err(Error::new(
+                loc.source,
+                loc.line_number,
+                ErrorType::SegmentDoesNotFit(st),
+            ));
+        };
         dls.lines.push(line);
-        dls.size += size;
+        dls.locations.push(loc);
+        dls.size = new_size;
+        Ok(())
     }
     fn unknown_defined(&self) -> bool {
         self.dls.contains_key(&SegmentType::Unknown)
     }
 }
 
-pub fn process<B: BufRead>(lines: SourceLines<B>) -> Result<ProcessedSource> {
+/// Processes the source lines, returning the processed source if there were no errors,
+/// all of the errors and any warnings
+pub fn process<B: BufRead>(
+    lines: SourceLines<B>,
+    recovery: Recovery,
+) -> (Option<ProcessedSource>, Vec<Error>, Vec<Warning>) {
     let mut symbols = Symbols::new();
-    let mut state = ProcessState::new();
+    let mut state = ProcessState::new(recovery);
 
     let src = lines.source.clone();
+    if let Ok(path) = Path::new(&*src).canonicalize() {
+        state.includes.push(path);
+        state.inputs.push(PathBuf::from(&*src));
+    }
+
+    let mut errors = inner_process(lines, &mut SegmentType::Unknown, &mut state, &mut symbols);
 
-    let mut errors = inner_process(lines, &mut state, &mut symbols);
+    let ProcessState {
+        mut dls,
+        recovery: _,
+        entry,
+        mut warnings,
+        includes: _,
+        inputs,
+        constants: _,
+        externs,
+        expanded: _,
+    } = state;
+    if recovery == Recovery::Stop {
+        if let Some(error) = errors {
+            return (None, error.into_vec().into_iter().take(1).collect(), warnings);
+        }
+    }
 
-    let ProcessState { mut dls, entry } = state;
+    for (lbl, loc) in symbols.unused() {
+        warnings.push(Warning::new(
+            loc.clone(),
+            WarningType::UnusedLabel(lbl.into()),
+        ));
+    }
 
     let mut last_end = PAGE_SIZE;
-    for s in dls.values_mut() {
-        s.start = align_end(last_end, PAGE_SIZE);
-        last_end = s.start + s.size;
+    for (&st, s) in dls.iter_mut() {
+        let start = checked_align_end(last_end, PAGE_SIZE).and_then(|a| s.layout.place(a));
+        let Some(start) = start.filter(|&start| s.layout.fits(start, s.size)) else {
+            add_error_opt(
+                &mut errors,
+                Error::new(src.clone(), 0, ErrorType::SegmentDoesNotFit(st)),
+            );
+            continue;
+        };
+        s.start = start;
+        last_end = s.start.saturating_add(s.size);
     }
 
-    let mut labels = Vec::with_capacity(symbols.size());
+    let mut labels = Vec::with_capacity(symbols.len());
 
     for (l, st, r) in symbols.into_iter() {
         let element;
@@ -411,7 +1271,7 @@ pub fn process<B: BufRead>(lines: SourceLines<B>) -> Result<ProcessedSource> {
         match r {
             Ok(addr) => {
                 let st = match st {
-                    Internal | Global => st,
+                    Internal | Weak | Global => st,
                     Reference => {
                         add_error_opt(
                             &mut errors,
@@ -451,8 +1311,9 @@ pub fn process<B: BufRead>(lines: SourceLines<B>) -> Result<ProcessedSource> {
                         add_error_opt(&mut errors, e);
                         continue;
                     }
-                    Reference | Global => {
-                        element = (l, Reference, SegmentType::Unknown, 0xfaff);
+                    Reference | Weak | Global => {
+                        // the location is not known until linking
+                        element = (l, Reference, SegmentType::Unknown, 0);
                     }
                 }
             }
@@ -462,18 +1323,119 @@ pub fn process<B: BufRead>(lines: SourceLines<B>) -> Result<ProcessedSource> {
     }
 
     if let Some(error) = errors {
-        Err(error)
+        (None, error.into_vec(), warnings)
     } else {
         let entry = entry.map(|addr| {
             let offset = dls.get(&addr.0).map(|dl| dl.start).unwrap_or(0);
             Entry(addr.0, addr.1 + offset)
         });
 
-        Ok(ProcessedSource { labels, dls, entry })
+        if entry.is_none()
+            && !labels
+                .iter()
+                .any(|(_, st, _, _)| matches!(st, SymbolType::Global | SymbolType::Weak))
+        {
+            warnings.push(Warning::new(
+                SourceLocation::new(&src, 0),
+                WarningType::NoEntryPoint,
+            ));
+        }
+
+        let relocations = find_relocations(&dls);
+        let source_map = build_source_map(&dls);
+
+        let ps = ProcessedSource {
+            labels,
+            dls,
+            entry,
+            relocations,
+            source_map,
+            externs,
+            inputs,
+            by_address: OnceCell::new(),
+        };
+        (Some(ps), Vec::new(), warnings)
+    }
+}
+impl ProcessedSource {
+    /// Brings the relocations and source map up to date after the data lines or labels changed
+    pub(crate) fn lines_changed(&mut self) {
+        self.relocations = find_relocations(&self.dls);
+        self.source_map = build_source_map(&self.dls);
+        self.by_address = OnceCell::new();
+    }
+}
+fn find_relocations(dls: &BTreeMap<SegmentType, DataLineSegment>) -> Vec<Relocation> {
+    let mut relocations = Vec::new();
+
+    for (&segment, dls) in dls {
+        let mut offset = 0;
+        for line in &dls.lines {
+            match *line {
+                DataLine::Ins(_, dat_op) => {
+                    // the operand comes after the opcode
+                    dat_op.encode(segment, offset + 1, &mut |label, lr| {
+                        relocations.push(Relocation {
+                            label,
+                            segment,
+                            offset: lr.position,
+                        });
+                        0
+                    });
+                    offset += 1 + dat_op.size();
+                }
+                DataLine::Wide(w) => {
+                    if let Wide::Label(label) = w {
+                        relocations.push(Relocation {
+                            label,
+                            segment,
+                            offset,
+                        });
+                    }
+                    offset += 2;
+                }
+                DataLine::Raw(ref bytes) => offset += bytes.len() as u16,
+            }
+        }
+    }
+
+    relocations
+}
+/// Whether the line starts (`true`) or ends (`false`) a `.rep` block
+fn rep_nesting(line: &str) -> Option<bool> {
+    let directive = line.split_whitespace().next()?;
+    match directive {
+        ".rep" => Some(true),
+        ".endrep" => Some(false),
+        _ => None,
+    }
+}
+
+/// Gives the lines of a `.rep` block with the index of the repetition put in
+///
+/// Blocks nested inside are left alone since their own index is used in them
+fn expand_rep_body(body: &[String], index: u32) -> String {
+    let mut text = String::new();
+    let mut depth = 0u32;
+    for line in body {
+        match rep_nesting(line) {
+            Some(true) => depth += 1,
+            Some(false) => depth -= 1,
+            None => (),
+        }
+        if depth == 0 {
+            text.push_str(&line.replace(REP_INDEX, &index.to_string()));
+        } else {
+            text.push_str(line);
+        }
+        text.push('\n');
     }
+    text
 }
+
 fn inner_process<B: BufRead>(
     mut lines: SourceLines<B>,
+    current_segment: &mut SegmentType,
     state: &mut ProcessState,
     symbols: &mut Symbols,
 ) -> Option<Error> {
@@ -486,16 +1448,46 @@ fn inner_process<B: BufRead>(
         symbols: &mut Symbols,
     ) -> Result<()> {
         match line {
-            SourceLine::DirSeg(seg) => {
+            SourceLine::DirSeg(seg, min_addr, max_addr) => {
                 let new_seg = match &*seg {
                     "data" => SegmentType::Data,
                     "rodata" => SegmentType::RoData,
+                    "boot" => SegmentType::Boot,
                     "text" => SegmentType::Text,
                     "heap" => SegmentType::Heap,
+                    "stack" => SegmentType::Stack,
                     seg => return Err(Error::new(src, ln, ErrorType::UnknownSegment(seg.into()))),
                 };
 
                 *current_segment = new_seg;
+
+                if min_addr.is_some() || max_addr.is_some() {
+                    let dls = state.dls.entry(new_seg).or_default();
+                    dls.layout = dls.layout.merge(SegmentLayout {
+                        min_addr: min_addr.unwrap_or(0),
+                        max_addr,
+                        ..SegmentLayout::default()
+                    });
+                }
+            }
+            SourceLine::DirAlign(alignment, padding_byte) => {
+                let size = state.get_size(*current_segment);
+                let Some(aligned) = checked_align_end(size, alignment) else {
+                    return Err(Error::new(
+                        src,
+                        ln,
+                        ErrorType::SegmentDoesNotFit(*current_segment),
+                    ));
+                };
+                let padding = aligned - size;
+                state.add_line(
+                    *current_segment,
+                    DataLine::Raw(vec![padding_byte; padding as usize]),
+                    padding,
+                    SourceLocation::new(src, ln),
+                )?;
+                let dls = state.dls.entry(*current_segment).or_default();
+                dls.layout.alignment = dls.layout.alignment.max(alignment);
             }
             SourceLine::DirEntry => {
                 if state.entry.is_some() {
@@ -508,95 +1500,356 @@ fn inner_process<B: BufRead>(
                 symbols.set_label(&s, addr, SourceLocation::new(src, ln))?;
             }
             SourceLine::Ins(s, ops) => {
-                let Some((opcode, dat_op)) =
-                    parse_ins(&s, ops, symbols, SourceLocation::new(src, ln))
-                        .map_err(|e| Error::new(src, ln, ErrorType::IncorrectOperands(e)))?
-                else {
-                    return Err(Error::new(
-                        src,
-                        ln,
-                        ErrorType::UnknownInstruction(s.into_boxed_str()),
-                    ));
+                let sl = SourceLocation::new(src, ln);
+                let res = match parse_ins(&s, &ops, symbols, sl.clone()) {
+                    Ok(Some(ins)) => Ok(ins),
+                    Ok(None) => Err(ErrorType::UnknownInstruction(s.into_boxed_str())),
+                    Err(e) => Err(operands::diagnose(&s, &ops, |ops| {
+                        matches!(parse_ins(&s, ops, symbols, sl.clone()), Ok(Some(_)))
+                    })
+                    .unwrap_or(ErrorType::IncorrectOperands(e))),
+                };
+                let (opcode, dat_op) = match res {
+                    Ok(ins) => ins,
+                    Err(et) => {
+                        let in_segment = *current_segment != SegmentType::Unknown;
+                        if state.recovery == Recovery::Continue && in_segment {
+                            // keep the addresses of the following lines right,
+                            // a full segment is left for the next line to report
+                            let _ = state.add_line(
+                                *current_segment,
+                                DataLine::Raw(vec![0]),
+                                1,
+                                SourceLocation::new(src, ln),
+                            );
+                        }
+                        return Err(Error::new(src, ln, et));
+                    }
                 };
                 state.add_line(
                     *current_segment,
                     DataLine::Ins(opcode, dat_op),
                     1 + dat_op.size(),
-                );
+                    SourceLocation::new(src, ln),
+                )?;
             }
-            SourceLine::DirByte(b) => {
-                state.add_line(*current_segment, DataLine::Raw(vec![b]), 1);
+            SourceLine::DirByte(bs) => {
+                let size = bs.len() as u16;
+                state.add_line(
+                    *current_segment,
+                    DataLine::Raw(bs),
+                    size,
+                    SourceLocation::new(src, ln),
+                )?;
             }
-            SourceLine::DirWide(w) => {
-                let wide = match w {
-                    Ok(w) => Wide::Number(w),
-                    Err(l) => Wide::Label(symbols.get_label(&l, SourceLocation::new(src, ln))),
-                };
-                state.add_line(*current_segment, DataLine::Wide(wide), 2);
+            SourceLine::DirWide(ws) => {
+                for w in ws {
+                    let wide = match w {
+                        Ok(w) => Wide::Number(w),
+                        Err(l) => Wide::Label(symbols.get_label(&l, SourceLocation::new(src, ln))),
+                    };
+                    state.add_line(
+                        *current_segment,
+                        DataLine::Wide(wide),
+                        2,
+                        SourceLocation::new(src, ln),
+                    )?;
+                }
             }
             SourceLine::DirString(s) => {
                 let size = s.len() as u16;
-                state.add_line(*current_segment, DataLine::Raw(s), size);
+                state.add_line(
+                    *current_segment,
+                    DataLine::Raw(s),
+                    size,
+                    SourceLocation::new(src, ln),
+                )?;
             }
             SourceLine::DirInclude(path) => {
-                let pth_buf;
+                let path = include_path(src, &path);
 
-                let path = if let Some(path) = path.strip_prefix('/') {
-                    Path::new(path)
-                } else {
-                    pth_buf = Path::new(&src).with_file_name("").join(&path);
-                    &pth_buf
-                };
+                let lines = SourceLines::new(&path)?;
+                state.inputs.push(path.clone());
 
-                let lines = SourceLines::new(path)?;
-                if let Some(e) = inner_process(lines, state, symbols) {
-                    return Err(e);
+                let path = path.canonicalize().unwrap_or(path);
+                if let Some(i) = state.includes.iter().position(|p| *p == path) {
+                    let chain = state.includes[i..]
+                        .iter()
+                        .map(|p| p.display().to_string().into_boxed_str())
+                        .collect();
+                    return Err(Error::new(
+                        src,
+                        ln,
+                        ErrorType::CircularInclude(path.display().to_string().into(), chain),
+                    ));
+                }
+                if state.includes.len() >= MAX_INCLUDE_DEPTH {
+                    return Err(Error::new(
+                        src,
+                        ln,
+                        ErrorType::IncludeTooDeep(MAX_INCLUDE_DEPTH),
+                    ));
+                }
+
+                state.includes.push(path);
+                let errors = inner_process(lines, &mut SegmentType::Unknown, state, symbols);
+                state.includes.pop();
+                if let Some(e) = errors {
+                    return Err(e.included_from(SourceLocation::new(src, ln)));
                 }
             }
-            SourceLine::DirGlobal(l) => {
-                let id = symbols.get_label(&l, SourceLocation::new(src, ln));
-                symbols.set_global(id);
-            }
-            SourceLine::DirReference(l) => {
-                let id = symbols.get_label(&l, SourceLocation::new(src, ln));
-                symbols.set_reference(id);
-            }
-            SourceLine::Comment => (),
-        }
+            SourceLine::DirIncludeBin(path, offset, length) => {
+                let path = include_path(src, &path);
+                let bytes =
+                    std::fs::read(&path).map_err(|e| Error::new(src, ln, ErrorType::IoError(e)))?;
+                state.inputs.push(path);
 
-        if state.unknown_defined() {
-            return Err(Error::new(
-                src,
-                ln,
-                ErrorType::Other("no segment was started".to_string().into_boxed_str()),
+                let offset = offset as usize;
+                let end = match length {
+                    Some(length) => offset + length as usize,
+                    None => bytes.len().max(offset),
+                };
+                let Some(bytes) = bytes.get(offset..end) else {
+                    return Err(Error::new(
+                        src,
+                        ln,
+                        ErrorType::IncbinOutOfBounds(end, bytes.len()),
+                    ));
+                };
+
+                let size = state.get_size(*current_segment) as usize;
+                if size + bytes.len() > 0xffff {
+                    return Err(Error::new(
+                        src,
+                        ln,
+                        ErrorType::SegmentDoesNotFit(*current_segment),
+                    ));
+                }
+                state.add_line(
+                    *current_segment,
+                    DataLine::Raw(bytes.to_vec()),
+                    bytes.len() as u16,
+                    SourceLocation::new(src, ln),
+                )?;
+            }
+            SourceLine::DirEqu(name, value) => {
+                let addr = Address(SegmentType::Zero, value);
+                symbols.set_label(&name, addr, SourceLocation::new(src, ln))?;
+                state.constants.insert(name.into(), value);
+            }
+            SourceLine::DirIf(_) | SourceLine::DirElse | SourceLine::DirEndif => {
+                unreachable!("conditions are handled by inner_process")
+            }
+            SourceLine::DirRep(_) | SourceLine::DirEndrep => {
+                unreachable!("repetitions are handled by inner_process")
+            }
+            SourceLine::DirStruct(_) | SourceLine::DirField(_, _) | SourceLine::DirEnds => {
+                unreachable!("structures are handled by inner_process")
+            }
+            SourceLine::DirGlobal(l) => {
+                let id = symbols.get_label(&l, SourceLocation::new(src, ln));
+                symbols.set_global(id);
+            }
+            SourceLine::DirWeak(l) => {
+                let id = symbols.get_label(&l, SourceLocation::new(src, ln));
+                symbols.set_weak(id);
+            }
+            SourceLine::DirReference(l) => {
+                let id = symbols.get_label(&l, SourceLocation::new(src, ln));
+                symbols.set_reference(id);
+            }
+            SourceLine::DirExtern(l) => {
+                let id = symbols.get_label(&l, SourceLocation::new(src, ln));
+                symbols.set_reference(id);
+                if !state.externs.iter().any(|e| **e == *l) {
+                    state.externs.push(l.into_boxed_str());
+                }
+            }
+            SourceLine::Comment => (),
+        }
+
+        if state.unknown_defined() {
+            return Err(Error::new(
+                src,
+                ln,
+                ErrorType::Other("no segment was started".to_string().into_boxed_str()),
             ));
         }
 
         Ok(())
     }
 
-    let mut current_segment = SegmentType::Unknown;
+    let mut conditions: Vec<Condition> = Vec::new();
+    let mut structure: Option<StructBuilder> = None;
 
     while let Some((ln, line)) = lines.parse_next_line() {
-        match inner_process_line(
-            &lines.source,
-            ln,
-            line,
-            &mut current_segment,
-            state,
-            symbols,
-        ) {
-            Ok(()) => (),
-            Err(e) => lines.add_error(e),
+        if state.recovery == Recovery::Stop && lines.errors.is_some() {
+            return lines.errors;
+        }
+        let active = conditions.last().is_none_or(Condition::active);
+        let src = &*lines.source;
+
+        let res = match line {
+            SourceLine::DirIf(cond) => {
+                // conditions in lines that are skipped are not evaluated
+                let value = if active {
+                    cond.evaluate(src, ln, state, symbols)
+                } else {
+                    Ok(false)
+                };
+                conditions.push(Condition {
+                    ln,
+                    parent_active: active,
+                    value: *value.as_ref().unwrap_or(&false),
+                    in_else: false,
+                });
+                value.map(|_| ())
+            }
+            SourceLine::DirElse => match conditions.last_mut() {
+                Some(c) if !c.in_else => {
+                    c.in_else = true;
+                    Ok(())
+                }
+                c => {
+                    let msg = if c.is_some() {
+                        "`.else' after another `.else'"
+                    } else {
+                        "`.else' without `.if'"
+                    };
+                    Err(Error::new(src, ln, ErrorType::Other(msg.into())))
+                }
+            },
+            SourceLine::DirEndif => match conditions.pop() {
+                Some(_) => Ok(()),
+                None => Err(Error::new(
+                    src,
+                    ln,
+                    ErrorType::Other("`.endif' without `.if'".into()),
+                )),
+            },
+            SourceLine::DirRep(count) => {
+                // the block is skipped as a whole when not active
+                let body = lines.read_rep_body();
+                if active {
+                    let src = lines.source.clone();
+                    body.and_then(|body| {
+                        let count = constant_value(&count, &src, ln, state, symbols)?;
+                        let count = u32::try_from(count)
+                            .ok()
+                            .filter(|&n| n <= MAX_REPETITIONS)
+                            .ok_or_else(|| {
+                                let msg = format!(
+                                    "`.rep' count {count} is not between 0 and {MAX_REPETITIONS}"
+                                );
+                                Error::new(&*src, ln, ErrorType::Other(msg.into_boxed_str()))
+                            })?;
+                        for i in 0..count {
+                            let text = expand_rep_body(&body, i);
+                            let mut lines = SourceLines::from_reader(io::Cursor::new(text));
+                            lines.source = src.clone();
+                            lines.ln = ln;
+                            if let Some(e) = inner_process(lines, current_segment, state, symbols) {
+                                return Err(e);
+                            }
+                        }
+                        Ok(())
+                    })
+                } else {
+                    body.map(|_| ())
+                }
+            }
+            SourceLine::DirEndrep => Err(Error::new(
+                src,
+                ln,
+                ErrorType::Other("`.endrep' without `.rep'".into()),
+            )),
+            _ if !active => Ok(()),
+            SourceLine::DirStruct(name) => match &structure {
+                Some(_) => Err(Error::new(
+                    src,
+                    ln,
+                    ErrorType::Other("`.struct' inside another `.struct'".into()),
+                )),
+                None => {
+                    structure = Some(StructBuilder { name, ln, offset: 0 });
+                    Ok(())
+                }
+            },
+            SourceLine::DirEnds => match structure.take() {
+                Some(s) => {
+                    let line = SourceLine::DirEqu(format!("SIZEOF_{}", s.name), s.offset);
+                    if let Some(expanded) = &mut state.expanded {
+                        expanded.push((src.into(), ln, line.clone()));
+                    }
+                    inner_process_line(src, ln, line, current_segment, state, symbols)
+                }
+                None => Err(Error::new(
+                    src,
+                    ln,
+                    ErrorType::Other("`.ends' without `.struct'".into()),
+                )),
+            },
+            SourceLine::DirField(_, _) if structure.is_none() => Err(Error::new(
+                src,
+                ln,
+                ErrorType::Other("field outside of a `.struct'".into()),
+            )),
+            line => {
+                let line = match &mut structure {
+                    Some(s) => s.take_line(src, ln, line),
+                    None => Ok(line),
+                };
+                line.and_then(|line| {
+                    if let Some(expanded) = &mut state.expanded {
+                        // the lines of an included file follow instead
+                        if !matches!(line, SourceLine::DirInclude(_)) {
+                            expanded.push((src.into(), ln, line.clone()));
+                        }
+                    }
+                    inner_process_line(src, ln, line, current_segment, state, symbols)
+                })
+            }
+        };
+        if let Err(e) = res {
+            lines.add_error(e);
+            if state.recovery == Recovery::Stop {
+                return lines.errors;
+            }
         }
     }
+    for c in conditions {
+        let e = Error::new(
+            lines.source.clone(),
+            c.ln,
+            ErrorType::Other("`.if' without `.endif'".into()),
+        );
+        lines.add_error(e);
+    }
+    if let Some(s) = structure {
+        let e = Error::new(
+            lines.source.clone(),
+            s.ln,
+            ErrorType::Other("`.struct' without `.ends'".into()),
+        );
+        lines.add_error(e);
+    }
 
+    state.warnings.append(&mut lines.warnings);
     lines.errors
 }
 
+/// Resolves a path in an include directive, relative to the including source unless it starts with `/`
+fn include_path(src: &str, path: &str) -> PathBuf {
+    if let Some(path) = path.strip_prefix('/') {
+        PathBuf::from(path)
+    } else {
+        Path::new(src).with_file_name("").join(path)
+    }
+}
+
 fn parse_ins(
     s: &str,
-    ops: Vec<SourceOperand>,
+    ops: &[SourceOperand],
     sym: &mut Symbols,
     sl: SourceLocation,
 ) -> StdResult<Option<(u8, DataOperand)>, &'static str> {
@@ -609,14 +1862,21 @@ fn parse_ins(
         "ctf" => (CTF, O::parse_nothing(ops).ok_or("no operands")?),
         "syscall" => (SYSCALL, O::parse_nothing(ops).ok_or("no operands")?),
         "reth" => (RETH, O::parse_nothing(ops).ok_or("no operands")?),
+        "cpuid" => (CPUID, O::parse_nothing(ops).ok_or("no operands")?),
         "nop" => (NOP, O::parse_nothing(ops).ok_or("no operands")?),
+        "wait" => (WAIT, O::parse_nothing(ops).ok_or("no operands")?),
         "push" => {
             if let Some(dat_op) = O::parse_breg(ops.clone()) {
                 (PUSH_B, dat_op)
             } else if let Some(dat_op) = O::parse_wreg(ops.clone()) {
                 (PUSH_W, dat_op)
+            } else if let Some(&SourceOperand::Byte(b)) = ops.clone().next() {
+                O::parse_imm_byte(ops).ok_or("one register or immediate")?;
+                (PUSH_IMM_B, DataOperand::ImmediateByte(b))
+            } else if let Some(dat_op) = O::parse_imm_wide(ops, sym, sl) {
+                (PUSH_IMM_W, dat_op)
             } else {
-                return Err("one register");
+                return Err("one register or immediate");
             }
         }
         "pop" => {
@@ -628,17 +1888,38 @@ fn parse_ins(
                 return Err("one register");
             }
         }
-        "call" => (
-            CALL,
-            O::parse_imm_wide(ops, sym, sl).ok_or("a wide (addr like a label or just a number)")?,
-        ),
-        "ret" => (
-            RET,
-            O::parse_nothing(ops.clone())
+        "call" => {
+            if let Some(dat_op) = O::parse_wreg(ops.clone()) {
+                if dat_op == DataOperand::WideRegister(R0) {
+                    return Err("any other register; r0 is not a valid call destination");
+                }
+                (CALL_R, dat_op)
+            } else {
+                (
+                    CALL,
+                    O::parse_imm_wide(ops, sym, sl)
+                        .ok_or("a wide (addr like a label or just a number) or wide register")?,
+                )
+            }
+        }
+        "ret" => {
+            // only numbers above 255 need the wide form, negative ones are truncated to a byte
+            // like for other byte operands
+            let fits_byte = match ops.clone().next() {
+                Some(&SourceOperand::Number(n)) => n <= u8::MAX as i32,
+                _ => true,
+            };
+            if let Some(dat_op) = O::parse_nothing(ops.clone())
                 .map(|_| DataOperand::ImmediateByte(0))
-                .or_else(|| O::parse_imm_byte(ops))
-                .ok_or("either nothing or a byte")?,
-        ),
+                .or_else(|| O::parse_imm_byte(ops.clone()).filter(|_| fits_byte))
+            {
+                (RET, dat_op)
+            } else if let Some(dat_op) = O::parse_imm_wide(ops, sym, sl) {
+                (RET_W, dat_op)
+            } else {
+                return Err("either nothing, a byte or a wide");
+            }
+        }
         "store" | "str" => {
             if let Some(dat_op) = O::parse_wide_imm_byte(ops.clone(), sym, sl.clone()) {
                 (STORE_BI, dat_op)
@@ -646,6 +1927,8 @@ fn parse_ins(
                 (STORE_WI, dat_op)
             } else if let Some(dat_op) = O::parse_two_wide_one_byte(ops.clone()) {
                 (STORE_BR, dat_op)
+            } else if let Some(dat_op) = O::parse_four_wide(ops.clone()) {
+                (STORE_D, dat_op)
             } else if let Some(dat_op) = O::parse_three_wide(ops.clone()) {
                 (STORE_WR, dat_op)
             } else {
@@ -659,6 +1942,8 @@ fn parse_ins(
                 (LOAD_WI, dat_op)
             } else if let Some(dat_op) = O::parse_byte_two_wide(ops.clone()) {
                 (LOAD_BR, dat_op)
+            } else if let Some(dat_op) = O::parse_four_wide(ops.clone()) {
+                (LOAD_D, dat_op)
             } else if let Some(dat_op) = O::parse_three_wide(ops.clone()) {
                 (LOAD_WR, dat_op)
             } else {
@@ -717,6 +2002,15 @@ fn parse_ins(
         "ldi" => {
             if let Some(dat_op) = O::parse_byte_imm(ops.clone()) {
                 (LDI_B, dat_op)
+            } else if let [SourceOperand::WideReg(r), SourceOperand::Number(n @ -128..=127)] =
+                ops.as_slice()
+            {
+                // a number that fits in a signed byte is loaded from one sign-extended byte
+                (LDI_W, DataOperand::WideNibbleByte(*r, U4::new(2), *n as u8))
+            } else if let [SourceOperand::WideReg(r), SourceOperand::Char(c @ '\0'..='\x7f')] =
+                ops.as_slice()
+            {
+                (LDI_W, DataOperand::WideNibbleByte(*r, U4::new(2), *c as u8))
             } else if let Some(dat_op) = O::parse_wide_imm(ops.clone(), sym, sl) {
                 let DataOperand::WideImm(r, w) = dat_op else { unreachable!() };
 
@@ -725,6 +2019,8 @@ fn parse_ins(
                 return Err("one register and one immediate");
             }
         }
+        // the `R1` is not a register but mode 1 of `ldi`, which jumps to the immediate when the
+        // register is r0 and otherwise to the register, leaving the immediate unused
         "jmp" | "jump" => {
             if let Some(dat_op) = O::parse_imm_wide(ops.clone(), sym, sl) {
                 let DataOperand::ImmediateWide(w) = dat_op else { unreachable!() };
@@ -732,23 +2028,31 @@ fn parse_ins(
                 (LDI_W, DataOperand::TwoWideImm(R0, R1, w))
             } else if let Some(dat_op) = O::parse_wreg(ops) {
                 let DataOperand::WideRegister(wr) = dat_op else { unreachable!() };
-                if wr == R0 {
-                    return Err("any other register; r0 is not a valid jmp destination");
-                }
+                // r0 is always zero, so `jmp r0` is `jmp 0`
                 (LDI_W, DataOperand::TwoWideImm(wr, R1, Wide::Number(0)))
             } else {
                 return Err("address or wide register");
             }
         }
 
-        "add" => parse_binop(ADD_B, ADD_W, ops)?,
-        "sub" => parse_binop(SUB_B, SUB_W, ops)?,
+        "add" => parse_binop_or_imm([ADD_B, ADD_W, ADD_B_IMM, ADD_W_IMM], ops, sym, sl)?,
+        "sub" => parse_binop_or_imm([SUB_B, SUB_W, SUB_B_IMM, SUB_W_IMM], ops, sym, sl)?,
         "and" => parse_binop(AND_B, AND_W, ops)?,
         "or" => parse_binop(OR_B, OR_W, ops)?,
         "xor" => parse_binop(XOR_B, XOR_W, ops)?,
-        "shl" => parse_binop(SHL_B, SHL_W, ops)?,
-        "asr" => parse_binop(ASR_B, ASR_W, ops)?,
-        "lsr" => parse_binop(LSR_B, LSR_W, ops)?,
+        "shl" => parse_shift([SHL_B, SHL_W, SHL_B_IMM, SHL_W_IMM], ops)?,
+        "asr" => parse_shift([ASR_B, ASR_W, ASR_B_IMM, ASR_W_IMM], ops)?,
+        "lsr" => parse_shift([LSR_B, LSR_W, LSR_B_IMM, LSR_W_IMM], ops)?,
+        "adc" => parse_binop(ADC_B, ADC_W, ops)?,
+        "sbb" => parse_binop(SBB_B, SBB_W, ops)?,
+        "cas" => (
+            CAS_W,
+            O::parse_three_wide(ops).ok_or("three wide registers")?,
+        ),
+        "mcpy" => (
+            MCPY,
+            O::parse_three_wide(ops).ok_or("three wide registers")?,
+        ),
         "mul" => {
             if let Some(dat_op) = O::parse_four_byte(ops.clone()) {
                 (MUL_B, dat_op)
@@ -767,6 +2071,101 @@ fn parse_ins(
                 return Err("four registers");
             }
         }
+        "sxt" => (SXT_B, O::parse_wide_byte(ops).ok_or("a wide and a byte register")?),
+        "zxt" | "movw" => (ZXT_B, O::parse_wide_byte(ops).ok_or("a wide and a byte register")?),
+        "movb" => (MOVB_WR, O::parse_byte_wide(ops).ok_or("a byte and a wide register")?),
+        "movbh" => (MOVBH_WR, O::parse_byte_wide(ops).ok_or("a byte and a wide register")?),
+        // pseudo-instructions
+        "mov" => {
+            if let Some(DataOperand::TwoByte(r1, r2)) = O::parse_two_byte(ops.clone()) {
+                (ADD_B, DataOperand::ThreeByte(r1, r2, R0B))
+            } else if let Some(DataOperand::TwoWide(r1, r2)) = O::parse_two_wide(ops) {
+                (ADD_W, DataOperand::ThreeWide(r1, r2, R0))
+            } else {
+                return Err("two registers of same size");
+            }
+        }
+        "clr" => {
+            if let Some(DataOperand::ByteRegister(r)) = O::parse_breg(ops.clone()) {
+                (XOR_B, DataOperand::ThreeByte(r, r, r))
+            } else if let Some(DataOperand::WideRegister(r)) = O::parse_wreg(ops) {
+                (XOR_W, DataOperand::ThreeWide(r, r, r))
+            } else {
+                return Err("one register");
+            }
+        }
+        "in" => (IN_B, O::parse_two_byte(ops).ok_or("two byte registers")?),
+        "out" => (OUT_B, O::parse_two_byte(ops).ok_or("two byte registers")?),
+        "bswap" => {
+            if let Some(dat_op) = O::parse_two_byte(ops.clone()) {
+                (BSWAP_B, dat_op)
+            } else if let Some(dat_op) = O::parse_two_wide(ops) {
+                (BSWAP_W, dat_op)
+            } else {
+                return Err("two registers of same size");
+            }
+        }
+        "popcnt" => {
+            if let Some(dat_op) = O::parse_two_byte(ops.clone()) {
+                (POPCNT_B, dat_op)
+            } else if let Some(dat_op) = O::parse_two_wide(ops) {
+                (POPCNT_W, dat_op)
+            } else {
+                return Err("two registers of same size");
+            }
+        }
+        "clz" => {
+            if let Some(dat_op) = O::parse_two_byte(ops.clone()) {
+                (CLZ_B, dat_op)
+            } else if let Some(dat_op) = O::parse_two_wide(ops) {
+                (CLZ_W, dat_op)
+            } else {
+                return Err("two registers of same size");
+            }
+        }
+        "ctz" => {
+            if let Some(dat_op) = O::parse_two_byte(ops.clone()) {
+                (CTZ_B, dat_op)
+            } else if let Some(dat_op) = O::parse_two_wide(ops) {
+                (CTZ_W, dat_op)
+            } else {
+                return Err("two registers of same size");
+            }
+        }
+        "neg" => {
+            if let Some(dat_op) = O::parse_two_byte(ops.clone()) {
+                let DataOperand::TwoByte(r1, _) = dat_op else { unreachable!() };
+                if r1 == R0B {
+                    return Err("two registers with the first not being r0b");
+                }
+                (NEG_B, dat_op)
+            } else if let Some(dat_op) = O::parse_two_wide(ops) {
+                let DataOperand::TwoWide(r1, _) = dat_op else { unreachable!() };
+                if r1 == R0 {
+                    return Err("two registers with the first not being r0");
+                }
+                (NEG_W, dat_op)
+            } else {
+                return Err("two registers of same size");
+            }
+        }
+        "xchg" => {
+            if let Some(dat_op) = O::parse_two_byte(ops.clone()) {
+                let DataOperand::TwoByte(r1, r2) = dat_op else { unreachable!() };
+                if r1 == R0B || r2 == R0B {
+                    return Err("two registers other than r0b; r0b cannot be exchanged");
+                }
+                (XCHG_B, dat_op)
+            } else if let Some(dat_op) = O::parse_two_wide(ops) {
+                let DataOperand::TwoWide(r1, r2) = dat_op else { unreachable!() };
+                if r1 == R0 || r2 == R0 {
+                    return Err("two registers other than r0; r0 cannot be exchanged");
+                }
+                (XCHG_W, dat_op)
+            } else {
+                return Err("two registers of same size");
+            }
+        }
         // TODO: BAD
         _ => {
             return Ok(None);
@@ -788,6 +2187,39 @@ fn parse_binop(
     }
 }
 
+/// Parses an operation on three registers or on two registers and an immediate
+fn parse_binop_or_imm(
+    [bop, wop, bop_imm, wop_imm]: [u8; 4],
+    ops: Iter<SourceOperand>,
+    sym: &mut Symbols,
+    sl: SourceLocation,
+) -> StdResult<(u8, DataOperand), &'static str> {
+    if let Ok(ins) = parse_binop(bop, wop, ops.clone()) {
+        Ok(ins)
+    } else if let Some(dat_op) = DataOperand::parse_two_byte_imm(ops.clone()) {
+        Ok((bop_imm, dat_op))
+    } else if let Some(dat_op) = DataOperand::parse_two_wide_imm(ops, sym, sl) {
+        Ok((wop_imm, dat_op))
+    } else {
+        Err("three regs of same size or two regs and an immediate")
+    }
+}
+
+/// Parses a shift by a register or by a number from 0 to 15
+fn parse_shift(
+    [bop, wop, bop_imm, wop_imm]: [u8; 4],
+    ops: Iter<SourceOperand>,
+) -> StdResult<(u8, DataOperand), &'static str> {
+    if let Some(dat_op) = DataOperand::parse_two_byte_nibble(ops.clone()) {
+        Ok((bop_imm, dat_op))
+    } else if let Some(dat_op) = DataOperand::parse_two_wide_nibble(ops.clone()) {
+        Ok((wop_imm, dat_op))
+    } else {
+        parse_binop(bop, wop, ops)
+            .map_err(|_| "three regs of same size or two regs and a shift count below 16")
+    }
+}
+
 fn parse_wide<F: FnOnce(usize, LabelRead) -> u16>(
     w: Wide,
     read_label: F,
@@ -800,88 +2232,31 @@ fn parse_wide<F: FnOnce(usize, LabelRead) -> u16>(
     }
 }
 
+/// Writes the encoding of `dat_op` to `w`
+///
+/// `position` is where in the segment the operand starts, it is given to `read_label`.
+/// This is kept for compatibility, prefer [`DataOperand::encode`]
 pub fn write_data_operand<F: FnOnce(usize, LabelRead) -> u16>(
     st: SegmentType,
-    mem: &mut Vec<u8>,
+    w: &mut dyn Write,
+    position: u16,
     read_label: F,
     dat_op: DataOperand,
-) {
-    use self::DataOperand::*;
-
-    match dat_op {
-        Nothing => (),
-        ByteRegister(r) => mem.push(r.0.pair(U4::ZERO)),
-        WideRegister(r) => mem.push(r.0.pair(U4::ZERO)),
-        ImmediateByte(b) => {
-            mem.push(b);
-        }
-        ImmediateWide(w) => {
-            let position = mem.len() as u16;
-            mem.extend_from_slice(&parse_wide(w, read_label, st, position).to_le_bytes());
-        }
-        ByteImm(r, b) => {
-            mem.push(r.0.pair(U4::ZERO));
-            mem.push(b);
-        }
-        WideImm(r, w) => {
-            mem.push(r.0.pair(U4::ZERO));
-            let position = mem.len() as u16;
-            mem.extend_from_slice(&parse_wide(w, read_label, st, position).to_le_bytes());
-        }
-        WideImmByte(r1, w, r2) => {
-            mem.push(r1.0.pair(r2.0));
-            let position = mem.len() as u16;
-            mem.extend_from_slice(&parse_wide(w, read_label, st, position).to_le_bytes());
-        }
-        WideImmWide(r1, w, r2) => {
-            mem.push(r1.0.pair(r2.0));
-            let position = mem.len() as u16;
-            mem.extend_from_slice(&parse_wide(w, read_label, st, position).to_le_bytes());
-        }
-        TwoWideOneByte(r1, r2, r3) => {
-            mem.push(r1.0.pair(r2.0));
-            mem.push(r3.0.pair(U4::ZERO))
-        }
-        ByteWideImm(r1, r2, w) => {
-            mem.push(r1.0.pair(r2.0));
-            let position = mem.len() as u16;
-            mem.extend_from_slice(&parse_wide(w, read_label, st, position).to_le_bytes());
-        }
-        TwoWideImm(r1, r2, w) => {
-            mem.push(r1.0.pair(r2.0));
-            let position = mem.len() as u16;
-            mem.extend_from_slice(&parse_wide(w, read_label, st, position).to_le_bytes());
-        }
-        ByteTwoWide(r1, r2, r3) => {
-            mem.push(r1.0.pair(r2.0));
-            mem.push(r3.0.pair(U4::ZERO));
-        }
-        ThreeByte(r1, r2, r3) => {
-            mem.push(r1.0.pair(r2.0));
-            mem.push(r3.0.pair(U4::ZERO));
-        }
-        ThreeWide(r1, r2, r3) => {
-            mem.push(r1.0.pair(r2.0));
-            mem.push(r3.0.pair(U4::ZERO));
-        }
-        FourByte(r1, r2, r3, r4) => {
-            mem.push(r1.0.pair(r2.0));
-            mem.push(r3.0.pair(r4.0));
-        }
-        FourWide(r1, r2, r3, r4) => {
-            mem.push(r1.0.pair(r2.0));
-            mem.push(r3.0.pair(r4.0));
-        }
-    }
+) -> io::Result<()> {
+    // an operand has at most one wide immediate, so the label is read at most once
+    let mut read_label = Some(read_label);
+    w.write_all(&dat_op.encode(st, position, &mut |id, lr| {
+        read_label.take().expect("operand reads only one label")(id, lr)
+    }))
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Wide {
     Number(u16),
     Label(usize),
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum DataOperand {
     Nothing,
     ByteRegister(BReg),
@@ -892,18 +2267,73 @@ pub enum DataOperand {
     WideImm(WReg, Wide),
     WideImmByte(WReg, Wide, BReg),
     WideImmWide(WReg, Wide, WReg),
+    TwoByte(BReg, BReg),
+    TwoWide(WReg, WReg),
+    WideByte(WReg, BReg),
+    ByteWide(BReg, WReg),
     TwoWideOneByte(WReg, WReg, BReg),
     ByteWideImm(BReg, WReg, Wide),
     TwoWideImm(WReg, WReg, Wide),
+    TwoByteImm(BReg, BReg, u8),
     ByteTwoWide(BReg, WReg, WReg),
     ThreeByte(BReg, BReg, BReg),
     ThreeWide(WReg, WReg, WReg),
+    /// Two registers and a 4-bit immediate
+    TwoByteNibble(BReg, BReg, U4),
+    TwoWideNibble(WReg, WReg, U4),
+    /// A wide register, a 4-bit immediate and a byte immediate
+    WideNibbleByte(WReg, U4, u8),
     FourByte(BReg, BReg, BReg, BReg),
     FourWide(WReg, WReg, WReg, WReg),
 }
 
+impl Display for Wide {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Wide::Number(n) => write!(f, "0x{n:04x}"),
+            // only the symbol table knows the name
+            Wide::Label(id) => write!(f, "label_{id}"),
+        }
+    }
+}
+
+/// Prints the operands separated by commas in the order they are stored
+impl Display for DataOperand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use self::DataOperand::*;
+        match *self {
+            Nothing => Ok(()),
+            ByteRegister(r) => write!(f, "{r}"),
+            WideRegister(r) => write!(f, "{r}"),
+            ImmediateByte(b) => write!(f, "0x{b:02x}"),
+            ImmediateWide(w) => write!(f, "{w}"),
+            ByteImm(r, b) => write!(f, "{r}, 0x{b:02x}"),
+            WideImm(r, w) => write!(f, "{r}, {w}"),
+            WideImmByte(r1, w, r2) => write!(f, "{r1}, {w}, {r2}"),
+            WideImmWide(r1, w, r2) => write!(f, "{r1}, {w}, {r2}"),
+            TwoByte(r1, r2) => write!(f, "{r1}, {r2}"),
+            TwoWide(r1, r2) => write!(f, "{r1}, {r2}"),
+            WideByte(r1, r2) => write!(f, "{r1}, {r2}"),
+            ByteWide(r1, r2) => write!(f, "{r1}, {r2}"),
+            TwoWideOneByte(r1, r2, r3) => write!(f, "{r1}, {r2}, {r3}"),
+            ByteWideImm(r1, r2, w) => write!(f, "{r1}, {r2}, {w}"),
+            TwoWideImm(r1, r2, w) => write!(f, "{r1}, {r2}, {w}"),
+            TwoByteImm(r1, r2, b) => write!(f, "{r1}, {r2}, 0x{b:02x}"),
+            ByteTwoWide(r1, r2, r3) => write!(f, "{r1}, {r2}, {r3}"),
+            ThreeByte(r1, r2, r3) => write!(f, "{r1}, {r2}, {r3}"),
+            ThreeWide(r1, r2, r3) => write!(f, "{r1}, {r2}, {r3}"),
+            TwoByteNibble(r1, r2, n) => write!(f, "{r1}, {r2}, {}", u8::from(n)),
+            TwoWideNibble(r1, r2, n) => write!(f, "{r1}, {r2}, {}", u8::from(n)),
+            WideNibbleByte(r, n, b) => write!(f, "{r}, {}, 0x{b:02x}", u8::from(n)),
+            FourByte(r1, r2, r3, r4) => write!(f, "{r1}, {r2}, {r3}, {r4}"),
+            FourWide(r1, r2, r3, r4) => write!(f, "{r1}, {r2}, {r3}, {r4}"),
+        }
+    }
+}
+
 impl DataOperand {
-    fn size(&self) -> u16 {
+    /// Number of bytes the operand takes up after the opcode
+    pub fn size(&self) -> u16 {
         use self::DataOperand::*;
         match self {
             Nothing => 0,
@@ -915,16 +2345,127 @@ impl DataOperand {
             WideImm(_, _) => 3,
             WideImmByte(_, _, _) => 3,
             WideImmWide(_, _, _) => 3,
+            TwoByte(_, _) => 1,
+            TwoWide(_, _) => 1,
+            WideByte(_, _) => 1,
+            ByteWide(_, _) => 1,
             TwoWideOneByte(_, _, _) => 2,
             ByteWideImm(_, _, _) => 3,
             TwoWideImm(_, _, _) => 3,
+            TwoByteImm(_, _, _) => 2,
             ByteTwoWide(_, _, _) => 2,
             ThreeByte(_, _, _) => 2,
             ThreeWide(_, _, _) => 2,
+            TwoByteNibble(_, _, _) => 2,
+            TwoWideNibble(_, _, _) => 2,
+            WideNibbleByte(_, _, _) => 2,
             FourByte(_, _, _, _) => 2,
             FourWide(_, _, _, _) => 2,
         }
     }
+    /// Encodes the operand as it would appear after the opcode
+    ///
+    /// `position` is where in `segment` the operand starts. `read_label` is given the label and a
+    /// `LabelRead` whose position is where in `segment` the label is read, so the read can be
+    /// relocated
+    pub fn encode(
+        &self,
+        segment: SegmentType,
+        position: u16,
+        read_label: &mut dyn FnMut(usize, LabelRead) -> u16,
+    ) -> Vec<u8> {
+        use self::DataOperand::*;
+
+        let mut mem = Vec::with_capacity(self.size() as usize);
+
+        match *self {
+            Nothing => (),
+            ByteRegister(r) => mem.push(r.0.pair(U4::ZERO)),
+            WideRegister(r) => mem.push(r.0.pair(U4::ZERO)),
+            ImmediateByte(b) => {
+                mem.push(b);
+            }
+            ImmediateWide(w) => {
+                let position = position + mem.len() as u16;
+                mem.extend_from_slice(&parse_wide(w, read_label, segment, position).to_le_bytes());
+            }
+            ByteImm(r, b) => {
+                mem.push(r.0.pair(U4::ZERO));
+                mem.push(b);
+            }
+            WideImm(r, w) => {
+                mem.push(r.0.pair(U4::ZERO));
+                let position = position + mem.len() as u16;
+                mem.extend_from_slice(&parse_wide(w, read_label, segment, position).to_le_bytes());
+            }
+            WideImmByte(r1, w, r2) => {
+                mem.push(r1.0.pair(r2.0));
+                let position = position + mem.len() as u16;
+                mem.extend_from_slice(&parse_wide(w, read_label, segment, position).to_le_bytes());
+            }
+            WideImmWide(r1, w, r2) => {
+                mem.push(r1.0.pair(r2.0));
+                let position = position + mem.len() as u16;
+                mem.extend_from_slice(&parse_wide(w, read_label, segment, position).to_le_bytes());
+            }
+            TwoByte(r1, r2) => mem.push(r1.0.pair(r2.0)),
+            TwoWide(r1, r2) => mem.push(r1.0.pair(r2.0)),
+            WideByte(r1, r2) => mem.push(r1.0.pair(r2.0)),
+            ByteWide(r1, r2) => mem.push(r1.0.pair(r2.0)),
+            TwoWideOneByte(r1, r2, r3) => {
+                mem.push(r1.0.pair(r2.0));
+                mem.push(r3.0.pair(U4::ZERO))
+            }
+            ByteWideImm(r1, r2, w) => {
+                mem.push(r1.0.pair(r2.0));
+                let position = position + mem.len() as u16;
+                mem.extend_from_slice(&parse_wide(w, read_label, segment, position).to_le_bytes());
+            }
+            TwoWideImm(r1, r2, w) => {
+                mem.push(r1.0.pair(r2.0));
+                let position = position + mem.len() as u16;
+                mem.extend_from_slice(&parse_wide(w, read_label, segment, position).to_le_bytes());
+            }
+            TwoByteImm(r1, r2, b) => {
+                mem.push(r1.0.pair(r2.0));
+                mem.push(b);
+            }
+            ByteTwoWide(r1, r2, r3) => {
+                mem.push(r1.0.pair(r2.0));
+                mem.push(r3.0.pair(U4::ZERO));
+            }
+            ThreeByte(r1, r2, r3) => {
+                mem.push(r1.0.pair(r2.0));
+                mem.push(r3.0.pair(U4::ZERO));
+            }
+            ThreeWide(r1, r2, r3) => {
+                mem.push(r1.0.pair(r2.0));
+                mem.push(r3.0.pair(U4::ZERO));
+            }
+            TwoByteNibble(r1, r2, n) => {
+                mem.push(r1.0.pair(r2.0));
+                mem.push(n.pair(U4::ZERO));
+            }
+            TwoWideNibble(r1, r2, n) => {
+                mem.push(r1.0.pair(r2.0));
+                mem.push(n.pair(U4::ZERO));
+            }
+            WideNibbleByte(r, n, b) => {
+                mem.push(r.0.pair(n));
+                mem.push(b);
+            }
+            FourByte(r1, r2, r3, r4) => {
+                mem.push(r1.0.pair(r2.0));
+                mem.push(r3.0.pair(r4.0));
+            }
+            FourWide(r1, r2, r3, r4) => {
+                mem.push(r1.0.pair(r2.0));
+                mem.push(r3.0.pair(r4.0));
+            }
+        }
+
+        mem
+    }
     fn parse_nothing<'a>(mut ops: impl Iterator<Item = &'a SourceOperand>) -> Option<DataOperand> {
         if ops.next().is_none() {
             Some(DataOperand::Nothing)
@@ -963,6 +2504,7 @@ impl DataOperand {
     fn parse_byte_imm<'a>(mut ops: impl Iterator<Item = &'a SourceOperand>) -> Option<DataOperand> {
         let reg1 = ops.next()?;
         let imm = ops.next()?;
+        Self::parse_nothing(ops)?;
         Some(DataOperand::ByteImm(
             Self::byte(reg1)?,
             Self::imm_byte(imm)?,
@@ -975,17 +2517,43 @@ impl DataOperand {
     ) -> Option<DataOperand> {
         let reg1 = ops.next()?;
         let imm = ops.next()?;
+        Self::parse_nothing(ops)?;
         Some(DataOperand::WideImm(
             Self::wide(reg1)?,
             Self::imm_wide(imm, sym, sl)?,
         ))
     }
+    fn parse_two_byte<'a>(mut ops: impl Iterator<Item = &'a SourceOperand>) -> Option<DataOperand> {
+        let reg1 = Self::byte(ops.next()?)?;
+        let reg2 = Self::byte(ops.next()?)?;
+        Self::parse_nothing(ops)?;
+        Some(DataOperand::TwoByte(reg1, reg2))
+    }
+    fn parse_two_wide<'a>(mut ops: impl Iterator<Item = &'a SourceOperand>) -> Option<DataOperand> {
+        let reg1 = Self::wide(ops.next()?)?;
+        let reg2 = Self::wide(ops.next()?)?;
+        Self::parse_nothing(ops)?;
+        Some(DataOperand::TwoWide(reg1, reg2))
+    }
+    fn parse_wide_byte<'a>(mut ops: impl Iterator<Item = &'a SourceOperand>) -> Option<DataOperand> {
+        let reg1 = Self::wide(ops.next()?)?;
+        let reg2 = Self::byte(ops.next()?)?;
+        Self::parse_nothing(ops)?;
+        Some(DataOperand::WideByte(reg1, reg2))
+    }
+    fn parse_byte_wide<'a>(mut ops: impl Iterator<Item = &'a SourceOperand>) -> Option<DataOperand> {
+        let reg1 = Self::byte(ops.next()?)?;
+        let reg2 = Self::wide(ops.next()?)?;
+        Self::parse_nothing(ops)?;
+        Some(DataOperand::ByteWide(reg1, reg2))
+    }
     fn parse_three_byte<'a>(
         mut ops: impl Iterator<Item = &'a SourceOperand>,
     ) -> Option<DataOperand> {
         let reg1 = ops.next()?;
         let reg2 = ops.next()?;
         let reg3 = ops.next()?;
+        Self::parse_nothing(ops)?;
         Some(DataOperand::ThreeByte(
             Self::byte(reg1)?,
             Self::byte(reg2)?,
@@ -998,12 +2566,31 @@ impl DataOperand {
         let reg1 = ops.next()?;
         let reg2 = ops.next()?;
         let reg3 = ops.next()?;
+        Self::parse_nothing(ops)?;
         Some(DataOperand::ThreeWide(
             Self::wide(reg1)?,
             Self::wide(reg2)?,
             Self::wide(reg3)?,
         ))
     }
+    fn parse_two_byte_nibble<'a>(
+        mut ops: impl Iterator<Item = &'a SourceOperand>,
+    ) -> Option<DataOperand> {
+        let reg1 = Self::byte(ops.next()?)?;
+        let reg2 = Self::byte(ops.next()?)?;
+        let n = Self::imm_nibble(ops.next()?)?;
+        Self::parse_nothing(ops)?;
+        Some(DataOperand::TwoByteNibble(reg1, reg2, n))
+    }
+    fn parse_two_wide_nibble<'a>(
+        mut ops: impl Iterator<Item = &'a SourceOperand>,
+    ) -> Option<DataOperand> {
+        let reg1 = Self::wide(ops.next()?)?;
+        let reg2 = Self::wide(ops.next()?)?;
+        let n = Self::imm_nibble(ops.next()?)?;
+        Self::parse_nothing(ops)?;
+        Some(DataOperand::TwoWideNibble(reg1, reg2, n))
+    }
     fn parse_wide_imm_byte<'a>(
         mut ops: impl Iterator<Item = &'a SourceOperand>,
         sym: &mut Symbols,
@@ -1012,6 +2599,7 @@ impl DataOperand {
         let reg1 = Self::wide(ops.next()?)?;
         let imm = Self::imm_wide(ops.next()?, sym, sl)?;
         let reg2 = Self::byte(ops.next()?)?;
+        Self::parse_nothing(ops)?;
         Some(DataOperand::WideImmByte(reg1, imm, reg2))
     }
     fn parse_wide_imm_wide<'a>(
@@ -1022,47 +2610,57 @@ impl DataOperand {
         let reg1 = Self::wide(ops.next()?)?;
         let imm = Self::imm_wide(ops.next()?, sym, sl)?;
         let reg2 = Self::wide(ops.next()?)?;
+        Self::parse_nothing(ops)?;
         Some(DataOperand::WideImmWide(reg1, imm, reg2))
     }
     fn parse_two_wide_one_byte<'a>(
         mut ops: impl Iterator<Item = &'a SourceOperand>,
     ) -> Option<DataOperand> {
-        Some(DataOperand::TwoWideOneByte(
-            Self::wide(ops.next()?)?,
-            Self::wide(ops.next()?)?,
-            Self::byte(ops.next()?)?,
-        ))
+        let reg1 = Self::wide(ops.next()?)?;
+        let reg2 = Self::wide(ops.next()?)?;
+        let reg3 = Self::byte(ops.next()?)?;
+        Self::parse_nothing(ops)?;
+        Some(DataOperand::TwoWideOneByte(reg1, reg2, reg3))
     }
     fn parse_byte_wide_imm<'a>(
         mut ops: impl Iterator<Item = &'a SourceOperand>,
         sym: &mut Symbols,
         sl: SourceLocation,
     ) -> Option<DataOperand> {
-        Some(DataOperand::ByteWideImm(
-            Self::byte(ops.next()?)?,
-            Self::wide(ops.next()?)?,
-            Self::imm_wide(ops.next()?, sym, sl)?,
-        ))
+        let reg1 = Self::byte(ops.next()?)?;
+        let reg2 = Self::wide(ops.next()?)?;
+        let imm = Self::imm_wide(ops.next()?, sym, sl)?;
+        Self::parse_nothing(ops)?;
+        Some(DataOperand::ByteWideImm(reg1, reg2, imm))
     }
     fn parse_two_wide_imm<'a>(
         mut ops: impl Iterator<Item = &'a SourceOperand>,
         sym: &mut Symbols,
         sl: SourceLocation,
     ) -> Option<DataOperand> {
-        Some(DataOperand::TwoWideImm(
-            Self::wide(ops.next()?)?,
-            Self::wide(ops.next()?)?,
-            Self::imm_wide(ops.next()?, sym, sl)?,
-        ))
+        let reg1 = Self::wide(ops.next()?)?;
+        let reg2 = Self::wide(ops.next()?)?;
+        let imm = Self::imm_wide(ops.next()?, sym, sl)?;
+        Self::parse_nothing(ops)?;
+        Some(DataOperand::TwoWideImm(reg1, reg2, imm))
+    }
+    fn parse_two_byte_imm<'a>(
+        mut ops: impl Iterator<Item = &'a SourceOperand>,
+    ) -> Option<DataOperand> {
+        let reg1 = Self::byte(ops.next()?)?;
+        let reg2 = Self::byte(ops.next()?)?;
+        let imm = Self::imm_byte(ops.next()?)?;
+        Self::parse_nothing(ops)?;
+        Some(DataOperand::TwoByteImm(reg1, reg2, imm))
     }
     fn parse_byte_two_wide<'a>(
         mut ops: impl Iterator<Item = &'a SourceOperand>,
     ) -> Option<DataOperand> {
-        Some(DataOperand::ByteTwoWide(
-            Self::byte(ops.next()?)?,
-            Self::wide(ops.next()?)?,
-            Self::wide(ops.next()?)?,
-        ))
+        let reg1 = Self::byte(ops.next()?)?;
+        let reg2 = Self::wide(ops.next()?)?;
+        let reg3 = Self::wide(ops.next()?)?;
+        Self::parse_nothing(ops)?;
+        Some(DataOperand::ByteTwoWide(reg1, reg2, reg3))
     }
     fn parse_four_byte<'a>(
         mut ops: impl Iterator<Item = &'a SourceOperand>,
@@ -1071,7 +2669,7 @@ impl DataOperand {
         let reg2 = ops.next()?;
         let reg3 = ops.next()?;
         let reg4 = ops.next()?;
-        Self::parse_nothing(ops);
+        Self::parse_nothing(ops)?;
         Some(DataOperand::FourByte(
             Self::byte(reg1)?,
             Self::byte(reg2)?,
@@ -1086,7 +2684,7 @@ impl DataOperand {
         let reg2 = ops.next()?;
         let reg3 = ops.next()?;
         let reg4 = ops.next()?;
-        Self::parse_nothing(ops);
+        Self::parse_nothing(ops)?;
         Some(DataOperand::FourWide(
             Self::wide(reg1)?,
             Self::wide(reg2)?,
@@ -1111,6 +2709,13 @@ impl DataOperand {
         match *op {
             SourceOperand::Number(n) => Some(n as u8),
             SourceOperand::Byte(n) => Some(n),
+            SourceOperand::Char(c) if c.is_ascii() => Some(c as u8),
+            _ => None,
+        }
+    }
+    fn imm_nibble(op: &SourceOperand) -> Option<U4> {
+        match *op {
+            SourceOperand::Number(n @ 0..=15) => Some(U4::new(n as u8)),
             _ => None,
         }
     }
@@ -1118,8 +2723,718 @@ impl DataOperand {
         match op {
             &SourceOperand::Number(n) => Some(Wide::Number(n as u16)),
             &SourceOperand::Wide(n) => Some(Wide::Number(n)),
+            &SourceOperand::Char(c) => u16::try_from(c as u32).ok().map(Wide::Number),
             SourceOperand::Label(lbl) => Some(Wide::Label(sym.get_label(lbl, sl))),
             _ => None,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A directory under the system temp directory for one test run, removed when dropped
+    pub(super) struct TempDir(PathBuf);
+
+    impl TempDir {
+        pub(super) fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("telda_{name}_{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+        pub(super) fn join(&self, file: &str) -> PathBuf {
+            self.0.join(file)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn bytechar_escapes() {
+        let escapes = [
+            (b"\\a", 0x07),
+            (b"\\b", 0x08),
+            (b"\\e", 0x1b),
+            (b"\\f", 0x0c),
+            (b"\\v", 0x0b),
+            (b"\\n", b'\n'),
+        ];
+        for (s, b) in escapes {
+            assert_eq!(parse_bytechar(s).unwrap(), (b, &b""[..]));
+        }
+        assert_eq!(parse_bytechar(b"\\x41b").unwrap(), (b'A', &b"b"[..]));
+    }
+
+    #[test]
+    fn octal_escapes() {
+        assert_eq!(parse_bytechar(b"\\101").unwrap(), (b'A', &b""[..]));
+        assert_eq!(parse_bytechar(b"\\377").unwrap(), (0xff, &b""[..]));
+        assert_eq!(parse_bytechar(b"\\0").unwrap(), (0, &b""[..]));
+        assert_eq!(parse_bytechar(b"\\0a").unwrap(), (0, &b"a"[..]));
+        assert_eq!(parse_bytechar(b"\\012").unwrap(), (b'\n', &b""[..]));
+        assert_eq!(parse_bytechar(b"\\1018").unwrap(), (b'A', &b"8"[..]));
+        assert!(matches!(
+            parse_bytechar(b"\\400"),
+            Err(ErrorType::InvalidEscapeSequence)
+        ));
+
+        let src = ".seg rodata\n.string \\101\\033[0m\n.byte '\\377'\n";
+        let ps = ProcessedSource::assemble(src).unwrap();
+        let DataLine::Raw(ref bytes) = ps.dls[&SegmentType::RoData].lines[0] else {
+            panic!("string should be raw bytes")
+        };
+        assert_eq!(bytes, b"A\x1b[0m");
+        assert!(matches!(
+            ps.dls[&SegmentType::RoData].lines[1],
+            DataLine::Raw(ref b) if b == &[0xff]
+        ));
+        assert!(ProcessedSource::assemble(".seg rodata\n.string \\777\n").is_err());
+    }
+
+    #[test]
+    fn bytechar_invalid_escapes() {
+        assert!(matches!(
+            parse_bytechar(b"\\q"),
+            Err(ErrorType::InvalidEscapeCharacter(b'q'))
+        ));
+        assert!(matches!(
+            parse_bytechar(b"\\x4"),
+            Err(ErrorType::InvalidEscapeSequence)
+        ));
+        assert!(matches!(
+            parse_bytechar(b"\\"),
+            Err(ErrorType::EscapeCharacterAtEnd)
+        ));
+    }
+
+    #[test]
+    fn unicode_characters() {
+        let src = ".seg data\n.string hé\n.string16 hé🦀\\x41\n.wide 'é'\n";
+        let ps = ProcessedSource::assemble(src).unwrap();
+        let data = &ps.dls[&SegmentType::Data];
+        assert!(matches!(&data.lines[0], DataLine::Raw(b) if b == "hé".as_bytes()));
+        let utf16 = [0x68, 0, 0xe9, 0, 0x3e, 0xd8, 0x80, 0xdd, 0x41, 0];
+        assert!(matches!(&data.lines[1], DataLine::Raw(b) if b == &utf16));
+        assert!(matches!(data.lines[2], DataLine::Wide(Wide::Number(0xe9))));
+
+        let src = ".seg text\nldi r1, 'é'\nldi r2, 'a'\nldi r3l, '\\n'\nldi r3h, '\\xff'\n";
+        let ps = ProcessedSource::assemble(src).unwrap();
+        let text = &ps.dls[&SegmentType::Text];
+        assert!(matches!(
+            text.lines[0],
+            DataLine::Ins(isa::LDI_W, DataOperand::TwoWideImm(R1, R0, Wide::Number(0xe9)))
+        ));
+        assert!(matches!(
+            text.lines[1],
+            DataLine::Ins(isa::LDI_W, DataOperand::WideNibbleByte(R2, _, b'a'))
+        ));
+        assert!(matches!(
+            text.lines[2],
+            DataLine::Ins(isa::LDI_B, DataOperand::ByteImm(R3L, b'\n'))
+        ));
+        assert!(matches!(
+            text.lines[3],
+            DataLine::Ins(isa::LDI_B, DataOperand::ByteImm(R3H, 0xff))
+        ));
+
+        let src = ".seg data\n.byte 'é'\n";
+        let errors = ProcessedSource::assemble(src).unwrap_err().into_vec();
+        assert!(matches!(
+            errors[0].error_type(),
+            ErrorType::CharacterDoesNotFit('é', LiteralSize::Byte)
+        ));
+    }
+
+    #[test]
+    fn label_as_byte() {
+        let src = ".seg data 0x200\nmy_label:\n.byte 0\n.seg text\n.byte my_label\n";
+        let errors = ProcessedSource::assemble(src).unwrap_err().into_vec();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].line_number(), 5);
+        assert!(errors[0].to_string().contains("cannot be a byte"));
+
+        let src = ".seg data 0x200\nmy_label:\n.byte 0\n.seg text\nldi r1l, my_label\n";
+        let errors = ProcessedSource::assemble(src).unwrap_err().into_vec();
+        assert!(matches!(
+            errors[0].error_type(),
+            ErrorType::WrongOperandType {
+                expected: "a byte",
+                got: "a label",
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn float_data() {
+        let src = ".seg data\n.float 2.75, -0.5, 1e3\n";
+        let ps = ProcessedSource::assemble(src).unwrap();
+        let (bytes, _) = ps.encode_segment(SegmentType::Data);
+        let expected: Vec<u8> = [2.75f32, -0.5, 1000.]
+            .iter()
+            .flat_map(|f| f.to_le_bytes())
+            .collect();
+        assert_eq!(bytes, expected);
+        assert_eq!(bytes[..4], [0, 0, 0x30, 0x40]);
+
+        assert!(ProcessedSource::assemble(".seg data\n.float 1.2.3\n").is_err());
+    }
+
+    #[test]
+    fn multiple_data_arguments() {
+        let src = ".seg data\nlbl:\n.byte 1, ',', 3\n.wide 0x0000, lbl, 0xffff\n";
+        let ps = ProcessedSource::assemble(src).unwrap();
+        let data = &ps.dls[&SegmentType::Data];
+        assert_eq!(data.size, 9);
+        assert!(matches!(&data.lines[0], DataLine::Raw(b) if b == &[1, b',', 3]));
+        assert!(matches!(data.lines[2], DataLine::Wide(Wide::Label(_))));
+
+        let src = ".seg data\n.byte\n";
+        assert!(ProcessedSource::assemble(src).is_err());
+    }
+
+    #[test]
+    fn shift_by_immediate() {
+        let src = ".seg text\nshl r1, r2, 3\nlsr r1l, r2l, r3l\nasr r1, r2, 15\n";
+        let ps = ProcessedSource::assemble(src).unwrap();
+        let text = &ps.dls[&SegmentType::Text];
+        let opcodes: Vec<_> = text.lines.iter().map(|l| match l {
+            &DataLine::Ins(opcode, _) => opcode,
+            _ => unreachable!(),
+        }).collect();
+        assert_eq!(opcodes, [isa::SHL_W_IMM, isa::LSR_B, isa::ASR_W_IMM]);
+        assert!(matches!(text.lines[0], DataLine::Ins(_, DataOperand::TwoWideNibble(R1, R2, n)) if u8::from(n) == 3));
+
+        let src = ".seg text\nshl r1, r2, 16\n";
+        assert!(ProcessedSource::assemble(src).is_err());
+    }
+
+    #[test]
+    fn add_immediate() {
+        let src = ".seg text\nadd r1, r2, 5\nadd r1, r2, r3\nsub r1l, r1l, 1\n";
+        let ps = ProcessedSource::assemble(src).unwrap();
+        let text = &ps.dls[&SegmentType::Text];
+        let opcodes: Vec<_> = text.lines.iter().map(|l| match l {
+            &DataLine::Ins(opcode, _) => opcode,
+            _ => unreachable!(),
+        }).collect();
+        assert_eq!(opcodes, [isa::ADD_W_IMM, isa::ADD_W, isa::SUB_B_IMM]);
+        assert_eq!(text.size, 4 + 3 + 3);
+        assert!(matches!(
+            text.lines[0],
+            DataLine::Ins(_, DataOperand::TwoWideImm(R1, R2, Wide::Number(5)))
+        ));
+
+        let src = ".seg text\nadd r1, r2l, 5\n";
+        assert!(ProcessedSource::assemble(src).is_err());
+    }
+
+    #[test]
+    fn ldi_small_number() {
+        let src = ".seg text\nldi r1, -1\nldi r2, 127\nldi r3, -129\nldi r4, 0xff\n";
+        let ps = ProcessedSource::assemble(src).unwrap();
+        let text = &ps.dls[&SegmentType::Text];
+        assert_eq!(text.size, 3 + 3 + 4 + 4);
+        assert!(matches!(
+            text.lines[0],
+            DataLine::Ins(isa::LDI_W, DataOperand::WideNibbleByte(R1, n, 0xff)) if u8::from(n) == 2
+        ));
+        assert!(matches!(
+            text.lines[2],
+            DataLine::Ins(isa::LDI_W, DataOperand::TwoWideImm(R3, R0, Wide::Number(0xff7f)))
+        ));
+    }
+
+    #[test]
+    fn push_immediate() {
+        let src = ".seg text\nstart:\npush 4b\npush 4\npush start\n";
+        let ps = ProcessedSource::assemble(src).unwrap();
+        let text = &ps.dls[&SegmentType::Text];
+        assert_eq!(text.size, 2 + 3 + 3);
+        assert!(matches!(
+            text.lines[0],
+            DataLine::Ins(isa::PUSH_IMM_B, DataOperand::ImmediateByte(4))
+        ));
+        assert!(matches!(
+            text.lines[1],
+            DataLine::Ins(isa::PUSH_IMM_W, DataOperand::ImmediateWide(Wide::Number(4)))
+        ));
+        assert!(matches!(
+            text.lines[2],
+            DataLine::Ins(isa::PUSH_IMM_W, DataOperand::ImmediateWide(Wide::Label(_)))
+        ));
+        assert_eq!(ps.relocations.len(), 1);
+        assert_eq!(ps.relocations[0].offset, 6);
+    }
+
+    #[test]
+    fn double_wide_load_store() {
+        let src = ".seg text\nload r5, r4, r1, r3\nstore r1, r2, r4, r3\nload r5, r1, r3\n";
+        let ps = ProcessedSource::assemble(src).unwrap();
+        let text = &ps.dls[&SegmentType::Text];
+        assert!(matches!(
+            text.lines[0],
+            DataLine::Ins(isa::LOAD_D, DataOperand::FourWide(R5, R4, R1, R3))
+        ));
+        assert!(matches!(
+            text.lines[1],
+            DataLine::Ins(isa::STORE_D, DataOperand::FourWide(R1, R2, R4, R3))
+        ));
+        assert!(matches!(
+            text.lines[2],
+            DataLine::Ins(isa::LOAD_WR, DataOperand::ThreeWide(R5, R1, R3))
+        ));
+    }
+
+    #[test]
+    fn mixed_register_moves() {
+        let src = ".seg text\nmovb r1l, r5\nmovbh r2h, r5\nmovw r3, r1l\n";
+        let ps = ProcessedSource::assemble(src).unwrap();
+        let text = &ps.dls[&SegmentType::Text];
+        assert!(matches!(
+            text.lines[0],
+            DataLine::Ins(isa::MOVB_WR, DataOperand::ByteWide(R1L, R5))
+        ));
+        assert!(matches!(text.lines[1], DataLine::Ins(isa::MOVBH_WR, DataOperand::ByteWide(_, R5))));
+        assert!(matches!(text.lines[2], DataLine::Ins(isa::ZXT_B, DataOperand::WideByte(R3, R1L))));
+
+        let src = ".seg text\nmovb r1, r5\n";
+        assert!(ProcessedSource::assemble(src).is_err());
+    }
+
+    #[test]
+    fn pseudo_instructions() {
+        let src = ".seg text\nmov r1l, r2h\nmov r3, r4\nclr r5l\nclr r6\n";
+        let ps = ProcessedSource::assemble(src).unwrap();
+        let (text, _) = ps.encode_segment(SegmentType::Text);
+        assert_eq!(
+            text,
+            [
+                isa::ADD_B, 0x14, 0x00, isa::ADD_W, 0x34, 0x00, isa::XOR_B, 0x99, 0x90, isa::XOR_W,
+                0x66, 0x60
+            ]
+        );
+
+        assert!(ProcessedSource::assemble(".seg text\nmov r1, r2l\n").is_err());
+        assert!(ProcessedSource::assemble(".seg text\nclr r1, r2\n").is_err());
+    }
+
+    #[test]
+    fn segment_load_order() {
+        let src = ".seg stack\n.byte 0\n.seg heap\n.byte 0\n.seg text\nhalt\n.seg boot\nnop\n\
+                   .seg rodata\n.byte 0\n.seg data\n.byte 0\n";
+        let ps = ProcessedSource::assemble(src).unwrap();
+        let order = [
+            SegmentType::Boot,
+            SegmentType::RoData,
+            SegmentType::Text,
+            SegmentType::Data,
+            SegmentType::Heap,
+            SegmentType::Stack,
+        ];
+        assert!(ps.dls.keys().copied().eq(order));
+        let starts: Vec<_> = order.iter().map(|st| ps.dls[st].start).collect();
+        assert!(starts.windows(2).all(|w| w[0] < w[1]), "{starts:x?}");
+    }
+
+    #[test]
+    fn zero_and_fill() {
+        let src = ".seg data\n.zero 3\n.zero 2, 0xff\n\
+                   .fill 2, 3, 0x1234\n.fill 0, 1, 0\n.fill 1, 1, -1\n";
+        let ps = ProcessedSource::assemble(src).unwrap();
+        assert_eq!(
+            ps.encode_segment(SegmentType::Data).0,
+            [0, 0, 0, 0xff, 0xff, 0x34, 0x12, 0, 0x34, 0x12, 0, 0xff]
+        );
+
+        for bad in [".zero", ".zero 1, 2, 3", ".zero x", ".fill 1, 2", ".fill 1, 1, lbl"] {
+            let src = format!(".seg data\n{bad}\n");
+            assert!(ProcessedSource::assemble(&src).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn zero_past_end_of_segment() {
+        for src in [
+            ".seg data\n.zero 0xffff\n.zero 0xffff\n",
+            ".seg data\n.rep 65536\n.zero 2\n.endrep\n",
+            ".seg data\n.zero 0xffff\n.fill 1, 2, 0\n",
+        ] {
+            let errors = ProcessedSource::assemble(src).unwrap_err().into_vec();
+            assert!(
+                matches!(errors[0].error_type(), ErrorType::SegmentDoesNotFit(SegmentType::Data)),
+                "{src}"
+            );
+        }
+    }
+
+    #[test]
+    fn align_padding() {
+        let ps = ProcessedSource::assemble(".seg text\nnop\n.align 4\nnop\n.align 4, 0x20\n")
+            .unwrap();
+        assert_eq!(ps.encode_segment(SegmentType::Text).0, [0x20, 0, 0, 0, 0x20, 0x20, 0x20, 0x20]);
+        assert_eq!(ps.dls[&SegmentType::Text].size, 8);
+
+        let e = ProcessedSource::assemble(".seg text\n.align 6\n").unwrap_err();
+        assert!(matches!(e.error_type(), ErrorType::InvalidAlignment(6)));
+        for bad in [".align 0", ".align 4, 2, 1", ".align 4, x"] {
+            let src = format!(".seg data\n{bad}\n");
+            assert!(ProcessedSource::assemble(&src).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn segment_at_end_of_memory() {
+        let ps = ProcessedSource::assemble(".seg text 0xffff\n.entry\nhalt\n").unwrap();
+        assert_eq!(ps.dls[&SegmentType::Text].start, 0xffff);
+
+        for src in [
+            ".seg text 0xffff\nhalt\nhalt\n",
+            ".seg text 0xffff\nhalt\n.seg data\n.byte 0\n",
+            ".seg text 0xffff\nhalt\n.align 2\n",
+        ] {
+            let e = ProcessedSource::assemble(src).unwrap_err();
+            assert!(matches!(e.error_type(), ErrorType::SegmentDoesNotFit(_)), "{src}");
+        }
+    }
+
+    #[test]
+    fn ret_encoding() {
+        let ps = ProcessedSource::assemble(".seg text\nret\nret 0\nret 10\nret 300\n").unwrap();
+        assert_eq!(
+            ps.encode_segment(SegmentType::Text).0,
+            [isa::RET, 0, isa::RET, 0, isa::RET, 10, isa::RET_W, 0x2c, 0x01]
+        );
+
+        let ps = ProcessedSource::assemble(".seg text\nret -1\nret 255\nret 256\n").unwrap();
+        assert_eq!(
+            ps.encode_segment(SegmentType::Text).0,
+            [isa::RET, 0xff, isa::RET, 0xff, isa::RET_W, 0x00, 0x01]
+        );
+    }
+
+    #[test]
+    fn jmp_encoding() {
+        let ps = ProcessedSource::assemble(".seg text\njmp r0\njmp 0\njmp r5\n").unwrap();
+        assert_eq!(
+            ps.encode_segment(SegmentType::Text).0,
+            [isa::LDI_W, 0x01, 0, 0, isa::LDI_W, 0x01, 0, 0, isa::LDI_W, 0x51, 0, 0]
+        );
+    }
+
+    #[test]
+    fn incbin_slices() {
+        let dir = TempDir::new("incbin_test");
+        std::fs::write(dir.join("blob.bin"), b"ABCDEFGH").unwrap();
+        let main = dir.join("main.telda");
+        std::fs::write(&main, ".seg data\n.incbin \"blob.bin\"\n.incbin blob.bin, 2, 3\n").unwrap();
+
+        let (ps, _, _) = process(SourceLines::new(&main).unwrap(), Recovery::Continue);
+        let ps = ps.unwrap();
+        let data = &ps.dls[&SegmentType::Data];
+        assert!(matches!(&data.lines[0], DataLine::Raw(b) if b == b"ABCDEFGH"));
+        assert!(matches!(&data.lines[1], DataLine::Raw(b) if b == b"CDE"));
+
+        std::fs::write(&main, ".seg data\n.incbin blob.bin, 6, 3\n").unwrap();
+        let (ps, errors, _) = process(SourceLines::new(&main).unwrap(), Recovery::Continue);
+        assert!(ps.is_none() && !errors.is_empty());
+    }
+
+    #[test]
+    fn include_chain() {
+        let dir = TempDir::new("include_chain_test");
+        std::fs::write(dir.join("inner.telda"), ".seg text\nbogus r1\n").unwrap();
+        std::fs::write(dir.join("middle.telda"), ".seg text\n.include inner.telda\n").unwrap();
+        let main = dir.join("main.telda");
+        std::fs::write(&main, ".seg text\n.include middle.telda\nhalt\n").unwrap();
+
+        let (ps, errors, _) = process(SourceLines::new(&main).unwrap(), Recovery::Continue);
+        assert!(ps.is_none());
+        let [e] = &errors[..] else {
+            panic!("expected one error: {errors:?}");
+        };
+        assert!(e.file().ends_with("inner.telda"));
+        assert_eq!(e.line_number(), 2);
+        let chain: Vec<_> = e
+            .include_chain()
+            .iter()
+            .map(|l| (l.source().rsplit('/').next().unwrap(), l.line_number()))
+            .collect();
+        assert_eq!(chain, [("middle.telda", 2), ("main.telda", 2)]);
+        let middle = dir.join("middle.telda");
+        let expected = format!(
+            "inner.telda:2: included from {}:2: included from {}:2: unknown instruction: bogus",
+            middle.display(),
+            main.display()
+        );
+        assert!(e.to_string().ends_with(&expected), "{e}");
+    }
+
+    #[test]
+    fn local_labels() {
+        let src = ".seg text\nf:\n.loop:\njmp .loop\ng:\n.loop:\njmp .end\n.end:\njmp .loop\n";
+        let ps = ProcessedSource::assemble(src).unwrap();
+        assert_eq!(ps.find_label("f$.loop"), Some(0x80));
+        assert_eq!(ps.find_label("g$.loop"), Some(0x84));
+        assert_eq!(ps.find_label("g$.end"), Some(0x88));
+
+        let src = ".seg text\nf:\n.end:\nnop\ng:\njmp .end\n";
+        assert!(ProcessedSource::assemble(src).is_err());
+    }
+
+    #[test]
+    fn encode_data_operand() {
+        let dat_op = DataOperand::WideImmByte(R1, Wide::Label(3), R2L);
+        let mut reads = Vec::new();
+        let bytes = dat_op.encode(SegmentType::Text, 0x10, &mut |id, lr| {
+            reads.push((id, lr.position));
+            0x1234
+        });
+        assert_eq!(bytes, [0x13, 0x34, 0x12]);
+        assert_eq!(bytes.len(), dat_op.size() as usize);
+        assert_eq!(reads, [(3, 0x11)]);
+        assert_eq!(dat_op.to_string(), "r1, label_3, r2l");
+        assert_eq!(DataOperand::ThreeWide(R4, RS, R0).to_string(), "r4, rs, r0");
+    }
+
+    #[test]
+    fn write_data_operand_at_position() {
+        let dat_op = DataOperand::WideImmByte(R1, Wide::Label(3), R2L);
+        let mut written = Vec::new();
+        // the label reads as where it is read from
+        write_data_operand(SegmentType::Text, &mut written, 0x10, |_, lr| lr.position, dat_op)
+            .unwrap();
+        assert_eq!(written, [0x13, 0x11, 0x00]);
+    }
+
+    #[test]
+    fn number_literals() {
+        use self::SourceOperand::*;
+
+        let cases = [
+            ("0xFF", Number(255)),
+            ("0x80b", Byte(0x80)),
+            ("0x8000w", Wide(0x8000)),
+            ("0x42", Number(0x42)),
+            ("0b1010b", Byte(10)),
+            ("0b1010", Number(10)),
+            ("0o17", Number(15)),
+            ("0o17w", Wide(15)),
+            ("-0x10", Number(-16)),
+            ("-1b", Byte(0xff)),
+            ("0b", Byte(0)),
+            ("12", Number(12)),
+            ("0x", Label("0x".to_owned())),
+            ("0xg", Label("0xg".to_owned())),
+        ];
+        for (s, expected) in cases {
+            assert_eq!(parse_number(s).ok(), Some(expected), "{s}");
+        }
+    }
+
+    #[test]
+    fn relocations() {
+        let src = ".ref ext\n.seg text\n.entry\nmain:\nldi r1, ext\njmp main\n.seg data\n.wide 3, ext, main\n";
+        let ps = ProcessedSource::assemble(src).unwrap();
+        let id = |name| ps.labels.iter().position(|l| &*l.0 == name).unwrap();
+        assert_eq!(ps.labels[id("ext")].3, 0);
+
+        let reloc = |label, segment, offset| Relocation {
+            label: id(label),
+            segment,
+            offset,
+        };
+        assert_eq!(
+            ps.relocations,
+            [
+                reloc("ext", SegmentType::Text, 2),
+                reloc("main", SegmentType::Text, 6),
+                reloc("ext", SegmentType::Data, 2),
+                reloc("main", SegmentType::Data, 4),
+            ]
+        );
+    }
+
+    #[test]
+    fn conditional_assembly() {
+        let src = "\
+.equ DEBUG, 1
+.equ LEVEL, 3
+.seg text
+.if DEBUG
+a:
+.else
+b:
+.endif
+.if LEVEL >= 4
+c:
+.endif
+.ifdef a
+.ifndef b
+d:
+.if !defined(c)
+e:
+.endif
+.endif
+.else
+f:
+.endif
+nop
+";
+        let ps = ProcessedSource::assemble(src).unwrap();
+        assert_eq!(ps.find_label("a"), Some(0x80));
+        assert_eq!(ps.find_label("b"), None);
+        assert_eq!(ps.find_label("c"), None);
+        assert_eq!(ps.find_label("d"), Some(0x80));
+        assert_eq!(ps.find_label("e"), Some(0x80));
+        assert_eq!(ps.find_label("f"), None);
+        assert_eq!(ps.find_label("LEVEL"), Some(3));
+
+        for src in [".if 1\n", ".else\n", ".endif\n", ".if 1\n.else\n.else\n.endif\n"] {
+            assert!(ProcessedSource::assemble(src).is_err());
+        }
+    }
+
+    #[test]
+    fn block_comments() {
+        let src = "\
+.seg text
+/* one */ nop /* two */
+/* start
+halt
+*/ a:
+nop /* a /* b */
+/**/halt
+";
+        let (ps, _, warnings) = process(SourceLines::from_str(src), Recovery::Continue);
+        let ps = ps.unwrap();
+        let text = &ps.dls[&SegmentType::Text];
+        let opcodes: Vec<_> = text.lines.iter().map(|l| match l {
+            &DataLine::Ins(opcode, _) => opcode,
+            _ => unreachable!(),
+        }).collect();
+        assert_eq!(opcodes, [isa::NOP, isa::NOP, isa::HALT]);
+        assert_eq!(ps.find_label("a"), Some(0x81));
+        assert!(warnings.iter().any(|w| *w.warning_type() == WarningType::NestedBlockComment));
+
+        let src = ".seg text\nnop\n/* never closed\nhalt\n";
+        assert!(ProcessedSource::assemble(src).is_err());
+    }
+
+    #[test]
+    fn block_comment_in_literals_and_line_comments() {
+        let src = "\
+.seg data
+; /* not a block comment
+// /* neither
+# /* nor this
+.string \"a/*b\"
+.byte '/', '*'
+.byte 1
+";
+        let ps = ProcessedSource::assemble(src).unwrap();
+        // the quotes are kept by `.string'
+        assert_eq!(ps.encode_segment(SegmentType::Data).0, b"\"a/*b\"/*\x01");
+        assert_eq!(block_comment_start("nop /* a"), Some(4));
+        assert_eq!(block_comment_start(".string \"\\\"/*\" /* a"), Some(15));
+    }
+
+    #[test]
+    fn repetitions() {
+        let src = "\
+.equ TIMES, 3
+.seg data
+.rep TIMES
+.byte $REPIDX
+.rep 2
+.byte $REPIDX
+.endrep
+.endrep
+.rep 0
+.byte 0xff
+.endrep
+.if 0
+.rep 70000
+.endrep
+.endif
+";
+        let ps = ProcessedSource::assemble(src).unwrap();
+        let data = &ps.dls[&SegmentType::Data];
+        let bytes: Vec<u8> = data.lines.iter().flat_map(|l| match l {
+            DataLine::Raw(b) => b.clone(),
+            _ => unreachable!(),
+        }).collect();
+        assert_eq!(bytes, [0, 0, 1, 1, 0, 1, 2, 0, 1]);
+        assert_eq!(data.locations[1].line_number(), 6);
+
+        for src in [
+            ".seg data\n.rep 2\n",
+            ".seg data\n.endrep\n",
+            ".seg data\n.rep 65537\n.endrep\n",
+            ".seg data\n.rep -1\n.endrep\n",
+        ] {
+            assert!(ProcessedSource::assemble(src).is_err());
+        }
+    }
+
+    #[test]
+    fn structures() {
+        let src = ".struct DEVICE\n.byte_field status\n.byte_field control\n; a comment\n\
+                   .wide_field buffer\n.field name, 8\n.ends\n\
+                   .seg text\nldi r1, DEVICE.name\nldi r2, SIZEOF_DEVICE\n";
+        let ps = ProcessedSource::assemble(src).unwrap();
+        assert_eq!(ps.find_label("DEVICE.status"), Some(0));
+        assert_eq!(ps.find_label("DEVICE.control"), Some(1));
+        assert_eq!(ps.find_label("DEVICE.buffer"), Some(2));
+        assert_eq!(ps.find_label("DEVICE.name"), Some(4));
+        assert_eq!(ps.find_label("SIZEOF_DEVICE"), Some(12));
+        // no bytes are emitted for the structure
+        let (bytes, _) = ps.encode_segment(SegmentType::Text);
+        assert_eq!(bytes[2..4], [4, 0]);
+        assert_eq!(bytes[6..8], [12, 0]);
+
+        for src in [
+            ".struct A\n",
+            ".ends\n",
+            ".field a, 2\n",
+            ".struct A\n.struct B\n.ends\n.ends\n",
+            ".struct A\nnop\n.ends\n",
+            ".struct A\n.field a, 0xffff\n.field b, 2\n.ends\n",
+            ".struct A\n.byte_field\n.ends\n",
+            ".struct A\n.wide_field a, b\n.ends\n",
+            ".struct A\n.field , 2\n.ends\n",
+        ] {
+            assert!(ProcessedSource::assemble(src).is_err(), "{src}");
+        }
+    }
+
+    #[test]
+    fn all_errors() {
+        let src = ".seg text\nbogus r1\nadd r1\nend:\nhalt\njmp nowhere\n";
+        let (ps, errors, _) = process(SourceLines::from_str(src), Recovery::Continue);
+        assert!(ps.is_none());
+        let lines: Vec<_> = errors.iter().map(|e| e.line_number()).collect();
+        assert_eq!(lines, [2, 3, 6]);
+        assert!(matches!(
+            errors[0].error_type(),
+            ErrorType::UnknownInstruction(i) if &**i == "bogus"
+        ));
+
+        let (_, errors, _) = process(SourceLines::from_str(src), Recovery::Stop);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].line_number(), 2);
+
+        // the placeholders keep the label where it would be without the errors
+        let mut state = ProcessState::new(Recovery::Continue);
+        let mut symbols = Symbols::new();
+        let lines = SourceLines::from_str(".seg text\nbogus\nbogus r1\nend:\n");
+        let mut segment = SegmentType::Unknown;
+        assert!(inner_process(lines, &mut segment, &mut state, &mut symbols).is_some());
+        assert_eq!(state.get_size(SegmentType::Text), 2);
+    }
+}
@@ -0,0 +1,197 @@
+use std::{io, ops::Range};
+
+use crate::aalv::obj::{
+    ExternTable, Object, RelocationEntry, RelocationTable, SegmentLayout, SegmentType, StackSize,
+    SymbolDefinition, SymbolTable, WeakTable,
+};
+
+use super::{
+    source_map_from_line_numbers, DataLine, DataLineSegment, ProcessedSource, Relocation,
+    SourceLocation, SymbolType, Wide,
+};
+
+impl ProcessedSource {
+    /// Encodes the lines of a segment with labels read as their addresses
+    ///
+    /// Gives the bytes along with the range of them each line became.
+    /// The stack segment only reserves space, so it has no bytes
+    pub fn encode_segment(&self, st: SegmentType) -> (Vec<u8>, Vec<Range<usize>>) {
+        let mut mem = Vec::new();
+        let mut ranges = Vec::new();
+        let Some(dls) = self.dls.get(&st).filter(|_| st != SegmentType::Stack) else {
+            return (mem, ranges);
+        };
+        mem.reserve(dls.size as usize);
+
+        for line in &dls.lines {
+            let start = mem.len();
+            match *line {
+                DataLine::Raw(ref bytes) => mem.extend_from_slice(bytes),
+                DataLine::Wide(Wide::Number(n)) => mem.extend_from_slice(&n.to_le_bytes()),
+                DataLine::Wide(Wide::Label(id)) => {
+                    mem.extend_from_slice(&self.labels[id].3.to_le_bytes())
+                }
+                DataLine::Ins(opcode, dat_op) => {
+                    mem.push(opcode);
+                    let position = mem.len() as u16;
+                    mem.extend(dat_op.encode(st, position, &mut |id, _| self.labels[id].3));
+                }
+            }
+            ranges.push(start..mem.len());
+        }
+
+        (mem, ranges)
+    }
+    /// Makes an object file of the source without a line number table
+    pub fn to_object(&self) -> Object {
+        let mut obj = Object {
+            entry: self.entry,
+            ..Object::default()
+        };
+
+        for (&st, dls) in &self.dls {
+            let (bytes, _) = self.encode_segment(st);
+            obj.segs.insert(st, (dls.start, bytes));
+            if st == SegmentType::Stack {
+                obj.stack_size = Some(StackSize(dls.size));
+            }
+            if dls.layout != SegmentLayout::default() {
+                obj.layouts.insert(st, dls.layout);
+            }
+        }
+
+        obj.symbols = SymbolTable(
+            self.labels
+                .iter()
+                .map(|&(ref name, st, segment_type, location)| {
+                    if st == SymbolType::Reference {
+                        assert_eq!(
+                            segment_type,
+                            SegmentType::Unknown,
+                            "reference symbols should have unknown segment type"
+                        );
+                    }
+                    SymbolDefinition {
+                        name: name.clone(),
+                        is_global: st != SymbolType::Internal,
+                        segment_type,
+                        location,
+                    }
+                })
+                .collect(),
+        );
+        obj.relocation_table = RelocationTable(
+            self.relocations
+                .iter()
+                .filter(|r| r.segment != SegmentType::Stack)
+                .map(|r| RelocationEntry {
+                    reference_location: self.dls[&r.segment].start + r.offset,
+                    reference_segment: r.segment,
+                    symbol_index: r.label as u16,
+                })
+                .collect(),
+        );
+        if !self.externs.is_empty() {
+            obj.externs = Some(ExternTable(self.externs.clone()));
+        }
+        let weak: Vec<_> = self
+            .labels
+            .iter()
+            .filter(|l| l.1 == SymbolType::Weak)
+            .map(|l| l.0.clone())
+            .collect();
+        if !weak.is_empty() {
+            obj.weak = Some(WeakTable(weak));
+        }
+
+        obj
+    }
+    /// Makes a processed source of an object file
+    ///
+    /// Each segment becomes a single line of raw bytes, the line numbers become the source map.
+    /// Fails with [`io::ErrorKind::InvalidData`] if a relocation is not within its segment or
+    /// refers to a symbol that does not exist
+    pub fn from_object(obj: Object) -> io::Result<Self> {
+        let location = SourceLocation::new("<object>", 0);
+
+        let dls = obj
+            .segs
+            .iter()
+            .map(|(&st, &(start, ref bytes))| {
+                let size = match obj.stack_size {
+                    Some(StackSize(size)) if st == SegmentType::Stack => size,
+                    _ => bytes.len() as u16,
+                };
+                let (lines, locations) = if bytes.is_empty() {
+                    (Vec::new(), Vec::new())
+                } else {
+                    (vec![DataLine::Raw(bytes.clone())], vec![location.clone()])
+                };
+                let dls = DataLineSegment {
+                    lines,
+                    locations,
+                    size,
+                    start,
+                    layout: obj.layouts.get(&st).copied().unwrap_or_default(),
+                };
+                (st, dls)
+            })
+            .collect();
+
+        let labels = obj
+            .symbols
+            .iter()
+            .map(|s| {
+                let weak = obj.weak.as_ref().is_some_and(|w| w.contains(&s.name));
+                let st = match (s.segment_type, s.is_global) {
+                    (SegmentType::Unknown, _) => SymbolType::Reference,
+                    (_, true) if weak => SymbolType::Weak,
+                    (_, true) => SymbolType::Global,
+                    (_, false) => SymbolType::Internal,
+                };
+                (s.name.clone(), st, s.segment_type, s.location)
+            })
+            .collect();
+
+        let relocations = obj
+            .relocation_table
+            .0
+            .iter()
+            .map(|r| {
+                let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+                let (start, bytes) = obj
+                    .segs
+                    .get(&r.reference_segment)
+                    .ok_or_else(|| invalid("relocation in a segment the object does not have"))?;
+                let offset = r
+                    .reference_location
+                    .checked_sub(*start)
+                    .filter(|&offset| offset as usize + 2 <= bytes.len())
+                    .ok_or_else(|| invalid("relocation outside of its segment"))?;
+                if r.symbol_index as usize >= obj.symbols.0.len() {
+                    return Err(invalid("relocation of a symbol that does not exist"));
+                }
+                Ok(Relocation {
+                    label: r.symbol_index as usize,
+                    segment: r.reference_segment,
+                    offset,
+                })
+            })
+            .collect::<io::Result<_>>()?;
+
+        Ok(ProcessedSource {
+            labels,
+            dls,
+            entry: obj.entry,
+            relocations,
+            source_map: obj
+                .line_numbers
+                .as_ref()
+                .map(source_map_from_line_numbers)
+                .unwrap_or_default(),
+            externs: obj.externs.map(|e| e.0).unwrap_or_default(),
+            inputs: Vec::new(),
+            by_address: Default::default(),
+        })
+    }
+}
@@ -0,0 +1,272 @@
+//! Working out what is wrong with the operands of an instruction that could not be assembled
+//!
+//! Instead of keeping a separate list of what each instruction takes, the instruction parser
+//! is tried with one operand of each kind to find the forms it accepts
+
+use crate::blf4::{R1, R1L};
+
+use super::{ErrorType, LiteralSize, SourceOperand};
+
+/// Most operands any instruction takes
+const MAX_OPERANDS: usize = 4;
+
+/// One operand of each kind, a number last so a more specific kind is preferred when describing
+const PROBES: [SourceOperand; 5] = [
+    SourceOperand::ByteReg(R1L),
+    SourceOperand::WideReg(R1),
+    SourceOperand::Number(0),
+    SourceOperand::Byte(0),
+    SourceOperand::Wide(0),
+];
+
+fn describe(op: &SourceOperand) -> &'static str {
+    match op {
+        SourceOperand::ByteReg(_) => "a byte register",
+        SourceOperand::WideReg(_) => "a wide register",
+        SourceOperand::Number(_) => "a number",
+        SourceOperand::Byte(_) => "a byte",
+        SourceOperand::Wide(_) => "a wide",
+        SourceOperand::Char(_) => "a character",
+        SourceOperand::Label(_) => "a label",
+    }
+}
+
+/// Whether `op` can be used where `probe` was accepted, disregarding the range of numbers
+fn fits(op: &SourceOperand, probe: &SourceOperand) -> bool {
+    use self::SourceOperand::*;
+    matches!(
+        (op, probe),
+        (ByteReg(_), ByteReg(_))
+            | (WideReg(_), WideReg(_))
+            | (Number(_) | Char(_), Number(_) | Byte(_) | Wide(_))
+            | (Byte(_), Byte(_))
+            | (Wide(_) | Label(_), Wide(_))
+    )
+}
+
+/// Finds out why `instruction` did not accept `ops`
+///
+/// `accepts` tells whether the instruction accepts some operands, it is never given labels.
+/// Gives `None` if the instruction accepts no operands at all
+pub(super) fn diagnose(
+    instruction: &str,
+    ops: &[SourceOperand],
+    mut accepts: impl FnMut(&[SourceOperand]) -> bool,
+) -> Option<ErrorType> {
+    let mut forms = Vec::new();
+    let mut form = Vec::with_capacity(MAX_OPERANDS);
+    find_forms(&mut form, &mut forms, &mut accepts);
+
+    let got = ops.len();
+    let same_count: Vec<_> = forms.iter().filter(|f| f.len() == got).collect();
+    if same_count.is_empty() {
+        let expected = forms
+            .iter()
+            .map(|f| f.len())
+            .min_by_key(|&n| (n.abs_diff(got), n))?;
+        return Some(ErrorType::WrongOperandCount {
+            instruction: instruction.into(),
+            expected: expected as u8,
+            got: got as u8,
+        });
+    }
+
+    // the form the operands get the furthest into before one does not fit
+    let (form, mismatch) = same_count
+        .into_iter()
+        .map(|f| (f, f.iter().zip(ops).position(|(p, op)| !fits(op, p))))
+        .max_by_key(|&(_, m)| m.unwrap_or(usize::MAX))?;
+    if let Some(i) = mismatch {
+        return Some(ErrorType::WrongOperandType {
+            instruction: instruction.into(),
+            operand_index: i as u8,
+            expected: describe(&form[i]),
+            got: describe(&ops[i]),
+        });
+    }
+
+    // every operand is of the right kind, so a character or number has to be out of range
+    let mut probe: Vec<_> = ops
+        .iter()
+        .map(|op| match op {
+            SourceOperand::Label(_) => SourceOperand::Wide(0),
+            op => op.clone(),
+        })
+        .collect();
+    let character = ops.iter().enumerate().find_map(|(i, op)| match *op {
+        SourceOperand::Char(c) => {
+            let mut probe = probe.clone();
+            probe[i] = SourceOperand::Number(0);
+            accepts(&probe).then_some(c)
+        }
+        _ => None,
+    });
+    if let Some(c) = character {
+        let too_big_for = if u16::try_from(c as u32).is_ok() {
+            LiteralSize::Byte
+        } else {
+            LiteralSize::Wide
+        };
+        return Some(ErrorType::CharacterDoesNotFit(c, too_big_for));
+    }
+    let (i, value) = ops.iter().enumerate().find_map(|(i, op)| match *op {
+        SourceOperand::Number(n) => {
+            let mut probe = probe.clone();
+            probe[i] = SourceOperand::Number(0);
+            accepts(&probe).then_some((i, n))
+        }
+        _ => None,
+    })?;
+
+    let mut accepts_value = |n| {
+        probe[i] = SourceOperand::Number(n);
+        accepts(&probe)
+    };
+    let max = last_accepted(0, i32::MAX, &mut accepts_value);
+    let min = last_accepted(0, i32::MIN, &mut accepts_value);
+    Some(ErrorType::OperandOutOfRange {
+        instruction: instruction.into(),
+        operand_index: i as u8,
+        value,
+        min,
+        max,
+    })
+}
+
+/// Adds every accepted form starting with `form` to `forms`
+fn find_forms(
+    form: &mut Vec<SourceOperand>,
+    forms: &mut Vec<Vec<SourceOperand>>,
+    accepts: &mut impl FnMut(&[SourceOperand]) -> bool,
+) {
+    if accepts(form) {
+        forms.push(form.clone());
+    }
+    if form.len() == MAX_OPERANDS {
+        return;
+    }
+    for probe in &PROBES {
+        form.push(probe.clone());
+        find_forms(form, forms, accepts);
+        form.pop();
+    }
+}
+
+/// Searches from `good` towards `bad` for the last accepted value, assuming they form a range
+fn last_accepted(mut good: i32, mut bad: i32, accepts: &mut impl FnMut(i32) -> bool) -> i32 {
+    if accepts(bad) {
+        return bad;
+    }
+    while good.abs_diff(bad) > 1 {
+        let mid = ((good as i64 + bad as i64) / 2) as i32;
+        if accepts(mid) {
+            good = mid;
+        } else {
+            bad = mid;
+        }
+    }
+    good
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::source::{Error, ErrorType, LiteralSize, ProcessedSource};
+
+    fn error(ins: &str) -> Error {
+        let src = format!(".seg text\n{ins}\n");
+        let mut errors = ProcessedSource::assemble(&src).unwrap_err().into_vec();
+        assert_eq!(errors.len(), 1, "{ins}");
+        errors.pop().unwrap()
+    }
+
+    #[test]
+    fn wrong_count() {
+        assert!(matches!(
+            error("push").error_type(),
+            ErrorType::WrongOperandCount {
+                expected: 1,
+                got: 0,
+                ..
+            }
+        ));
+        assert!(matches!(
+            error("halt r1").error_type(),
+            ErrorType::WrongOperandCount {
+                expected: 0,
+                got: 1,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn trailing_operands() {
+        for (ins, expected, got) in [
+            ("add r1, r2, r3, r4", 3, 4),
+            ("ldi r1l, 5, 6", 2, 3),
+            ("shl r1, r2, 3, 4", 3, 4),
+            ("mul r1, r2, r3, r4, r5", 4, 5),
+        ] {
+            let e = error(ins);
+            assert!(
+                matches!(
+                    e.error_type(),
+                    &ErrorType::WrongOperandCount { expected: e, got: g, .. } if e == expected && g == got
+                ),
+                "{ins}: {e}"
+            );
+        }
+    }
+
+    #[test]
+    fn out_of_range() {
+        let e = error("shl r1, r2, 16");
+        assert!(matches!(
+            e.error_type(),
+            ErrorType::OperandOutOfRange {
+                operand_index: 2,
+                value: 16,
+                min: 0,
+                max: 15,
+                ..
+            }
+        ));
+        assert_eq!(
+            e.to_string(),
+            "<input>:2: incorrect operands, operand 3 of `shl' is 16 but has to be from 0 to 15"
+        );
+    }
+
+    #[test]
+    fn character_too_big() {
+        assert!(matches!(
+            error("ldi r1l, 'é'").error_type(),
+            ErrorType::CharacterDoesNotFit('é', LiteralSize::Byte)
+        ));
+        assert!(matches!(
+            error("ldi r1, '🦀'").error_type(),
+            ErrorType::CharacterDoesNotFit('🦀', LiteralSize::Wide)
+        ));
+    }
+
+    #[test]
+    fn wrong_type() {
+        assert!(matches!(
+            error("add r1, r2l, r3").error_type(),
+            ErrorType::WrongOperandType {
+                operand_index: 1,
+                expected: "a wide register",
+                got: "a byte register",
+                ..
+            }
+        ));
+        assert!(matches!(
+            error("jez r1").error_type(),
+            ErrorType::WrongOperandType {
+                operand_index: 0,
+                got: "a wide register",
+                ..
+            }
+        ));
+    }
+}
@@ -0,0 +1,157 @@
+use std::{fs, io, path::Path, thread};
+
+use super::{
+    process, rep_nesting, Error, ErrorType, LineNumber, ParsedLine, ProcessedSource, Recovery,
+    Result, SourceLine, SourceLines, Warning,
+};
+
+/// Assembles a source file, parsing its lines on up to `thread_count` threads
+///
+/// The file is split into chunks at `.seg` lines outside of block comments and `.rep` blocks,
+/// which are parsed at the same time. The parsed lines are then processed in order like
+/// [`process`] does, so the result is the same. Included files are parsed on the calling thread.
+/// All errors are chained together into one with the parse errors first, any warnings are dropped
+pub fn process_parallel(path: &Path, thread_count: usize) -> Result<ProcessedSource> {
+    let source: Box<str> = path.display().to_string().into();
+    let text = fs::read_to_string(path)
+        .map_err(|e| Error::new(source.clone(), 0, ErrorType::IoError(e)))?;
+    let lines: Vec<&str> = text.lines().collect();
+    let bounds = chunk_bounds(&lines, thread_count.max(1));
+
+    let chunks = thread::scope(|s| {
+        let threads: Vec<_> = bounds
+            .windows(2)
+            .map(|w| {
+                let chunk = lines[w[0]..w[1]].join("\n");
+                let source = &*source;
+                s.spawn(move || parse_chunk(source, w[0] as LineNumber, &chunk))
+            })
+            .collect();
+        threads
+            .into_iter()
+            .map(|t| t.join().expect("parsing thread panicked"))
+            .collect::<Vec<_>>()
+    });
+
+    let mut all = SourceLines::from_reader(io::empty());
+    all.source = source;
+    for (parsed, errors, mut warnings) in chunks {
+        all.preparsed.extend(parsed);
+        if let Some(e) = errors {
+            all.add_error(e);
+        }
+        all.warnings.append(&mut warnings);
+    }
+
+    match process(all, Recovery::Continue) {
+        (Some(ps), _, _) => Ok(ps),
+        (None, errors, _) => Err(errors
+            .into_iter()
+            .reduce(Error::chain)
+            .expect("processing failed without errors")),
+    }
+}
+
+/// Indices of the lines the chunks start at followed by the number of lines
+///
+/// A chunk only starts at a `.seg` line, since nothing carries over from the lines before it
+fn chunk_bounds(lines: &[&str], chunk_count: usize) -> Vec<usize> {
+    let mut scanner = SourceLines::from_str("");
+    let mut rep_depth = 0u32;
+    let mut candidates = Vec::new();
+    for (i, line) in lines.iter().enumerate() {
+        let outside = !scanner.in_block_comment && rep_depth == 0;
+        let line = scanner.strip_block_comments(line);
+        if outside && line.split_whitespace().next() == Some(".seg") {
+            candidates.push(i);
+        }
+        match rep_nesting(&line) {
+            Some(true) => rep_depth += 1,
+            Some(false) => rep_depth = rep_depth.saturating_sub(1),
+            None => (),
+        }
+    }
+
+    let mut bounds = vec![0];
+    for k in 1..chunk_count {
+        let target = k * lines.len() / chunk_count;
+        let last = *bounds.last().unwrap();
+        if let Some(&c) = candidates.iter().find(|&&c| c >= target && c > last) {
+            bounds.push(c);
+        }
+    }
+    bounds.push(lines.len());
+    bounds.dedup();
+    bounds
+}
+
+fn parse_chunk(
+    source: &str,
+    first_ln: LineNumber,
+    text: &str,
+) -> (Vec<ParsedLine>, Option<Error>, Vec<Warning>) {
+    let mut lines = SourceLines::from_str(text);
+    lines.source = source.into();
+    lines.ln = first_ln;
+
+    let mut parsed = Vec::new();
+    while let Some((ln, line)) = lines.parse_next_line() {
+        let rep = matches!(line, SourceLine::DirRep(_)).then(|| {
+            let body = lines.read_rep_body();
+            (body, lines.ln)
+        });
+        parsed.push((ln, line, rep));
+    }
+    (parsed, lines.errors, lines.warnings)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt::Write;
+
+    use super::*;
+    use crate::source::tests::TempDir;
+
+    #[test]
+    fn same_as_sequential() {
+        let mut src = String::from(".global main\n.seg text\n.entry\nmain:\n    ldi r1, table\n");
+        for i in 0..40 {
+            writeln!(src, ".seg rodata\ntable_{i}:").unwrap();
+            for j in 0..50 {
+                writeln!(src, "    .byte {}, {}", i, j).unwrap();
+            }
+            writeln!(src, "/* a comment\n.seg data\n*/").unwrap();
+            writeln!(src, ".rep 3\n.seg text\n    push {i}\n.endrep").unwrap();
+            writeln!(src, ".seg text\n    call table_{i}\n.loop:\n    jmp .loop").unwrap();
+        }
+        src.push_str(".seg rodata\ntable:\n    .wide table_0, table_39\n");
+
+        let dir = TempDir::new("parallel_test");
+        let path = dir.join("big.telda");
+        fs::write(&path, &src).unwrap();
+
+        let lines: Vec<_> = src.lines().collect();
+        let bounds = chunk_bounds(&lines, 8);
+        assert_eq!(bounds.len(), 9);
+        for &b in &bounds[1..8] {
+            assert!(lines[b].starts_with(".seg"), "{b}");
+        }
+
+        let (sequential, _, _) = process(SourceLines::new(&path).unwrap(), Recovery::Continue);
+        let sequential = sequential.unwrap();
+        let parallel = process_parallel(&path, 8).unwrap();
+        assert_eq!(parallel.labels, sequential.labels);
+        assert_eq!(parallel.checksum(), sequential.checksum());
+        assert_eq!(parallel.source_map, sequential.source_map);
+
+        fs::write(
+            &path,
+            ".seg text\nhalt\n.seg data\nbad r1\n.seg text\n/* open\n",
+        )
+        .unwrap();
+        let errors = process_parallel(&path, 3).unwrap_err().into_vec();
+        // parse errors come before the others
+        let lines: Vec<_> = errors.iter().map(|e| e.line_number()).collect();
+        assert_eq!(lines, [6, 4]);
+    }
+}
@@ -0,0 +1,178 @@
+use std::{cell::OnceCell, collections::BTreeMap};
+
+use crate::aalv::obj::SegmentType;
+
+use super::{
+    process, Address, Error, ProcessedSource, Recovery, Result, SourceLines, SymbolType, Symbols,
+};
+
+impl ProcessedSource {
+    /// Assembles source code from a string, any warnings are dropped
+    ///
+    /// All errors are chained together into one
+    pub fn assemble(src: &str) -> Result<Self> {
+        match process(SourceLines::from_str(src), Recovery::Continue) {
+            (Some(ps), _, _) => Ok(ps),
+            (None, errors, _) => Err(errors
+                .into_iter()
+                .reduce(Error::chain)
+                .expect("processing failed without errors")),
+        }
+    }
+    /// Every label with its type, segment and address
+    pub fn labels(&self) -> &[(Box<str>, SymbolType, SegmentType, u16)] {
+        &self.labels
+    }
+    /// Gives the labels to change, forgetting what the lookups by address found
+    pub fn labels_mut(&mut self) -> &mut Vec<(Box<str>, SymbolType, SegmentType, u16)> {
+        self.by_address = OnceCell::new();
+        &mut self.labels
+    }
+    /// Gets the address a defined label resolves to
+    ///
+    /// Undefined references have no address and give `None`
+    pub fn find_label(&self, name: &str) -> Option<u16> {
+        self.labels
+            .iter()
+            .find(|(lbl, _, stype, _)| &**lbl == name && *stype != SegmentType::Unknown)
+            .map(|&(_, _, _, addr)| addr)
+    }
+    /// Gets the label defined exactly at `addr`, constants are not labels here
+    ///
+    /// If several labels share the address, the alphabetically first one is returned
+    pub fn label_at(&self, addr: u16) -> Option<&str> {
+        self.by_address().get(&addr).map(|&i| &*self.labels[i].0)
+    }
+    /// Gets the closest label at or before `addr` along with the address of that label
+    pub fn nearest_label_before(&self, addr: u16) -> Option<(&str, u16)> {
+        self.by_address()
+            .range(..=addr)
+            .next_back()
+            .map(|(&lbl_addr, &i)| (&*self.labels[i].0, lbl_addr))
+    }
+    /// The symbol table of the source, with addresses relative to the start of their segment
+    /// like while assembling
+    pub fn symbols(&self) -> Symbols {
+        Symbols::from_labels(self.labels.iter().map(|&(ref lbl, st, stype, addr)| {
+            let addr = (stype != SegmentType::Unknown).then(|| {
+                let start = self.dls.get(&stype).map(|dls| dls.start).unwrap_or(0);
+                Address(stype, addr.wrapping_sub(start))
+            });
+            (lbl.clone(), st, addr)
+        }))
+    }
+    /// Size of the stack segment, if there is one
+    pub fn stack_size(&self) -> Option<u16> {
+        self.dls.get(&SegmentType::Stack).map(|dls| dls.size)
+    }
+    /// Address just after the stack segment which is where the stack pointer starts
+    ///
+    /// A stack reaching the end of memory gives `0xffff`
+    pub fn stack_top_address(&self) -> Option<u16> {
+        self.dls
+            .get(&SegmentType::Stack)
+            .map(|dls| dls.start.saturating_add(dls.size))
+    }
+
+    fn by_address(&self) -> &BTreeMap<u16, usize> {
+        self.by_address.get_or_init(|| {
+            let mut map = BTreeMap::new();
+            for (i, (lbl, _, stype, addr)) in self.labels.iter().enumerate() {
+                // `.equ` constants are in the zero segment and are not at an address
+                if matches!(stype, SegmentType::Unknown | SegmentType::Zero) {
+                    continue;
+                }
+                map.entry(*addr)
+                    .and_modify(|j: &mut usize| {
+                        if *lbl < self.labels[*j].0 {
+                            *j = i;
+                        }
+                    })
+                    .or_insert(i);
+            }
+            map
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        aalv::obj::SegmentType,
+        source::{Address, ProcessedSource, SymbolType},
+    };
+
+    fn assemble(src: &str) -> ProcessedSource {
+        ProcessedSource::assemble(src).unwrap()
+    }
+
+    #[test]
+    fn forward_reference() {
+        let ps = assemble(".seg text\njmp end\nnop\nend:\nhalt\n");
+        assert_eq!(ps.find_label("end"), Some(0x85));
+        assert_eq!(ps.label_at(0x85), Some("end"));
+        assert_eq!(ps.find_label("start"), None);
+    }
+
+    #[test]
+    fn stack_segment() {
+        let ps = assemble(".seg text\nhalt\n.seg boot\nboot:\nnop\n.seg stack\n.byte 0, 0, 0, 0\n");
+        assert_eq!(ps.find_label("boot"), Some(0x80));
+        assert_eq!(ps.stack_size(), Some(4));
+        let stack_start = ps.dls[&SegmentType::Stack].start;
+        assert!(stack_start > ps.dls[&SegmentType::Text].start);
+        assert_eq!(ps.stack_top_address(), Some(stack_start + 4));
+
+        assert_eq!(assemble(".seg text\nhalt\n").stack_size(), None);
+    }
+
+    #[test]
+    fn duplicate_addresses() {
+        let ps = assemble(".seg text\nzeta:\nalpha:\nmid:\nhalt\n");
+        assert_eq!(ps.label_at(0x80), Some("alpha"));
+        assert_eq!(ps.nearest_label_before(0x80), Some(("alpha", 0x80)));
+    }
+
+    #[test]
+    fn constants_are_not_at_addresses() {
+        // SIZEOF_S is 0x81
+        let ps = assemble(".equ X, 0x80\n.struct S\n.field a, 0x81\n.ends\n\
+                           .seg text\nstart:\nnop\n");
+        assert_eq!(ps.label_at(0x80), Some("start"));
+        assert_eq!(ps.nearest_label_before(0x81), Some(("start", 0x80)));
+        assert_eq!(ps.nearest_label_before(0x7f), None);
+        assert_eq!(ps.find_label("X"), Some(0x80));
+    }
+
+    #[test]
+    fn moved_label() {
+        let mut ps = assemble(".seg text\nstart:\nnop\nend:\nhalt\n");
+        assert_eq!(ps.label_at(0x81), Some("end"));
+        ps.labels_mut()[1].3 = 0x82;
+        assert_eq!(ps.label_at(0x81), None);
+        assert_eq!(ps.label_at(0x82), Some("end"));
+    }
+
+    #[test]
+    fn symbol_table() {
+        let ps = assemble(".global main\n.ref ext\n.seg text\nmain:\nnop\n.loop:\ncall ext\n");
+        let symbols = ps.symbols();
+        assert_eq!(symbols.len(), 3);
+        assert_eq!(
+            symbols.get("main$.loop"),
+            Some((SymbolType::Internal, Some(Address(SegmentType::Text, 1))))
+        );
+        assert_eq!(symbols.get("ext"), Some((SymbolType::Reference, None)));
+        let names: Vec<_> = symbols.iter().map(|(lbl, _, _)| lbl).collect();
+        assert_eq!(names, ["main", "ext", "main$.loop"]);
+    }
+
+    #[test]
+    fn nearest_label() {
+        let ps = assemble(".seg text\n.ref ext\nstart:\nnop\nnop\nnext:\ncall ext\n");
+        assert_eq!(ps.nearest_label_before(0x7f), None);
+        assert_eq!(ps.nearest_label_before(0x81), Some(("start", 0x80)));
+        assert_eq!(ps.nearest_label_before(0x90), Some(("next", 0x82)));
+        assert_eq!(ps.find_label("ext"), None);
+    }
+}
@@ -0,0 +1,233 @@
+//! Mapping of addresses back to the source lines they were assembled from
+
+use std::{
+    collections::BTreeMap,
+    io::{self, Read, Write},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::aalv::obj::{LineEntry, LineNumberTable, SegmentType};
+
+use super::DataLineSegment;
+
+/// Extension of source map files
+pub const SOURCE_MAP_EXT: &str = "srcmap";
+/// Magic number at the start of a source map file
+pub const SOURCE_MAP_MAGIC: [u8; 4] = *b"TSRC";
+
+/// The source line that the bytes from `address` up to the next entry came from
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceMapEntry {
+    pub address: u16,
+    pub source: Box<str>,
+    pub line: u32,
+}
+
+/// Makes the entries sorted by address from the placed segments
+pub(super) fn build_source_map(
+    dls: &BTreeMap<SegmentType, DataLineSegment>,
+) -> Vec<SourceMapEntry> {
+    let mut entries: Vec<SourceMapEntry> = Vec::new();
+
+    for dls in dls.values() {
+        let mut address = dls.start;
+        for (line, loc) in dls.lines.iter().zip(&dls.locations) {
+            let size = line.size();
+            if size == 0 {
+                continue;
+            }
+            // a source line can give several data lines right after each other
+            let continues_last = entries.last().is_some_and(|last| {
+                *last.source == *loc.source() && last.line == loc.line_number()
+            });
+            if !continues_last {
+                entries.push(SourceMapEntry {
+                    address,
+                    source: loc.source().into(),
+                    line: loc.line_number(),
+                });
+            }
+            address = address.wrapping_add(size);
+        }
+    }
+    entries.sort_by_key(|e| e.address);
+
+    entries
+}
+
+/// Finds the entry covering `addr`
+///
+/// `entries` have to be sorted by address
+pub fn lookup_source(entries: &[SourceMapEntry], addr: u16) -> Option<&SourceMapEntry> {
+    let i = entries.partition_point(|e| e.address <= addr);
+    i.checked_sub(1).map(|i| &entries[i])
+}
+
+/// Makes the line number table of an object file from the entries
+///
+/// Gives `None` if the entries come from more source files than the table can refer to
+pub fn line_number_table(entries: &[SourceMapEntry]) -> Option<LineNumberTable> {
+    let mut table = LineNumberTable::default();
+    for entry in entries {
+        let file_id = match table.files.iter().position(|f| *f == entry.source) {
+            Some(i) => i,
+            None => {
+                table.files.push(entry.source.clone());
+                table.files.len() - 1
+            }
+        };
+        table.entries.push(LineEntry {
+            offset: entry.address,
+            file_id: u8::try_from(file_id).ok()?,
+            line: entry.line,
+        });
+    }
+
+    Some(table)
+}
+
+/// Gives the entries of a line number table, skipping those with an unknown file
+pub fn source_map_from_line_numbers(table: &LineNumberTable) -> Vec<SourceMapEntry> {
+    table
+        .entries
+        .iter()
+        .filter_map(|e| {
+            Some(SourceMapEntry {
+                address: e.offset,
+                source: table.files.get(e.file_id as usize)?.clone(),
+                line: e.line,
+            })
+        })
+        .collect()
+}
+
+/// Writes the entries compactly with each source file name only written once
+///
+/// The magic number is followed by the number of source files and the names of them,
+/// each name prefixed by its length, and then the number of entries followed by the entries as
+/// address, index of source file and line number. All numbers are little-endian and 16-bit
+/// except for line numbers that are 32-bit
+pub fn write_source_map(w: &mut dyn Write, entries: &[SourceMapEntry]) -> io::Result<()> {
+    fn write_len(w: &mut dyn Write, len: usize, what: &str) -> io::Result<()> {
+        let len = u16::try_from(len).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("too many {what} for a source map"))
+        })?;
+        w.write_all(&len.to_le_bytes())
+    }
+
+    let mut sources: Vec<&str> = Vec::new();
+    let mut indices = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let index = match sources.iter().position(|&s| s == &*entry.source) {
+            Some(i) => i,
+            None => {
+                sources.push(&entry.source);
+                sources.len() - 1
+            }
+        };
+        indices.push(index);
+    }
+
+    w.write_all(&SOURCE_MAP_MAGIC)?;
+    write_len(w, sources.len(), "source files")?;
+    for source in sources {
+        write_len(w, source.len(), "bytes in a source file name")?;
+        w.write_all(source.as_bytes())?;
+    }
+    write_len(w, entries.len(), "entries")?;
+    for (entry, index) in entries.iter().zip(indices) {
+        w.write_all(&entry.address.to_le_bytes())?;
+        // fits as there are fewer source files
+        w.write_all(&(index as u16).to_le_bytes())?;
+        w.write_all(&entry.line.to_le_bytes())?;
+    }
+
+    Ok(())
+}
+
+pub fn read_source_map(r: &mut dyn Read) -> io::Result<Vec<SourceMapEntry>> {
+    fn read_u16(r: &mut dyn Read) -> io::Result<u16> {
+        let mut buf = [0; 2];
+        r.read_exact(&mut buf)?;
+        Ok(u16::from_le_bytes(buf))
+    }
+    let invalid_data = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+    let mut magic = [0; 4];
+    r.read_exact(&mut magic)?;
+    if magic != SOURCE_MAP_MAGIC {
+        return Err(invalid_data("not a telda source map"));
+    }
+
+    let source_count = read_u16(r)?;
+    let mut sources = Vec::with_capacity(source_count as usize);
+    for _ in 0..source_count {
+        let mut name = vec![0; read_u16(r)? as usize];
+        r.read_exact(&mut name)?;
+        let name = String::from_utf8(name).map_err(|_| invalid_data("source name is not UTF-8"))?;
+        sources.push(name.into_boxed_str());
+    }
+
+    let entry_count = read_u16(r)?;
+    let mut entries = Vec::with_capacity(entry_count as usize);
+    for _ in 0..entry_count {
+        let address = read_u16(r)?;
+        let index = read_u16(r)?;
+        let mut line = [0; 4];
+        r.read_exact(&mut line)?;
+        let source = sources
+            .get(index as usize)
+            .ok_or_else(|| invalid_data("source index out of range"))?
+            .clone();
+        entries.push(SourceMapEntry {
+            address,
+            source,
+            line: u32::from_le_bytes(line),
+        });
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::ProcessedSource;
+
+    #[test]
+    fn maps_addresses_to_lines() {
+        let src =
+            ".seg text\n.entry\nmain:\nldi r1, 0x300\n\nadd r1, r1, r1\nhalt\n.seg data\n.byte 1, 2\n";
+        let ps = ProcessedSource::assemble(src).unwrap();
+        let text = ps.dls[&SegmentType::Text].start;
+        let data = ps.dls[&SegmentType::Data].start;
+
+        let lines: Vec<_> = ps.source_map.iter().map(|e| (e.address, e.line)).collect();
+        assert_eq!(lines, [(text, 4), (text + 4, 6), (text + 7, 7), (data, 9)]);
+        assert_eq!(lookup_source(&ps.source_map, text + 5).unwrap().line, 6);
+        assert_eq!(lookup_source(&ps.source_map, 0), None);
+
+        let mut buf = Vec::new();
+        write_source_map(&mut buf, &ps.source_map).unwrap();
+        assert_eq!(read_source_map(&mut &buf[..]).unwrap(), ps.source_map);
+
+        let table = line_number_table(&ps.source_map).unwrap();
+        assert_eq!(&*table.files, [Box::from("<input>")]);
+        assert_eq!(source_map_from_line_numbers(&table), ps.source_map);
+    }
+
+    #[test]
+    fn too_many_entries() {
+        let entry = |address| SourceMapEntry {
+            address,
+            source: "<input>".into(),
+            line: 1,
+        };
+        let mut entries: Vec<_> = (0..0xffff).map(entry).collect();
+        assert!(write_source_map(&mut Vec::new(), &entries).is_ok());
+        entries.push(entry(0xffff));
+        let e = write_source_map(&mut Vec::new(), &entries).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -1,14 +1,18 @@
 use std::{
+    borrow::Cow,
     fmt::{self, Display},
     iter, mem,
 };
 
+use serde::{Deserialize, Serialize};
+
 use crate::aalv::obj::SegmentType;
 
 use super::{Error, ErrorType, Result as SourceResult, SourceLocation};
 
+/// A position in a segment
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
-pub(super) struct Address(pub SegmentType, pub u16);
+pub struct Address(pub SegmentType, pub u16);
 
 impl Display for Address {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -21,19 +25,29 @@ pub struct LabelRead {
     pub position: u16,
 }
 
-#[derive(Debug, Clone, Copy, Default)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
 #[repr(u8)]
 pub enum SymbolType {
     #[default]
     Internal,
+    /// Global, but another object defining it as [`SymbolType::Global`] takes precedence
+    Weak,
     Global,
     Reference,
 }
 impl SymbolType {
+    fn set_weak(&mut self) {
+        use self::SymbolType::*;
+        match *self {
+            Internal => *self = Weak,
+            Weak | Global | Reference => (),
+        }
+    }
     fn set_global(&mut self) {
         use self::SymbolType::*;
         match *self {
-            Internal => *self = Global,
+            // the strong definition wins
+            Internal | Weak => *self = Global,
             Reference => (),
             Global => (),
         }
@@ -41,25 +55,54 @@ impl SymbolType {
     fn set_reference(&mut self) {
         use self::SymbolType::*;
         match *self {
-            Internal => *self = Reference,
-            Global => *self = Reference,
+            Internal | Weak | Global => *self = Reference,
             Reference => (),
         }
     }
 }
 
-pub(super) struct Symbols {
+/// The labels of a source as they are being assembled
+///
+/// Local labels are named `scope$.local` after the label they belong to
+pub struct Symbols {
     labels: Vec<Box<str>>,
     id_to_pos: Vec<Result<Address, Vec<SourceLocation>>>,
     symbol_types: Vec<SymbolType>,
+    /// Where each label was defined and whether it has been read
+    definitions: Vec<(Option<SourceLocation>, bool)>,
+    /// The last defined non-local label, which local labels belong to
+    scope: Option<Box<str>>,
 }
 
 impl Symbols {
-    pub fn new() -> Self {
+    pub(super) fn new() -> Self {
         Self {
             labels: Vec::new(),
             symbol_types: Vec::new(),
             id_to_pos: Vec::new(),
+            definitions: Vec::new(),
+            scope: None,
+        }
+    }
+    /// Makes a table of labels that are already assembled, `None` being an undefined reference
+    pub(super) fn from_labels(
+        labels: impl IntoIterator<Item = (Box<str>, SymbolType, Option<Address>)>,
+    ) -> Self {
+        let mut symbols = Self::new();
+        for (lbl, st, addr) in labels {
+            symbols.labels.push(lbl);
+            symbols.symbol_types.push(st);
+            symbols.id_to_pos.push(addr.ok_or_else(Vec::new));
+            symbols.definitions.push((None, false));
+        }
+        symbols
+    }
+    /// Gives the name a label is stored under,
+    /// local labels (starting with `.`) are prefixed by the label they belong to as `scope$.local`
+    fn scoped<'a>(&self, lbl: &'a str) -> Cow<'a, str> {
+        match &self.scope {
+            Some(scope) if lbl.starts_with('.') => format!("{scope}${lbl}").into(),
+            _ => lbl.into(),
         }
     }
     fn find_id(&mut self, lbl: &str) -> usize {
@@ -69,11 +112,24 @@ impl Symbols {
             let i = self.labels.len();
             self.labels.push(lbl.to_owned().into_boxed_str());
             self.id_to_pos.push(Err(Vec::new()));
+            self.definitions.push((None, false));
             i
         }
     }
-    pub fn set_label(&mut self, lbl: &str, addr: Address, loc: SourceLocation) -> SourceResult<()> {
+    pub(super) fn set_label(
+        &mut self,
+        lbl: &str,
+        addr: Address,
+        loc: SourceLocation,
+    ) -> SourceResult<()> {
+        if !lbl.starts_with('.') {
+            self.scope = Some(lbl.into());
+        }
+        let lbl = &*self.scoped(lbl);
         let id = self.find_id(lbl);
+        if self.definitions[id].0.is_none() {
+            self.definitions[id].0 = Some(loc.clone());
+        }
 
         match mem::replace(&mut self.id_to_pos[id], Ok(addr)) {
             Ok(cur_addr) => Err(Error::new(
@@ -87,8 +143,10 @@ impl Symbols {
             Err(_) => Ok(()),
         }
     }
-    pub fn get_label(&mut self, lbl: &str, loc: SourceLocation) -> usize {
-        let id = self.find_id(lbl);
+    pub(super) fn get_label(&mut self, lbl: &str, loc: SourceLocation) -> usize {
+        let lbl = self.scoped(lbl).into_owned();
+        let id = self.find_id(&lbl);
+        self.definitions[id].1 = true;
         match &mut self.id_to_pos[id] {
             Ok(_) => (),
             Err(v) => v.push(loc),
@@ -96,22 +154,71 @@ impl Symbols {
 
         id
     }
-    pub fn set_global(&mut self, id: usize) {
+    /// Whether the label has been defined yet
+    pub(super) fn is_defined(&self, lbl: &str) -> bool {
+        let lbl = self.scoped(lbl);
+        self.labels
+            .iter()
+            .position(|l| **l == *lbl)
+            .is_some_and(|id| self.id_to_pos[id].is_ok())
+    }
+    /// Internal labels that were defined but never read
+    pub(super) fn unused(&self) -> impl Iterator<Item = (&str, &SourceLocation)> {
+        self.labels
+            .iter()
+            .zip(&self.definitions)
+            .enumerate()
+            .filter_map(|(id, (lbl, (def, used)))| {
+                let st = self.symbol_types.get(id).copied().unwrap_or_default();
+                match (def, used, st) {
+                    (Some(loc), false, SymbolType::Internal) => Some((&**lbl, loc)),
+                    _ => None,
+                }
+            })
+    }
+    pub(super) fn set_weak(&mut self, id: usize) {
+        if id >= self.symbol_types.len() {
+            self.symbol_types.resize(id + 1, SymbolType::default());
+        }
+        self.symbol_types[id].set_weak();
+    }
+    pub(super) fn set_global(&mut self, id: usize) {
         if id >= self.symbol_types.len() {
             self.symbol_types.resize(id + 1, SymbolType::default());
         }
         self.symbol_types[id].set_global();
     }
-    pub fn set_reference(&mut self, id: usize) {
+    pub(super) fn set_reference(&mut self, id: usize) {
         if id >= self.symbol_types.len() {
             self.symbol_types.resize(id + 1, SymbolType::default());
         }
         self.symbol_types[id].set_reference();
     }
-    pub fn size(&self) -> usize {
+    /// Number of labels, including the ones only referred to
+    pub fn len(&self) -> usize {
         self.labels.len()
     }
-    pub fn into_iter(
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+    /// Gives the type of the label and its address if it has been defined
+    pub fn get(&self, name: &str) -> Option<(SymbolType, Option<Address>)> {
+        let id = self.labels.iter().position(|l| **l == *name)?;
+        Some((
+            self.symbol_type(id),
+            self.id_to_pos[id].as_ref().ok().copied(),
+        ))
+    }
+    pub fn iter(&self) -> SymbolsIter<'_> {
+        SymbolsIter {
+            symbols: self,
+            id: 0,
+        }
+    }
+    fn symbol_type(&self, id: usize) -> SymbolType {
+        self.symbol_types.get(id).copied().unwrap_or_default()
+    }
+    pub(super) fn into_iter(
         self,
     ) -> impl Iterator<Item = (Box<str>, SymbolType, Result<Address, Vec<SourceLocation>>)> {
         self.labels
@@ -125,3 +232,97 @@ impl Symbols {
             .map(|(a, (b, c))| (a, b, c))
     }
 }
+
+/// Iterator over the labels with their type and address if they have been defined
+pub struct SymbolsIter<'a> {
+    symbols: &'a Symbols,
+    id: usize,
+}
+
+impl<'a> Iterator for SymbolsIter<'a> {
+    type Item = (&'a str, SymbolType, Option<Address>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let symbols = self.symbols;
+        let lbl = symbols.labels.get(self.id)?;
+        let addr = symbols.id_to_pos[self.id].as_ref().ok().copied();
+        let st = symbols.symbol_type(self.id);
+        self.id += 1;
+        Some((lbl, st, addr))
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let left = self.symbols.len() - self.id;
+        (left, Some(left))
+    }
+}
+
+impl<'a> IntoIterator for &'a Symbols {
+    type Item = (&'a str, SymbolType, Option<Address>);
+    type IntoIter = SymbolsIter<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl fmt::Debug for Symbols {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut map = f.debug_map();
+        for (lbl, st, addr) in self {
+            match addr {
+                Some(addr) => map.entry(&lbl, &format_args!("{st:?} at {addr}")),
+                None => map.entry(&lbl, &format_args!("{st:?}, undefined")),
+            };
+        }
+        map.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn iterate_symbols() {
+        let loc = SourceLocation::new("<input>", 1);
+        let mut symbols = Symbols::new();
+        symbols
+            .set_label("main", Address(SegmentType::Text, 4), loc.clone())
+            .unwrap();
+        symbols
+            .set_label(".loop", Address(SegmentType::Text, 6), loc.clone())
+            .unwrap();
+        let ext = symbols.get_label("ext", loc);
+        symbols.set_reference(ext);
+        symbols.set_global(0);
+
+        assert_eq!(symbols.len(), 3);
+        assert_eq!(
+            symbols.get("main$.loop"),
+            Some((SymbolType::Internal, Some(Address(SegmentType::Text, 6))))
+        );
+        assert_eq!(symbols.get(".loop"), None);
+        let all: Vec<_> = symbols.iter().collect();
+        assert_eq!(
+            all,
+            [
+                (
+                    "main",
+                    SymbolType::Global,
+                    Some(Address(SegmentType::Text, 4))
+                ),
+                (
+                    "main$.loop",
+                    SymbolType::Internal,
+                    Some(Address(SegmentType::Text, 6))
+                ),
+                ("ext", SymbolType::Reference, None),
+            ]
+        );
+        assert_eq!(
+            format!("{symbols:?}"),
+            "{\"main\": Global at <text+0x04>, \"main$.loop\": Internal at <text+0x06>, \
+             \"ext\": Reference, undefined}"
+        );
+    }
+}
@@ -0,0 +1,99 @@
+use crate::blf4::{isa::*, R1};
+
+use super::{DataLine, DataOperand, ProcessedSource, Warning, WarningType};
+
+/// Whether execution never continues to the instruction after this one
+fn ends_flow(opcode: u8, dat_op: DataOperand) -> bool {
+    match (opcode, dat_op) {
+        (HALT | RET | RET_W | RETH, _) => true,
+        // mode 1 of `ldi` is `jmp`
+        (LDI_W, DataOperand::TwoWideImm(_, mode, _)) => mode == R1,
+        _ => false,
+    }
+}
+
+impl ProcessedSource {
+    /// Warns about instructions after a `halt`, `ret`, `reth` or `jmp` that come before any label
+    ///
+    /// Data lines in between are not counted. A label like `.allow_unreachable:` can be put in
+    /// front of code that is not meant to be reached this way, such as padding
+    pub fn unreachable_code(&self) -> Vec<Warning> {
+        let mut warnings = Vec::new();
+
+        for (&st, dls) in &self.dls {
+            let mut addr = dls.start;
+            // the instruction that ended the flow, the first unreachable one after it and how
+            // many there are
+            let mut after = None;
+            let mut first = None;
+            let mut count = 0;
+            let mut flush = |after: &mut Option<usize>, first: &mut Option<usize>, count| {
+                if let (Some(a), Some(f)) = (after.take(), first.take()) {
+                    warnings.push(Warning::new(
+                        dls.locations[f].clone(),
+                        WarningType::UnreachableCode {
+                            after: dls.locations[a].clone(),
+                            count,
+                        },
+                    ));
+                }
+            };
+
+            for (i, line) in dls.lines.iter().enumerate() {
+                let labelled = self.labels.iter().any(|l| l.2 == st && l.3 == addr)
+                    || self
+                        .entry
+                        .as_ref()
+                        .is_some_and(|e| e.0 == st && e.1 == addr);
+                if labelled {
+                    flush(&mut after, &mut first, count);
+                    count = 0;
+                }
+                addr = addr.wrapping_add(line.size());
+
+                let &DataLine::Ins(opcode, dat_op) = line else {
+                    continue;
+                };
+                if after.is_some() {
+                    first.get_or_insert(i);
+                    count += 1;
+                } else if ends_flow(opcode, dat_op) {
+                    after = Some(i);
+                }
+            }
+            flush(&mut after, &mut first, count);
+        }
+
+        warnings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::{process, Recovery, SourceLines};
+
+    fn unreachable(src: &str) -> Vec<(u32, u32, usize)> {
+        let (ps, _, _) = process(SourceLines::from_str(src), Recovery::Continue);
+        ps.unwrap()
+            .unreachable_code()
+            .iter()
+            .map(|w| match w.warning_type() {
+                WarningType::UnreachableCode { after, count } => {
+                    (w.location().line_number(), after.line_number(), *count)
+                }
+                w => panic!("unexpected warning {w:?}"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn after_jumps() {
+        let src = ".seg text\n.entry\nmain:\njmp main\nnop\nnop\nnext:\nhalt\n.byte 1\nnop\n";
+        assert_eq!(unreachable(src), [(5, 4, 2), (10, 8, 1)]);
+
+        // a conditional jump or a jump to a register that is followed by a label is fine
+        let src = ".seg text\nmain:\njez main\nnop\njmp r1\n.allow_unreachable:\nnop\nret 0\n";
+        assert_eq!(unreachable(src), []);
+    }
+}
@@ -0,0 +1,124 @@
+//! Map files (.map) with the placement of each segment and symbol, made by `tc --map`
+//!
+//! Each line is an entry of tab-separated fields, the first field says what kind of entry it is:
+//! `segment`, `global`, `weak`, `internal` or `undefined`
+
+use std::io::{self, BufRead, Write};
+
+use crate::{
+    aalv::obj::SegmentType,
+    source::{ProcessedSource, SymbolType},
+};
+
+/// Extension of map files
+pub const MAP_EXT: &str = "map";
+
+/// Writes where segments and symbols were placed
+///
+/// Segments are written as `segment <name> <start> <size>`,
+/// then the defined symbols sorted by address as
+/// `<global|weak|internal> <address> <segment> <function|data> <name>`
+/// and lastly references to symbols from other objects as `undefined <name>`
+pub fn write_map(w: &mut dyn Write, src: &ProcessedSource) -> io::Result<()> {
+    for (st, dls) in &src.dls {
+        writeln!(w, "segment\t{st}\t0x{:04x}\t0x{:04x}", dls.start, dls.size)?;
+    }
+
+    let mut defined: Vec<_> = src
+        .labels()
+        .iter()
+        .filter(|(_, st, _, _)| !matches!(st, SymbolType::Reference))
+        .collect();
+    defined.sort_by_key(|&&(ref lbl, _, _, addr)| (addr, lbl.clone()));
+    for (lbl, st, segment, addr) in defined {
+        let visibility = match st {
+            SymbolType::Global => "global",
+            SymbolType::Weak => "weak",
+            _ => "internal",
+        };
+        let kind = match segment {
+            SegmentType::Text => "function",
+            _ => "data",
+        };
+        writeln!(w, "{visibility}\t0x{addr:04x}\t{segment}\t{kind}\t{lbl}")?;
+    }
+
+    for (lbl, ..) in src
+        .labels()
+        .iter()
+        .filter(|(_, st, _, _)| matches!(st, SymbolType::Reference))
+    {
+        writeln!(w, "undefined\t{lbl}")?;
+    }
+
+    Ok(())
+}
+
+/// Reads the defined symbols of a map file as their name, whether they are global and address
+///
+/// Other entries are skipped
+pub fn parse_sym_file(r: impl BufRead) -> io::Result<Vec<(Box<str>, bool, u16)>> {
+    let mut symbols = Vec::new();
+    for (i, line) in r.lines().enumerate() {
+        let line = line?;
+        let mut fields = line.split('\t');
+        let is_global = match fields.next() {
+            Some("global" | "weak") => true,
+            Some("internal") => false,
+            _ => continue,
+        };
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid symbol on line {}", i + 1),
+            )
+        };
+        let addr = fields
+            .next()
+            .and_then(|a| a.strip_prefix("0x"))
+            .and_then(|a| u16::from_str_radix(a, 16).ok())
+            .ok_or_else(invalid)?;
+        // the segment and kind come before the name
+        let name = fields.nth(2).ok_or_else(invalid)?;
+        symbols.push((name.into(), is_global, addr));
+    }
+    Ok(symbols)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let src = "\
+.global main
+.ref ext
+.seg text
+.entry
+main:
+    call ext
+.loop:
+    jmp .loop
+.seg data 0x8000
+counter:
+.wide 0
+";
+        let ps = ProcessedSource::assemble(src).unwrap();
+        let mut map = Vec::new();
+        write_map(&mut map, &ps).unwrap();
+
+        let symbols = parse_sym_file(&map[..]).unwrap();
+        assert_eq!(
+            symbols,
+            [
+                ("main".into(), true, 0x80),
+                ("main$.loop".into(), false, 0x83),
+                ("counter".into(), false, 0x8000),
+            ]
+        );
+
+        let err = parse_sym_file(&b"segment\ttext\nglobal\t12\ttext\tfunction\tx\n"[..]);
+        assert_eq!(err.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
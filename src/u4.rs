@@ -1,9 +1,32 @@
+use std::{
+    fmt::{self, Display},
+    ops::Add,
+};
+
+use serde::{Deserialize, Serialize};
+
 #[repr(transparent)]
-#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Default, Hash)]
+#[derive(
+    Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Default, Hash, Serialize, Deserialize,
+)]
+#[serde(try_from = "u8", into = "u8")]
 pub struct U4(u8);
 
+/// A value did not fit in a [`U4`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct U4Error(pub u8);
+
+impl Display for U4Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "value {} was too big for u4", self.0)
+    }
+}
+
+impl std::error::Error for U4Error {}
+
 impl U4 {
     pub const ZERO: Self = Self::new_unchecked(0);
+    pub const MAX: Self = Self::new_unchecked(0xf);
     pub fn new(n: u8) -> Self {
         if n <= 0xf {
             U4(n)
@@ -15,11 +38,34 @@ impl U4 {
         debug_assert!(n <= 0xf);
         Self(n)
     }
-    pub const fn paired(n: u8) -> (U4, U4) {
+    /// `None` if the value is above 15
+    pub const fn from_u8_checked(n: u8) -> Option<Self> {
+        if n <= 0xf {
+            Some(U4(n))
+        } else {
+            None
+        }
+    }
+    /// Splits a byte into its high and low nibble
+    pub const fn to_nibbles(n: u8) -> (U4, U4) {
         (Self::new_unchecked(n >> 4), Self::new_unchecked(n & 0xf))
     }
+    /// Puts two nibbles together into a byte, the inverse of [`Self::to_nibbles`]
+    pub const fn join(hi: U4, lo: U4) -> u8 {
+        (hi.0 << 4) | lo.0
+    }
+    /// This as the high nibble and `other` as the low nibble of a byte, see [`Self::join`]
     pub const fn pair(self, other: Self) -> u8 {
-        (self.0 << 4) | other.0
+        Self::join(self, other)
+    }
+}
+
+/// Wraps around past 15
+impl Add for U4 {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self {
+        U4((self.0 + rhs.0) & 0xf)
     }
 }
 
@@ -28,3 +74,29 @@ impl From<U4> for u8 {
         n
     }
 }
+
+impl TryFrom<u8> for U4 {
+    type Error = U4Error;
+
+    fn try_from(n: u8) -> Result<Self, Self::Error> {
+        Self::from_u8_checked(n).ok_or(U4Error(n))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nibbles() {
+        let (hi, lo) = U4::to_nibbles(0xa5);
+        assert_eq!((u8::from(hi), u8::from(lo)), (0xa, 0x5));
+        assert_eq!(U4::join(hi, lo), 0xa5);
+        assert_eq!(lo.pair(hi), 0x5a);
+
+        assert_eq!(U4::try_from(15), Ok(U4::MAX));
+        assert_eq!(U4::try_from(16), Err(U4Error(16)));
+        assert_eq!(U4::from_u8_checked(3), Some(U4::new(3)));
+        assert_eq!(U4::new(9) + U4::new(9), U4::new(2));
+    }
+}